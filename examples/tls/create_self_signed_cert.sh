@@ -61,3 +61,12 @@ openssl pkcs12 -export -out identity.pfx -inkey server.key -in server.crt -certf
 
 # clean up
 rm server.csr csr.conf cert.conf
+
+# Optional. Create a client certificate for mutual authentication, which is
+# set as `client.transport.tls.client_cert` and `client_key`, with `rootCA.crt`
+# as `server.transport.tls.ca`
+openssl req -new -nodes -newkey rsa:2048 -subj "/CN=rathole-client" \
+    -keyout client.key -out client.csr
+openssl x509 -req -in client.csr -CA rootCA.crt -CAkey rootCA.key \
+    -out client.crt -days 365 -sha256
+rm client.csr
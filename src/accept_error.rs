@@ -0,0 +1,125 @@
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{error, warn};
+
+// At most one log of accept errors in every interval
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handles errors of `accept()` in a loop.
+///
+/// Running out of fds or memory doesn't go away by retrying right away, which
+/// only makes the loop spin. So the loop backs off for a while on such errors.
+/// Other errors concern a single connection, and the loop continues at once.
+/// Either way, logs are throttled.
+#[derive(Debug)]
+pub struct AcceptErrorHandler {
+    backoff: Duration,
+    last_log: Option<Instant>,
+    // Errors not logged since the last log
+    suppressed: u64,
+}
+
+impl AcceptErrorHandler {
+    pub fn new(backoff_ms: u64) -> AcceptErrorHandler {
+        AcceptErrorHandler {
+            backoff: Duration::from_millis(backoff_ms),
+            last_log: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Handle an accept error, sleeping if the loop should back off
+    pub async fn handle(&mut self, e: &io::Error) {
+        if let Some(d) = self.handle_at(e, Instant::now()) {
+            time::sleep(d).await;
+        }
+    }
+
+    // Log the error if not throttled. Returns how long to back off
+    fn handle_at(&mut self, e: &io::Error, now: Instant) -> Option<Duration> {
+        let exhausted = is_resource_exhausted(e);
+
+        match self.last_log {
+            Some(t) if now.duration_since(t) < LOG_INTERVAL => self.suppressed += 1,
+            _ => {
+                if exhausted {
+                    error!(
+                        "Failed to accept: {}. Retry in {:?}. {} similar errors suppressed",
+                        e, self.backoff, self.suppressed
+                    );
+                } else {
+                    warn!(
+                        "Failed to accept: {}. {} similar errors suppressed",
+                        e, self.suppressed
+                    );
+                }
+                self.last_log = Some(now);
+                self.suppressed = 0;
+            }
+        }
+
+        exhausted.then_some(self.backoff)
+    }
+}
+
+// EMFILE, ENFILE, ENOMEM or ENOBUFS
+#[cfg(unix)]
+fn is_resource_exhausted(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOMEM | libc::ENOBUFS)
+    )
+}
+
+// Not worth telling errors apart. Backing off on every error is safe
+#[cfg(not(unix))]
+fn is_resource_exhausted(_e: &io::Error) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_accept_error_handler() {
+        let mut h = AcceptErrorHandler::new(50);
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+        let start = Instant::now();
+
+        // Every EMFILE backs off, but only the first one is logged
+        for i in 0..10 {
+            let now = start + Duration::from_millis(i * 50);
+            assert_eq!(h.handle_at(&emfile, now), Some(Duration::from_millis(50)));
+        }
+        assert_eq!(h.suppressed, 9);
+
+        // The next log reports the suppressed errors
+        h.handle_at(&emfile, start + LOG_INTERVAL);
+        assert_eq!(h.suppressed, 0);
+
+        // An aborted connection doesn't stop the loop
+        let aborted = io::Error::from_raw_os_error(libc::ECONNABORTED);
+        assert_eq!(h.handle_at(&aborted, start + LOG_INTERVAL), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_accept_loop_backs_off() {
+        let mut h = AcceptErrorHandler::new(20);
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+
+        // A loop hitting EMFILE over and over
+        let start = Instant::now();
+        let mut n = 0;
+        while start.elapsed() < Duration::from_millis(200) {
+            h.handle(&emfile).await;
+            n += 1;
+        }
+
+        // Instead of spinning, it tries about once per backoff
+        assert!(n <= 10, "accept retried {} times", n);
+    }
+}
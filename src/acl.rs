@@ -0,0 +1,62 @@
+use crate::config::{Cidr, ServerServiceConfig};
+use std::net::IpAddr;
+
+/// Decides which source networks can visit a service
+#[derive(Debug)]
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Acl {
+    /// Create an ACL for the service, if `allow` or `deny` is set
+    pub fn from_service_cfg(cfg: &ServerServiceConfig) -> Option<Acl> {
+        (!cfg.allow.is_empty() || !cfg.deny.is_empty()).then(|| Acl {
+            allow: cfg.allow.clone(),
+            deny: cfg.deny.clone(),
+        })
+    }
+
+    /// `deny` takes precedence over `allow`. An empty `allow` allows all
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        let mut cfg = ServerServiceConfig::with_name("foo");
+        let cidrs = |v: &[&str]| {
+            v.iter()
+                .map(|s| Cidr::try_from(s.to_string()).unwrap())
+                .collect()
+        };
+        cfg.allow = cidrs(allow);
+        cfg.deny = cidrs(deny);
+        Acl::from_service_cfg(&cfg).unwrap()
+    }
+
+    #[test]
+    fn test_acl() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let a = acl(&["10.0.0.0/8", "192.168.1.1"], &["10.0.0.0/24"]);
+        assert!(a.allows(ip("10.1.2.3")));
+        assert!(a.allows(ip("192.168.1.1")));
+        assert!(!a.allows(ip("10.0.0.5")));
+        assert!(!a.allows(ip("172.16.0.1")));
+
+        // Only denying
+        let a = acl(&[], &["203.0.113.0/24"]);
+        assert!(a.allows(ip("10.1.2.3")));
+        assert!(!a.allows(ip("203.0.113.7")));
+
+        assert!(Acl::from_service_cfg(&ServerServiceConfig::with_name("foo")).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Service label used for failures against services that don't exist.
+/// Keeps the table bounded no matter how many digests are probed.
+pub const UNKNOWN_SERVICE: &str = "<unknown>";
+
+#[derive(Debug, Default)]
+struct Stat {
+    total: u64,
+    suppressed: u64,
+    last_reported: Option<Instant>,
+}
+
+/// What to put in an auth failure event
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuthFailureEvent {
+    // Failures of the service since startup
+    pub total: u64,
+    // Failures of the service not reported since the last event
+    pub suppressed: u64,
+}
+
+/// Counts control channel authentication failures per service, and decides
+/// when a failure should be reported, so that an attack doesn't flood the log.
+/// At most one event per service is emitted in every `interval`.
+#[derive(Debug)]
+pub struct AuthFailureTracker {
+    interval: Duration,
+    services: HashMap<String, Stat>,
+}
+
+impl AuthFailureTracker {
+    pub fn new(interval: Duration) -> AuthFailureTracker {
+        AuthFailureTracker {
+            interval,
+            services: HashMap::new(),
+        }
+    }
+
+    /// Record a failure of `service`. Returns an event if it should be reported
+    pub fn record(&mut self, service: &str) -> Option<AuthFailureEvent> {
+        self.record_at(service, Instant::now())
+    }
+
+    fn record_at(&mut self, service: &str, now: Instant) -> Option<AuthFailureEvent> {
+        let stat = self.services.entry(service.to_string()).or_default();
+        stat.total += 1;
+
+        match stat.last_reported {
+            Some(t) if now.duration_since(t) < self.interval => {
+                stat.suppressed += 1;
+                None
+            }
+            _ => {
+                stat.last_reported = Some(now);
+                let suppressed = stat.suppressed;
+                stat.suppressed = 0;
+                Some(AuthFailureEvent {
+                    total: stat.total,
+                    suppressed,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_failure_tracker() {
+        let mut t = AuthFailureTracker::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        // The first failure is always reported
+        assert_eq!(
+            t.record_at("foo", start),
+            Some(AuthFailureEvent {
+                total: 1,
+                suppressed: 0
+            })
+        );
+
+        // Repeated failures are counted but not reported
+        for i in 1..5 {
+            assert_eq!(t.record_at("foo", start + Duration::from_secs(i)), None);
+        }
+
+        // Other services are rate-limited and counted independently
+        assert_eq!(
+            t.record_at("bar", start),
+            Some(AuthFailureEvent {
+                total: 1,
+                suppressed: 0
+            })
+        );
+
+        // Once the interval passes, the next failure is reported with the suppressed count
+        assert_eq!(
+            t.record_at("foo", start + Duration::from_secs(10)),
+            Some(AuthFailureEvent {
+                total: 6,
+                suppressed: 4
+            })
+        );
+    }
+}
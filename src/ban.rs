@@ -0,0 +1,145 @@
+use crate::config::ServerConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// Forget expired peers once this many are tracked, so that the table stays bounded
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Peer {
+    // Failures since `since`
+    failures: u32,
+    since: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Temporarily bans source IPs that fail handshakes too often, like ones guessing tokens.
+/// `threshold` failures within `duration` ban the IP for `duration`.
+#[derive(Debug)]
+pub struct BanList {
+    threshold: u32,
+    duration: Duration,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+impl BanList {
+    pub fn new(threshold: u32, duration: Duration) -> BanList {
+        BanList {
+            threshold,
+            duration,
+            peers: Default::default(),
+        }
+    }
+
+    /// Create a ban list, if `server.ban_threshold` is set
+    pub fn from_server_cfg(cfg: &ServerConfig) -> Option<BanList> {
+        cfg.ban_threshold
+            .map(|threshold| BanList::new(threshold, Duration::from_secs(cfg.ban_duration)))
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let peers = self.peers.lock().unwrap();
+        matches!(peers.get(&ip), Some(Peer { banned_until: Some(t), .. }) if now < *t)
+    }
+
+    /// Record a failed handshake from `ip`
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.record_failure_at(ip, Instant::now()) {
+            warn!(
+                event = "ban",
+                %ip,
+                "Banned for {:?} after {} failed handshakes",
+                self.duration,
+                self.threshold
+            );
+        }
+    }
+
+    // Returns true if `ip` gets banned by the failure
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.len() >= PRUNE_THRESHOLD {
+            peers.retain(|_, p| {
+                now.duration_since(p.since) < self.duration
+                    || p.banned_until.is_some_and(|t| now < t)
+            });
+        }
+
+        let peer = peers.entry(ip).or_insert(Peer {
+            failures: 0,
+            since: now,
+            banned_until: None,
+        });
+        if peer.banned_until.is_some_and(|t| now < t) {
+            return false;
+        }
+        // Start over once the window or the ban has passed
+        if now.duration_since(peer.since) >= self.duration || peer.banned_until.is_some() {
+            *peer = Peer {
+                failures: 0,
+                since: now,
+                banned_until: None,
+            };
+        }
+
+        peer.failures += 1;
+        if peer.failures >= self.threshold {
+            peer.banned_until = Some(now + self.duration);
+            return true;
+        }
+        false
+    }
+
+    /// Forget the failures of `ip`, since it has passed a handshake
+    pub fn forgive(&self, ip: IpAddr) {
+        self.peers.lock().unwrap().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban() {
+        let bans = BanList::new(3, Duration::from_secs(60));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(!bans.record_failure_at(a, now));
+        assert!(!bans.record_failure_at(a, now));
+        assert!(!bans.is_banned_at(a, now));
+        assert!(bans.record_failure_at(a, now));
+        assert!(bans.is_banned_at(a, now));
+        assert!(!bans.is_banned_at(b, now));
+
+        // The ban expires
+        let later = now + Duration::from_secs(61);
+        assert!(!bans.is_banned_at(a, later));
+        assert!(!bans.record_failure_at(a, later));
+    }
+
+    #[test]
+    fn test_window() {
+        let bans = BanList::new(2, Duration::from_secs(60));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        // Failures far apart don't add up
+        assert!(!bans.record_failure_at(a, now));
+        assert!(!bans.record_failure_at(a, now + Duration::from_secs(61)));
+
+        // Nor do failures before a successful handshake
+        bans.forgive(a);
+        assert!(!bans.record_failure_at(a, now + Duration::from_secs(62)));
+        assert!(bans.record_failure_at(a, now + Duration::from_secs(63)));
+    }
+}
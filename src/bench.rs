@@ -0,0 +1,252 @@
+use crate::config::{ClientConfig, Config, ServiceType};
+use crate::config_watcher::STDIN_PATH;
+use crate::embed::Client;
+use crate::event::Event;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::time::{self, Duration};
+
+// Round trips to take the percentiles of RTT from
+const RTT_SAMPLES: usize = 100;
+// The size of each write when measuring the throughput
+const CHUNK_SIZE: usize = 64 * 1024;
+// Timeout for the control channel to be established, and for every round trip
+const TIMEOUT_SECS: u64 = 10;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What's measured of a service
+#[derive(Debug)]
+pub struct Measurement {
+    // From starting the client to the control channel being established
+    handshake: Duration,
+    // The first round trip of a visitor, which waits for a data channel
+    first_round_trip: Duration,
+    // Sorted
+    rtts: Vec<Duration>,
+    // Bytes sent and echoed back per second
+    throughput: f64,
+}
+
+impl Measurement {
+    fn rtt(&self, percentile: usize) -> Duration {
+        let i = (self.rtts.len() * percentile / 100).min(self.rtts.len() - 1);
+        self.rtts[i]
+    }
+}
+
+impl Display for Measurement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Control channel handshake: {:?}", self.handshake)?;
+        writeln!(f, "First round trip:          {:?}", self.first_round_trip)?;
+        writeln!(
+            f,
+            "RTT:                       p50 {:?}, p90 {:?}, p99 {:?}",
+            self.rtt(50),
+            self.rtt(90),
+            self.rtt(99)
+        )?;
+        writeln!(
+            f,
+            "Throughput:                {:.2} MiB/s ({:.2} Mbit/s)",
+            self.throughput / (1024.0 * 1024.0),
+            self.throughput * 8.0 / 1_000_000.0
+        )
+    }
+}
+
+/// Measure `service` of the client configuration at `path`, and print the result.
+/// Visitors reach the service at `addr`, or `remote_port` of the service at the host of `remote_addr`
+pub async fn run_bench(path: &Path, service: &str, addr: Option<&str>, secs: u64) -> Result<()> {
+    let config = if path == Path::new(STDIN_PATH) {
+        Config::from_stdin(&[]).await?
+    } else {
+        Config::from_file(path).await?
+    };
+    let client = config
+        .client
+        .ok_or_else(|| anyhow!("No `[client]` block. Only clients can be benchmarked"))?;
+    println!(
+        "Benchmarking {} over {:?}",
+        service, client.transport.transport_type
+    );
+    let m = bench(client, service, addr, secs).await?;
+    print!("{}", m);
+    Ok(())
+}
+
+// Run `service` as an echo service, and send traffic to it through the server
+async fn bench(
+    mut client: ClientConfig,
+    service: &str,
+    addr: Option<&str>,
+    secs: u64,
+) -> Result<Measurement> {
+    let mut s = client
+        .services
+        .remove(service)
+        .ok_or_else(|| anyhow!("No service {} in the configuration", service))?;
+    let addr = match addr {
+        Some(v) => v.to_string(),
+        None => {
+            let port = s.remote_port.ok_or_else(|| {
+                anyhow!("Unknown where visitors reach the service. Please specify `--bench-addr`")
+            })?;
+            let remote_addr = client.remote_addr.iter().next().unwrap();
+            let host = remote_addr
+                .rsplit_once(':')
+                .map_or(remote_addr.as_str(), |v| v.0);
+            format!("{}:{}", host, port)
+        }
+    };
+
+    // Echo instead of forwarding to `local_addr`
+    s.service_type = ServiceType::Echo;
+    s.transparent = false;
+    s.health_check = None;
+    client.services = HashMap::from([(service.to_string(), s)]);
+
+    let client = Client::new(client)?;
+    let mut events = client.subscribe();
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let started = Instant::now();
+    let task = tokio::spawn(client.run(shutdown_rx));
+
+    let handshake = time::timeout(Duration::from_secs(TIMEOUT_SECS), async {
+        loop {
+            match events.recv().await? {
+                Event::Online { .. } => return Ok(started.elapsed()),
+                Event::HandshakeFailed { .. } => bail!("The control channel handshake failed"),
+                _ => (),
+            }
+        }
+    })
+    .await
+    .with_context(|| "Timeout establishing the control channel")
+    .and_then(|v| v);
+
+    let m = match handshake {
+        Ok(handshake) => measure(&addr, secs).await.map(|mut m| {
+            m.handshake = handshake;
+            m
+        }),
+        Err(e) => Err(e),
+    };
+    let _ = shutdown_tx.send(true);
+    let _ = task.await;
+    m
+}
+
+// Measure the latency and the throughput of the echo service at `addr`
+async fn measure(addr: &str, secs: u64) -> Result<Measurement> {
+    let started = Instant::now();
+    // The server starts listening for visitors right after the handshake
+    let mut conn = time::timeout(Duration::from_secs(TIMEOUT_SECS), async {
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(v) => return v,
+                Err(_) => time::sleep(CONNECT_RETRY_INTERVAL).await,
+            }
+        }
+    })
+    .await
+    .with_context(|| format!("Failed to connect to {}", addr))?;
+    conn.set_nodelay(true)?;
+    round_trip(&mut conn).await?;
+    let first_round_trip = started.elapsed();
+
+    let mut rtts = Vec::with_capacity(RTT_SAMPLES);
+    for _ in 0..RTT_SAMPLES {
+        let started = Instant::now();
+        round_trip(&mut conn).await?;
+        rtts.push(started.elapsed());
+    }
+    rtts.sort();
+    drop(conn);
+
+    // Write for `secs`, and count what's echoed back until the echo service closes
+    let conn = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}", addr))?;
+    let (mut rd, mut wr) = conn.into_split();
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    let writer = tokio::spawn(async move {
+        let chunk = vec![0u8; CHUNK_SIZE];
+        while Instant::now() < deadline {
+            wr.write_all(&chunk).await?;
+        }
+        wr.shutdown().await
+    });
+    let started = Instant::now();
+    let mut echoed = 0u64;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = time::timeout(Duration::from_secs(TIMEOUT_SECS), rd.read(&mut buf))
+            .await
+            .with_context(|| "Timeout waiting for the echo")??;
+        if n == 0 {
+            break;
+        }
+        echoed += n as u64;
+    }
+    writer.await??;
+    let throughput = echoed as f64 / started.elapsed().as_secs_f64();
+
+    Ok(Measurement {
+        handshake: Duration::ZERO,
+        first_round_trip,
+        rtts,
+        throughput,
+    })
+}
+
+async fn round_trip(conn: &mut TcpStream) -> Result<()> {
+    let mut buf = [0u8; 1];
+    conn.write_all(b"x").await?;
+    time::timeout(Duration::from_secs(TIMEOUT_SECS), conn.read_exact(&mut buf))
+        .await
+        .with_context(|| "Timeout waiting for the echo")??;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::server::run_server;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_bench() -> Result<()> {
+        let config = Config::from_file(Path::new("tests/for_bench/server.toml")).await?;
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        tokio::spawn(run_server(
+            config,
+            shutdown_rx,
+            update_rx,
+            Default::default(),
+        ));
+        time::sleep(Duration::from_millis(500)).await;
+
+        let config = Config::from_file(Path::new("tests/for_bench/client.toml")).await?;
+        let client = config.client.unwrap();
+        let m = bench(client.clone(), "foo", Some("127.0.0.1:2364"), 1).await?;
+        assert_eq!(m.rtts.len(), RTT_SAMPLES);
+        assert!(m.rtt(50) <= m.rtt(99));
+        assert!(m.throughput > 0.0);
+
+        assert!(bench(client.clone(), "bar", Some("127.0.0.1:2364"), 1)
+            .await
+            .is_err());
+        // No `remote_port` to tell where visitors reach the service
+        assert!(bench(client, "foo", None, 1).await.is_err());
+
+        shutdown_tx.send(true)?;
+        Ok(())
+    }
+}
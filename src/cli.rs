@@ -1,5 +1,10 @@
+use anyhow::{bail, Result};
 use clap::{AppSettings, ArgGroup, Parser};
 use lazy_static::lazy_static;
+use std::str::FromStr;
+
+use crate::config::ConfigOverride;
+use crate::protocol::CURRENT_PROTO_VERSION;
 
 #[derive(clap::ArgEnum, Clone, Debug, Copy)]
 pub enum KeypairType {
@@ -7,6 +12,49 @@ pub enum KeypairType {
     X448,
 }
 
+#[derive(clap::ArgEnum, Clone, Debug, Copy, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// The private key and the public key, labeled
+    #[default]
+    Text,
+    /// The private key and the public key in base64, one per line
+    Base64,
+    /// Snippets of the server and the client configuration
+    Toml,
+    /// PKCS#8 of the private key and SubjectPublicKeyInfo of the public key in PEM
+    Pem,
+}
+
+/// A service given on the command line, as `name,addr[,token]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceArg {
+    pub name: String,
+    pub addr: String,
+    pub token: Option<String>,
+}
+
+impl FromStr for ServiceArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ServiceArg> {
+        // The token is the rest, which may contain `,`
+        let mut parts = s.splitn(3, ',');
+        let name = parts.next().unwrap_or_default();
+        let addr = match parts.next() {
+            Some(v) if !v.is_empty() => v,
+            _ => bail!("Expect `name,addr[,token]`, got {:?}", s),
+        };
+        if name.is_empty() || name.contains('.') {
+            bail!("Invalid service name {:?}", name);
+        }
+        Ok(ServiceArg {
+            name: name.to_owned(),
+            addr: addr.to_owned(),
+            token: parts.next().map(|v| v.to_owned()),
+        })
+    }
+}
+
 lazy_static! {
     static ref VERSION: &'static str =
         option_env!("VERGEN_GIT_SEMVER_LIGHTWEIGHT").unwrap_or(env!("VERGEN_BUILD_SEMVER"));
@@ -14,6 +62,7 @@ lazy_static! {
         "
 Build Timestamp:     {}
 Build Version:       {}
+Protocol Version:    {}
 Commit SHA:          {:?}
 Commit Date:         {:?}
 Commit Branch:       {:?}
@@ -23,6 +72,7 @@ cargo Features:      {}
 ",
         env!("VERGEN_BUILD_TIMESTAMP"),
         env!("VERGEN_BUILD_SEMVER"),
+        CURRENT_PROTO_VERSION,
         option_env!("VERGEN_GIT_SHA"),
         option_env!("VERGEN_GIT_COMMIT_TIMESTAMP"),
         option_env!("VERGEN_GIT_BRANCH"),
@@ -42,16 +92,23 @@ cargo Features:      {}
 #[clap(group(
             ArgGroup::new("cmds")
                 .required(true)
-                .args(&["CONFIG", "genkey"]),
+                .args(&["CONFIG", "genkey", "service"]),
         ))]
 pub struct Cli {
     /// The path to the configuration file
     ///
     /// Running as a client or a server is automatically determined
-    /// according to the configuration file.
+    /// according to the configuration file. Use `-` to read it from stdin,
+    /// or a `http` or `https` URL to fetch it from a server.
     #[clap(parse(from_os_str), name = "CONFIG")]
     pub config_path: Option<std::path::PathBuf>,
 
+    /// How often to poll the configuration, if it's a `http` or `https` URL, in seconds [default: 60]
+    ///
+    /// It's fetched again only if it's changed, by `ETag` and `Last-Modified`
+    #[clap(long, requires = "CONFIG", value_name = "SECS")]
+    pub config_poll_interval: Option<u64>,
+
     /// Run as a server
     #[clap(long, short, group = "mode")]
     pub server: bool,
@@ -60,9 +117,155 @@ pub struct Cli {
     #[clap(long, short, group = "mode")]
     pub client: bool,
 
+    /// Set a value of the configuration, like `client.remote_addr=example.com:2333`
+    ///
+    /// Overrides the one in the configuration file, and is kept through hot-reloads.
+    /// The value is parsed as TOML if it's valid, like `true`, `10` or `["a", "b"]`,
+    /// or taken as a string otherwise. Quote it, like `token='"123"'`, to force a string.
+    #[clap(long, value_name = "KEY=VALUE", conflicts_with_all = &["genkey", "diagnose", "bench"])]
+    pub set: Vec<ConfigOverride>,
+
+    /// Run a service without a configuration file, given as `name,addr[,token]`
+    ///
+    /// `addr` is the `local_addr` of the service, or the `bind_addr` of it with `--server`.
+    /// The token defaults to `default_token`. Can be repeated. Other values, like
+    /// `client.remote_addr` or `server.bind_addr`, are given by `--set`.
+    #[clap(long, value_name = "SERVICE")]
+    pub service: Vec<ServiceArg>,
+
     /// Generate a keypair for the use of the noise protocol
     ///
-    /// The DH function to use is x25519
+    /// The DH function to use is x25519. x448 is not supported by the noise implementation
     #[clap(long, arg_enum, value_name = "CURVE")]
     pub genkey: Option<Option<KeypairType>>,
+
+    /// How the generated keypair is printed
+    #[clap(
+        long,
+        arg_enum,
+        requires = "genkey",
+        value_name = "FORMAT",
+        default_value = "text"
+    )]
+    pub genkey_format: KeyFormat,
+
+    /// Derive the public key of this private key in base64, instead of generating a keypair
+    #[clap(long, requires = "genkey", value_name = "PRIVATE_KEY")]
+    pub genkey_from: Option<String>,
+
+    /// Write the private key to `local_private_key` of the noise transport in this
+    /// configuration file, and print the `remote_public_key` block for the peer
+    ///
+    /// The key is for the server if the file has `[server]`, or the client otherwise.
+    /// Other content of the file is kept as is.
+    #[clap(
+        long,
+        parse(from_os_str),
+        requires = "genkey",
+        conflicts_with = "genkey-format",
+        value_name = "CONFIG"
+    )]
+    pub genkey_write: Option<std::path::PathBuf>,
+
+    /// Check the connectivity of a client configuration, and print a report
+    ///
+    /// Checks the configuration, the DNS resolution, the reachability of the
+    /// server and the proxy, the transport handshake, the token of each service
+    /// and the reachability of `local_addr`. Authenticating a service establishes
+    /// a control channel, which takes over the one of a running client for a while.
+    #[clap(long, requires = "CONFIG")]
+    pub diagnose: bool,
+
+    /// Measure the latency and the throughput of a service of a client configuration
+    ///
+    /// Runs the service as an echo service, which takes over the control channel
+    /// of a running client for a while, and sends traffic to it through the server.
+    /// The service must be of type "tcp" at the server.
+    #[clap(long, requires = "CONFIG", value_name = "SERVICE")]
+    pub bench: Option<String>,
+
+    /// Where visitors reach the benchmarked service, like "example.com:5202"
+    ///
+    /// Defaults to `remote_port` of the service at the host of `remote_addr`
+    #[clap(long, requires = "bench", value_name = "ADDR")]
+    pub bench_addr: Option<String>,
+
+    /// How long to measure the throughput for, in seconds
+    #[clap(long, requires = "bench", value_name = "SECS", default_value = "10")]
+    pub bench_duration: u64,
+}
+
+impl Cli {
+    /// The overrides of the configuration by `--service` and then `--set`
+    pub fn config_overrides(&self) -> Result<Vec<ConfigOverride>> {
+        let (mode, addr_key) = if self.server {
+            ("server", "bind_addr")
+        } else {
+            ("client", "local_addr")
+        };
+        let mut ret = Vec::new();
+        for s in &self.service {
+            let key = |k: &str| format!("{}.services.{}.{}", mode, s.name, k);
+            ret.push(ConfigOverride::new(
+                &key(addr_key),
+                toml::Value::String(s.addr.clone()),
+            )?);
+            if let Some(token) = &s.token {
+                ret.push(ConfigOverride::new(
+                    &key("token"),
+                    toml::Value::String(token.clone()),
+                )?);
+            }
+        }
+        ret.extend(self.set.iter().cloned());
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_version() {
+        let line = LONG_VERSION
+            .lines()
+            .find_map(|l| l.strip_prefix("Protocol Version:"))
+            .unwrap();
+        assert_eq!(line.trim(), CURRENT_PROTO_VERSION.to_string());
+    }
+
+    #[test]
+    fn test_service_arg() -> Result<()> {
+        let args = Cli::try_parse_from([
+            "rathole",
+            "--service",
+            "web,127.0.0.1:80,a,b",
+            "--service",
+            "ssh,127.0.0.1:22",
+            "--set",
+            "client.remote_addr=example.com:2333",
+        ])?;
+        assert!(args.config_path.is_none());
+        assert_eq!(
+            args.service[0],
+            ServiceArg {
+                name: "web".into(),
+                addr: "127.0.0.1:80".into(),
+                token: Some("a,b".into()),
+            }
+        );
+        let overrides = args.config_overrides()?;
+        assert_eq!(overrides.len(), 4);
+        assert_eq!(overrides[3], "client.remote_addr=example.com:2333".parse()?);
+
+        // `--set` takes one value, so the config path is not taken by it
+        let args = Cli::try_parse_from(["rathole", "--set", "a=1", "config.toml"])?;
+        assert!(args.config_path.is_some());
+
+        assert!("web".parse::<ServiceArg>().is_err());
+        assert!("web.a,127.0.0.1:80".parse::<ServiceArg>().is_err());
+        assert!(Cli::try_parse_from(["rathole", "--set", "a=1"]).is_err());
+        Ok(())
+    }
 }
@@ -1,32 +1,55 @@
-use crate::config::{ClientConfig, ClientServiceConfig, Config, ServiceType, TransportType};
+use crate::config::{
+    Addrs, ClientConfig, ClientServiceConfig, Config, KeepaliveConfig, RemoteAddrSelection,
+    ServiceType, TransportType,
+};
 use crate::config_watcher::{ClientServiceChange, ConfigChange};
-use crate::helper::udp_connect;
+use crate::conn_log::{self, ConnId, ConnStats};
+use crate::discovery::{run_discovery, ServiceDefaults};
+use crate::event::{CommandHook, Hooks};
+use crate::health_check::{run_health_check, Health};
+use crate::helper::{
+    copy_bidirectional_with_close_timeout, spawn_command, try_set_linger, try_set_nodelay,
+    try_set_tcp_keepalive, udp_connect,
+};
+use crate::http_proxy;
+use crate::metrics::{self, Metrics, ServiceMetrics};
+use crate::mux::{self, DataChannel};
 use crate::protocol::Hello::{self, *};
 use crate::protocol::{
-    self, read_ack, read_control_cmd, read_data_cmd, read_hello, Ack, Auth, ControlChannelCmd,
-    DataChannelCmd, UdpTraffic, CURRENT_PROTO_VERSION, HASH_WIDTH_IN_BYTES,
+    self, read_ack, read_bound_addr, read_control_cmd, read_data_cmd, read_hello,
+    read_service_digest, read_visitor, read_visitor_addr, write_registration, Ack, Auth,
+    ControlChannelCmd, DataChannelCmd, Registration, UdpTraffic, CURRENT_PROTO_VERSION,
+    HASH_WIDTH_IN_BYTES,
+};
+use crate::rate_limit::{RateLimitedStream, ServiceBandwidth};
+use crate::socket::SocketStream;
+use crate::socks5;
+use crate::systemd::Readiness;
+use crate::transport::{
+    is_permanent_handshake_error, AddrMaybeCached, SocketOpts, TcpTransport, Transport,
 };
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::backoff::Backoff;
 use backoff::future::retry_notify;
 use backoff::ExponentialBackoff;
 use bytes::{Bytes, BytesMut};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock};
 use tokio::time::{self, Duration, Instant};
 use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
 
+#[cfg(all(target_os = "linux", feature = "splice"))]
+use crate::splice;
 #[cfg(feature = "noise")]
 use crate::transport::NoiseTransport;
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
 use crate::transport::TlsTransport;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
-use crate::transport::WebsocketTransport;
+use crate::transport::{HttpTransport, WebsocketTransport};
 
 use crate::constants::{run_control_chan_backoff, UDP_BUFFER_SIZE, UDP_SENDQ_SIZE, UDP_TIMEOUT};
 
@@ -35,22 +58,28 @@ pub async fn run_client(
     config: Config,
     shutdown_rx: broadcast::Receiver<bool>,
     update_rx: mpsc::Receiver<ConfigChange>,
+    mut hooks: Hooks,
 ) -> Result<()> {
+    let metrics_config = config.metrics;
     let config = config.client.ok_or_else(|| {
         anyhow!(
         "Try to run as a client, but the configuration is missing. Please add the `[client]` block"
     )
     })?;
 
+    // Ready once the control channels of the services are all authenticated
+    hooks.push(Arc::new(Readiness::new(config.services.keys().cloned())));
+    let metrics = metrics::start(metrics_config.as_ref(), hooks, shutdown_rx.resubscribe()).await?;
+
     match config.transport.transport_type {
         TransportType::Tcp => {
-            let mut client = Client::<TcpTransport>::from(config).await?;
+            let mut client = Client::<TcpTransport>::from(config, metrics).await?;
             client.run(shutdown_rx, update_rx).await
         }
         TransportType::Tls => {
             #[cfg(any(feature = "native-tls", feature = "rustls"))]
             {
-                let mut client = Client::<TlsTransport>::from(config).await?;
+                let mut client = Client::<TlsTransport>::from(config, metrics).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
@@ -59,7 +88,7 @@ pub async fn run_client(
         TransportType::Noise => {
             #[cfg(feature = "noise")]
             {
-                let mut client = Client::<NoiseTransport>::from(config).await?;
+                let mut client = Client::<NoiseTransport>::from(config, metrics).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(feature = "noise"))]
@@ -68,7 +97,16 @@ pub async fn run_client(
         TransportType::Websocket => {
             #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
             {
-                let mut client = Client::<WebsocketTransport>::from(config).await?;
+                let mut client = Client::<WebsocketTransport>::from(config, metrics).await?;
+                client.run(shutdown_rx, update_rx).await
+            }
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            crate::helper::feature_neither_compile("websocket-native-tls", "websocket-rustls")
+        }
+        TransportType::Http => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            {
+                let mut client = Client::<HttpTransport>::from(config, metrics).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
@@ -79,23 +117,31 @@ pub async fn run_client(
 
 type ServiceDigest = protocol::Digest;
 type Nonce = protocol::Digest;
+// What the data channels of each service run with, indexed by ServiceDigest, for the streams
+// of a service that the server coalesces into data channels of another
+type DataChannelArgsMap<T> = Arc<Mutex<HashMap<ServiceDigest, Weak<RunDataChannelArgs<T>>>>>;
 
 // Holds the state of a client
 struct Client<T: Transport> {
     config: ClientConfig,
     service_handles: HashMap<String, ControlChannelHandle>,
     transport: Arc<T>,
+    // Counters exposed by `[metrics]`
+    metrics: Arc<Metrics>,
+    data_ch_args: DataChannelArgsMap<T>,
 }
 
 impl<T: 'static + Transport> Client<T> {
     // Create a Client from `[client]` config block
-    async fn from(config: ClientConfig) -> Result<Client<T>> {
+    async fn from(config: ClientConfig, metrics: Arc<Metrics>) -> Result<Client<T>> {
         let transport =
             Arc::new(T::new(&config.transport).with_context(|| "Failed to create the transport")?);
         Ok(Client {
             config,
             service_handles: HashMap::new(),
             transport,
+            metrics,
+            data_ch_args: Default::default(),
         })
     }
 
@@ -105,15 +151,21 @@ impl<T: 'static + Transport> Client<T> {
         mut shutdown_rx: broadcast::Receiver<bool>,
         mut update_rx: mpsc::Receiver<ConfigChange>,
     ) -> Result<()> {
-        for (name, config) in &self.config.services {
-            // Create a control channel for each service defined
-            let handle = ControlChannelHandle::new(
-                (*config).clone(),
-                self.config.remote_addr.clone(),
-                self.transport.clone(),
-                self.config.heartbeat_timeout,
-            );
-            self.service_handles.insert(name.clone(), handle);
+        self.start_services();
+
+        // Poll the registry for more services if configured
+        let (discovery_tx, mut discovery_rx) = mpsc::channel(1024);
+        let (static_tx, static_rx) = watch::channel(self.config.services.keys().cloned().collect());
+        if let Some(discovery) = self.config.discovery.clone() {
+            tokio::spawn(run_discovery(
+                discovery,
+                static_rx,
+                ServiceDefaults {
+                    default_token: self.config.default_token.clone(),
+                    retry_interval: self.config.retry_interval,
+                },
+                discovery_tx,
+            ));
         }
 
         // Wait for the shutdown signal
@@ -130,8 +182,12 @@ impl<T: 'static + Transport> Client<T> {
                 },
                 e = update_rx.recv() => {
                     if let Some(e) = e {
+                        update_static_services(&static_tx, &e);
                         self.handle_hot_reload(e).await;
                     }
+                },
+                Some(e) = discovery_rx.recv() => {
+                    self.handle_hot_reload(ConfigChange::ClientChange(e)).await;
                 }
             }
         }
@@ -144,34 +200,152 @@ impl<T: 'static + Transport> Client<T> {
         Ok(())
     }
 
+    // Create a control channel for each service defined
+    fn start_services(&mut self) {
+        for (name, config) in &self.config.services {
+            let handle = ControlChannelHandle::new(
+                (*config).clone(),
+                &self.config,
+                self.transport.clone(),
+                self.metrics.service(name),
+                self.data_ch_args.clone(),
+            );
+            self.service_handles.insert(name.clone(), handle);
+        }
+    }
+
     async fn handle_hot_reload(&mut self, e: ConfigChange) {
         match e {
             ConfigChange::ClientChange(client_change) => match client_change {
                 ClientServiceChange::Add(cfg) => {
                     let name = cfg.name.clone();
                     let handle = ControlChannelHandle::new(
-                        cfg,
-                        self.config.remote_addr.clone(),
+                        *cfg,
+                        &self.config,
                         self.transport.clone(),
-                        self.config.heartbeat_timeout,
+                        self.metrics.service(&name),
+                        self.data_ch_args.clone(),
                     );
                     let _ = self.service_handles.insert(name, handle);
                 }
                 ClientServiceChange::Delete(s) => {
                     let _ = self.service_handles.remove(&s);
+                    self.metrics.remove_service(&s);
                 }
             },
+            ConfigChange::ClientReload(config) => {
+                // Re-establish the control channels with the new transport and settings
+                let transport = match T::new(&config.transport) {
+                    Ok(v) => Arc::new(v),
+                    Err(e) => {
+                        error!(
+                            "{:#}. Keep running with the current configuration",
+                            e.context("Failed to create the transport")
+                        );
+                        return;
+                    }
+                };
+                self.transport = transport;
+                for name in self.service_handles.keys() {
+                    if !config.services.contains_key(name) {
+                        self.metrics.remove_service(name);
+                    }
+                }
+                self.config = *config;
+                for (_, handle) in self.service_handles.drain() {
+                    handle.shutdown();
+                }
+                self.start_services();
+                info!("Configuration reloaded");
+            }
             ignored => warn!("Ignored {:?} since running as a client", ignored),
         }
     }
 }
 
+// Keep the services defined in the configuration, which discovery leaves alone, up to date
+fn update_static_services(static_tx: &watch::Sender<HashSet<String>>, e: &ConfigChange) {
+    match e {
+        ConfigChange::ClientChange(ClientServiceChange::Add(cfg)) => static_tx.send_modify(|s| {
+            s.insert(cfg.name.clone());
+        }),
+        ConfigChange::ClientChange(ClientServiceChange::Delete(name)) => {
+            static_tx.send_modify(|s| {
+                s.remove(name);
+            })
+        }
+        ConfigChange::ClientReload(cfg) => {
+            static_tx.send_replace(cfg.services.keys().cloned().collect());
+        }
+        _ => (),
+    }
+}
+
 struct RunDataChannelArgs<T: Transport> {
     session_key: Nonce,
-    remote_addr: AddrMaybeCached,
+    // Updated once `remote_addr` resolves to other addresses
+    remote_addr: Mutex<AddrMaybeCached>,
     connector: Arc<T>,
     socket_opts: SocketOpts,
     service: ClientServiceConfig,
+    local_addr: LocalAddrs,
+    metrics: Arc<ServiceMetrics>,
+    bandwidth: ServiceBandwidth,
+    // Those of all services
+    others: DataChannelArgsMap<T>,
+}
+
+// How long a local address that fails to connect is tried only after the others
+const LOCAL_ADDR_DOWN_SECS: u64 = 10;
+
+// `local_addr` of a service in order of preference. Connections go to the first one that's up,
+// so they go back to the preferred one once it recovers
+struct LocalAddrs {
+    addrs: Addrs,
+    // Until when each address is considered down, after failing to connect
+    down_until: Mutex<Vec<Option<Instant>>>,
+}
+
+impl LocalAddrs {
+    fn new(addrs: Addrs) -> LocalAddrs {
+        let down_until = Mutex::new(vec![None; addrs.len()]);
+        LocalAddrs { addrs, down_until }
+    }
+
+    // Connect from `from` instead of an address of this host, if given
+    async fn connect(&self, from: Option<SocketAddr>) -> Result<SocketStream> {
+        // The ones down go last, in case all of them are
+        let mut order: Vec<usize> = (0..self.addrs.len()).collect();
+        {
+            let now = Instant::now();
+            let down_until = self.down_until.lock().unwrap();
+            order.sort_by_key(|i| down_until[*i].is_some_and(|t| now < t));
+        }
+
+        let mut last_err = None;
+        for i in order {
+            let conn = match from {
+                #[cfg(target_os = "linux")]
+                Some(from) => SocketStream::connect_transparent(&self.addrs[i], from).await,
+                _ => SocketStream::connect(&self.addrs[i]).await,
+            };
+            match conn {
+                Ok(v) => {
+                    self.down_until.lock().unwrap()[i] = None;
+                    return Ok(v);
+                }
+                Err(e) => {
+                    if self.addrs.len() > 1 {
+                        warn!("{:#}. Try the next local_addr", e);
+                    }
+                    self.down_until.lock().unwrap()[i] =
+                        Some(Instant::now() + Duration::from_secs(LOCAL_ADDR_DOWN_SECS));
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No local_addr")))
+    }
 }
 
 async fn do_data_channel_handshake<T: Transport>(
@@ -188,11 +362,20 @@ async fn do_data_channel_handshake<T: Transport>(
     let mut conn: T::Stream = retry_notify(
         backoff,
         || async {
-            args.connector
-                .connect(&args.remote_addr)
-                .await
-                .with_context(|| format!("Failed to connect to {}", &args.remote_addr))
-                .map_err(backoff::Error::transient)
+            let remote_addr = args.remote_addr.lock().unwrap().clone();
+            match args.connector.connect(&remote_addr).await {
+                Ok(v) => Ok(v),
+                Err(e) => {
+                    // The server may have moved. Resolve it again for later connections
+                    let mut addr = AddrMaybeCached::new(&remote_addr.addr);
+                    if addr.resolve().await.is_ok() {
+                        *args.remote_addr.lock().unwrap() = addr;
+                    }
+                    Err(backoff::Error::transient(
+                        e.context(format!("Failed to connect to {}", &remote_addr)),
+                    ))
+                }
+            }
         },
         |e, duration| {
             warn!("{:#}. Retry in {:?}", e, duration);
@@ -211,40 +394,309 @@ async fn do_data_channel_handshake<T: Transport>(
     Ok(conn)
 }
 
-async fn run_data_channel<T: Transport>(args: Arc<RunDataChannelArgs<T>>) -> Result<()> {
+async fn run_data_channel<T: 'static + Transport>(args: Arc<RunDataChannelArgs<T>>) -> Result<()> {
     // Do the handshake
     let mut conn = do_data_channel_handshake(args.clone()).await?;
+    let _data_channel = args.metrics.data_channel();
+
+    let cmd = match read_data_cmd(&mut conn).await {
+        Ok(v) => v,
+        Err(e) if is_eof(&e) => {
+            // The server shrinks its pool of data channels when visitors are few
+            debug!("Data channel closed by the server as unused");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    if let DataChannelCmd::StartMux = cmd {
+        // Each stream is served like a data channel of its own
+        let mut session = mux::Session::new(conn, mux::Mode::Server, args.metrics.mux_session());
+        debug!("Data channel starts multiplexing");
+        while let Some(mut stream) = session.accept().await {
+            let args = args.clone();
+            tokio::spawn(
+                async move {
+                    let served = match read_data_cmd(&mut stream).await {
+                        Ok(cmd) => serve_stream(stream, cmd, &args).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = served.with_context(|| "Failed to run the stream") {
+                        warn!("{:#}", e);
+                    }
+                }
+                .instrument(Span::current()),
+            );
+        }
+        return Ok(());
+    }
+    serve_data_channel(DataChannel::Direct(conn), cmd, &args).await
+}
+
+// Serve a multiplexed stream, which may tell first that it's of another service
+async fn serve_stream<T: Transport>(
+    mut stream: mux::MuxStream,
+    cmd: DataChannelCmd,
+    args: &Arc<RunDataChannelArgs<T>>,
+) -> Result<()> {
+    let (cmd, args) = match cmd {
+        DataChannelCmd::ForService => {
+            let digest = read_service_digest(&mut stream).await?;
+            let args = args
+                .others
+                .lock()
+                .unwrap()
+                .get(&digest)
+                .and_then(Weak::upgrade)
+                .with_context(|| format!("No service {} for the stream", hex::encode(digest)))?;
+            (read_data_cmd(&mut stream).await?, args)
+        }
+        cmd => (cmd, args.clone()),
+    };
+    serve_data_channel(DataChannel::Muxed(stream), cmd, &args).await
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.root_cause()
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+// Forward the traffic of a data channel, which starts with `cmd`
+async fn serve_data_channel<T: Transport>(
+    mut conn: DataChannel<T::Stream>,
+    cmd: DataChannelCmd,
+    args: &Arc<RunDataChannelArgs<T>>,
+) -> Result<()> {
+    // The ID of the connection and the address of the visitor, if the server tells
+    let (id, visitor) = match cmd {
+        DataChannelCmd::StartForwardTcpFrom => (None, Some(read_visitor_addr(&mut conn).await?)),
+        DataChannelCmd::StartForwardTcpConn => {
+            let (id, addr) = read_visitor(&mut conn).await?;
+            (Some(id), addr)
+        }
+        _ => (None, None),
+    };
 
     // Forward
-    match read_data_cmd(&mut conn).await? {
-        DataChannelCmd::StartForwardTcp => {
-            if args.service.service_type != ServiceType::Tcp {
-                bail!("Expect TCP traffic. Please check the configuration.")
-            }
-            run_data_channel_for_tcp::<T>(conn, &args.service.local_addr).await?;
+    match cmd {
+        DataChannelCmd::StartForwardTcp
+        | DataChannelCmd::StartForwardTcpFrom
+        | DataChannelCmd::StartForwardTcpConn => {
+            // Older servers don't tell the ID, so make one up for the logs of this side
+            let span = conn_log::span(id.unwrap_or_else(ConnId::generate), visitor);
+            let stats = ConnStats::new();
+            let forwarded = run_data_channel_for_visitor(conn, args, visitor, &stats)
+                .instrument(span.clone())
+                .await;
+            span.in_scope(|| stats.log_closed());
+            forwarded?
         }
         DataChannelCmd::StartForwardUdp => {
             if args.service.service_type != ServiceType::Udp {
                 bail!("Expect UDP traffic. Please check the configuration.")
             }
-            run_data_channel_for_udp::<T>(conn, &args.service.local_addr, args.service.prefer_ipv6).await?;
+            let conn = match conn {
+                DataChannel::Direct(v) => v,
+                DataChannel::Muxed(_) => bail!("UDP traffic is never multiplexed"),
+            };
+            run_data_channel_for_udp::<T>(conn, &args.service, &args.metrics).await?;
         }
+        DataChannelCmd::StartMux => bail!("A multiplexed stream can't be multiplexed again"),
+        DataChannelCmd::ForService => bail!("Only a multiplexed stream tells its service"),
     }
     Ok(())
 }
 
+// Forward a TCP visitor as the type of the service
+async fn run_data_channel_for_visitor<T: Transport>(
+    conn: DataChannel<T::Stream>,
+    args: &RunDataChannelArgs<T>,
+    visitor: Option<SocketAddr>,
+    stats: &Arc<ConnStats>,
+) -> Result<()> {
+    match args.service.service_type {
+        ServiceType::Tcp | ServiceType::Sni | ServiceType::Http => {
+            let from = match (args.service.transparent, visitor) {
+                (true, None) => {
+                    warn!("No address of the visitor to connect from, e.g. the server is older than the client. Connect as usual");
+                    None
+                }
+                (transparent, v) => v.filter(|_| transparent),
+            };
+            run_data_channel_for_tcp::<T>(
+                conn,
+                &args.local_addr,
+                from,
+                args.service.linger_secs,
+                args.service.local_nodelay,
+                args.service.local_keepalive.as_ref(),
+                args.service.close_timeout_secs,
+                &args.metrics,
+                &args.bandwidth,
+                stats,
+            )
+            .await
+        }
+        ServiceType::Echo => run_data_channel_for_echo::<T>(conn, &args.metrics, stats).await,
+        ServiceType::Socks5 => run_data_channel_for_socks5::<T>(conn, args, stats).await,
+        ServiceType::HttpProxy => run_data_channel_for_http_proxy::<T>(conn, args, stats).await,
+        ServiceType::Udp => {
+            bail!("Expect TCP traffic. Please check the configuration.")
+        }
+    }
+}
+
 // Simply copying back and forth for TCP
-#[instrument(skip(conn))]
+#[instrument(skip(conn, local_addr, metrics, bandwidth, stats), fields(local_addr = %local_addr.addrs))]
+#[allow(clippy::too_many_arguments)]
 async fn run_data_channel_for_tcp<T: Transport>(
-    mut conn: T::Stream,
-    local_addr: &str,
+    conn: DataChannel<T::Stream>,
+    local_addr: &LocalAddrs,
+    from: Option<SocketAddr>,
+    linger_secs: Option<u64>,
+    nodelay: Option<bool>,
+    keepalive: Option<&KeepaliveConfig>,
+    close_timeout_secs: Option<u64>,
+    metrics: &Arc<ServiceMetrics>,
+    bandwidth: &ServiceBandwidth,
+    stats: &Arc<ConnStats>,
 ) -> Result<()> {
     debug!("New data channel starts forwarding");
 
-    let mut local = TcpStream::connect(local_addr)
-        .await
-        .with_context(|| format!("Failed to connect to {}", local_addr))?;
-    let _ = copy_bidirectional(&mut conn, &mut local).await;
+    let local = local_addr.connect(from).await?;
+    forward_tcp::<T>(
+        conn,
+        local,
+        linger_secs,
+        nodelay,
+        keepalive,
+        close_timeout_secs,
+        metrics,
+        bandwidth,
+        stats,
+    )
+    .await
+}
+
+// Serve the visitor as a SOCKS5 proxy, and forward to where it asks
+#[instrument(skip_all)]
+async fn run_data_channel_for_socks5<T: Transport>(
+    mut conn: DataChannel<T::Stream>,
+    args: &RunDataChannelArgs<T>,
+    stats: &Arc<ConnStats>,
+) -> Result<()> {
+    debug!("New data channel starts serving SOCKS5");
+
+    let dest = socks5::accept(&mut conn, args.service.proxy_auth.as_ref()).await?;
+    forward_tcp::<T>(
+        conn,
+        SocketStream::Tcp(dest),
+        args.service.linger_secs,
+        args.service.local_nodelay,
+        args.service.local_keepalive.as_ref(),
+        args.service.close_timeout_secs,
+        &args.metrics,
+        &args.bandwidth,
+        stats,
+    )
+    .await
+}
+
+// Serve the visitor as an HTTP proxy, and forward to where it asks
+#[instrument(skip_all)]
+async fn run_data_channel_for_http_proxy<T: Transport>(
+    mut conn: DataChannel<T::Stream>,
+    args: &RunDataChannelArgs<T>,
+    stats: &Arc<ConnStats>,
+) -> Result<()> {
+    debug!("New data channel starts serving HTTP proxy");
+
+    let (mut dest, first) = http_proxy::accept(&mut conn, args.service.proxy_auth.as_ref()).await?;
+    dest.write_all(&first).await?;
+    forward_tcp::<T>(
+        conn,
+        SocketStream::Tcp(dest),
+        args.service.linger_secs,
+        args.service.local_nodelay,
+        args.service.local_keepalive.as_ref(),
+        args.service.close_timeout_secs,
+        &args.metrics,
+        &args.bandwidth,
+        stats,
+    )
+    .await
+}
+
+// Copy between a data channel and a connection to the local side
+#[allow(clippy::too_many_arguments)]
+async fn forward_tcp<T: Transport>(
+    mut conn: DataChannel<T::Stream>,
+    local: SocketStream,
+    linger_secs: Option<u64>,
+    nodelay: Option<bool>,
+    keepalive: Option<&KeepaliveConfig>,
+    close_timeout_secs: Option<u64>,
+    metrics: &Arc<ServiceMetrics>,
+    bandwidth: &ServiceBandwidth,
+    stats: &Arc<ConnStats>,
+) -> Result<()> {
+    if let (Some(secs), Some(tcp)) = (linger_secs, local.tcp()) {
+        if let Err(e) = try_set_linger(tcp, Duration::from_secs(secs)) {
+            error!("Failed to set linger: {:#}", e);
+        }
+    }
+    if let (Some(nodelay), Some(tcp)) = (nodelay, local.tcp()) {
+        if let Err(e) = try_set_nodelay(tcp, nodelay) {
+            error!("Failed to set nodelay: {:#}", e);
+        }
+    }
+    if let (Some(keepalive), Some(tcp)) = (keepalive, local.tcp()) {
+        if let Err(e) = try_set_tcp_keepalive(tcp, keepalive) {
+            error!("Failed to set keepalive: {:#}", e);
+        }
+    }
+    let close_timeout = close_timeout_secs.map(Duration::from_secs);
+
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    if bandwidth.is_unlimited() && splice::is_supported() {
+        if let (Some(conn), Some(local)) = (conn.direct().and_then(T::as_plain_tcp), local.tcp()) {
+            debug!("Forward with splice");
+            let _ = splice::splice_bidirectional_with_close_timeout(
+                conn,
+                local,
+                close_timeout,
+                |n| {
+                    metrics.add_bytes_in(n);
+                    stats.add_bytes_in(n);
+                },
+                |n| {
+                    metrics.add_bytes_out(n);
+                    stats.add_bytes_out(n);
+                },
+            )
+            .await;
+            return Ok(());
+        }
+    }
+
+    let local = bandwidth.limit_local(RateLimitedStream::new(local, 0, 0));
+    let mut local = metrics.count_local(local).with_conn(stats);
+    let _ = copy_bidirectional_with_close_timeout(&mut conn, &mut local, close_timeout).await;
+    Ok(())
+}
+
+// Send back whatever is received
+#[instrument(skip_all)]
+async fn run_data_channel_for_echo<T: Transport>(
+    conn: DataChannel<T::Stream>,
+    metrics: &Arc<ServiceMetrics>,
+    stats: &Arc<ConnStats>,
+) -> Result<()> {
+    debug!("New data channel starts echoing");
+
+    let conn = metrics.count_visitor(conn).with_conn(stats);
+    let (mut rd, mut wr) = io::split(conn);
+    let _ = io::copy(&mut rd, &mut wr).await;
     Ok(())
 }
 
@@ -254,9 +706,15 @@ async fn run_data_channel_for_tcp<T: Transport>(
 // to the socket will work fine for the map's value.
 type UdpPortMap = Arc<RwLock<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
 
-#[instrument(skip(conn))]
-async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &str, prefer_ipv6: bool) -> Result<()> {
+#[instrument(skip_all, fields(local_addr = %service.local_addr))]
+async fn run_data_channel_for_udp<T: Transport>(
+    conn: T::Stream,
+    service: &ClientServiceConfig,
+    metrics: &Arc<ServiceMetrics>,
+) -> Result<()> {
     debug!("New data channel starts forwarding");
+    let local_addr = &service.local_addr[0];
+    let timeout = Duration::from_secs(service.udp_timeout.unwrap_or(UDP_TIMEOUT));
 
     let port_map: UdpPortMap = Arc::new(RwLock::new(HashMap::new()));
 
@@ -305,7 +763,16 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
             // grabbing the writer lock
             let mut m = port_map.write().await;
 
-            match udp_connect(local_addr, prefer_ipv6).await {
+            if service.udp_max_sessions.is_some_and(|max| m.len() >= max) {
+                debug!(
+                    "Too many UDP sessions. Dropped the packet from {}",
+                    packet.from
+                );
+                metrics.connection_rejected();
+                continue;
+            }
+
+            match udp_connect(local_addr, service.prefer_ipv6).await {
                 Ok(s) => {
                     let (inbound_tx, inbound_rx) = mpsc::channel(UDP_SENDQ_SIZE);
                     m.insert(packet.from, inbound_tx);
@@ -314,7 +781,9 @@ async fn run_data_channel_for_udp<T: Transport>(conn: T::Stream, local_addr: &st
                         inbound_rx,
                         outbound_tx.clone(),
                         packet.from,
+                        timeout,
                         port_map.clone(),
+                        metrics.clone(),
                     ));
                 }
                 Err(e) => {
@@ -338,7 +807,9 @@ async fn run_udp_forwarder(
     mut inbound_rx: mpsc::Receiver<Bytes>,
     outbount_tx: mpsc::Sender<UdpTraffic>,
     from: SocketAddr,
+    timeout: Duration,
     port_map: UdpPortMap,
+    metrics: Arc<ServiceMetrics>,
 ) -> Result<()> {
     debug!("Forwarder created");
     let mut buf = BytesMut::new();
@@ -350,6 +821,7 @@ async fn run_udp_forwarder(
             data = inbound_rx.recv() => {
                 if let Some(data) = data {
                     s.send(&data).await?;
+                    metrics.add_bytes_in(data.len());
                 } else {
                     break;
                 }
@@ -361,6 +833,7 @@ async fn run_udp_forwarder(
                     Ok(v) => v,
                     Err(_) => break
                 };
+                metrics.add_bytes_out(len);
 
                 let t = UdpTraffic{
                     from,
@@ -370,8 +843,8 @@ async fn run_udp_forwarder(
                 outbount_tx.send(t).await?;
             },
 
-            // No traffic for the duration of UDP_TIMEOUT, clean up the state
-            _ = time::sleep(Duration::from_secs(UDP_TIMEOUT)) => {
+            // No traffic for the duration of `timeout`, clean up the state
+            _ = time::sleep(timeout) => {
                 break;
             }
         }
@@ -386,12 +859,91 @@ async fn run_udp_forwarder(
 
 // Control channel, using T as the transport layer
 struct ControlChannel<T: Transport> {
-    digest: ServiceDigest,              // SHA256 of the service name
-    service: ClientServiceConfig,       // `[client.services.foo]` config block
-    shutdown_rx: oneshot::Receiver<u8>, // Receives the shutdown signal
-    remote_addr: String,                // `client.remote_addr`
-    transport: Arc<T>,                  // Wrapper around the transport layer
-    heartbeat_timeout: u64,             // Application layer heartbeat timeout in secs
+    digest: ServiceDigest,               // SHA256 of the service name
+    service: ClientServiceConfig,        // `[client.services.foo]` config block
+    shutdown_rx: oneshot::Receiver<u8>,  // Receives the shutdown signal
+    remote_addrs: Addrs,                 // `client.remote_addr`
+    selection: RemoteAddrSelection,      // `client.remote_addr_selection`
+    next_remote_addr: usize,             // Index into `remote_addrs` of the next one to connect to
+    transport: Arc<T>,                   // Wrapper around the transport layer
+    heartbeat_timeout: u64,              // Application layer heartbeat timeout in secs
+    dns_refresh_interval: u64,           // Secs between re-resolving `remote_addr`. 0 disables it
+    socket_opts: SocketOpts,             // Socket options of the control channel
+    metrics: Arc<ServiceMetrics>,        // Counters of the service
+    health: Option<Health>,              // Whether `local_addr` is healthy, if checked
+    data_ch_args: DataChannelArgsMap<T>, // What the data channels of all services run with
+}
+
+// What to ask the server to expose, if `remote_port` of the service is set
+pub(crate) fn registration(service: &ClientServiceConfig) -> Option<Registration> {
+    service.remote_port.map(|port| Registration {
+        name: service.name.clone(),
+        // Echo and proxy services are forwarded as TCP
+        service_type: match service.service_type {
+            ServiceType::Echo | ServiceType::Socks5 | ServiceType::HttpProxy => ServiceType::Tcp,
+            v => v,
+        },
+        port,
+    })
+}
+
+// Do the handshake of a control channel for the service of `digest`, asking the server to
+// expose it by `registration` if the server doesn't configure it.
+// Returns the session key, and the ack from the server
+pub(crate) async fn do_control_channel_handshake<S>(
+    conn: &mut S,
+    digest: &ServiceDigest,
+    token: &str,
+    registration: Option<&Registration>,
+) -> Result<(Nonce, Ack)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Send hello
+    debug!("Sending hello");
+    let hello_send = match registration {
+        Some(_) => Hello::RegisterHello(CURRENT_PROTO_VERSION, *digest),
+        None => Hello::ControlChannelHello(CURRENT_PROTO_VERSION, *digest),
+    };
+    conn.write_all(&bincode::serialize(&hello_send).unwrap())
+        .await?;
+    conn.flush().await?;
+
+    // Read hello
+    debug!("Reading hello");
+    let nonce = match read_hello(conn).await? {
+        // The server replies with the version to speak, which is never newer than ours
+        ControlChannelHello(v, _) if v > CURRENT_PROTO_VERSION => {
+            bail!("Unexpected protocol version {} from the server", v);
+        }
+        ControlChannelHello(v, d) => {
+            debug!("Speaking protocol version {}", v);
+            d
+        }
+        _ => {
+            bail!("Unexpected type of hello");
+        }
+    };
+
+    if let Some(r) = registration {
+        debug!("Sending registration");
+        write_registration(conn, r).await?;
+    }
+
+    // Send auth
+    debug!("Sending auth");
+    let mut concat = Vec::from(token.as_bytes());
+    concat.extend_from_slice(&nonce);
+
+    let session_key = protocol::digest(&concat);
+    let auth = Auth(session_key);
+    conn.write_all(&bincode::serialize(&auth).unwrap()).await?;
+    conn.flush().await?;
+
+    // Read ack
+    debug!("Reading ack");
+    let ack = read_ack(conn).await?;
+    Ok((session_key, ack))
 }
 
 // Handle of a control channel
@@ -403,47 +955,57 @@ struct ControlChannelHandle {
 impl<T: 'static + Transport> ControlChannel<T> {
     #[instrument(skip_all)]
     async fn run(&mut self) -> Result<()> {
-        let mut remote_addr = AddrMaybeCached::new(&self.remote_addr);
+        // Stay offline while the local service is unhealthy, so that no visitors come
+        if let Some(health) = self.health.as_mut() {
+            if !*health.borrow() {
+                info!("Waiting for the local service to be healthy");
+            }
+            tokio::select! {
+                r = health.wait_for(|v| *v) => {
+                    r?;
+                }
+                _ = &mut self.shutdown_rx => return Ok(()),
+            }
+        }
+
+        // Move on to the next address, unless this one turns out to work
+        let addr = self.remote_addrs[self.next_remote_addr % self.remote_addrs.len()].clone();
+        self.next_remote_addr = (self.next_remote_addr + 1) % self.remote_addrs.len();
+
+        let mut remote_addr = AddrMaybeCached::new(&addr);
         remote_addr.resolve().await?;
 
         let mut conn = self
             .transport
             .connect(&remote_addr)
             .await
-            .with_context(|| format!("Failed to connect to {}", &self.remote_addr))?;
-        T::hint(&conn, SocketOpts::for_control_channel());
-
-        // Send hello
-        debug!("Sending hello");
-        let hello_send =
-            Hello::ControlChannelHello(CURRENT_PROTO_VERSION, self.digest[..].try_into().unwrap());
-        conn.write_all(&bincode::serialize(&hello_send).unwrap())
-            .await?;
-        conn.flush().await?;
-
-        // Read hello
-        debug!("Reading hello");
-        let nonce = match read_hello(&mut conn).await? {
-            ControlChannelHello(_, d) => d,
-            _ => {
-                bail!("Unexpected type of hello");
-            }
-        };
-
-        // Send auth
-        debug!("Sending auth");
-        let mut concat = Vec::from(self.service.token.as_ref().unwrap().as_bytes());
-        concat.extend_from_slice(&nonce);
-
-        let session_key = protocol::digest(&concat);
-        let auth = Auth(session_key);
-        conn.write_all(&bincode::serialize(&auth).unwrap()).await?;
-        conn.flush().await?;
-
-        // Read ack
-        debug!("Reading ack");
-        match read_ack(&mut conn).await? {
+            .with_context(|| format!("Failed to connect to {}", &addr))
+            .inspect_err(|_| self.metrics.handshake_failed())?;
+        T::hint(&conn, self.socket_opts);
+
+        let (session_key, ack) = do_control_channel_handshake(
+            &mut conn,
+            &self.digest,
+            self.service.token.as_ref().unwrap(),
+            registration(&self.service).as_ref(),
+        )
+        .await
+        .inspect_err(|_| self.metrics.handshake_failed())?;
+        if !matches!(ack, Ack::Ok) {
+            self.metrics.handshake_failed();
+        }
+        match ack {
             Ack::Ok => {}
+            Ack::AuthFailed if self.service.token_next.is_some() => {
+                // The server may have retired the token. Try the other one next time.
+                // Keep the rejected one, in case the rotation is rolled back
+                let service = &mut self.service;
+                std::mem::swap(&mut service.token, &mut service.token_next);
+                bail!(
+                    "Authentication failed: {}. Switching to the next token",
+                    service.name
+                );
+            }
             v => {
                 return Err(anyhow!("{}", v))
                     .with_context(|| format!("Authentication failed: {}", self.service.name));
@@ -451,17 +1013,38 @@ impl<T: 'static + Transport> ControlChannel<T> {
         }
 
         // Channel ready
-        info!("Control channel established");
+        info!("Control channel established to {}", addr);
+        let _online = self.metrics.online(addr.clone());
+        // Start over from the most preferred address after a disconnection
+        if self.selection == RemoteAddrSelection::Priority {
+            self.next_remote_addr = 0;
+        }
 
         // Socket options for the data channel
         let socket_opts = SocketOpts::from_client_cfg(&self.service);
         let data_ch_args = Arc::new(RunDataChannelArgs {
             session_key,
-            remote_addr,
+            remote_addr: Mutex::new(remote_addr),
             connector: self.transport.clone(),
             socket_opts,
             service: self.service.clone(),
+            local_addr: LocalAddrs::new(self.service.local_addr.clone()),
+            metrics: self.metrics.clone(),
+            bandwidth: ServiceBandwidth::new(
+                self.service.max_upload_speed,
+                self.service.max_download_speed,
+            ),
+            others: self.data_ch_args.clone(),
         });
+        {
+            let mut all = self.data_ch_args.lock().unwrap();
+            all.retain(|_, v| v.strong_count() > 0);
+            all.insert(self.digest, Arc::downgrade(&data_ch_args));
+        }
+
+        let dns_refresh_interval = Duration::from_secs(self.dns_refresh_interval);
+        let dns_refresh = time::sleep(dns_refresh_interval);
+        tokio::pin!(dns_refresh);
 
         loop {
             tokio::select! {
@@ -477,12 +1060,48 @@ impl<T: 'static + Transport> ControlChannel<T> {
                                 }
                             }.instrument(Span::current()));
                         },
-                        ControlChannelCmd::HeartBeat => ()
+                        ControlChannelCmd::HeartBeat => (),
+                        ControlChannelCmd::Shutdown => {
+                            bail!("The server is shutting down");
+                        }
+                        ControlChannelCmd::BoundAddr => {
+                            let addr = read_bound_addr(&mut conn).await?;
+                            info!("Exposed at port {} of the server", addr.port());
+                            self.metrics.set_bound_port(addr.port());
+                            if let Some(cmd) = self.service.on_bind.as_deref() {
+                                spawn_command(
+                                    "on_bind",
+                                    cmd,
+                                    &[
+                                        ("RATHOLE_SERVICE", self.service.name.clone()),
+                                        ("RATHOLE_BIND_ADDR", addr.to_string()),
+                                        ("RATHOLE_BIND_PORT", addr.port().to_string()),
+                                    ],
+                                );
+                            }
+                        }
                     }
                 },
                 _ = time::sleep(Duration::from_secs(self.heartbeat_timeout)), if self.heartbeat_timeout != 0 => {
                     return Err(anyhow!("Heartbeat timed out"))
                 }
+                _ = &mut dns_refresh, if !dns_refresh_interval.is_zero() => {
+                    dns_refresh.as_mut().reset(time::Instant::now() + dns_refresh_interval);
+                    let mut resolved = AddrMaybeCached::new(&addr);
+                    match resolved.resolve().await {
+                        Ok(()) => {
+                            let mut cached = data_ch_args.remote_addr.lock().unwrap();
+                            if cached.socket_addrs != resolved.socket_addrs {
+                                info!("{} now resolves to {}", addr, resolved);
+                                *cached = resolved;
+                            }
+                        }
+                        Err(e) => warn!("Failed to resolve {}: {:#}", addr, e),
+                    }
+                }
+                _ = unhealthy(&mut self.health) => {
+                    bail!("The local service is unhealthy");
+                }
                 _ = &mut self.shutdown_rx => {
                     break;
                 }
@@ -494,29 +1113,70 @@ impl<T: 'static + Transport> ControlChannel<T> {
     }
 }
 
+// Resolves once the local service turns unhealthy. Never if it's not checked
+async fn unhealthy(health: &mut Option<Health>) {
+    if let Some(health) = health {
+        if health.wait_for(|v| !*v).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+// How long to wait before retrying after `err`. Retrying soon only helps with transient errors
+fn next_retry(
+    err: &anyhow::Error,
+    backoff: &mut ExponentialBackoff,
+    cert_error_retry_interval: u64,
+) -> Option<Duration> {
+    if is_permanent_handshake_error(err) {
+        Some(Duration::from_secs(cert_error_retry_interval))
+    } else {
+        backoff.next_backoff()
+    }
+}
+
 impl ControlChannelHandle {
     #[instrument(name="handle", skip_all, fields(service = %service.name))]
     fn new<T: 'static + Transport>(
         service: ClientServiceConfig,
-        remote_addr: String,
+        config: &ClientConfig,
         transport: Arc<T>,
-        heartbeat_timeout: u64,
+        metrics: Arc<ServiceMetrics>,
+        data_ch_args: DataChannelArgsMap<T>,
     ) -> ControlChannelHandle {
         let digest = protocol::digest(service.name.as_bytes());
 
         info!("Starting {}", hex::encode(digest));
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-        let mut retry_backoff = run_control_chan_backoff(service.retry_interval.unwrap());
+        let mut retry_backoff =
+            run_control_chan_backoff(service.retry_interval.unwrap(), &config.backoff);
+        metrics.set_command_hook(CommandHook {
+            on_connect: service.on_connect.clone(),
+            on_disconnect: service.on_disconnect.clone(),
+        });
+        let health = service
+            .health_check
+            .clone()
+            .map(|v| run_health_check(service.local_addr.clone(), *v));
 
         let mut s = ControlChannel {
             digest,
             service,
             shutdown_rx,
-            remote_addr,
+            remote_addrs: config.remote_addr.clone(),
+            selection: config.remote_addr_selection,
+            next_remote_addr: 0,
             transport,
-            heartbeat_timeout,
+            heartbeat_timeout: config.heartbeat_timeout,
+            dns_refresh_interval: config.dns_refresh_interval,
+            socket_opts: SocketOpts::for_control_channel(&config.transport.tcp),
+            metrics,
+            health,
+            data_ch_args,
         };
+        let cert_error_retry_interval = config.cert_error_retry_interval;
 
         tokio::spawn(
             async move {
@@ -536,12 +1196,15 @@ impl ControlChannelHandle {
                         retry_backoff.reset();
                     }
 
-                    if let Some(duration) = retry_backoff.next_backoff() {
+                    if let Some(duration) =
+                        next_retry(&err, &mut retry_backoff, cert_error_retry_interval)
+                    {
                         error!("{:#}. Retry in {:?}...", err, duration);
                         time::sleep(duration).await;
                     } else {
-                        // Should never reach
-                        panic!("{:#}. Break", err);
+                        // `backoff.max_elapsed_time` is reached
+                        error!("{:#}. Give up reconnecting", err);
+                        break;
                     }
 
                     start = Instant::now();
@@ -558,3 +1221,111 @@ impl ControlChannelHandle {
         let _ = self.shutdown_tx.send(0u8);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackoffConfig;
+    use crate::transport::PermanentHandshakeError;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_next_retry() {
+        let mut backoff = run_control_chan_backoff(1, &BackoffConfig::default());
+
+        // Transient errors are retried soon
+        let transient = anyhow!("Connection reset").context("Failed to connect");
+        for _ in 0..10 {
+            let d = next_retry(&transient, &mut backoff, 300).unwrap();
+            assert!(d <= Duration::from_millis(1200), "{:?}", d);
+        }
+
+        // Retrying a certificate error waits for long
+        let permanent = anyhow!("certificate verify failed")
+            .context(PermanentHandshakeError)
+            .context("Failed to connect");
+        assert_eq!(
+            next_retry(&permanent, &mut backoff, 300),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn test_backoff_config() {
+        let config = BackoffConfig {
+            initial_interval_ms: Some(50),
+            multiplier: Some(2.0),
+            max_interval_ms: Some(200),
+            max_elapsed_time: None,
+            jitter: Some(0.0),
+        };
+        let mut backoff = run_control_chan_backoff(1, &config);
+        let err = anyhow!("Connection refused");
+        let intervals: Vec<_> = (0..4)
+            .map(|_| next_retry(&err, &mut backoff, 300).unwrap())
+            .collect();
+        assert_eq!(
+            intervals,
+            [50, 100, 200, 200].map(Duration::from_millis).to_vec()
+        );
+
+        // Give up once `max_elapsed_time` is reached
+        let config = BackoffConfig {
+            max_elapsed_time: Some(0),
+            ..config
+        };
+        let mut backoff = run_control_chan_backoff(1, &config);
+        assert_eq!(next_retry(&err, &mut backoff, 300), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_addr_failover() -> Result<()> {
+        let primary = TcpListener::bind("127.0.0.1:0").await?;
+        let backup = TcpListener::bind("127.0.0.1:0").await?;
+        let (primary_addr, backup_addr) = (primary.local_addr()?, backup.local_addr()?);
+        let addrs = LocalAddrs::new(Addrs::from(vec![
+            primary_addr.to_string(),
+            backup_addr.to_string(),
+        ]));
+        let peer = |s: SocketStream| s.tcp().unwrap().peer_addr().unwrap();
+
+        assert_eq!(peer(addrs.connect(None).await?), primary_addr);
+
+        // Fail over to the backup, and stay there while the primary is considered down
+        drop(primary);
+        assert_eq!(peer(addrs.connect(None).await?), backup_addr);
+        let primary = TcpListener::bind(primary_addr).await?;
+        assert_eq!(peer(addrs.connect(None).await?), backup_addr);
+
+        // Then back to the primary
+        addrs.down_until.lock().unwrap()[0] = Some(Instant::now());
+        assert_eq!(peer(addrs.connect(None).await?), primary_addr);
+
+        drop(backup);
+        drop(primary);
+        assert!(addrs.connect(None).await.is_err());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_local_addr_transparent() -> Result<()> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let addrs = LocalAddrs::new(Addrs::from(vec![l.local_addr()?.to_string()]));
+        let visitor: SocketAddr = "127.0.0.2:12345".parse()?;
+
+        // IP_TRANSPARENT needs CAP_NET_ADMIN
+        let conn = match addrs.connect(Some(visitor)).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Skipped: {:#}", e);
+                return Ok(());
+            }
+        };
+        let local = conn.tcp().unwrap().local_addr()?;
+        assert_eq!(local.ip(), visitor.ip());
+        let (_, peer) = l.accept().await?;
+        assert_eq!(peer.ip(), visitor.ip());
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use toml::value::{Table, Value};
+
+/// The key of the config file listing the files merged into it
+const INCLUDE_KEY: &str = "include";
+
+/// Take `include` out of the config file at `path`, whose content is `root`.
+/// Returns the patterns of the included files, relative to the directory of `path` if not absolute,
+/// or None if nothing is included
+pub fn take_patterns(path: &Path, root: &mut Value) -> Result<Option<Vec<PathBuf>>> {
+    let include = match root.as_table_mut().and_then(|t| t.remove(INCLUDE_KEY)) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let patterns = match include {
+        Value::String(v) => vec![v],
+        Value::Array(v) => v
+            .into_iter()
+            .map(|v| match v {
+                Value::String(v) => Ok(v),
+                _ => bail!("`include` must be a list of paths"),
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!("`include` must be a list of paths"),
+    };
+
+    patterns
+        .into_iter()
+        .map(|v| {
+            let pattern = dir.join(v);
+            match pattern.parent() {
+                Some(dir) if has_wildcard(&dir.to_string_lossy()) => bail!(
+                    "Wildcards are only supported in the file names of `include`, not in {:?}",
+                    dir
+                ),
+                _ => Ok(pattern),
+            }
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Merge the files matching `patterns` into `root`, in the order of the patterns,
+/// and files matching the same pattern in the order of their names.
+/// A key defined more than once is an error, unless both are tables
+pub async fn merge(root: &mut Value, patterns: &[PathBuf]) -> Result<()> {
+    let root = match root.as_table_mut() {
+        Some(v) => v,
+        None => bail!("The config is not a table"),
+    };
+    let mut merged: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        for path in expand(pattern).await? {
+            // Matched by several patterns
+            if merged.contains(&path) {
+                continue;
+            }
+            let s = fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Failed to read the included config {:?}", path))?;
+            let table: Table = toml::from_str(&s)
+                .with_context(|| format!("Failed to parse the included config {:?}", path))?;
+            if table.contains_key(INCLUDE_KEY) {
+                bail!("The included config {:?} can't include others", path);
+            }
+            merge_table(root, table, "")
+                .with_context(|| format!("Failed to include {:?}", path))?;
+            merged.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn merge_table(into: &mut Table, from: Table, prefix: &str) -> Result<()> {
+    for (k, v) in from {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match (into.get_mut(&k), v) {
+            (None, v) => {
+                into.insert(k, v);
+            }
+            (Some(Value::Table(into)), Value::Table(from)) => merge_table(into, from, &key)?,
+            (Some(_), _) => bail!("`{}` is defined more than once", key),
+        }
+    }
+    Ok(())
+}
+
+// The files matching `pattern`, sorted by name. A pattern without wildcards must match a file
+async fn expand(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let name = pattern
+        .file_name()
+        .map(|v| v.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !has_wildcard(&name) {
+        return Ok(vec![pattern.to_owned()]);
+    }
+
+    let dir = match pattern.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v,
+        _ => Path::new("."),
+    };
+    let mut ret = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Failed to read the directory {:?}", dir))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file()
+            && wildcard_match(&name, &entry.file_name().to_string_lossy())
+        {
+            ret.push(entry.path());
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Whether `path` is one of the files `pattern` matches
+#[cfg_attr(not(feature = "notify"), allow(dead_code))]
+pub fn matches(pattern: &Path, path: &Path) -> bool {
+    match (pattern.file_name(), path.file_name()) {
+        (Some(p), Some(name)) => {
+            pattern.parent() == path.parent()
+                && wildcard_match(&p.to_string_lossy(), &name.to_string_lossy())
+        }
+        _ => false,
+    }
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+// Match `name` against `pattern`, where `*` matches any characters and `?` matches one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // Where the last `*` is, and where in `name` it matches up to
+    let mut star: Option<(usize, usize)> = None;
+    while j < n.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == n[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            // Let the `*` match one more character
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.toml", "web.toml"));
+        assert!(wildcard_match("*.toml", ".toml"));
+        assert!(!wildcard_match("*.toml", "web.toml.bak"));
+        assert!(wildcard_match("web-?.toml", "web-1.toml"));
+        assert!(!wildcard_match("web-?.toml", "web-10.toml"));
+        assert!(wildcard_match("*-*.toml", "a-b-c.toml"));
+        assert!(wildcard_match("web.toml", "web.toml"));
+        assert!(!wildcard_match("web.toml", "ssh.toml"));
+    }
+
+    #[tokio::test]
+    async fn test_merge() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rathole_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("services")).await?;
+        let path = dir.join("rathole.toml");
+        fs::write(
+            dir.join("services/b.toml"),
+            "[server.services.b]\nbind_addr = \"0.0.0.0:2001\"",
+        )
+        .await?;
+        fs::write(
+            dir.join("services/a.toml"),
+            "[server.services.a]\nbind_addr = \"0.0.0.0:2000\"",
+        )
+        .await?;
+        fs::write(dir.join("services/a.toml.bak"), "invalid").await?;
+
+        let mut root: Value = toml::from_str(
+            r#"
+include = ["services/*.toml"]
+
+[server]
+bind_addr = "0.0.0.0:2333"
+"#,
+        )?;
+        let patterns = take_patterns(&path, &mut root)?.unwrap();
+        assert_eq!(patterns, [dir.join("services/*.toml")]);
+        assert!(matches(&patterns[0], &dir.join("services/c.toml")));
+        assert!(!matches(&patterns[0], &dir.join("c.toml")));
+
+        merge(&mut root, &patterns).await?;
+        let services = root["server"]["services"].as_table().unwrap();
+        assert_eq!(
+            services.keys().collect::<Vec<_>>(),
+            [&String::from("a"), &String::from("b")]
+        );
+        assert!(root.get(INCLUDE_KEY).is_none());
+
+        // A service defined twice
+        fs::write(
+            dir.join("services/c.toml"),
+            "[server.services.a]\nbind_addr = \"0.0.0.0:2002\"",
+        )
+        .await?;
+        let mut root: Value = toml::from_str("include = [\"services/*.toml\"]")?;
+        let patterns = take_patterns(&path, &mut root)?.unwrap();
+        assert!(merge(&mut root, &patterns).await.is_err());
+
+        // Nothing to include
+        let mut root: Value = toml::from_str("[server]")?;
+        assert!(take_patterns(&path, &mut root)?.is_none());
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
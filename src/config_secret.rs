@@ -0,0 +1,186 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::fs;
+use toml::value::{Table, Value};
+
+// Secrets that can be read from a file by `<name>_file` instead
+const SECRET_KEYS: &[&str] = &[
+    "token",
+    "token_next",
+    "default_token",
+    "api_token",
+    "password",
+    "pkcs12_password",
+    "proxy_password",
+    "local_private_key",
+    "psk",
+];
+
+/// Expand `${NAME}` in strings of the config to the environment variable `NAME`,
+/// and replace `<secret>_file = "path"` with `<secret>` read from the file.
+/// `$${` stays as `${`. Returns whether anything is expanded
+pub fn expand(root: &mut Value) -> Result<bool> {
+    match root {
+        Value::Table(t) => expand_table("", t),
+        _ => Ok(false),
+    }
+}
+
+fn expand_table(name: &str, table: &mut Table) -> Result<bool> {
+    let mut changed = false;
+    for (k, v) in table.iter_mut() {
+        changed |= expand_value(k, v).with_context(|| format!("Failed to expand `{}`", k))?;
+    }
+
+    let files: Vec<String> = table
+        .keys()
+        .filter(|k| k.strip_suffix("_file").is_some_and(|v| is_secret(name, v)))
+        .cloned()
+        .collect();
+    for k in files {
+        let secret = k.trim_end_matches("_file").to_owned();
+        if table.contains_key(&secret) {
+            bail!("Only one of `{}` and `{}` can be set", secret, k);
+        }
+        let path = match table.remove(&k) {
+            Some(Value::String(v)) => v,
+            _ => bail!("`{}` must be a path", k),
+        };
+        let s = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read `{}` {:?}", k, path))?;
+        // Files usually end with a newline, which is not a part of the secret
+        let s = s.trim_end_matches(['\r', '\n']).to_owned();
+        table.insert(secret, Value::String(s));
+        changed = true;
+    }
+    Ok(changed)
+}
+
+fn expand_value(name: &str, v: &mut Value) -> Result<bool> {
+    match v {
+        Value::String(s) => match expand_str(s)? {
+            Some(expanded) => {
+                *s = expanded;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Value::Array(a) => {
+            let mut changed = false;
+            for v in a {
+                changed |= expand_value(name, v)?;
+            }
+            Ok(changed)
+        }
+        Value::Table(t) => expand_table(name, t),
+        _ => Ok(false),
+    }
+}
+
+fn is_secret(table: &str, key: &str) -> bool {
+    // `key` of `[transport.tls]` is a path
+    SECRET_KEYS.contains(&key) || (table == "obfs" && key == "key")
+}
+
+// None if there's nothing to expand in `s`
+fn expand_str(s: &str) -> Result<Option<String>> {
+    if !s.contains("${") {
+        return Ok(None);
+    }
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find("${") {
+        // Escaped by another `$`
+        if rest[..i].ends_with('$') {
+            ret.push_str(&rest[..i - 1]);
+            ret.push_str("${");
+            rest = &rest[i + 2..];
+            continue;
+        }
+        ret.push_str(&rest[..i]);
+        let end = rest[i..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed `${{` in {:?}", s))?;
+        let var = &rest[i + 2..i + end];
+        let value =
+            env::var(var).map_err(|_| anyhow!("The environment variable {} is not set", var))?;
+        ret.push_str(&value);
+        rest = &rest[i + end + 1..];
+    }
+    ret.push_str(rest);
+    Ok(Some(ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_str() -> Result<()> {
+        env::set_var("RATHOLE_TEST_TOKEN", "secret");
+        assert_eq!(expand_str("plain")?, None);
+        assert_eq!(
+            expand_str("${RATHOLE_TEST_TOKEN}")?,
+            Some(String::from("secret"))
+        );
+        assert_eq!(
+            expand_str("a-${RATHOLE_TEST_TOKEN}-b")?,
+            Some(String::from("a-secret-b"))
+        );
+        assert_eq!(
+            expand_str("$${RATHOLE_TEST_TOKEN}")?,
+            Some(String::from("${RATHOLE_TEST_TOKEN}"))
+        );
+        assert!(expand_str("${RATHOLE_TEST_UNSET}").is_err());
+        assert!(expand_str("${RATHOLE_TEST_TOKEN").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand() -> Result<()> {
+        let path = env::temp_dir().join(format!("rathole_secret_{}", std::process::id()));
+        fs::write(&path, "from_file\n")?;
+        env::set_var("RATHOLE_TEST_ADDR", "example.com:2333");
+
+        let mut root: Value = toml::from_str(&format!(
+            r#"
+[client]
+remote_addr = "${{RATHOLE_TEST_ADDR}}"
+
+[client.transport.obfs]
+key_file = {:?}
+
+[client.transport.tls]
+key = "key.pem"
+
+[client.services.foo]
+token_file = {:?}
+"#,
+            path, path
+        ))?;
+        assert!(expand(&mut root)?);
+        let client = &root["client"];
+        assert_eq!(client["remote_addr"].as_str(), Some("example.com:2333"));
+        assert_eq!(
+            client["services"]["foo"]["token"].as_str(),
+            Some("from_file")
+        );
+        assert!(client["services"]["foo"].get("token_file").is_none());
+        assert_eq!(
+            client["transport"]["obfs"]["key"].as_str(),
+            Some("from_file")
+        );
+        assert_eq!(client["transport"]["tls"]["key"].as_str(), Some("key.pem"));
+
+        // Both set
+        let mut root: Value = toml::from_str(&format!("token = \"a\"\ntoken_file = {:?}", path))?;
+        assert!(expand(&mut root).is_err());
+
+        // Nothing to expand
+        let mut root: Value = toml::from_str("[client]\nremote_addr = \"example.com:2333\"")?;
+        assert!(!expand(&mut root)?);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,41 +1,55 @@
 use crate::{
-    config::{ClientConfig, ClientServiceConfig, ServerConfig, ServerServiceConfig},
+    config::{
+        ClientConfig, ClientServiceConfig, ConfigOverride, ServerConfig, ServerServiceConfig,
+        TransportConfig,
+    },
+    helper::http_exchange,
     Config,
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use std::{
     collections::HashMap,
     env,
     path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{self, Instant},
 };
-use tokio::sync::{broadcast, mpsc};
 use tracing::{error, info, instrument};
+use url::Url;
 
 #[cfg(feature = "notify")]
 use notify::{EventKind, RecursiveMode, Watcher};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ConfigChange {
     General(Box<Config>), // Trigger a full restart
     ServerChange(ServerServiceChange),
     ClientChange(ClientServiceChange),
+    // Applied by re-establishing the control channels with the new `[server]` or `[client]`
+    ServerReload(Box<ServerConfig>),
+    ClientReload(Box<ClientConfig>),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ClientServiceChange {
-    Add(ClientServiceConfig),
+    Add(Box<ClientServiceConfig>),
     Delete(String),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ServerServiceChange {
-    Add(ServerServiceConfig),
+    Add(Box<ServerServiceConfig>),
     Delete(String),
 }
 
 trait InstanceConfig: Clone {
     type ServiceConfig: PartialEq + Eq + Clone;
     fn equal_without_service(&self, rhs: &Self) -> bool;
+    // None if the changes need a full restart
+    fn reload_change(old: &Self, new: &Self) -> Option<ConfigChange>;
     fn service_delete_change(s: String) -> ConfigChange;
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange;
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig>;
@@ -56,11 +70,27 @@ impl InstanceConfig for ServerConfig {
 
         left == right
     }
+    fn reload_change(old: &Self, new: &Self) -> Option<ConfigChange> {
+        // Everything else is set up once when the server starts
+        let fixed = |c: &ServerConfig| ServerConfig {
+            bind_addr: Default::default(),
+            default_token: None,
+            services: Default::default(),
+            transport: TransportConfig {
+                transport_type: c.transport.transport_type,
+                ..Default::default()
+            },
+            heartbeat_interval: 0,
+            handshake_timeout: None,
+            ..c.clone()
+        };
+        (fixed(old) == fixed(new)).then(|| ConfigChange::ServerReload(Box::new(new.clone())))
+    }
     fn service_delete_change(s: String) -> ConfigChange {
         ConfigChange::ServerChange(ServerServiceChange::Delete(s))
     }
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange {
-        ConfigChange::ServerChange(ServerServiceChange::Add(cfg))
+        ConfigChange::ServerChange(ServerServiceChange::Add(Box::new(cfg)))
     }
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig> {
         &self.services
@@ -82,25 +112,150 @@ impl InstanceConfig for ClientConfig {
 
         left == right
     }
+    fn reload_change(old: &Self, new: &Self) -> Option<ConfigChange> {
+        // The registry is polled by a task of its own
+        (old.transport.transport_type == new.transport.transport_type
+            && old.discovery == new.discovery)
+            .then(|| ConfigChange::ClientReload(Box::new(new.clone())))
+    }
     fn service_delete_change(s: String) -> ConfigChange {
         ConfigChange::ClientChange(ClientServiceChange::Delete(s))
     }
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange {
-        ConfigChange::ClientChange(ClientServiceChange::Add(cfg))
+        ConfigChange::ClientChange(ClientServiceChange::Add(Box::new(cfg)))
     }
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig> {
         &self.services
     }
 }
 
+/// The config path that reads the config from stdin
+pub const STDIN_PATH: &str = "-";
+/// How often a config served over HTTP(S) is polled by default, in secs
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 60;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The URL of the config, if `path` is a `http` or `https` one
+fn remote_url(path: &Path) -> Option<Url> {
+    let path = path.to_str()?;
+    if !(path.starts_with("http://") || path.starts_with("https://")) {
+        return None;
+    }
+    Url::parse(path).ok()
+}
+
+// A config served over HTTP(S), which is fetched again only if it's changed
+struct RemoteConfig {
+    url: Url,
+    // The validators of the last config fetched
+    etag: Option<String>,
+    last_modified: Option<String>,
+    overrides: Vec<ConfigOverride>,
+}
+
+impl RemoteConfig {
+    fn new(url: Url, overrides: Vec<ConfigOverride>) -> RemoteConfig {
+        RemoteConfig {
+            url,
+            etag: None,
+            last_modified: None,
+            overrides,
+        }
+    }
+
+    // None if it's not modified since the last fetch
+    async fn fetch(&mut self) -> Result<Option<Config>> {
+        let mut headers = vec![];
+        if let Some(v) = &self.etag {
+            headers.push(("If-None-Match", v.as_str()));
+        }
+        if let Some(v) = &self.last_modified {
+            headers.push(("If-Modified-Since", v.as_str()));
+        }
+        let mut resp = time::timeout(
+            FETCH_TIMEOUT,
+            http_exchange("GET", &self.url, &headers, None),
+        )
+        .await
+        .with_context(|| "Timeout")??;
+
+        match resp.code {
+            304 => return Ok(None),
+            200..=299 => (),
+            code => bail!("The config server responded with status {}", code),
+        }
+        let s = String::from_utf8(resp.body).with_context(|| "The config is not UTF-8")?;
+        let config = Config::parse(&s, &self.overrides).with_context(|| {
+            "Configuration is invalid. Please refer to the configuration specification."
+        })?;
+
+        // An invalid config is fetched again, in case it's fixed in place
+        self.etag = resp.headers.remove("etag");
+        self.last_modified = resp.headers.remove("last-modified");
+        Ok(Some(config))
+    }
+}
+
 pub struct ConfigWatcherHandle {
     pub event_rx: mpsc::UnboundedReceiver<ConfigChange>,
 }
 
 impl ConfigWatcherHandle {
-    pub async fn new(path: &Path, shutdown_rx: broadcast::Receiver<bool>) -> Result<Self> {
+    /// Watch the config at `path`, with `overrides` applied to every version of it.
+    /// A `http` or `https` URL is polled every `poll_interval`.
+    /// Without `path`, the config is made of `overrides` only
+    pub async fn new(
+        path: Option<&Path>,
+        overrides: Vec<ConfigOverride>,
+        poll_interval: Duration,
+        shutdown_rx: broadcast::Receiver<bool>,
+    ) -> Result<Self> {
         let (event_tx, event_rx) = mpsc::unbounded_channel();
-        let origin_cfg = Config::from_file(path).await?;
+
+        let path = match path {
+            Some(v) => v,
+            None => {
+                let origin_cfg = Config::from_overrides(&overrides)?;
+                event_tx
+                    .send(ConfigChange::General(Box::new(origin_cfg)))
+                    .unwrap();
+                tokio::spawn(idle_config_watcher(shutdown_rx, event_tx));
+                return Ok(ConfigWatcherHandle { event_rx });
+            }
+        };
+
+        if let Some(url) = remote_url(path) {
+            let mut remote = RemoteConfig::new(url, overrides);
+            let origin_cfg = remote
+                .fetch()
+                .await
+                .with_context(|| format!("Failed to fetch the config {}", remote.url))?
+                // Never `Not Modified`, since nothing is fetched before
+                .ok_or_else(|| anyhow!("The config server responded with `Not Modified`"))?;
+            event_tx
+                .send(ConfigChange::General(Box::new(origin_cfg.clone())))
+                .unwrap();
+            tokio::spawn(remote_config_watcher(
+                remote,
+                poll_interval,
+                shutdown_rx,
+                event_tx,
+                origin_cfg,
+            ));
+            return Ok(ConfigWatcherHandle { event_rx });
+        }
+
+        // A config from stdin can be read only once, so there's nothing to watch
+        if path == Path::new(STDIN_PATH) {
+            let origin_cfg = Config::from_stdin(&overrides).await?;
+            event_tx
+                .send(ConfigChange::General(Box::new(origin_cfg)))
+                .unwrap();
+            tokio::spawn(idle_config_watcher(shutdown_rx, event_tx));
+            return Ok(ConfigWatcherHandle { event_rx });
+        }
+
+        let (origin_cfg, includes) = Config::load_file(path, &overrides).await?;
 
         // Initial start
         event_tx
@@ -109,6 +264,8 @@ impl ConfigWatcherHandle {
 
         tokio::spawn(config_watcher(
             path.to_owned(),
+            includes,
+            overrides,
             shutdown_rx,
             event_tx,
             origin_cfg,
@@ -118,61 +275,82 @@ impl ConfigWatcherHandle {
     }
 }
 
-// Fake config watcher when compiling without `notify`
-#[cfg(not(feature = "notify"))]
-async fn config_watcher(
-    _path: PathBuf,
+// Keep the event channel open without watching anything
+async fn idle_config_watcher(
     mut shutdown_rx: broadcast::Receiver<bool>,
     _event_tx: mpsc::UnboundedSender<ConfigChange>,
-    _old: Config,
 ) -> Result<()> {
     // Do nothing except waiting for ctrl-c
     let _ = shutdown_rx.recv().await;
     Ok(())
 }
 
+// Fake config watcher when compiling without `notify`
+#[cfg(not(feature = "notify"))]
+async fn config_watcher(
+    _path: PathBuf,
+    _includes: Vec<PathBuf>,
+    _overrides: Vec<ConfigOverride>,
+    shutdown_rx: broadcast::Receiver<bool>,
+    event_tx: mpsc::UnboundedSender<ConfigChange>,
+    _old: Config,
+) -> Result<()> {
+    idle_config_watcher(shutdown_rx, event_tx).await
+}
+
 #[cfg(feature = "notify")]
-#[instrument(skip(shutdown_rx, event_tx, old))]
+#[instrument(skip(includes, overrides, shutdown_rx, event_tx, old))]
 async fn config_watcher(
     path: PathBuf,
+    includes: Vec<PathBuf>,
+    overrides: Vec<ConfigOverride>,
     mut shutdown_rx: broadcast::Receiver<bool>,
     event_tx: mpsc::UnboundedSender<ConfigChange>,
     mut old: Config,
 ) -> Result<()> {
     let (fevent_tx, mut fevent_rx) = mpsc::unbounded_channel();
-    let path = if path.is_absolute() {
-        path
-    } else {
-        env::current_dir()?.join(path)
-    };
-    let parent_path = path.parent().expect("config file should have a parent dir");
-    let path_clone = path.clone();
+    let cwd = env::current_dir()?;
+    let path = cwd.join(path);
+    let mut includes: Vec<PathBuf> = includes.into_iter().map(|v| cwd.join(v)).collect();
     let mut watcher =
         notify::recommended_watcher(move |res: Result<notify::Event, _>| match res {
             Ok(e) => {
-                if matches!(e.kind, EventKind::Modify(_))
-                    && e.paths
-                        .iter()
-                        .map(|x| x.file_name())
-                        .any(|x| x == path_clone.file_name())
-                {
-                    let _ = fevent_tx.send(true);
+                // Included files may come and go
+                if matches!(
+                    e.kind,
+                    EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)
+                ) {
+                    let _ = fevent_tx.send(e.paths);
                 }
             }
             Err(e) => error!("watch error: {:#}", e),
         })?;
 
-    watcher.watch(parent_path, RecursiveMode::NonRecursive)?;
+    // The directories of the config and the included files
+    let mut watched = Vec::new();
+    watch_dirs(&mut watcher, &mut watched, &path, &includes)?;
     info!("Start watching the config");
 
     loop {
         tokio::select! {
           e = fevent_rx.recv() => {
             match e {
-              Some(_) => {
+              Some(paths) => {
+                    if !paths.iter().any(|p| {
+                        p.file_name() == path.file_name()
+                            || includes.iter().any(|v| crate::config_include::matches(v, p))
+                    }) {
+                        continue;
+                    }
                     info!("Rescan the configuration");
-                    let new = match Config::from_file(&path).await.with_context(|| "The changed configuration is invalid. Ignored") {
-                      Ok(v) => v,
+                    let new = match Config::load_file(&path, &overrides).await.with_context(|| "The changed configuration is invalid. Ignored") {
+                      Ok((v, patterns)) => {
+                        includes = patterns;
+                        if let Err(e) = watch_dirs(&mut watcher, &mut watched, &path, &includes) {
+                            error!("{:#}", e);
+                        }
+                        v
+                      },
                       Err(e) => {
                         error!("{:#}", e);
                         // If the config is invalid, just ignore it
@@ -199,6 +377,68 @@ async fn config_watcher(
     Ok(())
 }
 
+#[instrument(skip_all, fields(url = %remote.url))]
+async fn remote_config_watcher(
+    mut remote: RemoteConfig,
+    poll_interval: Duration,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    event_tx: mpsc::UnboundedSender<ConfigChange>,
+    mut old: Config,
+) -> Result<()> {
+    let mut interval = time::interval_at(Instant::now() + poll_interval, poll_interval);
+    info!("Start polling the config");
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown_rx.recv() => break,
+        }
+
+        let new = match remote
+            .fetch()
+            .await
+            .with_context(|| "Failed to fetch the config. Keep the current one")
+        {
+            Ok(Some(v)) => v,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("{:#}", e);
+                continue;
+            }
+        };
+
+        info!("Rescan the configuration");
+        for event in calculate_events(&old, &new).into_iter().flatten() {
+            event_tx.send(event)?;
+        }
+        old = new;
+    }
+
+    info!("Config watcher exiting");
+
+    Ok(())
+}
+
+// Watch the directories of `path` and `includes` that are not in `watched` yet
+#[cfg(feature = "notify")]
+fn watch_dirs<W: Watcher>(
+    watcher: &mut W,
+    watched: &mut Vec<PathBuf>,
+    path: &Path,
+    includes: &[PathBuf],
+) -> Result<()> {
+    for file in std::iter::once(path).chain(includes.iter().map(|v| v.as_path())) {
+        let dir = file.parent().expect("config file should have a parent dir");
+        if !watched.iter().any(|v| v == dir) {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {:?}", dir))?;
+            watched.push(dir.to_owned());
+        }
+    }
+    Ok(())
+}
+
 fn calculate_events(old: &Config, new: &Config) -> Option<Vec<ConfigChange>> {
     if old == new {
         return None;
@@ -206,6 +446,9 @@ fn calculate_events(old: &Config, new: &Config) -> Option<Vec<ConfigChange>> {
 
     if (old.server.is_some() != new.server.is_some())
         || (old.client.is_some() != new.client.is_some())
+        || old.metrics != new.metrics
+        || old.notify != new.notify
+        || old.log != new.log
     {
         return Some(vec![ConfigChange::General(Box::new(new.clone()))]);
     }
@@ -241,7 +484,7 @@ fn calculate_instance_config_events<T: InstanceConfig>(
     new: &T,
 ) -> Option<Vec<ConfigChange>> {
     if !old.equal_without_service(new) {
-        return None;
+        return T::reload_change(old, new).map(|v| vec![v]);
     }
 
     let old = old.get_services();
@@ -262,7 +505,10 @@ fn calculate_instance_config_events<T: InstanceConfig>(
 
 #[cfg(test)]
 mod test {
-    use crate::config::ServerConfig;
+    use crate::config::{MetricsConfig, ServerConfig, TransportType};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
     use super::*;
 
@@ -287,10 +533,16 @@ mod test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: Some(Default::default()),
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
             },
             Test {
@@ -300,6 +552,9 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -308,12 +563,18 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
             },
             Test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -321,6 +582,9 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
             },
             Test {
@@ -330,10 +594,16 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
             },
             Test {
@@ -346,6 +616,9 @@ mod test {
                         services: collection!(String::from("foo1") => ClientServiceConfig::with_name("foo1"), String::from("foo2") => ClientServiceConfig::with_name("foo2")),
                         ..Default::default()
                     }),
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -356,33 +629,58 @@ mod test {
                         services: collection!(String::from("bar1") => ClientServiceConfig::with_name("bar1"), String::from("bar2") => ClientServiceConfig::with_name("bar2")),
                         ..Default::default()
                     }),
+                    metrics: None,
+                    notify: None,
+                    log: None,
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
+                },
+                new: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    metrics: Some(MetricsConfig {
+                        bind_addr: Some(String::from("127.0.0.1:9090")),
+                        statsd: None,
+                    }),
+                    notify: None,
+                    log: None,
                 },
             },
         ];
 
         let mut expected = [
             vec![ConfigChange::General(Box::new(tests[0].new.clone()))],
-            vec![ConfigChange::General(Box::new(tests[1].new.clone()))],
+            vec![ConfigChange::ServerReload(Box::new(
+                tests[1].new.server.clone().unwrap(),
+            ))],
             vec![ConfigChange::ServerChange(ServerServiceChange::Add(
-                Default::default(),
+                Box::default(),
             ))],
             vec![ConfigChange::ServerChange(ServerServiceChange::Delete(
                 String::from("foo"),
             ))],
             vec![
                 ConfigChange::ServerChange(ServerServiceChange::Delete(String::from("foo1"))),
-                ConfigChange::ServerChange(ServerServiceChange::Add(
+                ConfigChange::ServerChange(ServerServiceChange::Add(Box::new(
                     tests[4].new.server.as_ref().unwrap().services["bar1"].clone(),
-                )),
+                ))),
                 ConfigChange::ClientChange(ClientServiceChange::Delete(String::from("foo1"))),
                 ConfigChange::ClientChange(ClientServiceChange::Delete(String::from("foo2"))),
-                ConfigChange::ClientChange(ClientServiceChange::Add(
+                ConfigChange::ClientChange(ClientServiceChange::Add(Box::new(
                     tests[4].new.client.as_ref().unwrap().services["bar1"].clone(),
-                )),
-                ConfigChange::ClientChange(ClientServiceChange::Add(
+                ))),
+                ConfigChange::ClientChange(ClientServiceChange::Add(Box::new(
                     tests[4].new.client.as_ref().unwrap().services["bar2"].clone(),
-                )),
+                ))),
             ],
+            vec![ConfigChange::General(Box::new(tests[5].new.clone()))],
         ];
 
         assert_eq!(tests.len(), expected.len());
@@ -393,6 +691,8 @@ mod test {
             let get_key = |x: &ConfigChange| -> String {
                 match x {
                     ConfigChange::General(_) => String::from("g"),
+                    ConfigChange::ServerReload(_) => String::from("s_reload"),
+                    ConfigChange::ClientReload(_) => String::from("c_reload"),
                     ConfigChange::ServerChange(sc) => match sc {
                         ServerServiceChange::Add(c) => "s_add_".to_owned() + &c.name,
                         ServerServiceChange::Delete(s) => "s_del_".to_owned() + s,
@@ -416,13 +716,215 @@ mod test {
                 &Config {
                     server: Default::default(),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
                 &Config {
                     server: Default::default(),
                     client: None,
+                    metrics: None,
+                    notify: None,
+                    log: None,
                 },
             ),
             None
         );
     }
+
+    // A config server serving `doc`, with its length as the ETag
+    async fn mock_config_server(doc: Arc<Mutex<String>>) -> Url {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/rathole.toml", l.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = l.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = conn.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]).to_string();
+                let doc = doc.lock().unwrap().clone();
+                let etag = format!("\"{}\"", doc.len());
+                let resp = if req.contains(&format!("If-None-Match: {}\r\n", etag)) {
+                    String::from("HTTP/1.0 304 Not Modified\r\n\r\n")
+                } else {
+                    format!("HTTP/1.0 200 OK\r\nETag: {}\r\n\r\n{}", etag, doc)
+                };
+                let _ = conn.write_all(resp.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_remote_url() {
+        assert!(remote_url(Path::new("https://example.com/rathole.toml")).is_some());
+        assert!(remote_url(Path::new("http://example.com/rathole.toml")).is_some());
+        assert!(remote_url(Path::new("config/rathole.toml")).is_none());
+        assert!(remote_url(Path::new(STDIN_PATH)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_config() {
+        let config = r#"
+[server]
+bind_addr = "0.0.0.0:2333"
+default_token = "123"
+
+[server.services.foo]
+bind_addr = "0.0.0.0:5202"
+"#;
+        let doc = Arc::new(Mutex::new(String::from(config)));
+        let url = mock_config_server(doc.clone()).await;
+
+        // Fetched only if it's changed
+        let mut remote = RemoteConfig::new(url.clone(), vec![]);
+        assert!(remote.fetch().await.unwrap().is_some());
+        assert!(remote.fetch().await.unwrap().is_none());
+
+        // An invalid one is not taken
+        *doc.lock().unwrap() = String::from("[server");
+        assert!(remote.fetch().await.is_err());
+
+        // Changes are polled, and go through the same diff as a file
+        *doc.lock().unwrap() = String::from(config);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let mut handle = ConfigWatcherHandle::new(
+            Some(Path::new(url.as_str())),
+            vec![],
+            Duration::from_millis(100),
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            handle.event_rx.recv().await.unwrap(),
+            ConfigChange::General(_)
+        ));
+        doc.lock().unwrap().push_str(
+            r#"
+[server.services.bar]
+bind_addr = "0.0.0.0:5203"
+"#,
+        );
+        match handle.event_rx.recv().await.unwrap() {
+            ConfigChange::ServerChange(ServerServiceChange::Add(s)) => assert_eq!(s.name, "bar"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "notify")]
+    #[tokio::test]
+    async fn test_watch_includes() {
+        let dir = env::temp_dir().join(format!("rathole_watch_includes_{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("services"))
+            .await
+            .unwrap();
+        let path = dir.join("rathole.toml");
+        tokio::fs::write(
+            dir.join("services/bar.toml"),
+            "[server.services.bar]\nbind_addr = \"0.0.0.0:5203\"",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            &path,
+            "include = [\"services/*.toml\"]\n[server]\nbind_addr = \"0.0.0.0:2333\"\ndefault_token = \"123\"",
+        )
+        .await
+        .unwrap();
+
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let mut handle =
+            ConfigWatcherHandle::new(Some(&path), vec![], Duration::from_secs(1), shutdown_rx)
+                .await
+                .unwrap();
+        assert!(matches!(
+            handle.event_rx.recv().await.unwrap(),
+            ConfigChange::General(_)
+        ));
+
+        // A new file in the included directory adds its service
+        time::sleep(Duration::from_millis(500)).await; // Wait for the watcher to start
+        tokio::fs::write(
+            dir.join("services/foo.toml"),
+            "[server.services.foo]\nbind_addr = \"0.0.0.0:5202\"",
+        )
+        .await
+        .unwrap();
+        let event = time::timeout(Duration::from_secs(5), handle.event_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        match event {
+            ConfigChange::ServerChange(ServerServiceChange::Add(s)) => assert_eq!(s.name, "foo"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_reload_events() {
+        let server = |s: ServerConfig| Config {
+            server: Some(s),
+            client: None,
+            metrics: None,
+            notify: None,
+            log: None,
+        };
+        let old = ServerConfig {
+            bind_addr: String::from("0.0.0.0:2333"),
+            services: collection!(String::from("foo") => ServerServiceConfig::with_name("foo")),
+            ..Default::default()
+        };
+
+        // Applied by re-establishing the control channels
+        let mut new = old.clone();
+        new.heartbeat_interval = 10;
+        new.transport.tls = Some(Default::default());
+        assert_eq!(
+            calculate_events(&server(old.clone()), &server(new.clone())),
+            Some(vec![ConfigChange::ServerReload(Box::new(new))])
+        );
+
+        // Needs a full restart
+        let mut new = old.clone();
+        new.transport.transport_type = TransportType::Noise;
+        assert!(matches!(
+            calculate_events(&server(old.clone()), &server(new)).unwrap()[..],
+            [ConfigChange::General(_)]
+        ));
+        let mut new = old.clone();
+        new.grace_period = 30;
+        assert!(matches!(
+            calculate_events(&server(old), &server(new)).unwrap()[..],
+            [ConfigChange::General(_)]
+        ));
+
+        let client = |c: ClientConfig| Config {
+            server: None,
+            client: Some(c),
+            metrics: None,
+            notify: None,
+            log: None,
+        };
+        let old = ClientConfig {
+            remote_addr: "example.com:2333".into(),
+            ..Default::default()
+        };
+        let new = ClientConfig {
+            remote_addr: "example.org:2333".into(),
+            heartbeat_timeout: 60,
+            ..old.clone()
+        };
+        assert_eq!(
+            calculate_events(&client(old.clone()), &client(new.clone())),
+            Some(vec![ConfigChange::ClientReload(Box::new(new))])
+        );
+        let mut new = old.clone();
+        new.transport.transport_type = TransportType::Tls;
+        assert!(matches!(
+            calculate_events(&client(old), &client(new)).unwrap()[..],
+            [ConfigChange::General(_)]
+        ));
+    }
 }
@@ -0,0 +1,155 @@
+use crate::config::{OverflowPolicy, ServerConfig, ServerServiceConfig};
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+pub const DEFAULT_QUEUE_SIZE: usize = 64;
+pub const DEFAULT_QUEUE_TIMEOUT_SECS: u64 = 5;
+
+/// Limits the number of concurrent visitors of a service.
+/// A visitor holds a permit for as long as it's forwarded.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    slots: Arc<Semaphore>,
+    overflow: OverflowPolicy,
+    queue_size: usize,
+    queue_timeout: Duration,
+    // The number of visitors waiting for a slot
+    queued: AtomicUsize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(
+        max_connections: usize,
+        overflow: OverflowPolicy,
+        queue_size: usize,
+        queue_timeout: Duration,
+    ) -> ConnectionLimiter {
+        ConnectionLimiter {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            overflow,
+            queue_size,
+            queue_timeout,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a limiter for the service, if `max_connections` is set
+    pub fn from_service_cfg(cfg: &ServerServiceConfig) -> Option<ConnectionLimiter> {
+        cfg.max_connections.map(|max| {
+            ConnectionLimiter::new(
+                max,
+                cfg.overflow,
+                cfg.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+                Duration::from_secs(cfg.queue_timeout_secs.unwrap_or(DEFAULT_QUEUE_TIMEOUT_SECS)),
+            )
+        })
+    }
+
+    /// Create a limiter for all services, if `server.max_connections` is set
+    pub fn from_server_cfg(cfg: &ServerConfig) -> Option<ConnectionLimiter> {
+        cfg.max_connections
+            .map(|max| ConnectionLimiter::new(max, OverflowPolicy::Reject, 0, Duration::ZERO))
+    }
+
+    /// Take a slot for a new visitor, waiting in the queue if the policy allows.
+    /// An error tells why the visitor is turned away.
+    pub async fn admit(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        if self.overflow == OverflowPolicy::Reject {
+            bail!("The service is full");
+        }
+
+        // Reserve a place in the queue
+        if self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.queue_size).then_some(n + 1)
+            })
+            .is_err()
+        {
+            bail!("The service is full and the queue is full");
+        }
+
+        let ret = time::timeout(self.queue_timeout, self.slots.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+
+        match ret {
+            Ok(permit) => Ok(permit.expect("the semaphore is never closed")),
+            Err(_) => bail!(
+                "Timed out after {:?} waiting for a free slot",
+                self.queue_timeout
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reject() {
+        let l = ConnectionLimiter::new(1, OverflowPolicy::Reject, 1, Duration::from_secs(1));
+        let p = l.admit().await.unwrap();
+        assert!(l.admit().await.is_err());
+        drop(p);
+        assert!(l.admit().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_server_limit() {
+        let cfg = ServerConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let l = ConnectionLimiter::from_server_cfg(&cfg).unwrap();
+        let _p = l.admit().await.unwrap();
+
+        // Rejected right away, without queueing
+        let start = time::Instant::now();
+        assert!(l.admit().await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        assert!(ConnectionLimiter::from_server_cfg(&ServerConfig::default()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let l = Arc::new(ConnectionLimiter::new(
+            1,
+            OverflowPolicy::Queue,
+            1,
+            Duration::from_millis(500),
+        ));
+
+        // Fill the service
+        let p = l.admit().await.unwrap();
+
+        // The next one waits in the queue
+        let queued = tokio::spawn({
+            let l = l.clone();
+            async move { l.admit().await }
+        });
+        time::sleep(Duration::from_millis(100)).await;
+
+        // The queue is full, so the one after that is rejected right away
+        assert!(l.admit().await.is_err());
+
+        // The queued visitor proceeds once the slot is freed
+        drop(p);
+        let p = queued.await.unwrap().unwrap();
+
+        // Nobody frees the slot this time, so the queued visitor times out
+        let start = time::Instant::now();
+        assert!(l.admit().await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        drop(p);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{field, info, info_span, Span};
+
+/// The ID of a visitor connection. Assigned by the server and told to the client,
+/// so that the logs of both ends can be matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnId(pub u64);
+
+impl ConnId {
+    pub fn generate() -> ConnId {
+        ConnId(rand::random())
+    }
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// The span of a visitor connection, which logs of it are in
+pub fn span(id: ConnId, peer: Option<SocketAddr>) -> Span {
+    let span = info_span!("conn", %id, peer = field::Empty);
+    if let Some(peer) = peer {
+        span.record("peer", field::display(peer));
+    }
+    span
+}
+
+/// The traffic of a visitor connection, logged when it's closed
+#[derive(Debug)]
+pub struct ConnStats {
+    since: Instant,
+    // Bytes from the visitor
+    bytes_in: AtomicU64,
+    // Bytes to the visitor
+    bytes_out: AtomicU64,
+}
+
+impl ConnStats {
+    pub fn new() -> Arc<ConnStats> {
+        Arc::new(ConnStats {
+            since: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        })
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Log the traffic and the duration of the connection, in its span
+    pub fn log_closed(&self) {
+        info!(
+            bytes_in = self.bytes_in.load(Ordering::Relaxed),
+            bytes_out = self.bytes_out.load(Ordering::Relaxed),
+            duration_ms = self.since.elapsed().as_millis() as u64,
+            "Connection closed"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::ServerServiceConfig;
+use crate::conn_tracker::Activity;
+use tokio::time::{self, Duration, Instant};
+use tracing::debug;
+
+/// Closes forwarded connections of a service that are idle for `idle_timeout`,
+/// or open for `max_lifetime`, like half-dead ones behind NATs
+#[derive(Debug, Clone, Copy)]
+pub struct ConnTimeout {
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+impl ConnTimeout {
+    /// Create timeouts for the service, if either is set
+    pub fn from_service_cfg(cfg: &ServerServiceConfig) -> Option<ConnTimeout> {
+        if cfg.idle_timeout.is_none() && cfg.max_lifetime.is_none() {
+            return None;
+        }
+        Some(ConnTimeout {
+            idle_timeout: cfg.idle_timeout.map(Duration::from_secs),
+            max_lifetime: cfg.max_lifetime.map(Duration::from_secs),
+        })
+    }
+
+    /// Resolves when the connection opened just now with `activity` should be closed
+    pub async fn expired(self, activity: Activity) {
+        let opened = Instant::now();
+        loop {
+            let mut wait = Duration::MAX;
+            if let Some(max) = self.max_lifetime {
+                let left = max.saturating_sub(opened.elapsed());
+                if left.is_zero() {
+                    debug!("Connection reached the max lifetime");
+                    return;
+                }
+                wait = wait.min(left);
+            }
+            if let Some(idle) = self.idle_timeout {
+                let left = idle.saturating_sub(activity.idle());
+                if left.is_zero() {
+                    debug!("Connection idle for too long");
+                    return;
+                }
+                wait = wait.min(left);
+            }
+            time::sleep(wait).await;
+        }
+    }
+}
+
+/// Resolves when the connection should be closed by `timeout`, or never if it's None
+pub async fn expired(timeout: Option<ConnTimeout>, activity: Activity) {
+    match timeout {
+        Some(t) => t.expired(activity).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn timeout(idle: Option<u64>, max: Option<u64>) -> ConnTimeout {
+        ConnTimeout {
+            idle_timeout: idle.map(Duration::from_millis),
+            max_lifetime: max.map(Duration::from_millis),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let activity = Activity::since(std::time::Instant::now());
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut a = activity.wrap(a);
+        let expired = timeout(Some(200), None).expired(activity);
+        tokio::pin!(expired);
+
+        // Traffic keeps it open
+        for _ in 0..4 {
+            tokio::select! {
+                _ = &mut expired => panic!("Expired while active"),
+                _ = time::sleep(Duration::from_millis(100)) => {}
+            }
+            b.write_all(b"x").await.unwrap();
+            a.read_exact(&mut [0u8; 1]).await.unwrap();
+        }
+
+        let start = Instant::now();
+        expired.await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime() {
+        let activity = Activity::since(std::time::Instant::now());
+        let start = Instant::now();
+        timeout(Some(10_000), Some(200)).expired(activity).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+// Evict down to this fraction of the limit, so that it's not hit again right away
+const EVICTION_TARGET_PERCENT: usize = 90;
+// A forwarded connection holds a visitor and a data channel
+const FDS_PER_CONN: usize = 2;
+// How often to count the open file descriptors, which takes O(open fds).
+// In between, the count is estimated by the connections tracked since
+const FD_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Entry {
+    activity: Activity,
+    reap_tx: oneshot::Sender<()>,
+}
+
+/// The time of the last traffic of a connection
+#[derive(Debug, Clone)]
+pub struct Activity {
+    start: Instant,
+    // In millis since `start`
+    last_active: Arc<AtomicU64>,
+}
+
+/// Tracks the activity of forwarded connections, so that the least recently
+/// active ones can be reaped when the process is running out of file descriptors
+pub struct ConnTracker {
+    fd_soft_limit: usize,
+    start: Instant,
+    next_id: AtomicU64,
+    conns: Mutex<HashMap<u64, Entry>>,
+    fd_sample: Mutex<Option<FdSample>>,
+}
+
+// The open file descriptors counted at `at`, when `tracked` connections were tracked
+struct FdSample {
+    at: Instant,
+    open_fds: usize,
+    tracked: usize,
+}
+
+/// A connection registered in a `ConnTracker`. Unregistered on drop
+pub struct TrackedConn {
+    id: u64,
+    tracker: Arc<ConnTracker>,
+    activity: Activity,
+    /// Resolves when the connection is reaped
+    pub reaped: oneshot::Receiver<()>,
+}
+
+impl ConnTracker {
+    pub fn new(fd_soft_limit: usize) -> ConnTracker {
+        ConnTracker {
+            fd_soft_limit,
+            start: Instant::now(),
+            next_id: AtomicU64::new(0),
+            conns: Mutex::new(HashMap::new()),
+            fd_sample: Mutex::new(None),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.lock().unwrap().len()
+    }
+
+    /// Register a new connection
+    pub fn track(self: &Arc<Self>) -> TrackedConn {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let activity = Activity::since(self.start);
+        let (reap_tx, reaped) = oneshot::channel();
+        self.conns.lock().unwrap().insert(
+            id,
+            Entry {
+                activity: activity.clone(),
+                reap_tx,
+            },
+        );
+        TrackedConn {
+            id,
+            tracker: self.clone(),
+            activity,
+            reaped,
+        }
+    }
+
+    /// Reap the idlest connections if the number of open file descriptors
+    /// reaches the soft limit. Returns the number of connections reaped
+    pub fn reap_if_needed(&self) -> usize {
+        let open_fds = self.open_fds_at(Instant::now(), count_open_fds);
+        self.reap_for(open_fds)
+    }
+
+    // The number of open file descriptors, counted by `count` at most once in `FD_SAMPLE_INTERVAL`
+    fn open_fds_at(&self, now: Instant, count: impl FnOnce() -> Option<usize>) -> usize {
+        let tracked = self.len();
+        let mut sample = self.fd_sample.lock().unwrap();
+        if sample
+            .as_ref()
+            .is_none_or(|s| now.duration_since(s.at) >= FD_SAMPLE_INTERVAL)
+        {
+            *sample = count().map(|open_fds| FdSample {
+                at: now,
+                open_fds,
+                tracked,
+            });
+        }
+        match sample.as_ref() {
+            Some(s) => {
+                (s.open_fds + tracked * FDS_PER_CONN).saturating_sub(s.tracked * FDS_PER_CONN)
+            }
+            None => tracked * FDS_PER_CONN,
+        }
+    }
+
+    fn reap_for(&self, open_fds: usize) -> usize {
+        if open_fds < self.fd_soft_limit {
+            return 0;
+        }
+
+        let target = self.fd_soft_limit * EVICTION_TARGET_PERCENT / 100;
+        let n = (open_fds - target).div_ceil(FDS_PER_CONN);
+        warn!(
+            "{} file descriptors are open, reaching the soft limit {}. Reaping {} idle connections",
+            open_fds, self.fd_soft_limit, n
+        );
+        self.reap_idlest(n)
+    }
+
+    // Reap the `n` least recently active connections
+    fn reap_idlest(&self, n: usize) -> usize {
+        let mut conns = self.conns.lock().unwrap();
+
+        let mut by_activity: Vec<(u64, u64)> = conns
+            .iter()
+            .map(|(id, e)| (e.activity.last_active(), *id))
+            .collect();
+        by_activity.sort_unstable();
+
+        let mut reaped = 0;
+        for (_, id) in by_activity.into_iter().take(n) {
+            if let Some(e) = conns.remove(&id) {
+                let _ = e.reap_tx.send(());
+                reaped += 1;
+            }
+        }
+        debug!("Reaped {} connections", reaped);
+        reaped
+    }
+}
+
+impl TrackedConn {
+    /// Wrap `s` so that any traffic on it marks the connection as active
+    pub fn wrap<S>(&self, s: S) -> ActivityStream<S> {
+        self.activity.wrap(s)
+    }
+}
+
+impl Activity {
+    /// A connection active just now. Times are measured from `start`
+    pub fn since(start: Instant) -> Activity {
+        Activity {
+            start,
+            last_active: Arc::new(AtomicU64::new(start.elapsed().as_millis() as u64)),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_active
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last_active(&self) -> u64 {
+        self.last_active.load(Ordering::Relaxed)
+    }
+
+    /// How long the connection has had no traffic
+    pub fn idle(&self) -> Duration {
+        self.start
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_active()))
+    }
+
+    /// Wrap `s` so that any traffic on it marks the connection as active
+    pub fn wrap<S>(&self, s: S) -> ActivityStream<S> {
+        ActivityStream {
+            inner: s,
+            activity: self.clone(),
+        }
+    }
+}
+
+impl Drop for TrackedConn {
+    fn drop(&mut self) {
+        self.tracker.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A stream that records the time of the last traffic
+pub struct ActivityStream<S> {
+    inner: S,
+    activity: Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        ret
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// The number of file descriptors opened by the process, if the platform tells
+fn count_open_fds() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::read_dir("/proc/self/fd").ok().map(|d| d.count())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Push some traffic through a stream of `conn`
+    async fn make_active(conn: &TrackedConn) {
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut a = conn.wrap(a);
+        b.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        a.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reap_idlest() {
+        let tracker = Arc::new(ConnTracker::new(10));
+
+        let mut conns: Vec<TrackedConn> = (0..4).map(|_| tracker.track()).collect();
+        assert_eq!(tracker.len(), 4);
+
+        // Everything but the first two stays active
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for c in &conns[2..] {
+            make_active(c).await;
+        }
+
+        // Then the second one
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        make_active(&conns[1]).await;
+
+        // Under the limit, nothing happens
+        assert_eq!(tracker.reap_for(9), 0);
+
+        // 10 fds open, so reap down to 9, which is one connection: the idlest
+        assert_eq!(tracker.reap_for(10), 1);
+        assert!(conns[0].reaped.try_recv().is_ok());
+        for c in &mut conns[1..] {
+            assert!(c.reaped.try_recv().is_err());
+        }
+        assert_eq!(tracker.len(), 3);
+
+        // 13 fds open, so reap 2 more: the ones active before the second one
+        assert_eq!(tracker.reap_for(13), 2);
+        assert!(conns[2].reaped.try_recv().is_ok());
+        assert!(conns[3].reaped.try_recv().is_ok());
+        assert!(conns[1].reaped.try_recv().is_err());
+
+        // Dropping a connection unregisters it
+        drop(conns);
+        assert_eq!(tracker.len(), 0);
+    }
+
+    #[test]
+    fn test_open_fds() {
+        let tracker = Arc::new(ConnTracker::new(10));
+        let now = Instant::now();
+
+        // Without a count, estimated by the connections
+        let a = tracker.track();
+        assert_eq!(tracker.open_fds_at(now, || None), 2);
+
+        assert_eq!(tracker.open_fds_at(now, || Some(100)), 100);
+        // Counted again only after the interval, with the connections tracked since
+        let b = tracker.track();
+        let c = tracker.track();
+        drop(a);
+        assert_eq!(
+            tracker.open_fds_at(now + Duration::from_millis(500), || unreachable!()),
+            102
+        );
+        drop((b, c));
+        assert_eq!(
+            tracker.open_fds_at(now + FD_SAMPLE_INTERVAL, || Some(50)),
+            50
+        );
+    }
+}
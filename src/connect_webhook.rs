@@ -0,0 +1,146 @@
+use crate::config::{ConnectWebhookConfig, ServerServiceConfig};
+use crate::helper::http_request;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+/// What's posted to the webhook, in TOML
+#[derive(Debug, Serialize)]
+struct ConnectRequest<'a> {
+    service: &'a str,
+    peer: String,
+}
+
+/// What the webhook responds with, in TOML
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    allow: bool,
+    // Logged with the verdict
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+/// Asks a webhook whether to accept each new visitor of a service
+#[derive(Debug)]
+pub struct ConnectWebhook {
+    service: String,
+    config: ConnectWebhookConfig,
+}
+
+impl ConnectWebhook {
+    /// Create a webhook for the service, if `connect_webhook` is set
+    pub fn from_service_cfg(cfg: &ServerServiceConfig) -> Option<ConnectWebhook> {
+        cfg.connect_webhook.as_deref().map(|config| ConnectWebhook {
+            service: cfg.name.clone(),
+            config: config.clone(),
+        })
+    }
+
+    /// Whether the visitor from `peer` is allowed.
+    /// If the webhook fails to tell, `fail_open` decides.
+    pub async fn allows(&self, peer: SocketAddr) -> bool {
+        match self.ask(peer).await {
+            Ok(v) => {
+                if !v.metadata.is_empty() {
+                    info!(%peer, allow = v.allow, metadata = ?v.metadata, "Connect webhook verdict");
+                }
+                v.allow
+            }
+            Err(e) => {
+                warn!(
+                    "Connect webhook failed: {:#}. {} the visitor from {}",
+                    e,
+                    if self.config.fail_open {
+                        "Accept"
+                    } else {
+                        "Refuse"
+                    },
+                    peer
+                );
+                self.config.fail_open
+            }
+        }
+    }
+
+    async fn ask(&self, peer: SocketAddr) -> Result<Verdict> {
+        let req = toml::to_string(&ConnectRequest {
+            service: &self.service,
+            peer: peer.to_string(),
+        })?;
+
+        let (code, body) = time::timeout(
+            Duration::from_millis(self.config.timeout_ms),
+            http_request("POST", &self.config.url, Some(req.as_bytes())),
+        )
+        .await
+        .with_context(|| "Timeout")??;
+
+        if !(200..300).contains(&code) {
+            bail!("The webhook responded with status {}", code);
+        }
+
+        let body = String::from_utf8(body).with_context(|| "The webhook response is not UTF-8")?;
+        toml::from_str(&body).with_context(|| "Failed to parse the webhook response")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    // A webhook that denies visitors from `denied`
+    async fn mock_webhook(denied: SocketAddr) -> Result<Url> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/connect", l.local_addr()?))?;
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = l.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = conn.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]);
+                let body = if req.contains(&format!("peer = \"{}\"", denied)) {
+                    "allow = false\n[metadata]\nreason = \"blocked\"\n"
+                } else {
+                    "allow = true\n"
+                };
+                let resp = format!("HTTP/1.0 200 OK\r\n\r\n{}", body);
+                let _ = conn.write_all(resp.as_bytes()).await;
+            }
+        });
+        Ok(url)
+    }
+
+    fn webhook(url: Url, fail_open: bool) -> ConnectWebhook {
+        let mut cfg = ServerServiceConfig::with_name("foo");
+        cfg.connect_webhook = Some(Box::new(ConnectWebhookConfig {
+            url,
+            timeout_ms: 1000,
+            fail_open,
+        }));
+        ConnectWebhook::from_service_cfg(&cfg).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_webhook() -> Result<()> {
+        let denied: SocketAddr = "10.0.0.1:1234".parse()?;
+        let allowed: SocketAddr = "10.0.0.2:1234".parse()?;
+
+        let w = webhook(mock_webhook(denied).await?, false);
+        assert!(!w.allows(denied).await);
+        assert!(w.allows(allowed).await);
+
+        // Nothing listens at the url, so the policy decides
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/connect", l.local_addr()?))?;
+        drop(l);
+        assert!(!webhook(url.clone(), false).allows(allowed).await);
+        assert!(webhook(url, true).allows(allowed).await);
+
+        Ok(())
+    }
+}
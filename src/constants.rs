@@ -1,3 +1,4 @@
+use crate::config::BackoffConfig;
 use backoff::ExponentialBackoff;
 use std::time::Duration;
 
@@ -15,12 +16,20 @@ pub fn listen_backoff() -> ExponentialBackoff {
     }
 }
 
-pub fn run_control_chan_backoff(interval: u64) -> ExponentialBackoff {
+pub fn run_control_chan_backoff(interval: u64, config: &BackoffConfig) -> ExponentialBackoff {
+    let default = ExponentialBackoff::default();
+    let initial_interval = config
+        .initial_interval_ms
+        .map_or(default.initial_interval, Duration::from_millis);
     ExponentialBackoff {
-        randomization_factor: 0.2,
-        max_elapsed_time: None,
-        multiplier: 3.0,
-        max_interval: Duration::from_secs(interval),
-        ..Default::default()
+        current_interval: initial_interval,
+        initial_interval,
+        randomization_factor: config.jitter.unwrap_or(0.2),
+        max_elapsed_time: config.max_elapsed_time.map(Duration::from_secs),
+        multiplier: config.multiplier.unwrap_or(3.0),
+        max_interval: config
+            .max_interval_ms
+            .map_or(Duration::from_secs(interval), Duration::from_millis),
+        ..default
     }
 }
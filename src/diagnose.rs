@@ -0,0 +1,320 @@
+use crate::client::{do_control_channel_handshake, registration};
+use crate::config::{ClientConfig, Config, ServiceType, TransportType};
+use crate::config_watcher::STDIN_PATH;
+use crate::env_proxy;
+use crate::helper::{tcp_connect_with_proxy, to_socket_addr};
+use crate::protocol::{self, Ack};
+use crate::socket::SocketStream;
+use crate::transport::{AddrMaybeCached, ConnectOpts, TcpTransport, Transport};
+use anyhow::{anyhow, bail, Result};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+
+#[cfg(feature = "noise")]
+use crate::transport::NoiseTransport;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::transport::TlsTransport;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+use crate::transport::{HttpTransport, WebsocketTransport};
+
+// Timeout for every check that goes through the network
+const CHECK_TIMEOUT_SECS: u64 = 5;
+
+/// The result of a single check
+struct Check {
+    name: String,
+    // What's found if passed
+    result: Result<String>,
+}
+
+/// The results of all checks, in the order they run
+#[derive(Default)]
+pub struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn push(&mut self, name: impl Into<String>, result: Result<String>) -> bool {
+        let ok = result.is_ok();
+        self.checks.push(Check {
+            name: name.into(),
+            result,
+        });
+        ok
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.result.is_ok())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for c in &self.checks {
+            match &c.result {
+                Ok(v) => writeln!(f, "[PASS] {}: {}", c.name, v)?,
+                Err(e) => writeln!(f, "[FAIL] {}: {:#}", c.name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run all checks against the client configuration at `path`, and print the report.
+/// Fails if any check fails
+pub async fn run_diagnose(path: &Path) -> Result<()> {
+    let report = diagnose(path).await;
+    print!("{}", report);
+    if !report.passed() {
+        bail!("Some checks failed");
+    }
+    Ok(())
+}
+
+pub async fn diagnose(path: &Path) -> Report {
+    let mut report = Report::default();
+
+    let config = if path == Path::new(STDIN_PATH) {
+        Config::from_stdin(&[]).await
+    } else {
+        Config::from_file(path).await
+    };
+    let client = match config {
+        Ok(Config {
+            client: Some(client),
+            ..
+        }) => client,
+        Ok(_) => {
+            report.push(
+                "config",
+                Err(anyhow!(
+                    "No `[client]` block. Only clients can be diagnosed"
+                )),
+            );
+            return report;
+        }
+        Err(e) => {
+            report.push("config", Err(e));
+            return report;
+        }
+    };
+    report.push("config", Ok(format!("{} is valid", path.display())));
+
+    match client.transport.transport_type {
+        TransportType::Tcp => diagnose_client::<TcpTransport>(&client, &mut report).await,
+        TransportType::Tls => {
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            diagnose_client::<TlsTransport>(&client, &mut report).await;
+            #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+            report.push("transport", Err(anyhow!("TLS is not compiled in")));
+        }
+        TransportType::Noise => {
+            #[cfg(feature = "noise")]
+            diagnose_client::<NoiseTransport>(&client, &mut report).await;
+            #[cfg(not(feature = "noise"))]
+            report.push("transport", Err(anyhow!("Noise is not compiled in")));
+        }
+        TransportType::Websocket => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            diagnose_client::<WebsocketTransport>(&client, &mut report).await;
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            report.push("transport", Err(anyhow!("Websocket is not compiled in")));
+        }
+        TransportType::Http => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            diagnose_client::<HttpTransport>(&client, &mut report).await;
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            report.push("transport", Err(anyhow!("HTTP is not compiled in")));
+        }
+    }
+
+    report
+}
+
+async fn with_timeout<F, V>(f: F) -> Result<V>
+where
+    F: std::future::Future<Output = Result<V>>,
+{
+    time::timeout(Duration::from_secs(CHECK_TIMEOUT_SECS), f)
+        .await
+        .map_err(|_| anyhow!("Timeout"))?
+}
+
+// Checks every server address, and then the local address of each service
+async fn diagnose_client<T: Transport>(client: &ClientConfig, report: &mut Report) {
+    for addr in client.remote_addr.iter() {
+        diagnose_server::<T>(client, addr, report).await;
+    }
+
+    let mut services: Vec<_> = client.services.values().collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    for s in services {
+        for addr in s.local_addr.iter() {
+            let r = match s.service_type {
+                ServiceType::Tcp | ServiceType::Sni | ServiceType::Http => {
+                    with_timeout(async {
+                        SocketStream::connect(addr).await?;
+                        Ok(format!("{} is reachable", addr))
+                    })
+                    .await
+                }
+                // Being connectionless, there's nothing to check but the address
+                ServiceType::Udp => with_timeout(to_socket_addr(addr))
+                    .await
+                    .map(|v| format!("{} resolves to {}", addr, v)),
+                ServiceType::Echo | ServiceType::Socks5 | ServiceType::HttpProxy => continue,
+            };
+            // Tell the addresses apart if there're several
+            let name = if s.local_addr.len() > 1 {
+                format!("local {} {}", s.name, addr)
+            } else {
+                format!("local {}", s.name)
+            };
+            report.push(name, r);
+        }
+    }
+}
+
+// Checks from the server address `addr` down to the auth of each service. Later checks are
+// skipped if an earlier one that they depend on fails
+async fn diagnose_server<T: Transport>(client: &ClientConfig, addr: &str, report: &mut Report) {
+    // Tell the addresses apart if there're several
+    let name = |v: &str| {
+        if client.remote_addr.len() > 1 {
+            format!("{} {}", v, addr)
+        } else {
+            v.to_string()
+        }
+    };
+    let proxy = match env_proxy::proxies(&client.transport.tcp, addr) {
+        Ok(v) => v,
+        Err(e) => {
+            report.push(name("proxy"), Err(e));
+            return;
+        }
+    };
+    let mut remote_addr = AddrMaybeCached::new(addr);
+
+    // Through a proxy, the proxy resolves the address
+    if proxy.is_empty() {
+        let resolved = with_timeout(remote_addr.resolve()).await;
+        let resolved = resolved.map(|_| {
+            let addrs: Vec<String> = remote_addr
+                .socket_addrs
+                .iter()
+                .map(|v| v.to_string())
+                .collect();
+            format!("{} resolves to {}", addr, addrs.join(", "))
+        });
+        if !report.push(name("dns"), resolved) {
+            return;
+        }
+    }
+
+    // The rest of the hops are only reachable through it
+    if let Some(url) = proxy.first() {
+        let r = with_timeout(async {
+            let host = url.host_str().unwrap_or_default();
+            let port = url.port_or_known_default().unwrap_or_default();
+            TcpStream::connect((host, port)).await?;
+            Ok(format!("{}:{} is reachable", host, port))
+        })
+        .await;
+        if !report.push(name("proxy"), r) {
+            return;
+        }
+    }
+
+    let r = with_timeout(async {
+        tcp_connect_with_proxy(
+            &remote_addr,
+            &proxy,
+            &ConnectOpts::from_cfg(&client.transport),
+        )
+        .await?;
+        Ok(format!("{} is reachable", addr))
+    })
+    .await;
+    if !report.push(name("tcp"), r) {
+        return;
+    }
+
+    let transport = match T::new(&client.transport) {
+        Ok(v) => v,
+        Err(e) => {
+            report.push(name("handshake"), Err(e));
+            return;
+        }
+    };
+    let r = with_timeout(async {
+        transport.connect(&remote_addr).await?;
+        Ok(format!(
+            "{:?} handshake succeeded",
+            client.transport.transport_type
+        ))
+    })
+    .await;
+    if !report.push(name("handshake"), r) {
+        return;
+    }
+
+    let mut services: Vec<_> = client.services.values().collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    for s in services {
+        let digest = protocol::digest(s.name.as_bytes());
+        let token = s.token.as_ref().unwrap();
+        let r = with_timeout(async {
+            let mut conn = transport.connect(&remote_addr).await?;
+            let (_, ack) =
+                do_control_channel_handshake(&mut conn, &digest, token, registration(s).as_ref())
+                    .await?;
+            match ack {
+                Ack::Ok => Ok("The token is accepted".to_string()),
+                v => Err(anyhow!("{}", v)),
+            }
+        })
+        .await;
+        report.push(name(&format!("auth {}", s.name)), r);
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::server::run_server;
+    use tokio::net::TcpListener;
+    use tokio::sync::{broadcast, mpsc};
+
+    #[tokio::test]
+    async fn test_diagnose() -> Result<()> {
+        let config = Config::from_file(Path::new("tests/for_diagnose/server.toml")).await?;
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        tokio::spawn(run_server(
+            config,
+            shutdown_rx,
+            update_rx,
+            Default::default(),
+        ));
+        let _local = TcpListener::bind("127.0.0.1:8094").await?;
+        time::sleep(Duration::from_millis(500)).await;
+
+        let report = diagnose(Path::new("tests/for_diagnose/client.toml")).await;
+        assert!(report.passed(), "{}", report);
+
+        let report = diagnose(Path::new("tests/for_diagnose/client_bad_token.toml"))
+            .await
+            .to_string();
+        assert!(
+            report.contains("[FAIL] auth foo: Incorrect token"),
+            "{}",
+            report
+        );
+        assert!(report.contains("[PASS] handshake"), "{}", report);
+
+        shutdown_tx.send(true)?;
+        Ok(())
+    }
+}
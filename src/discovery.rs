@@ -0,0 +1,247 @@
+use crate::config::{
+    expand_port_ranges, ClientServiceConfig, Config, DiscoveryConfig, MaskedString,
+};
+use crate::config_watcher::ClientServiceChange;
+use crate::helper::http_request_with_headers;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration};
+use tracing::{error, info, instrument, warn};
+
+const FETCH_TIMEOUT: u64 = 10; // Timeout for fetching the registry in secs
+
+/// The document served by the registry, in the same format as `[client.services]`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Registry {
+    #[serde(default)]
+    services: HashMap<String, ClientServiceConfig>,
+}
+
+/// Per service defaults inherited from `[client]`
+#[derive(Debug, Clone)]
+pub struct ServiceDefaults {
+    pub default_token: Option<MaskedString>,
+    pub retry_interval: u64,
+}
+
+// Fetch service definitions from the registry and fill in the defaults
+async fn fetch_services(
+    config: &DiscoveryConfig,
+    defaults: &ServiceDefaults,
+) -> Result<HashMap<String, ClientServiceConfig>> {
+    let auth = config
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", &**token));
+    let headers: Vec<_> = auth.iter().map(|v| ("Authorization", v.as_str())).collect();
+    let (code, body) = time::timeout(
+        Duration::from_secs(FETCH_TIMEOUT),
+        http_request_with_headers("GET", &config.url, &headers, None),
+    )
+    .await
+    .with_context(|| "Timeout")??;
+
+    if !(200..300).contains(&code) {
+        bail!("The registry responded with status {}", code);
+    }
+
+    let body = String::from_utf8(body).with_context(|| "The registry response is not UTF-8")?;
+    let mut registry: Registry =
+        toml::from_str(&body).with_context(|| "Failed to parse the registry response")?;
+
+    expand_port_ranges(&mut registry.services, ClientServiceConfig::port_range_addr)?;
+    for (name, s) in &mut registry.services {
+        Config::validate_client_service_config(
+            name,
+            s,
+            defaults.default_token.as_ref(),
+            defaults.retry_interval,
+        )?;
+    }
+
+    Ok(registry.services)
+}
+
+// Calculate the changes needed to go from `old` to `new`
+fn calculate_events(
+    old: &HashMap<String, ClientServiceConfig>,
+    new: &HashMap<String, ClientServiceConfig>,
+) -> Vec<ClientServiceChange> {
+    let deletions = old
+        .keys()
+        .filter(|&name| !new.contains_key(name))
+        .map(|name| ClientServiceChange::Delete(name.to_owned()));
+
+    let additions = new
+        .iter()
+        .filter(|(name, c)| old.get(*name) != Some(*c))
+        .map(|(_, c)| ClientServiceChange::Add(Box::new(c.clone())));
+
+    deletions.chain(additions).collect()
+}
+
+/// Poll the registry and send service changes to the client.
+/// Services defined in the configuration file take precedence over discovered ones.
+/// `static_services` follows the configuration as it's reloaded
+#[instrument(skip_all, fields(url = %config.url))]
+pub async fn run_discovery(
+    config: DiscoveryConfig,
+    static_services: watch::Receiver<HashSet<String>>,
+    defaults: ServiceDefaults,
+    event_tx: mpsc::Sender<ClientServiceChange>,
+) {
+    let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+    let mut discovered = HashMap::new();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            // The client is gone
+            _ = event_tx.closed() => break,
+        }
+
+        let mut new = match fetch_services(&config, &defaults)
+            .await
+            .with_context(|| "Failed to fetch services from the registry")
+        {
+            Ok(v) => v,
+            Err(e) => {
+                // Keep the services discovered last time
+                error!("{:#}", e);
+                continue;
+            }
+        };
+
+        let static_services = static_services.borrow().clone();
+        // Replaced by the ones defined in the configuration since the last poll
+        discovered.retain(|name, _| !static_services.contains(name));
+        new.retain(|name, _| {
+            let is_static = static_services.contains(name);
+            if is_static {
+                warn!(
+                    "Ignored discovered service {} since it's defined in the configuration",
+                    name
+                );
+            }
+            !is_static
+        });
+
+        for event in calculate_events(&discovered, &new) {
+            info!("Service change discovered. {:?}", event);
+            if event_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+
+        discovered = new;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    const TOKEN: &str = "registry_token";
+
+    // A registry that serves whatever is in `doc` to requests with `TOKEN`
+    async fn mock_registry(doc: Arc<Mutex<String>>) -> Result<Url> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/services", l.local_addr()?))?;
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = l.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = conn.read(&mut buf).await.unwrap_or_default();
+                let auth = format!("\r\nAuthorization: Bearer {}\r\n", TOKEN);
+                let resp = match String::from_utf8_lossy(&buf[..n]).contains(&auth) {
+                    true => format!("HTTP/1.0 200 OK\r\n\r\n{}", doc.lock().unwrap()),
+                    false => String::from("HTTP/1.0 401 Unauthorized\r\n\r\n"),
+                };
+                let _ = conn.write_all(resp.as_bytes()).await;
+            }
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_discovery() -> Result<()> {
+        let doc = Arc::new(Mutex::new(String::from(
+            r#"
+[services.foo]
+local_addr = "127.0.0.1:80"
+
+[services.static]
+local_addr = "127.0.0.1:81"
+"#,
+        )));
+        let url = mock_registry(doc.clone()).await?;
+        let defaults = ServiceDefaults {
+            default_token: Some("123".into()),
+            retry_interval: 1,
+        };
+
+        // The registry needs the token
+        let mut config = DiscoveryConfig {
+            url,
+            interval_secs: 1,
+            token: None,
+        };
+        assert!(fetch_services(&config, &defaults).await.is_err());
+        config.token = Some(TOKEN.into());
+
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let (static_tx, static_rx) = watch::channel(HashSet::from([String::from("static")]));
+        tokio::spawn(run_discovery(config, static_rx, defaults, event_tx));
+
+        // The initial fetch exposes `foo` with the default token filled in
+        match event_rx.recv().await.unwrap() {
+            ClientServiceChange::Add(s) => {
+                assert_eq!(s.name, "foo");
+                assert_eq!(s.token, Some("123".into()));
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        // A service added to the registry mid-run shows up on the next poll
+        doc.lock().unwrap().push_str(
+            r#"
+[services.bar]
+local_addr = "127.0.0.1:82"
+token = "456"
+"#,
+        );
+        match event_rx.recv().await.unwrap() {
+            ClientServiceChange::Add(s) => assert_eq!(s.name, "bar"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        // A service defined in the configuration by a reload isn't deleted with the discovered one
+        static_tx.send_modify(|s| {
+            s.insert(String::from("foo"));
+        });
+        // And a removed one is deleted
+        *doc.lock().unwrap() = String::new();
+        match event_rx.recv().await.unwrap() {
+            ClientServiceChange::Delete(s) => assert_eq!(s, "bar"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        // A service removed from the configuration can be discovered
+        static_tx.send_modify(|s| {
+            s.remove("static");
+        });
+        *doc.lock().unwrap() = String::from("[services.static]\nlocal_addr = \"127.0.0.1:81\"\n");
+        match event_rx.recv().await.unwrap() {
+            ClientServiceChange::Add(s) => assert_eq!(s.name, "static"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::LoadBalance;
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+// The capacity of the chan of each member
+const MEMBER_CHAN_SIZE: usize = 2048;
+
+/// The number of items that a member is busy with
+#[derive(Debug, Clone, Default)]
+pub struct Load(Arc<AtomicUsize>);
+
+impl Load {
+    /// Count an item as busy until the returned guard is dropped
+    pub fn start(&self) -> LoadGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        LoadGuard(self.0.clone())
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct LoadGuard(Arc<AtomicUsize>);
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Member<T> {
+    weight: u32,
+    tx: mpsc::Sender<T>,
+    load: Load,
+    // The credit in the smooth weighted round-robin
+    current: i64,
+}
+
+impl<T> Member<T> {
+    // Items being handled, and those still queued for the member
+    fn load(&self) -> u64 {
+        (self.load.get() + MEMBER_CHAN_SIZE - self.tx.capacity()) as u64
+    }
+}
+
+/// Distributes items, e.g. visitors of a service, across members that come and go.
+/// Each item goes to one member picked by the `LoadBalance` policy, in proportion to the weights.
+/// Members are gone once they drop their receivers.
+pub struct Dispatcher<T> {
+    members: Arc<Mutex<Vec<Member<T>>>>,
+    // Shutdown the source of items by dropping it
+    _shutdown_tx: broadcast::Sender<bool>,
+}
+
+impl<T: 'static + Send> Dispatcher<T> {
+    /// Dispatch items from `rx`. `shutdown_tx` is dropped along with the dispatcher
+    pub fn new(
+        mut rx: mpsc::Receiver<T>,
+        policy: LoadBalance,
+        shutdown_tx: broadcast::Sender<bool>,
+    ) -> Dispatcher<T> {
+        let members = Arc::new(Mutex::new(Vec::new()));
+
+        let members_clone = members.clone();
+        tokio::spawn(async move {
+            while let Some(item) = rx.recv().await {
+                let tx = pick(&mut members_clone.lock().unwrap(), policy);
+                match tx {
+                    Some(tx) => {
+                        let _ = tx.send(item).await;
+                    }
+                    None => warn!("No member to dispatch to. Dropped"),
+                }
+            }
+            debug!("Dispatcher shutdown");
+        });
+
+        Dispatcher {
+            members,
+            _shutdown_tx: shutdown_tx,
+        }
+    }
+
+    /// Join as a member with `weight`. Returns where the items for it come,
+    /// and the load that the member reports for `LoadBalance::LeastConnections`
+    pub fn join(&self, weight: u32) -> (mpsc::Receiver<T>, Load) {
+        let (tx, rx) = mpsc::channel(MEMBER_CHAN_SIZE);
+        let load = Load::default();
+        self.members.lock().unwrap().push(Member {
+            weight,
+            tx,
+            load: load.clone(),
+            current: 0,
+        });
+        (rx, load)
+    }
+}
+
+// Pick a member by `policy`, forgetting the gone ones. Members weighing 0 get nothing
+fn pick<T>(members: &mut Vec<Member<T>>, policy: LoadBalance) -> Option<mpsc::Sender<T>> {
+    members.retain(|m| !m.tx.is_closed());
+
+    let total: u64 = members.iter().map(|m| m.weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    match policy {
+        LoadBalance::Random => Some(pick_random(members, total)),
+        LoadBalance::RoundRobin => {
+            // Smooth weighted round-robin, which spreads the picks of a heavy member out
+            let mut picked: Option<&mut Member<T>> = None;
+            for m in members.iter_mut().filter(|m| m.weight > 0) {
+                m.current += m.weight as i64;
+                if picked.as_ref().is_none_or(|p| m.current > p.current) {
+                    picked = Some(m);
+                }
+            }
+            let picked = picked.unwrap();
+            picked.current -= total as i64;
+            Some(picked.tx.clone())
+        }
+        LoadBalance::LeastConnections => members
+            .iter()
+            .filter(|m| m.weight > 0)
+            // The least load per weight, without dividing
+            .min_by(|a, b| (a.load() * b.weight as u64).cmp(&(b.load() * a.weight as u64)))
+            .map(|m| m.tx.clone()),
+    }
+}
+
+// Pick a member at random in proportion to the weights
+fn pick_random<T>(members: &[Member<T>], total: u64) -> mpsc::Sender<T> {
+    let mut n = rand::thread_rng().gen_range(0..total);
+    for m in members.iter() {
+        if n < m.weight as u64 {
+            return m.tx.clone();
+        }
+        n -= m.weight as u64;
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dispatch_by_weight() {
+        let (tx, rx) = mpsc::channel(4096);
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let d = Dispatcher::new(rx, LoadBalance::Random, shutdown_tx);
+
+        let (mut light, _) = d.join(1);
+        let (mut heavy, _) = d.join(3);
+        let (gone, _) = d.join(100);
+        drop(gone);
+
+        for i in 0..4000 {
+            tx.send(i).await.unwrap();
+        }
+
+        // Nothing goes to the gone member, and the rest is about 1:3
+        let (mut l, mut h) = (0, 0);
+        while l + h < 4000 {
+            tokio::select! {
+                Some(_) = light.recv() => l += 1,
+                Some(_) = heavy.recv() => h += 1,
+            }
+        }
+        assert!((800..1200).contains(&l), "{} vs {}", l, h);
+    }
+
+    fn member(weight: u32) -> (Member<u32>, mpsc::Receiver<u32>) {
+        let (tx, rx) = mpsc::channel(MEMBER_CHAN_SIZE);
+        let m = Member {
+            weight,
+            tx,
+            load: Load::default(),
+            current: 0,
+        };
+        (m, rx)
+    }
+
+    // Which member of `members` gets the next item
+    fn pick_index(members: &mut Vec<Member<u32>>, policy: LoadBalance) -> usize {
+        let tx = pick(members, policy).unwrap();
+        members.iter().position(|m| m.tx.same_channel(&tx)).unwrap()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let (a, _a) = member(1);
+        let (b, _b) = member(2);
+        let (c, _c) = member(0);
+        let mut members = vec![a, b, c];
+
+        let picks: Vec<_> = (0..6)
+            .map(|_| pick_index(&mut members, LoadBalance::RoundRobin))
+            .collect();
+        assert_eq!(picks, [1, 0, 1, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let (a, _a) = member(1);
+        let (b, _b) = member(2);
+        let mut members = vec![a, b];
+
+        // Ties go to the first one
+        assert_eq!(pick_index(&mut members, LoadBalance::LeastConnections), 0);
+
+        let _busy = members[0].load.start();
+        assert_eq!(pick_index(&mut members, LoadBalance::LeastConnections), 1);
+
+        // Twice the weight takes twice the load
+        let _busy = [members[1].load.start(), members[1].load.start()];
+        assert_eq!(pick_index(&mut members, LoadBalance::LeastConnections), 0);
+
+        let _busy = members[0].load.start();
+        assert_eq!(pick_index(&mut members, LoadBalance::LeastConnections), 1);
+
+        // Queued items count too
+        members[1].tx.try_send(0).unwrap();
+        members[1].tx.try_send(1).unwrap();
+        assert_eq!(pick_index(&mut members, LoadBalance::LeastConnections), 0);
+    }
+}
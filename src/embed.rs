@@ -0,0 +1,181 @@
+#[cfg(feature = "client")]
+use crate::config::ClientConfig;
+use crate::config::Config;
+#[cfg(feature = "server")]
+use crate::config::ServerConfig;
+use crate::event::{Event, Hook, Hooks};
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+
+// Events beyond this are dropped for subscribers that fall behind
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+/// A client to run within another application, instead of the binary
+#[cfg(feature = "client")]
+pub struct Client {
+    config: Config,
+    events: broadcast::Sender<Event>,
+    hooks: Hooks,
+}
+
+#[cfg(feature = "client")]
+impl Client {
+    /// Create a client from `config`, which is validated and filled in with the defaults like `[client]`
+    pub fn new(mut config: ClientConfig) -> Result<Client> {
+        Config::validate_client_config(&mut config)?;
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        Ok(Client {
+            config: Config {
+                server: None,
+                client: Some(config),
+                metrics: None,
+                notify: None,
+                log: None,
+            },
+            events,
+            hooks: Hooks::default(),
+        })
+    }
+
+    /// Receive the events of services from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Call `hook` on every event of services
+    pub fn add_hook(&mut self, hook: Arc<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Run until `shutdown_rx` receives
+    pub async fn run(mut self, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+        // Services don't change, but the channel has to stay open
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        self.hooks.push(Arc::new(self.events));
+        crate::client::run_client(self.config, shutdown_rx, update_rx, self.hooks).await
+    }
+}
+
+/// A server to run within another application, instead of the binary
+#[cfg(feature = "server")]
+pub struct Server {
+    config: Config,
+    events: broadcast::Sender<Event>,
+    hooks: Hooks,
+}
+
+#[cfg(feature = "server")]
+impl Server {
+    /// Create a server from `config`, which is validated and filled in with the defaults like `[server]`
+    pub fn new(mut config: ServerConfig) -> Result<Server> {
+        Config::validate_server_config(&mut config)?;
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        Ok(Server {
+            config: Config {
+                server: Some(config),
+                client: None,
+                metrics: None,
+                notify: None,
+                log: None,
+            },
+            events,
+            hooks: Hooks::default(),
+        })
+    }
+
+    /// Receive the events of services from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Call `hook` on every event of services
+    pub fn add_hook(&mut self, hook: Arc<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Run until `shutdown_rx` receives
+    pub async fn run(mut self, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+        // Services don't change, but the channel has to stay open
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        self.hooks.push(Arc::new(self.events));
+        crate::server::run_server(self.config, shutdown_rx, update_rx, self.hooks).await
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::config::{ClientServiceConfig, ServerServiceConfig, ServiceType};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::{self, Duration};
+
+    // Keeps the kinds of events
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<&'static str>>);
+
+    impl Hook for Recorder {
+        fn on_event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embed() -> Result<()> {
+        let mut server_config = ServerConfig {
+            bind_addr: "127.0.0.1:2359".into(),
+            default_token: Some("t".into()),
+            ..Default::default()
+        };
+        let mut s = ServerServiceConfig::with_name("echo");
+        s.bind_addr = "127.0.0.1:2360".into();
+        server_config.services.insert("echo".into(), s);
+
+        let mut client_config = ClientConfig {
+            remote_addr: "127.0.0.1:2359".into(),
+            default_token: Some("t".into()),
+            ..Default::default()
+        };
+        let mut s = ClientServiceConfig::with_name("echo");
+        s.service_type = ServiceType::Echo;
+        client_config.services.insert("echo".into(), s);
+
+        let mut server = Server::new(server_config)?;
+        let client = Client::new(client_config)?;
+        let mut server_events = server.subscribe();
+        let mut client_events = client.subscribe();
+        let recorder = Arc::new(Recorder::default());
+        server.add_hook(recorder.clone());
+
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let server = tokio::spawn(server.run(shutdown_tx.subscribe()));
+        let client = tokio::spawn(client.run(shutdown_tx.subscribe()));
+
+        let timeout = Duration::from_secs(5);
+        let e = time::timeout(timeout, client_events.recv()).await??;
+        assert_eq!(
+            e,
+            Event::Online {
+                service: "echo".into(),
+                peer: "127.0.0.1:2359".into()
+            }
+        );
+        let e = time::timeout(timeout, server_events.recv()).await??;
+        assert!(matches!(e, Event::Online { service, .. } if service == "echo"));
+
+        let mut visitor = TcpStream::connect("127.0.0.1:2360").await?;
+        visitor.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        time::timeout(timeout, visitor.read_exact(&mut buf)).await??;
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["online", "visitor_connected", "data_channel_opened"]
+        );
+
+        shutdown_tx.send(true)?;
+        let _ = tokio::join!(server, client);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use crate::helper::spawn_command;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Something that happens to a service, for applications embedding rathole to react to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A control channel of the service is established with `peer`
+    Online { service: String, peer: String },
+    /// The control channel of the service with `peer` is closed
+    Offline { service: String, peer: String },
+    /// A control channel handshake of the service failed, e.g. with an incorrect token
+    HandshakeFailed { service: String },
+    /// `peer` failed to authenticate a control channel of the service.
+    /// Rate limited per service, so that an attack doesn't flood the hooks
+    AuthFailed {
+        service: String,
+        peer: SocketAddr,
+        reason: AuthFailureReason,
+    },
+    /// A visitor of the service is accepted by the server. `peer` is unknown for Unix domain sockets
+    VisitorConnected {
+        service: String,
+        peer: Option<SocketAddr>,
+    },
+    /// A data channel of the service starts forwarding
+    DataChannelOpened { service: String },
+    /// A data channel of the service stops forwarding
+    DataChannelClosed { service: String },
+}
+
+/// Why a control channel failed to authenticate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailureReason {
+    ServiceNotExist,
+    IncorrectToken,
+}
+
+impl Display for AuthFailureReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthFailureReason::ServiceNotExist => "service not exist",
+            AuthFailureReason::IncorrectToken => "incorrect token",
+        })
+    }
+}
+
+impl Event {
+    /// The name of the kind of the event, like "online"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Online { .. } => "online",
+            Event::Offline { .. } => "offline",
+            Event::HandshakeFailed { .. } => "handshake_failed",
+            Event::AuthFailed { .. } => "auth_failed",
+            Event::VisitorConnected { .. } => "visitor_connected",
+            Event::DataChannelOpened { .. } => "data_channel_opened",
+            Event::DataChannelClosed { .. } => "data_channel_closed",
+        }
+    }
+
+    pub fn service(&self) -> &str {
+        match self {
+            Event::Online { service, .. }
+            | Event::Offline { service, .. }
+            | Event::HandshakeFailed { service }
+            | Event::AuthFailed { service, .. }
+            | Event::VisitorConnected { service, .. }
+            | Event::DataChannelOpened { service }
+            | Event::DataChannelClosed { service } => service,
+        }
+    }
+
+    /// The address of the other end, if known
+    pub fn peer(&self) -> Option<String> {
+        match self {
+            Event::Online { peer, .. } | Event::Offline { peer, .. } => Some(peer.clone()),
+            Event::VisitorConnected { peer, .. } => peer.map(|v| v.to_string()),
+            Event::AuthFailed { peer, .. } => Some(peer.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Gets events of services as they happen, e.g. to audit visitors or update a firewall.
+/// It's called inline, so it should return quickly
+pub trait Hook: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl Hook for broadcast::Sender<Event> {
+    fn on_event(&self, event: &Event) {
+        // Nobody subscribes for now
+        let _ = self.send(event.clone());
+    }
+}
+
+/// The hooks of an instance
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn Hook>>);
+
+impl Hooks {
+    pub fn push(&mut self, hook: Arc<dyn Hook>) {
+        self.0.push(hook);
+    }
+
+    pub fn on_event(&self, event: &Event) {
+        for hook in &self.0 {
+            hook.on_event(event);
+        }
+    }
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
+}
+
+/// Runs the `on_connect` and `on_disconnect` commands of a service
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandHook {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+}
+
+impl Hook for CommandHook {
+    fn on_event(&self, event: &Event) {
+        let (what, cmd) = match event {
+            Event::Online { .. }
+            | Event::VisitorConnected { .. }
+            | Event::DataChannelOpened { .. } => ("on_connect", &self.on_connect),
+            Event::Offline { .. } | Event::DataChannelClosed { .. } => {
+                ("on_disconnect", &self.on_disconnect)
+            }
+            Event::HandshakeFailed { .. } | Event::AuthFailed { .. } => return,
+        };
+        if let Some(cmd) = cmd {
+            spawn_command(
+                what,
+                cmd,
+                &[
+                    ("RATHOLE_EVENT", event.kind().to_string()),
+                    ("RATHOLE_SERVICE", event.service().to_string()),
+                    ("RATHOLE_PEER_ADDR", event.peer().unwrap_or_default()),
+                ],
+            );
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use crate::config::ServerConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Forget peers with full buckets once this many are tracked, so that the table stays bounded
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Limits the rate of new connections to `server.bind_addr` from each source IP,
+/// so that a scanner can't keep the server busy with handshakes.
+/// A token bucket per IP, which allows a burst of one second of connections
+#[derive(Debug)]
+pub struct HandshakeLimiter {
+    // Connections per second
+    rate: f64,
+    peers: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(per_sec: u32) -> HandshakeLimiter {
+        HandshakeLimiter {
+            rate: per_sec as f64,
+            peers: Default::default(),
+        }
+    }
+
+    /// Create a limiter, if `server.handshake_rate_limit` is set
+    pub fn from_server_cfg(cfg: &ServerConfig) -> Option<HandshakeLimiter> {
+        cfg.handshake_rate_limit.map(HandshakeLimiter::new)
+    }
+
+    /// Take a token for a new connection from `ip`. False if it's over the rate
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        if peers.len() >= PRUNE_THRESHOLD {
+            let rate = self.rate;
+            peers.retain(|_, b| {
+                b.tokens + now.duration_since(b.last_refill).as_secs_f64() * rate < rate
+            });
+        }
+
+        let b = peers.entry(ip).or_insert(Bucket {
+            tokens: self.rate,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(b.last_refill).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * self.rate).min(self.rate);
+        b.last_refill = now;
+
+        if b.tokens >= 1.0 {
+            b.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_allow() {
+        let limiter = HandshakeLimiter::new(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(a, now));
+        assert!(limiter.allow_at(a, now));
+        assert!(!limiter.allow_at(a, now));
+        // Other IPs have their own buckets
+        assert!(limiter.allow_at(b, now));
+
+        // Refilled at the rate
+        assert!(limiter.allow_at(a, now + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(a, now + Duration::from_millis(600)));
+        // But no more than a burst of one second
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.allow_at(a, later));
+        assert!(limiter.allow_at(a, later));
+        assert!(!limiter.allow_at(a, later));
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = HandshakeLimiter::new(1);
+        let now = Instant::now();
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(limiter.allow_at(IpAddr::from(i.to_be_bytes()), now));
+        }
+        // Peers with full buckets again are forgotten
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.allow_at("10.0.0.1".parse().unwrap(), later));
+        assert_eq!(limiter.peers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::{Addrs, HealthCheckConfig, HealthCheckType};
+use crate::helper::http_request;
+use crate::socket::SocketStream;
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tracing::{info, warn, Instrument, Span};
+use url::Url;
+
+/// Whether the local service is healthy
+pub type Health = watch::Receiver<bool>;
+
+/// Check the service at `local_addr` every `interval` in the background. It's healthy if
+/// any of the addresses passes. Returns whether it's healthy, which is false until the first
+/// check passes. Checking stops once all receivers are dropped
+pub fn run_health_check(local_addr: Addrs, config: HealthCheckConfig) -> Health {
+    let (tx, rx) = watch::channel(false);
+
+    tokio::spawn(
+        async move {
+            let mut interval = time::interval(Duration::from_secs(config.interval));
+            // The first check can't tell a change
+            let mut first = true;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = tx.closed() => break,
+                }
+
+                let mut r = Err(anyhow!("No local_addr"));
+                for addr in local_addr.iter() {
+                    r = check(addr, &config)
+                        .await
+                        .with_context(|| format!("Failed to check {}", addr));
+                    if r.is_ok() {
+                        break;
+                    }
+                }
+                let healthy = r.is_ok();
+                if healthy == *tx.borrow() && !first {
+                    continue;
+                }
+                match r {
+                    Ok(()) => info!("{} is healthy", local_addr),
+                    Err(e) => warn!("{} is unhealthy: {:#}", local_addr, e),
+                }
+                first = false;
+                tx.send_replace(healthy);
+            }
+        }
+        .instrument(Span::current()),
+    );
+
+    rx
+}
+
+async fn check(local_addr: &str, config: &HealthCheckConfig) -> Result<()> {
+    let check = async {
+        match config.check_type {
+            HealthCheckType::Tcp => {
+                SocketStream::connect(local_addr).await?;
+            }
+            HealthCheckType::Http => {
+                let url = Url::parse(&format!("http://{}{}", local_addr, config.path))?;
+                let (code, _) = http_request("GET", &url, None).await?;
+                if !(200..400).contains(&code) {
+                    bail!("Responded with status {}", code);
+                }
+            }
+        }
+        Ok(())
+    };
+    time::timeout(Duration::from_secs(config.timeout), check)
+        .await
+        .with_context(|| "Timeout")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn config(check_type: HealthCheckType) -> HealthCheckConfig {
+        HealthCheckConfig {
+            check_type,
+            interval: 1,
+            timeout: 1,
+            path: "/healthz".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_check() -> Result<()> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = l.local_addr()?.to_string();
+        let mut health = run_health_check(addr.as_str().into(), config(HealthCheckType::Tcp));
+        health.wait_for(|v| *v).await?;
+
+        drop(l);
+        health.wait_for(|v| !*v).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_check() -> Result<()> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = l.local_addr()?.to_string();
+        tokio::spawn(async move {
+            let mut n = 0;
+            while let Ok((mut conn, _)) = l.accept().await {
+                let mut buf = [0; 1024];
+                let len = conn.read(&mut buf).await.unwrap();
+                assert!(buf[..len].starts_with(b"GET /healthz "));
+                // Healthy only for the first request
+                let status = if n == 0 { "200 OK" } else { "503 Unavailable" };
+                let resp = format!("HTTP/1.0 {}\r\n\r\n", status);
+                conn.write_all(resp.as_bytes()).await.unwrap();
+                n += 1;
+            }
+        });
+
+        let mut health = run_health_check(addr.as_str().into(), config(HealthCheckType::Http));
+        health.wait_for(|v| *v).await?;
+        health.wait_for(|v| !*v).await?;
+        Ok(())
+    }
+}
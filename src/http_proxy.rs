@@ -0,0 +1,291 @@
+use crate::config::ProxyAuth;
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+use tracing::debug;
+use url::Url;
+
+const CONNECT_TIMEOUT: u64 = 10; // Timeout for connecting to the destination in secs
+const MAX_HTTP_HEAD_LEN: usize = 16384;
+const MAX_HTTP_HEADERS: usize = 64;
+
+// Headers meant for the proxy, which are not forwarded
+const HOP_BY_HOP_HEADERS: [&str; 3] = ["proxy-authorization", "proxy-connection", "connection"];
+
+/// Serve an HTTP proxy visitor on `conn`, and return the connection to the destination it asks
+/// for, along with what should be sent to the destination before the rest of `conn`.
+/// Both `CONNECT` and requests with an absolute URI are supported.
+/// The visitor must present `auth` in the Proxy-Authorization header if given
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    auth: Option<&ProxyAuth>,
+) -> Result<(TcpStream, Vec<u8>)> {
+    let head = read_head(conn).await?;
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(&head) {
+        Ok(httparse::Status::Complete(v)) => v,
+        _ => {
+            reply(conn, "400 Bad Request").await?;
+            bail!("Invalid HTTP request");
+        }
+    };
+
+    if let Some(auth) = auth {
+        let expected = format!("Basic {}", basic_credentials(auth));
+        let authorized = req.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("proxy-authorization") && h.value == expected.as_bytes()
+        });
+        if !authorized {
+            conn.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Basic realm=\"rathole\"\r\n\
+                  Content-Length: 0\r\n\r\n",
+            )
+            .await?;
+            bail!("Incorrect or missing proxy credentials");
+        }
+    }
+
+    let method = req.method.unwrap_or_default();
+    let target = req.path.unwrap_or_default();
+    let (host, port, first) = if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = match parse_authority(target) {
+            Some(v) => v,
+            None => {
+                reply(conn, "400 Bad Request").await?;
+                bail!("Invalid CONNECT target {}", target);
+            }
+        };
+        (host, port, head[len..].to_vec())
+    } else {
+        let url = match Url::parse(target) {
+            Ok(v) if v.scheme() == "http" && v.host_str().is_some() => v,
+            _ => {
+                reply(conn, "400 Bad Request").await?;
+                bail!("Not a request for a proxy: {} {}", method, target);
+            }
+        };
+        let host = url.host_str().unwrap_or_default();
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        (host, port, rewrite_head(&req, &url, &head[len..]))
+    };
+
+    debug!("Connecting to {}:{}", host, port);
+    let dest = time::timeout(
+        Duration::from_secs(CONNECT_TIMEOUT),
+        TcpStream::connect((host.as_str(), port)),
+    )
+    .await;
+    let dest = match dest {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            reply(conn, "502 Bad Gateway").await?;
+            return Err(e).with_context(|| format!("Failed to connect to {}:{}", host, port));
+        }
+        Err(_) => {
+            reply(conn, "504 Gateway Timeout").await?;
+            bail!("Timeout connecting to {}:{}", host, port);
+        }
+    };
+    if method.eq_ignore_ascii_case("CONNECT") {
+        conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        conn.flush().await?;
+    }
+    Ok((dest, first))
+}
+
+// Read until the end of the head of an HTTP request, and possibly more
+async fn read_head<S: AsyncRead + Unpin>(conn: &mut S) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        if !matches!(req.parse(&head), Ok(httparse::Status::Partial)) {
+            return Ok(head);
+        }
+        if head.len() >= MAX_HTTP_HEAD_LEN {
+            bail!("The head of the HTTP request is too large");
+        }
+        let mut buf = [0u8; 4096];
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Closed before the head of the HTTP request is complete");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+}
+
+// `("example.com", 443)` of `example.com:443`, and `("::1", 443)` of `[::1]:443`
+fn parse_authority(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+// Turn the request into one for the destination, with the path in the origin form and headers
+// for the proxy removed. The connection is closed after the response, since following requests
+// may be for other destinations
+fn rewrite_head(req: &httparse::Request, url: &Url, rest: &[u8]) -> Vec<u8> {
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let mut ret = format!(
+        "{} {} HTTP/1.{}\r\n",
+        req.method.unwrap_or_default(),
+        path,
+        req.version.unwrap_or_default()
+    )
+    .into_bytes();
+    for h in req.headers.iter() {
+        if HOP_BY_HOP_HEADERS
+            .iter()
+            .any(|v| h.name.eq_ignore_ascii_case(v))
+        {
+            continue;
+        }
+        ret.extend_from_slice(h.name.as_bytes());
+        ret.extend_from_slice(b": ");
+        ret.extend_from_slice(h.value);
+        ret.extend_from_slice(b"\r\n");
+    }
+    ret.extend_from_slice(b"Connection: close\r\n\r\n");
+    ret.extend_from_slice(rest);
+    ret
+}
+
+async fn reply<S: AsyncWrite + Unpin>(conn: &mut S, status: &str) -> Result<()> {
+    conn.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+        .await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+// `username:password` in base64, as in the Proxy-Authorization header
+fn basic_credentials(auth: &ProxyAuth) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let input = format!("{}:{}", auth.username, &*auth.password).into_bytes();
+    let mut ret = String::new();
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(n >> (18 - 6 * i)) & 0x3f] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use tokio::net::TcpListener;
+
+    fn auth(username: &str, password: &str) -> ProxyAuth {
+        ProxyAuth {
+            username: username.to_string(),
+            password: password.into(),
+        }
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(basic_credentials(&auth("user", "pass")), "dXNlcjpwYXNz");
+        assert_eq!(basic_credentials(&auth("a", "")), "YTo=");
+        assert_eq!(basic_credentials(&auth("ab", "")), "YWI6");
+        assert_eq!(basic_credentials(&auth("abc", "")), "YWJjOg==");
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(
+            parse_authority("example.com:443"),
+            Some(("example.com".to_string(), 443))
+        );
+        assert_eq!(parse_authority("[::1]:80"), Some(("::1".to_string(), 80)));
+        assert_eq!(parse_authority("example.com"), None);
+        assert_eq!(parse_authority(":80"), None);
+    }
+
+    #[tokio::test]
+    async fn test_connect() -> Result<()> {
+        let dest = TcpListener::bind("127.0.0.1:0").await?;
+        let port = dest.local_addr()?.port();
+        let (mut visitor, mut conn) = duplex(1024);
+        let server = tokio::spawn(async move { accept(&mut conn, None).await });
+
+        visitor
+            .write_all(format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\nearly", port).as_bytes())
+            .await?;
+        let mut buf = [0u8; 39];
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        let (_, first) = server.await??;
+        assert_eq!(first, b"early");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_absolute_uri() -> Result<()> {
+        let dest = TcpListener::bind("127.0.0.1:0").await?;
+        let port = dest.local_addr()?.port();
+        let (mut visitor, mut conn) = duplex(1024);
+        let server =
+            tokio::spawn(async move { accept(&mut conn, Some(&auth("user", "pass"))).await });
+
+        visitor
+            .write_all(
+                format!(
+                    "GET http://127.0.0.1:{}/a?b HTTP/1.1\r\n\
+                     Host: 127.0.0.1\r\n\
+                     Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+                     Proxy-Connection: keep-alive\r\n\r\n",
+                    port
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let (_, first) = server.await??;
+        assert_eq!(
+            first,
+            b"GET /a?b HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> Result<()> {
+        let (mut visitor, mut conn) = duplex(1024);
+        let server =
+            tokio::spawn(async move { accept(&mut conn, Some(&auth("user", "pass"))).await });
+
+        visitor
+            .write_all(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\nProxy-Authorization: Basic eDp5\r\n\r\n")
+            .await?;
+        let mut buf = [0u8; 12];
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"HTTP/1.1 407");
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+}
@@ -1,75 +1,166 @@
 mod cli;
 mod config;
+mod config_include;
+mod config_secret;
 mod config_watcher;
+mod conn_log;
 mod constants;
+mod embed;
+mod env_proxy;
+mod event;
 mod helper;
+#[cfg(feature = "noise")]
+mod keygen;
+mod log_output;
+mod metrics;
 mod multi_map;
+mod mux;
 mod protocol;
+mod rate_limit;
+mod socket;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+mod splice;
+mod statsd;
+mod systemd;
 mod transport;
 
 pub use cli::Cli;
 use cli::KeypairType;
-pub use config::Config;
+pub use config::{
+    Addrs, ClientConfig, ClientServiceConfig, Config, MaskedString, ServerConfig,
+    ServerServiceConfig, ServiceType, TransportConfig, TransportType,
+};
 pub use constants::UDP_BUFFER_SIZE;
+#[cfg(feature = "client")]
+pub use embed::Client;
+#[cfg(feature = "server")]
+pub use embed::Server;
+pub use event::{AuthFailureReason, Event, Hook};
+pub use log_output::LogWriter;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc};
 use tracing::{debug, info};
 
+#[cfg(feature = "client")]
+mod bench;
 #[cfg(feature = "client")]
 mod client;
 #[cfg(feature = "client")]
+mod diagnose;
+#[cfg(feature = "client")]
+mod discovery;
+#[cfg(feature = "client")]
+mod health_check;
+#[cfg(feature = "client")]
+mod http_proxy;
+#[cfg(feature = "client")]
+mod socks5;
+#[cfg(feature = "client")]
 use client::run_client;
 
+#[cfg(feature = "server")]
+mod accept_error;
+#[cfg(feature = "server")]
+mod acl;
+#[cfg(feature = "server")]
+mod admin_api;
+#[cfg(feature = "server")]
+mod auth_failure;
+#[cfg(feature = "server")]
+mod ban;
+#[cfg(feature = "server")]
+mod conn_limit;
+#[cfg(feature = "server")]
+mod conn_timeout;
+#[cfg(feature = "server")]
+mod conn_tracker;
+#[cfg(feature = "server")]
+mod connect_webhook;
+#[cfg(feature = "server")]
+mod dispatcher;
+#[cfg(feature = "server")]
+mod handshake_limit;
+#[cfg(feature = "server")]
+mod notify;
+#[cfg(feature = "server")]
+mod pool_sizer;
+#[cfg(all(unix, feature = "server"))]
+mod privilege;
+#[cfg(feature = "server")]
+mod proxy_protocol;
 #[cfg(feature = "server")]
 mod server;
 #[cfg(feature = "server")]
+mod socket_activation;
+#[cfg(feature = "server")]
+mod traffic;
+#[cfg(feature = "server")]
+mod vhost;
+#[cfg(feature = "server")]
 use server::run_server;
 
-use crate::config_watcher::{ConfigChange, ConfigWatcherHandle};
-
-const DEFAULT_CURVE: KeypairType = KeypairType::X25519;
-
-fn get_str_from_keypair_type(curve: KeypairType) -> &'static str {
-    match curve {
-        KeypairType::X25519 => "25519",
-        KeypairType::X448 => "448",
-    }
-}
+use crate::config_watcher::{ConfigChange, ConfigWatcherHandle, DEFAULT_POLL_INTERVAL_SECS};
 
 #[cfg(feature = "noise")]
-fn genkey(curve: Option<KeypairType>) -> Result<()> {
-    let curve = curve.unwrap_or(DEFAULT_CURVE);
-    let builder = snowstorm::Builder::new(
-        format!(
-            "Noise_KK_{}_ChaChaPoly_BLAKE2s",
-            get_str_from_keypair_type(curve)
-        )
-        .parse()?,
-    );
-    let keypair = builder.generate_keypair()?;
-
-    println!("Private Key:\n{}\n", base64::encode(keypair.private));
-    println!("Public Key:\n{}", base64::encode(keypair.public));
-    Ok(())
+fn genkey(curve: Option<KeypairType>, args: &Cli) -> Result<()> {
+    keygen::run_genkey(
+        curve.unwrap_or(keygen::DEFAULT_CURVE),
+        args.genkey_format,
+        args.genkey_from.as_deref(),
+        args.genkey_write.as_deref(),
+    )
 }
 
 #[cfg(not(feature = "noise"))]
-fn genkey(curve: Option<KeypairType>) -> Result<()> {
+fn genkey(curve: Option<KeypairType>, _args: &Cli) -> Result<()> {
     crate::helper::feature_not_compile("nosie")
 }
 
 pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
-    if args.genkey.is_some() {
-        return genkey(args.genkey.unwrap());
+    if let Some(curve) = args.genkey {
+        return genkey(curve, &args);
+    }
+
+    if args.diagnose {
+        #[cfg(not(feature = "client"))]
+        crate::helper::feature_not_compile("client");
+        #[cfg(feature = "client")]
+        return diagnose::run_diagnose(args.config_path.as_ref().unwrap()).await;
+    }
+
+    if let Some(service) = &args.bench {
+        #[cfg(not(feature = "client"))]
+        crate::helper::feature_not_compile("client");
+        #[cfg(feature = "client")]
+        return bench::run_bench(
+            args.config_path.as_ref().unwrap(),
+            service,
+            args.bench_addr.as_deref(),
+            args.bench_duration,
+        )
+        .await;
     }
 
     // Raise `nofile` limit on linux and mac
     fdlimit::raise_fd_limit();
 
     // Spawn a config watcher. The watcher will send a initial signal to start the instance with a config
-    let config_path = args.config_path.as_ref().unwrap();
-    let mut cfg_watcher = ConfigWatcherHandle::new(config_path, shutdown_rx).await?;
+    let poll_interval = args
+        .config_poll_interval
+        .unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+    if poll_interval == 0 {
+        bail!("`--config-poll-interval` must be greater than 0");
+    }
+    let poll_interval = Duration::from_secs(poll_interval);
+    let mut cfg_watcher = ConfigWatcherHandle::new(
+        args.config_path.as_deref(),
+        args.config_overrides()?,
+        poll_interval,
+        shutdown_rx,
+    )
+    .await?;
 
     // shutdown_tx owns the instance
     let (shutdown_tx, _) = broadcast::channel(1);
@@ -77,7 +168,26 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
     // (The join handle of the last instance, The service update channel sender)
     let mut last_instance: Option<(tokio::task::JoinHandle<_>, mpsc::Sender<ConfigChange>)> = None;
 
-    while let Some(e) = cfg_watcher.event_rx.recv().await {
+    // Answer the watchdog of systemd, if any, as long as the loop goes
+    let mut watchdog = systemd::watchdog();
+
+    // The result of the instance if it stops on its own, e.g. drained by the admin API
+    let mut exited = None;
+    loop {
+        let e = tokio::select! {
+            e = cfg_watcher.event_rx.recv() => match e {
+                Some(e) => e,
+                None => break,
+            },
+            r = async { (&mut last_instance.as_mut().unwrap().0).await }, if last_instance.is_some() => {
+                exited = Some(r);
+                break;
+            },
+            _ = systemd::tick(&mut watchdog) => {
+                systemd::notify("WATCHDOG=1");
+                continue;
+            }
+        };
         match e {
             ConfigChange::General(config) => {
                 if let Some((i, _)) = last_instance {
@@ -86,6 +196,7 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
                     i.await??;
                 }
 
+                log_output::configure(config.log.as_ref())?;
                 debug!("{:?}", config);
 
                 let (service_update_tx, service_update_rx) = mpsc::channel(1024);
@@ -101,7 +212,12 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
                 ));
             }
             ev => {
-                info!("Service change detected. {:?}", ev);
+                match ev {
+                    ConfigChange::ServerReload(_) | ConfigChange::ClientReload(_) => {
+                        info!("Configuration change detected. Re-establishing the channels...")
+                    }
+                    _ => info!("Service change detected. {:?}", ev),
+                }
                 if let Some((_, service_update_tx)) = &last_instance {
                     let _ = service_update_tx.send(ev).await;
                 }
@@ -109,7 +225,15 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
         }
     }
 
+    systemd::notify("STOPPING=1");
+    if let Some(r) = exited {
+        return r?;
+    }
     let _ = shutdown_tx.send(true);
+    // Let the instance drain its connections
+    if let Some((i, _)) = last_instance {
+        let _ = i.await;
+    }
 
     Ok(())
 }
@@ -126,21 +250,78 @@ async fn run_instance(
             #[cfg(not(feature = "client"))]
             crate::helper::feature_not_compile("client");
             #[cfg(feature = "client")]
-            run_client(config, shutdown_rx, service_update).await
+            run_client(config, shutdown_rx, service_update, Default::default()).await
         }
         RunMode::Server => {
             #[cfg(not(feature = "server"))]
             crate::helper::feature_not_compile("server");
             #[cfg(feature = "server")]
-            run_server(config, shutdown_rx, service_update).await
+            run_server(config, shutdown_rx, service_update, Default::default()).await
+        }
+        RunMode::Both => {
+            #[cfg(not(feature = "server"))]
+            crate::helper::feature_not_compile("server");
+            #[cfg(all(feature = "server", not(feature = "client")))]
+            crate::helper::feature_not_compile("client");
+            #[cfg(all(feature = "server", feature = "client"))]
+            run_both(config, shutdown_rx, service_update).await
         }
     }
 }
 
+// Run the server and the client of `config` side by side, like a relay node.
+// Either failing stops both
+#[cfg(all(feature = "server", feature = "client"))]
+async fn run_both(
+    config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    mut service_update: mpsc::Receiver<ConfigChange>,
+) -> Result<()> {
+    let (server_tx, server_rx) = mpsc::channel(1024);
+    let (client_tx, client_rx) = mpsc::channel(1024);
+    // `[metrics]` and `[notify]` are served by the server, since they can't be bound twice
+    let client_config = Config {
+        metrics: None,
+        notify: None,
+        ..config.clone()
+    };
+    let roles = async {
+        tokio::try_join!(
+            run_server(
+                config,
+                shutdown_rx.resubscribe(),
+                server_rx,
+                Default::default()
+            ),
+            run_client(client_config, shutdown_rx, client_rx, Default::default()),
+        )
+    };
+    tokio::pin!(roles);
+
+    loop {
+        let ev = tokio::select! {
+            r = &mut roles => return r.map(|_| ()),
+            ev = service_update.recv() => match ev {
+                Some(v) => v,
+                None => return roles.await.map(|_| ()),
+            },
+        };
+        let tx = match ev {
+            ConfigChange::ServerChange(_) | ConfigChange::ServerReload(_) => &server_tx,
+            ConfigChange::ClientChange(_) | ConfigChange::ClientReload(_) => &client_tx,
+            // Handled by restarting the instance
+            ConfigChange::General(_) => continue,
+        };
+        let _ = tx.send(ev).await;
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 enum RunMode {
     Server,
     Client,
+    // Both `[server]` and `[client]` are present, and no mode is given
+    Both,
     Undetermine,
 }
 
@@ -156,6 +337,8 @@ fn determine_run_mode(config: &Config, args: &Cli) -> RunMode {
         Client
     } else if config.server.is_some() && config.client.is_none() {
         Server
+    } else if config.server.is_some() && config.client.is_some() {
+        Both
     } else {
         Undetermine
     }
@@ -205,7 +388,7 @@ mod tests {
                 cfg_c: true,
                 arg_s: false,
                 arg_c: false,
-                run_mode: Undetermine,
+                run_mode: Both,
             },
             T {
                 cfg_s: true,
@@ -240,6 +423,9 @@ mod tests {
                     true => Some(ClientConfig::default()),
                     false => None,
                 },
+                metrics: None,
+                notify: None,
+                log: None,
             };
 
             let args = Cli {
@@ -0,0 +1,288 @@
+use crate::config::{LogConfig, LogOutput};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+// The syslog facility of messages, which is `daemon`
+#[cfg(unix)]
+const SYSLOG_FACILITY: u8 = 3;
+#[cfg(unix)]
+const SYSLOG_IDENTIFIER: &str = "rathole";
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output::Stdout);
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+}
+
+impl Output {
+    fn new(config: Option<&LogConfig>) -> Result<Output> {
+        let config = match config {
+            Some(v) => v,
+            None => return Ok(Output::Stdout),
+        };
+        Ok(match config.output {
+            LogOutput::Stdout => Output::Stdout,
+            LogOutput::File => Output::File(RotatingFile::open(config)?),
+            #[cfg(unix)]
+            LogOutput::Syslog => Output::Syslog(UnixDatagram::unbound()?),
+            #[cfg(unix)]
+            LogOutput::Journald => Output::Journald(UnixDatagram::unbound()?),
+            #[cfg(not(unix))]
+            LogOutput::Syslog | LogOutput::Journald => unreachable!("Checked by the config"),
+        })
+    }
+
+    fn write(&mut self, level: Level, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().lock().write_all(buf),
+            Output::File(f) => f.write(&strip_ansi(buf)),
+            #[cfg(unix)]
+            Output::Syslog(s) => {
+                let mut msg = format!(
+                    "<{}>{}[{}]: ",
+                    SYSLOG_FACILITY * 8 + severity(level),
+                    SYSLOG_IDENTIFIER,
+                    std::process::id()
+                )
+                .into_bytes();
+                msg.extend_from_slice(strip_ansi(buf).trim_ascii_end());
+                s.send_to(&msg, SYSLOG_SOCKET).map(|_| ())
+            }
+            #[cfg(unix)]
+            Output::Journald(s) => s
+                .send_to(&journald_entry(level, buf), JOURNALD_SOCKET)
+                .map(|_| ()),
+        }
+    }
+}
+
+/// Where logs go, which is stdout until `[log]` says otherwise.
+/// Pass it to `with_writer` of the `tracing_subscriber` formatter
+pub struct LogWriter;
+
+impl<'a> MakeWriter<'a> for LogWriter {
+    type Writer = LogLine;
+
+    fn make_writer(&'a self) -> LogLine {
+        LogLine {
+            level: Level::INFO,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> LogLine {
+        LogLine {
+            level: *meta.level(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// A log line being formatted, which is sent to the output at once when dropped
+pub struct LogLine {
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for LogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogLine {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            // Nowhere to tell if logging fails
+            let mut output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = output.write(self.level, &self.buf);
+        }
+    }
+}
+
+/// Send logs of `LogWriter` to where `[log]` says, or stdout if not configured
+pub fn configure(config: Option<&LogConfig>) -> Result<()> {
+    let output = Output::new(config).with_context(|| "Failed to open the log output")?;
+    *OUTPUT.lock().unwrap_or_else(|e| e.into_inner()) = output;
+    Ok(())
+}
+
+// A log file, which is renamed to `<path>.1` once it grows beyond `max_size`,
+// with older ones shifted to `<path>.2` and so on up to `max_files`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(config: &LogConfig) -> Result<RotatingFile> {
+        let path = PathBuf::from(config.path.as_deref().unwrap_or_default());
+        let file = open_append(&path)
+            .with_context(|| format!("Failed to open the log file {:?}", path))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size: config.max_size,
+            max_files: config.max_files,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + buf.len() as u64 > max)
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| {
+            let mut s = self.path.clone().into_os_string();
+            s.push(format!(".{}", i));
+            PathBuf::from(s)
+        };
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let _ = fs::rename(rotated(i), rotated(i + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Remove the color codes, which are only meant for terminals
+fn strip_ansi(buf: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(buf.len());
+    let mut iter = buf.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        if b == 0x1b && iter.peek() == Some(&b'[') {
+            // Skip to the final byte of the sequence
+            for b in iter.by_ref().skip(1) {
+                if (0x40..=0x7e).contains(&b) {
+                    break;
+                }
+            }
+        } else {
+            ret.push(b);
+        }
+    }
+    ret
+}
+
+#[cfg(unix)]
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+// An entry in the native protocol of journald, where MESSAGE is sized, since it may have newlines
+#[cfg(unix)]
+fn journald_entry(level: Level, buf: &[u8]) -> Vec<u8> {
+    let msg = strip_ansi(buf);
+    let msg = msg.trim_ascii_end();
+    let mut ret = format!(
+        "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE\n",
+        severity(level),
+        SYSLOG_IDENTIFIER
+    )
+    .into_bytes();
+    ret.extend_from_slice(&(msg.len() as u64).to_le_bytes());
+    ret.extend_from_slice(msg);
+    ret.push(b'\n');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi(b"\x1b[2m2024\x1b[0m \x1b[32m INFO\x1b[0m hello"),
+            b"2024  INFO hello"
+        );
+        assert_eq!(strip_ansi(b"plain\n"), b"plain\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_journald_entry() {
+        let entry = journald_entry(Level::WARN, b"a\nb\n");
+        let mut expected = b"PRIORITY=4\nSYSLOG_IDENTIFIER=rathole\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn test_rotate() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rathole-log-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("rathole.log");
+        let config = LogConfig {
+            output: LogOutput::File,
+            path: Some(path.to_string_lossy().into_owned()),
+            max_size: Some(10),
+            max_files: 2,
+        };
+
+        let mut f = RotatingFile::open(&config)?;
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            f.write(line.as_bytes())?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("rathole.log.1"))?, "third\n");
+        assert_eq!(fs::read_to_string(dir.join("rathole.log.2"))?, "second\n");
+        assert!(!dir.join("rathole.log.3").exists());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
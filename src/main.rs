@@ -1,6 +1,6 @@
 use anyhow::Result;
 use clap::Parser;
-use rathole::{run, Cli};
+use rathole::{run, Cli, LogWriter};
 use tokio::{signal, sync::broadcast};
 use tracing_subscriber::EnvFilter;
 
@@ -10,9 +10,9 @@ async fn main() -> Result<()> {
 
     let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
     tokio::spawn(async move {
-        if let Err(e) = signal::ctrl_c().await {
+        if let Err(e) = shutdown_signal().await {
             // Something really weird happened. So just panic
-            panic!("Failed to listen for the ctrl-c signal: {:?}", e);
+            panic!("Failed to listen for the shutdown signal: {:?}", e);
         }
 
         if let Err(e) = shutdown_tx.send(true) {
@@ -38,8 +38,23 @@ async fn main() -> Result<()> {
                 EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::from(level)),
             )
             .with_ansi(is_atty)
+            .with_writer(LogWriter)
             .init();
     }
 
     run(args, shutdown_rx).await
 }
+
+// Ctrl-C, or SIGTERM on Unix, like from `systemctl stop` and container orchestrators
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            r = signal::ctrl_c() => r,
+            _ = term.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
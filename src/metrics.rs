@@ -0,0 +1,618 @@
+use crate::config::MetricsConfig;
+use crate::conn_log::ConnStats;
+use crate::event::{CommandHook, Event, Hook, Hooks};
+use crate::helper::{spawn_http_server, HttpResponse};
+use crate::statsd;
+use anyhow::{Context as _, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Counters of a service
+#[derive(Debug, Default)]
+pub struct ServiceMetrics {
+    name: String,
+    // Told what happens to the service
+    hooks: Hooks,
+    // The commands configured for the service
+    command_hook: Mutex<CommandHook>,
+    // Bytes from visitors to the service
+    bytes_in: AtomicU64,
+    // Bytes from the service to visitors
+    bytes_out: AtomicU64,
+    data_channels: AtomicI64,
+    connects: AtomicU64,
+    handshake_failures: AtomicU64,
+    // Visitors turned away by `max_connections`
+    rejected_connections: AtomicU64,
+    // The port of the server that the service is exposed at, if picked by the OS
+    bound_port: AtomicI64,
+    // Multiplexed data channels by a sequence number, gone once the session is dropped
+    mux_sessions: Mutex<BTreeMap<u64, Weak<MuxMetrics>>>,
+    mux_seq: AtomicU64,
+}
+
+/// A metric, and its samples by their labels
+pub(crate) struct Family {
+    pub name: &'static str,
+    // "counter" or "gauge"
+    pub kind: &'static str,
+    pub help: &'static str,
+    pub samples: Vec<(Vec<(&'static str, String)>, i64)>,
+}
+
+/// Counters of a multiplexed data channel
+#[derive(Debug, Default)]
+pub struct MuxMetrics {
+    // The streams that are open, by ID
+    streams: Mutex<BTreeMap<u32, Arc<StreamMetrics>>>,
+}
+
+/// Bytes through a stream of a multiplexed data channel
+#[derive(Debug, Default)]
+pub struct StreamMetrics {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// The registry of all counters, which is rendered in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Handshakes that fail before the service is known
+    handshake_failures: AtomicU64,
+    // Failed authentications of control channels by the service tried,
+    // which is `UNKNOWN_SERVICE` for services that don't exist
+    auth_failures: Mutex<BTreeMap<String, u64>>,
+    services: Mutex<BTreeMap<String, Arc<ServiceMetrics>>>,
+    hooks: Hooks,
+}
+
+impl Metrics {
+    /// Get the counters of `service`, creating them if not seen before
+    pub fn service(&self, service: &str) -> Arc<ServiceMetrics> {
+        self.services
+            .lock()
+            .unwrap()
+            .entry(service.to_string())
+            .or_insert_with(|| {
+                Arc::new(ServiceMetrics {
+                    name: service.to_string(),
+                    hooks: self.hooks.clone(),
+                    ..Default::default()
+                })
+            })
+            .clone()
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self, service: &str) {
+        *self
+            .auth_failures
+            .lock()
+            .unwrap()
+            .entry(service.to_string())
+            .or_default() += 1;
+    }
+
+    /// Tell the hooks about an event that's not of a known service
+    pub fn emit(&self, event: &Event) {
+        self.hooks.on_event(event);
+    }
+
+    /// Forget the counters of `service` once it's removed, so that they don't pile up
+    pub fn remove_service(&self, service: &str) {
+        self.services.lock().unwrap().remove(service);
+        self.auth_failures.lock().unwrap().remove(service);
+    }
+
+    /// Data channels that are forwarding, of all services
+    pub fn data_channels(&self) -> i64 {
+        let services = self.services.lock().unwrap();
+        services
+            .values()
+            .map(|m| m.data_channels.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// All metrics with their current samples, for the exporters to format
+    pub(crate) fn families(&self) -> Vec<Family> {
+        let services = self.services.lock().unwrap();
+        let mut families = vec![
+            Family {
+                name: "rathole_handshake_failures_total",
+                kind: "counter",
+                help: "Handshakes that failed before the service is known",
+                samples: vec![(
+                    vec![],
+                    self.handshake_failures.load(Ordering::Relaxed) as i64,
+                )],
+            },
+            Family {
+                name: "rathole_auth_failures_total",
+                kind: "counter",
+                help: "Control channel authentications that failed, by the service tried",
+                samples: self
+                    .auth_failures
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(service, n)| (vec![("service", service.clone())], *n as i64))
+                    .collect(),
+            },
+        ];
+
+        type Getter = fn(&ServiceMetrics) -> i64;
+        let service_families: [(&'static str, &'static str, &'static str, Getter); 7] = [
+            (
+                "rathole_service_bytes_in_total",
+                "counter",
+                "Bytes forwarded from visitors to the service",
+                |m| m.bytes_in.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "rathole_service_bytes_out_total",
+                "counter",
+                "Bytes forwarded from the service to visitors",
+                |m| m.bytes_out.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "rathole_service_data_channels",
+                "gauge",
+                "Data channels that are forwarding",
+                |m| m.data_channels.load(Ordering::Relaxed),
+            ),
+            (
+                "rathole_service_connects_total",
+                "counter",
+                "Control channels established. Reconnects are all but the first",
+                |m| m.connects.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "rathole_service_handshake_failures_total",
+                "counter",
+                "Control channel handshakes that failed",
+                |m| m.handshake_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "rathole_service_rejected_connections_total",
+                "counter",
+                "Visitors rejected by `max_connections` of the service or the server",
+                |m| m.rejected_connections.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "rathole_service_bound_port",
+                "gauge",
+                "The port of the server that the service is exposed at, if picked by the OS. 0 if not",
+                |m| m.bound_port.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, kind, help, get) in service_families {
+            families.push(Family {
+                name,
+                kind,
+                help,
+                samples: services
+                    .iter()
+                    .map(|(service, m)| (vec![("service", service.clone())], get(m)))
+                    .collect(),
+            });
+        }
+
+        // Multiplexed data channels, labeled by the service and the sequence number of each
+        let mut sessions = Vec::new();
+        for (service, m) in services.iter() {
+            for (seq, session) in m.mux_sessions.lock().unwrap().iter() {
+                if let Some(session) = session.upgrade() {
+                    let labels = vec![
+                        ("service", service.clone()),
+                        ("connection", seq.to_string()),
+                    ];
+                    sessions.push((labels, session));
+                }
+            }
+        }
+        families.push(Family {
+            name: "rathole_mux_streams",
+            kind: "gauge",
+            help: "Streams that are open on a multiplexed data channel",
+            samples: sessions
+                .iter()
+                .map(|(labels, session)| {
+                    (labels.clone(), session.streams.lock().unwrap().len() as i64)
+                })
+                .collect(),
+        });
+        type StreamGetter = fn(&StreamMetrics) -> u64;
+        let stream_families: [(&'static str, &'static str, StreamGetter); 2] = [
+            (
+                "rathole_mux_stream_bytes_sent_total",
+                "Bytes sent by an open stream of a multiplexed data channel",
+                |m| m.sent.load(Ordering::Relaxed),
+            ),
+            (
+                "rathole_mux_stream_bytes_received_total",
+                "Bytes received by an open stream of a multiplexed data channel",
+                |m| m.received.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, get) in stream_families {
+            let mut samples = Vec::new();
+            for (labels, session) in sessions.iter() {
+                for (id, m) in session.streams.lock().unwrap().iter() {
+                    let mut labels = labels.clone();
+                    labels.push(("stream", id.to_string()));
+                    samples.push((labels, get(m) as i64));
+                }
+            }
+            families.push(Family {
+                name,
+                kind: "counter",
+                help,
+                samples,
+            });
+        }
+
+        families
+    }
+
+    /// In the Prometheus text format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        for f in self.families() {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} {}",
+                f.name, f.help, f.name, f.kind
+            );
+            for (labels, value) in f.samples {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                    .collect();
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", f.name, value);
+                } else {
+                    let _ = writeln!(out, "{}{{{}}} {}", f.name, labels.join(","), value);
+                }
+            }
+        }
+        out
+    }
+}
+
+impl ServiceMetrics {
+    pub fn set_bound_port(&self, port: u16) {
+        self.bound_port.store(port as i64, Ordering::Relaxed);
+    }
+
+    fn emit(&self, event: impl FnOnce(String) -> Event) {
+        let event = event(self.name.clone());
+        self.hooks.on_event(&event);
+        self.command_hook.lock().unwrap().on_event(&event);
+    }
+
+    /// Run the `on_connect` and `on_disconnect` commands of the service from now on
+    pub fn set_command_hook(&self, hook: CommandHook) {
+        *self.command_hook.lock().unwrap() = hook;
+    }
+
+    /// Count a control channel with `peer` as established, which is online until the guard is dropped
+    pub fn online(self: &Arc<Self>, peer: String) -> OnlineGuard {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        self.emit(|service| Event::Online {
+            service,
+            peer: peer.clone(),
+        });
+        OnlineGuard(self.clone(), peer)
+    }
+
+    pub fn visitor_connected(&self, peer: Option<SocketAddr>) {
+        self.emit(|service| Event::VisitorConnected { service, peer });
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        self.emit(|service| Event::HandshakeFailed { service });
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Register a multiplexed data channel, which is counted until the returned counters are dropped
+    pub fn mux_session(&self) -> Arc<MuxMetrics> {
+        let session = Arc::new(MuxMetrics::default());
+        let seq = self.mux_seq.fetch_add(1, Ordering::Relaxed);
+        let mut sessions = self.mux_sessions.lock().unwrap();
+        sessions.retain(|_, s| s.strong_count() > 0);
+        sessions.insert(seq, Arc::downgrade(&session));
+        session
+    }
+
+    /// Count a data channel as forwarding until the guard is dropped
+    pub fn data_channel(self: &Arc<Self>) -> DataChannelGuard {
+        self.data_channels.fetch_add(1, Ordering::Relaxed);
+        self.emit(|service| Event::DataChannelOpened { service });
+        DataChannelGuard(self.clone())
+    }
+
+    /// Count the traffic of a visitor stream, which reads bytes in and writes bytes out
+    pub fn count_visitor<S>(self: &Arc<Self>, s: S) -> CountedStream<S> {
+        CountedStream {
+            inner: s,
+            metrics: self.clone(),
+            conn: None,
+            reads_in: true,
+        }
+    }
+
+    /// Count the traffic of a stream to the local service, which reads bytes out and writes bytes in
+    pub fn count_local<S>(self: &Arc<Self>, s: S) -> CountedStream<S> {
+        CountedStream {
+            inner: s,
+            metrics: self.clone(),
+            conn: None,
+            reads_in: false,
+        }
+    }
+}
+
+impl MuxMetrics {
+    /// Count stream `id` as open, until `stream_closed`
+    pub fn stream_opened(&self, id: u32) -> Arc<StreamMetrics> {
+        let m = Arc::new(StreamMetrics::default());
+        self.streams.lock().unwrap().insert(id, m.clone());
+        m
+    }
+
+    pub fn stream_closed(&self, id: u32) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+}
+
+impl StreamMetrics {
+    pub fn add_sent(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct OnlineGuard(Arc<ServiceMetrics>, String);
+
+impl Drop for OnlineGuard {
+    fn drop(&mut self) {
+        let peer = std::mem::take(&mut self.1);
+        self.0.emit(|service| Event::Offline { service, peer });
+    }
+}
+
+pub struct DataChannelGuard(Arc<ServiceMetrics>);
+
+impl Drop for DataChannelGuard {
+    fn drop(&mut self) {
+        self.0.data_channels.fetch_sub(1, Ordering::Relaxed);
+        self.0.emit(|service| Event::DataChannelClosed { service });
+    }
+}
+
+/// A stream that counts the bytes through it
+pub struct CountedStream<S> {
+    inner: S,
+    metrics: Arc<ServiceMetrics>,
+    // The connection to count the traffic to as well
+    conn: Option<Arc<ConnStats>>,
+    // Whether reading from the stream is the traffic from visitors
+    reads_in: bool,
+}
+
+impl<S> CountedStream<S> {
+    /// Count the traffic to the visitor connection of `stats` as well
+    pub fn with_conn(mut self, stats: &Arc<ConnStats>) -> Self {
+        self.conn = Some(stats.clone());
+        self
+    }
+
+    fn add_bytes_in(&self, n: usize) {
+        self.metrics.add_bytes_in(n);
+        if let Some(conn) = &self.conn {
+            conn.add_bytes_in(n);
+        }
+    }
+
+    fn add_bytes_out(&self, n: usize) {
+        self.metrics.add_bytes_out(n);
+        if let Some(conn) = &self.conn {
+            conn.add_bytes_out(n);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        if self.reads_in {
+            self.add_bytes_in(n);
+        } else {
+            self.add_bytes_out(n);
+        }
+        ret
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret {
+            if self.reads_in {
+                self.add_bytes_out(n);
+            } else {
+                self.add_bytes_in(n);
+            }
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Escape a label value of the Prometheus text format
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Create the registry of an instance, and serve it at `/metrics` or push it to StatsD
+/// as `[metrics]` configures. Events of services are told to `hooks`
+pub async fn start(
+    config: Option<&MetricsConfig>,
+    hooks: Hooks,
+    shutdown_rx: broadcast::Receiver<bool>,
+) -> Result<Arc<Metrics>> {
+    let metrics = Arc::new(Metrics {
+        hooks,
+        ..Default::default()
+    });
+    let config = match config {
+        Some(v) => v,
+        None => return Ok(metrics),
+    };
+
+    if let Some(statsd) = &config.statsd {
+        statsd::start(metrics.clone(), statsd, shutdown_rx.resubscribe()).await?;
+    }
+    let bind_addr = match &config.bind_addr {
+        Some(v) => v,
+        None => return Ok(metrics),
+    };
+
+    let l = TcpListener::bind(bind_addr)
+        .await
+        .with_context(|| format!("Failed to listen at `metrics.bind_addr` {}", bind_addr))?;
+    info!("Serving metrics at {}", bind_addr);
+
+    let m = metrics.clone();
+    spawn_http_server(
+        l,
+        move |req| {
+            let resp = if req.path == "/metrics" {
+                HttpResponse::ok("text/plain; version=0.0.4", m.render())
+            } else {
+                HttpResponse::error("404 Not Found")
+            };
+            async move { resp }
+        },
+        shutdown_rx,
+    );
+
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::http_request;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<()> {
+        let config = MetricsConfig {
+            bind_addr: Some("127.0.0.1:2350".to_string()),
+            statsd: None,
+        };
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let metrics = start(Some(&config), Hooks::default(), shutdown_rx).await?;
+
+        let foo = metrics.service("foo");
+        let _online = foo.online("127.0.0.1:2333".into());
+        foo.connection_rejected();
+        metrics.handshake_failed();
+        metrics.auth_failed("foo");
+        metrics.auth_failed("foo");
+        metrics.auth_failed("<unknown>");
+        let guard = foo.data_channel();
+
+        // A visitor sends 4 bytes and receives 2
+        let (visitor, mut remote) = tokio::io::duplex(64);
+        let mut visitor = foo.count_visitor(visitor);
+        remote.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        visitor.read_exact(&mut buf).await?;
+        visitor.write_all(b"ok").await?;
+
+        let url = Url::parse("http://127.0.0.1:2350/metrics")?;
+        let (code, body) = http_request("GET", &url, None).await?;
+        let body = String::from_utf8(body)?;
+        assert_eq!(code, 200);
+        for line in [
+            "rathole_handshake_failures_total 1",
+            "rathole_auth_failures_total{service=\"foo\"} 2",
+            "rathole_auth_failures_total{service=\"<unknown>\"} 1",
+            "rathole_service_bytes_in_total{service=\"foo\"} 4",
+            "rathole_service_bytes_out_total{service=\"foo\"} 2",
+            "rathole_service_data_channels{service=\"foo\"} 1",
+            "rathole_service_connects_total{service=\"foo\"} 1",
+            "rathole_service_handshake_failures_total{service=\"foo\"} 0",
+            "rathole_service_rejected_connections_total{service=\"foo\"} 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+
+        drop(guard);
+        assert!(metrics
+            .render()
+            .contains("rathole_service_data_channels{service=\"foo\"} 0"));
+
+        // Removed services are forgotten
+        metrics.remove_service("foo");
+        let body = metrics.render();
+        assert!(!body.contains("service=\"foo\""), "{}", body);
+        assert!(body.contains("rathole_auth_failures_total{service=\"<unknown>\"} 1"));
+
+        let url = Url::parse("http://127.0.0.1:2350/")?;
+        assert_eq!(http_request("GET", &url, None).await?.0, 404);
+
+        shutdown_tx.send(true)?;
+        Ok(())
+    }
+}
@@ -105,6 +105,30 @@ where
         self.map1.remove(&item.0);
         Some(item.2)
     }
+
+    /// Iterate over all items with their `k1`
+    pub fn iter(&self) -> impl Iterator<Item = (&K1, &V)> {
+        self.map1.values().map(|item| {
+            let item = unsafe { &*item.0 };
+            (&item.0, &item.2)
+        })
+    }
+
+    /// Remove all items whose `k1` satisfies `f`
+    pub fn remove1_if<F>(&mut self, mut f: F) -> Vec<V>
+    where
+        K1: Clone,
+        F: FnMut(&K1) -> bool,
+    {
+        let keys: Vec<K1> = self
+            .map1
+            .keys()
+            .map(|k| k.borrow())
+            .filter(|k| f(k))
+            .cloned()
+            .collect();
+        keys.iter().filter_map(|k| self.remove1(k)).collect()
+    }
 }
 
 impl<K1, K2, V> Drop for MultiMap<K1, K2, V> {
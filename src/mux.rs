@@ -0,0 +1,919 @@
+// Many streams over a single connection, in the framing of yamux.
+// See https://github.com/hashicorp/yamux/blob/master/spec.md
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::metrics::{MuxMetrics, StreamMetrics};
+
+#[cfg(feature = "server")]
+use crate::{helper::write_and_flush, metrics::ServiceMetrics, protocol::DataChannelCmd};
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+// How much a stream may receive before it's read, which is the initial window of yamux
+const WINDOW: u32 = 256 * 1024;
+// The most data in a frame
+const MAX_FRAME_LEN: usize = 16 * 1024;
+// The most to write to the connection at once, when frames are queued up
+const MAX_BATCH_LEN: usize = 64 * 1024;
+
+/// The side of a session. Clients open streams of odd IDs, and servers even ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Client,
+    Server,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    ty: u8,
+    flags: u16,
+    id: u32,
+    len: u32,
+}
+
+impl Header {
+    fn new(ty: u8, flags: u16, id: u32, len: u32) -> Header {
+        Header { ty, flags, id, len }
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(VERSION);
+        buf.put_u8(self.ty);
+        buf.put_u16(self.flags);
+        buf.put_u32(self.id);
+        buf.put_u32(self.len);
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Header> {
+        let version = buf.get_u8();
+        if version != VERSION {
+            bail!("Unsupported yamux version {}", version);
+        }
+        Ok(Header {
+            ty: buf.get_u8(),
+            flags: buf.get_u16(),
+            id: buf.get_u32(),
+            len: buf.get_u32(),
+        })
+    }
+}
+
+enum Out {
+    Frame(Header, Bytes),
+    // Close the connection after the frames before
+    Close,
+}
+
+#[derive(Default)]
+struct StreamState {
+    // How much may be sent before the peer updates the window
+    send_window: u32,
+    // How much the peer may send before the window is updated
+    recv_window: u32,
+    write_waker: Option<Waker>,
+    reset: bool,
+}
+
+struct Entry {
+    // Dropped once the peer finishes sending
+    data_tx: Option<mpsc::UnboundedSender<Bytes>>,
+    state: Arc<Mutex<StreamState>>,
+}
+
+struct Shared {
+    mode: Mode,
+    streams: Mutex<HashMap<u32, Entry>>,
+    out_tx: mpsc::UnboundedSender<Out>,
+    next_id: AtomicU32,
+    // The connection is gone
+    closed: AtomicBool,
+    // The `Session` is dropped, so the connection is closed once the streams are
+    dropped: AtomicBool,
+    metrics: Arc<MuxMetrics>,
+}
+
+impl Shared {
+    fn send(&self, header: Header, data: Bytes) -> io::Result<()> {
+        self.out_tx
+            .send(Out::Frame(header, data))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The session is closed"))
+    }
+
+    fn new_stream(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(Mutex::new(StreamState {
+            send_window: WINDOW,
+            recv_window: WINDOW,
+            ..Default::default()
+        }));
+        self.streams.lock().unwrap().insert(
+            id,
+            Entry {
+                data_tx: Some(data_tx),
+                state: state.clone(),
+            },
+        );
+        MuxStream {
+            id,
+            shared: self.clone(),
+            metrics: self.metrics.stream_opened(id),
+            state,
+            data_rx,
+            buf: Bytes::new(),
+            consumed: 0,
+            eof: false,
+            fin_sent: false,
+        }
+    }
+
+    fn remove(&self, id: u32) {
+        let mut streams = self.streams.lock().unwrap();
+        streams.remove(&id);
+        if self.dropped.load(Ordering::Relaxed) && streams.is_empty() {
+            let _ = self.out_tx.send(Out::Close);
+        }
+    }
+
+    // Reset all streams, since the connection is gone
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            let mut state = entry.state.lock().unwrap();
+            state.reset = true;
+            if let Some(w) = state.write_waker.take() {
+                w.wake();
+            }
+        }
+        let _ = self.out_tx.send(Out::Close);
+    }
+
+    // Handle a frame of data or a window update of a stream
+    fn on_stream_frame(
+        self: &Arc<Self>,
+        h: &Header,
+        data: Bytes,
+        accept_tx: &mpsc::UnboundedSender<MuxStream>,
+    ) -> Result<()> {
+        if h.flags & FLAG_SYN != 0 {
+            let local = (h.id % 2 == 1) == (self.mode == Mode::Client);
+            if local || h.id == 0 || self.streams.lock().unwrap().contains_key(&h.id) {
+                bail!("Invalid ID {} of a new stream", h.id);
+            }
+            let stream = self.new_stream(h.id);
+            if self.dropped.load(Ordering::Relaxed) {
+                // Reset by dropping
+                drop(stream);
+            } else {
+                self.send(
+                    Header::new(TYPE_WINDOW_UPDATE, FLAG_ACK, h.id, 0),
+                    Bytes::new(),
+                )?;
+                let _ = accept_tx.send(stream);
+            }
+        }
+
+        let (state, data_tx) = match self.streams.lock().unwrap().get_mut(&h.id) {
+            Some(e) => {
+                let data_tx = e.data_tx.clone();
+                if h.flags & (FLAG_FIN | FLAG_RST) != 0 {
+                    e.data_tx = None;
+                }
+                (e.state.clone(), data_tx)
+            }
+            // Closed on this side
+            None => return Ok(()),
+        };
+        let mut state = state.lock().unwrap();
+        if h.ty == TYPE_WINDOW_UPDATE {
+            state.send_window = state.send_window.saturating_add(h.len);
+        } else if !data.is_empty() {
+            if data.len() > state.recv_window as usize {
+                bail!("Stream {} sent beyond the window", h.id);
+            }
+            state.recv_window -= data.len() as u32;
+            if let Some(tx) = data_tx {
+                let _ = tx.send(data);
+            }
+        }
+        if h.flags & FLAG_RST != 0 {
+            state.reset = true;
+        }
+        if let Some(w) = state.write_waker.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+}
+
+/// A connection that carries many streams
+pub struct Session {
+    shared: Arc<Shared>,
+    accept_rx: mpsc::UnboundedReceiver<MuxStream>,
+}
+
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("mode", &self.shared.mode)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Session {
+    /// Multiplex `conn`, counting its streams to `metrics`
+    pub fn new<S: AsyncRead + AsyncWrite + Send + 'static>(
+        conn: S,
+        mode: Mode,
+        metrics: Arc<MuxMetrics>,
+    ) -> Session {
+        let (rd, wr) = tokio::io::split(conn);
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            mode,
+            streams: Default::default(),
+            out_tx,
+            next_id: AtomicU32::new(match mode {
+                Mode::Client => 1,
+                Mode::Server => 2,
+            }),
+            closed: AtomicBool::new(false),
+            dropped: AtomicBool::new(false),
+            metrics,
+        });
+
+        // The writer holds no reference, or the queue never ends
+        let weak = Arc::downgrade(&shared);
+        tokio::spawn(async move {
+            if let Err(e) = write_frames(wr, out_rx).await {
+                debug!("Failed to write to the session: {:#}", e);
+            }
+            if let Some(shared) = Weak::upgrade(&weak) {
+                shared.close();
+            }
+        });
+        let reader = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = read_frames(rd, &reader, &accept_tx).await {
+                debug!("Failed to read from the session: {:#}", e);
+            }
+            reader.close();
+        });
+
+        Session { shared, accept_rx }
+    }
+
+    /// Open a stream, which the peer gets from `accept`
+    pub fn open(&self) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The session is closed",
+            ));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.new_stream(id);
+        self.shared.send(
+            Header::new(TYPE_WINDOW_UPDATE, FLAG_SYN, id, 0),
+            Bytes::new(),
+        )?;
+        Ok(stream)
+    }
+
+    /// The next stream opened by the peer. None once the session is closed
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.accept_rx.recv().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Session {
+    // Streams still open go on, and the connection is closed after them
+    fn drop(&mut self) {
+        self.shared.dropped.store(true, Ordering::Relaxed);
+        let _ = self
+            .shared
+            .send(Header::new(TYPE_GO_AWAY, 0, 0, 0), Bytes::new());
+        if self.shared.streams.lock().unwrap().is_empty() {
+            let _ = self.shared.out_tx.send(Out::Close);
+        }
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut rd: R,
+    shared: &Arc<Shared>,
+    accept_tx: &mpsc::UnboundedSender<MuxStream>,
+) -> Result<()> {
+    let mut buf = [0u8; HEADER_LEN];
+    loop {
+        match rd.read_exact(&mut buf).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let h = Header::decode(&buf)?;
+        match h.ty {
+            TYPE_DATA => {
+                if h.len > WINDOW {
+                    bail!("Frame of {} bytes beyond the window", h.len);
+                }
+                let mut data = vec![0u8; h.len as usize];
+                rd.read_exact(&mut data).await?;
+                shared.on_stream_frame(&h, data.into(), accept_tx)?;
+            }
+            TYPE_WINDOW_UPDATE => shared.on_stream_frame(&h, Bytes::new(), accept_tx)?,
+            TYPE_PING => {
+                if h.flags & FLAG_SYN != 0 {
+                    shared.send(Header::new(TYPE_PING, FLAG_ACK, 0, h.len), Bytes::new())?;
+                }
+            }
+            // The peer opens no more streams, and nothing is opened on this side anyway
+            TYPE_GO_AWAY => debug!("The peer is going away"),
+            v => bail!("Unknown frame type {}", v),
+        }
+    }
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut wr: W,
+    mut out_rx: mpsc::UnboundedReceiver<Out>,
+) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    loop {
+        let mut next = out_rx.recv().await;
+        let mut close = next.is_none();
+        // Write what's queued up at once
+        while let Some(out) = next {
+            match out {
+                Out::Frame(h, data) => {
+                    h.encode(&mut buf);
+                    buf.extend_from_slice(&data);
+                }
+                Out::Close => {
+                    close = true;
+                    break;
+                }
+            }
+            if buf.len() >= MAX_BATCH_LEN {
+                break;
+            }
+            next = out_rx.try_recv().ok();
+        }
+        wr.write_all(&buf).await?;
+        buf.clear();
+        wr.flush().await?;
+        if close {
+            return wr.shutdown().await;
+        }
+    }
+}
+
+/// A stream of a session
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    metrics: Arc<StreamMetrics>,
+    state: Arc<Mutex<StreamState>>,
+    data_rx: mpsc::UnboundedReceiver<Bytes>,
+    // Received, but not read yet
+    buf: Bytes,
+    // Read, but not told to the peer by a window update yet
+    consumed: u32,
+    eof: bool,
+    fin_sent: bool,
+}
+
+impl Debug for MuxStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream").field("id", &self.id).finish()
+    }
+}
+
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "The stream is reset")
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buf.is_empty() {
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.data_rx.poll_recv(cx)) {
+                Some(v) => this.buf = v,
+                None => {
+                    if this.state.lock().unwrap().reset {
+                        return Poll::Ready(Err(reset_error()));
+                    }
+                    this.eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..n]);
+        this.buf.advance(n);
+        this.metrics.add_received(n);
+        this.consumed += n as u32;
+        if this.consumed >= WINDOW / 2 {
+            this.state.lock().unwrap().recv_window += this.consumed;
+            // Nowhere to tell the error but the next write
+            let _ = this.shared.send(
+                Header::new(TYPE_WINDOW_UPDATE, 0, this.id, this.consumed),
+                Bytes::new(),
+            );
+            this.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = {
+            let mut state = this.state.lock().unwrap();
+            if state.reset {
+                return Poll::Ready(Err(reset_error()));
+            }
+            if state.send_window == 0 {
+                state.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(state.send_window as usize).min(MAX_FRAME_LEN);
+            state.send_window -= n as u32;
+            n
+        };
+        this.shared.send(
+            Header::new(TYPE_DATA, 0, this.id, n as u32),
+            Bytes::copy_from_slice(&buf[..n]),
+        )?;
+        this.metrics.add_sent(n);
+        Poll::Ready(Ok(n))
+    }
+
+    // Frames are flushed by the session as soon as they're written
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.fin_sent {
+            this.fin_sent = true;
+            this.shared
+                .send(Header::new(TYPE_DATA, FLAG_FIN, this.id, 0), Bytes::new())?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        // Done with, or reset by the peer
+        let closed = (self.fin_sent && self.eof) || self.state.lock().unwrap().reset;
+        if !closed {
+            let _ = self.shared.send(
+                Header::new(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0),
+                Bytes::new(),
+            );
+        }
+        self.shared.remove(self.id);
+        self.shared.metrics.stream_closed(self.id);
+    }
+}
+
+/// A data channel, or a stream multiplexed over one
+#[derive(Debug)]
+pub enum DataChannel<S> {
+    Direct(S),
+    Muxed(MuxStream),
+}
+
+#[cfg(all(target_os = "linux", feature = "splice"))]
+impl<S> DataChannel<S> {
+    /// The data channel, if it's not multiplexed
+    pub fn direct(&self) -> Option<&S> {
+        match self {
+            DataChannel::Direct(s) => Some(s),
+            DataChannel::Muxed(_) => None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DataChannel<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_read(cx, buf),
+            DataChannel::Muxed(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DataChannel<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_write(cx, buf),
+            DataChannel::Muxed(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_flush(cx),
+            DataChannel::Muxed(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_shutdown(cx),
+            DataChannel::Muxed(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A pool that control channels share, if coalesced
+#[cfg(feature = "server")]
+pub type SharedMuxPool = Arc<tokio::sync::Mutex<MuxPool>>;
+
+/// Sessions over a few data channels of a control channel, where visitors are spread
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub struct MuxPool {
+    size: usize,
+    sessions: Vec<Session>,
+    next: usize,
+    // Where the sessions are counted
+    metrics: Arc<ServiceMetrics>,
+}
+
+#[cfg(feature = "server")]
+impl MuxPool {
+    pub fn new(size: usize, metrics: Arc<ServiceMetrics>) -> MuxPool {
+        MuxPool {
+            size,
+            sessions: Vec::new(),
+            next: 0,
+            metrics,
+        }
+    }
+
+    /// Open a stream on one of the sessions. Sessions are started over data channels from
+    /// `data_ch_rx`, which are requested as the pool lacks them. `pending` counts those
+    /// requested but not received yet, so that a pool shared by control channels asks each
+    /// only for what it's short of. None if no more data channels come
+    pub async fn open<S>(
+        &mut self,
+        data_ch_rx: &mut mpsc::Receiver<S>,
+        data_ch_req_tx: &mpsc::UnboundedSender<bool>,
+        pending: &mut usize,
+    ) -> Option<MuxStream>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        loop {
+            self.sessions.retain(|s| !s.is_closed());
+            while self.sessions.len() < self.size {
+                match data_ch_rx.try_recv() {
+                    Ok(ch) => {
+                        *pending = pending.saturating_sub(1);
+                        self.start(ch).await
+                    }
+                    Err(_) => break,
+                }
+            }
+            while self.sessions.len() + *pending < self.size {
+                data_ch_req_tx.send(true).ok()?;
+                *pending += 1;
+            }
+            if self.sessions.is_empty() {
+                let ch = data_ch_rx.recv().await?;
+                *pending = pending.saturating_sub(1);
+                self.start(ch).await;
+                continue;
+            }
+
+            self.next = (self.next + 1) % self.sessions.len();
+            // If it's closed, it's replaced in the next round
+            if let Ok(v) = self.sessions[self.next].open() {
+                return Some(v);
+            }
+        }
+    }
+
+    // A broken data channel is replaced in the next round
+    async fn start<S>(&mut self, mut ch: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let cmd = bincode::serialize(&DataChannelCmd::StartMux).unwrap();
+        if write_and_flush(&mut ch, &cmd).await.is_ok() {
+            let metrics = self.metrics.mux_session();
+            self.sessions.push(Session::new(ch, Mode::Client, metrics))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::duplex;
+
+    fn pair() -> (Session, Session) {
+        let (a, b) = duplex(64 * 1024);
+        (
+            Session::new(a, Mode::Client, Default::default()),
+            Session::new(b, Mode::Server, Default::default()),
+        )
+    }
+
+    #[test]
+    fn test_header() -> Result<()> {
+        let h = Header::new(TYPE_WINDOW_UPDATE, FLAG_SYN | FLAG_ACK, 7, 1024);
+        let mut buf = BytesMut::new();
+        h.encode(&mut buf);
+        assert_eq!(
+            &buf[..],
+            &[0, 1, 0, 3, 0, 0, 0, 7, 0, 0, 4, 0][..],
+            "Not in the yamux layout"
+        );
+        assert_eq!(Header::decode(&buf)?, h);
+        buf[0] = 1;
+        assert!(Header::decode(&buf).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams() -> Result<()> {
+        let (client, mut server) = pair();
+
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut s = client.open()?;
+            s.write_all(&[i; 10]).await?;
+            streams.push(s);
+        }
+        for i in 0..3u8 {
+            let mut s = server.accept().await.unwrap();
+            let mut buf = [0u8; 10];
+            s.read_exact(&mut buf).await?;
+            assert_eq!(buf, [i; 10]);
+            s.write_all(&[i + 10; 5]).await?;
+            s.shutdown().await?;
+            let mut buf = Vec::new();
+            streams[i as usize].read_to_end(&mut buf).await?;
+            assert_eq!(buf, [i + 10; 5]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<()> {
+        let registry = crate::metrics::Metrics::default();
+        let service = registry.service("foo");
+        let (a, b) = duplex(64 * 1024);
+        let client = Session::new(a, Mode::Client, service.mux_session());
+        let mut server = Session::new(b, Mode::Server, Default::default());
+
+        // Streams 1, 3 and 5 send 1, 2 and 3 bytes, and receive 10 each
+        let mut streams = Vec::new();
+        for i in 1..=3 {
+            let mut s = client.open()?;
+            s.write_all(&vec![0u8; i]).await?;
+            let mut peer = server.accept().await.unwrap();
+            peer.write_all(&[0u8; 10]).await?;
+            s.read_exact(&mut [0u8; 10]).await?;
+            streams.push((s, peer));
+        }
+        let body = registry.render();
+        let labels = "service=\"foo\",connection=\"0\"";
+        assert!(
+            body.contains(&format!("rathole_mux_streams{{{}}} 3", labels)),
+            "{}",
+            body
+        );
+        for (id, sent) in [(1, 1), (3, 2), (5, 3)] {
+            for line in [
+                format!(
+                    "rathole_mux_stream_bytes_sent_total{{{},stream=\"{}\"}} {}",
+                    labels, id, sent
+                ),
+                format!(
+                    "rathole_mux_stream_bytes_received_total{{{},stream=\"{}\"}} 10",
+                    labels, id
+                ),
+            ] {
+                assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+            }
+        }
+
+        // Closed streams are gone, and so is the connection once closed
+        streams.remove(0);
+        let body = registry.render();
+        assert!(
+            body.contains(&format!("rathole_mux_streams{{{}}} 2", labels)),
+            "{}",
+            body
+        );
+        assert!(!body.contains("stream=\"1\""), "{}", body);
+        drop(streams);
+        drop(client);
+        drop(server);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while registry.render().contains("connection=") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_window() -> Result<()> {
+        // Much more than the window, which gets through only with window updates
+        let (client, mut server) = pair();
+        let data: Vec<u8> = (0..4 * WINDOW).map(|i| i as u8).collect();
+        let mut s = client.open()?;
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            s.write_all(&sent).await.unwrap();
+            s.shutdown().await.unwrap();
+            s
+        });
+
+        let mut s = server.accept().await.unwrap();
+        let mut buf = Vec::new();
+        s.read_to_end(&mut buf).await?;
+        assert_eq!(buf, data);
+        writer.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reset() -> Result<()> {
+        let (client, mut server) = pair();
+        let mut s = client.open()?;
+        s.write_all(b"hello").await?;
+        let mut peer = server.accept().await.unwrap();
+        drop(s);
+        let mut buf = Vec::new();
+        assert_eq!(
+            peer.read_to_end(&mut buf).await.unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        // The connection going away resets every stream
+        let mut s = client.open()?;
+        drop(peer);
+        drop(server);
+        assert!(s.read_to_end(&mut buf).await.is_err());
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !client.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(client.open().is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_data_channel() -> Result<()> {
+        // A data channel that a mux pool starts a session over
+        let (a, b) = duplex(64 * 1024);
+        let (data_ch_tx, mut data_ch_rx) = mpsc::channel(1);
+        let (data_ch_req_tx, _data_ch_req_rx) = mpsc::unbounded_channel();
+        data_ch_tx.send(a).await?;
+
+        let mut pool = MuxPool::new(1, Default::default());
+        let mut pending = 1;
+        let mut s = pool
+            .open(&mut data_ch_rx, &data_ch_req_tx, &mut pending)
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+        s.write_all(b"hello").await?;
+
+        let mut b = b;
+        let cmd = crate::protocol::read_data_cmd(&mut b).await?;
+        assert!(matches!(cmd, DataChannelCmd::StartMux));
+        let mut session = Session::new(b, Mode::Server, Default::default());
+        let mut peer =
+            DataChannel::<tokio::io::DuplexStream>::Muxed(session.accept().await.unwrap());
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_shared_pool() -> Result<()> {
+        // Two control channels share a pool of one data channel, which comes from the first
+        let (a, b) = duplex(64 * 1024);
+        let (data_ch_tx, mut data_ch_rx1) = mpsc::channel(1);
+        let (data_ch_req_tx1, mut data_ch_req_rx1) = mpsc::unbounded_channel();
+        let (_data_ch_tx2, mut data_ch_rx2) = mpsc::channel::<tokio::io::DuplexStream>(1);
+        let (data_ch_req_tx2, mut data_ch_req_rx2) = mpsc::unbounded_channel();
+        data_ch_tx.send(a).await?;
+        let (mut pending1, mut pending2) = (1, 0);
+
+        let mut pool = MuxPool::new(1, Default::default());
+        let mut s1 = pool
+            .open(&mut data_ch_rx1, &data_ch_req_tx1, &mut pending1)
+            .await
+            .unwrap();
+        let mut s2 = pool
+            .open(&mut data_ch_rx2, &data_ch_req_tx2, &mut pending2)
+            .await
+            .unwrap();
+        s1.write_all(b"one").await?;
+        s2.write_all(b"two").await?;
+        // Neither asks for more
+        assert!(data_ch_req_rx1.try_recv().is_err());
+        assert!(data_ch_req_rx2.try_recv().is_err());
+
+        // Both streams are of the same session
+        let mut b = b;
+        crate::protocol::read_data_cmd(&mut b).await?;
+        let mut session = Session::new(b, Mode::Server, Default::default());
+        for expected in [b"one", b"two"] {
+            let mut peer = session.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            peer.read_exact(&mut buf).await?;
+            assert_eq!(&buf, expected);
+        }
+
+        // Once the session is gone, the control channel opening a stream asks for a data channel
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !pool.sessions[0].is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let opened = tokio::time::timeout(
+            Duration::from_millis(100),
+            pool.open(&mut data_ch_rx2, &data_ch_req_tx2, &mut pending2),
+        )
+        .await;
+        assert!(opened.is_err());
+        assert_eq!(data_ch_req_rx2.try_recv(), Ok(true));
+        assert!(data_ch_req_rx1.try_recv().is_err());
+        Ok(())
+    }
+}
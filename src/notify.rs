@@ -0,0 +1,154 @@
+use crate::config::NotifyConfig;
+use crate::event::{Event, Hook};
+use crate::helper::http_request_with_headers;
+use anyhow::{bail, Context, Result};
+use tokio::time::{self, Duration};
+use tracing::warn;
+use url::Url;
+
+const NOTIFY_TIMEOUT: u64 = 10; // Timeout for posting a notification in secs
+
+/// Posts control channels going online and offline, and failing handshakes, to `[notify]`
+#[derive(Debug)]
+pub struct Notifier {
+    url: Url,
+}
+
+impl Notifier {
+    pub fn new(config: &NotifyConfig) -> Notifier {
+        Notifier {
+            url: config.webhook_url.clone(),
+        }
+    }
+}
+
+impl Hook for Notifier {
+    fn on_event(&self, event: &Event) {
+        let text = match event {
+            Event::Online { service, peer } => {
+                format!("Service {} is online with {}", service, peer)
+            }
+            Event::Offline { service, peer } => {
+                format!("Service {} is offline from {}", service, peer)
+            }
+            Event::HandshakeFailed { service } => {
+                format!(
+                    "A control channel of service {} failed the handshake",
+                    service
+                )
+            }
+            _ => return,
+        };
+        let body = to_json(event, &text);
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = post(&url, &body).await {
+                warn!("Failed to notify the webhook: {:#}", e);
+            }
+        });
+    }
+}
+
+// The payload of the webhook. `text` makes it readable by chat webhooks like Slack's
+fn to_json(event: &Event, text: &str) -> String {
+    let mut fields = vec![
+        ("event", event.kind().to_string()),
+        ("service", event.service().to_string()),
+    ];
+    if let Some(peer) = event.peer() {
+        fields.push(("peer", peer));
+    }
+    fields.push(("text", text.to_string()));
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|(k, v)| format!("\"{}\":{}", k, json_string(v)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+// Quote `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+async fn post(url: &Url, body: &str) -> Result<()> {
+    let (code, _) = time::timeout(
+        Duration::from_secs(NOTIFY_TIMEOUT),
+        http_request_with_headers(
+            "POST",
+            url,
+            &[("Content-Type", "application/json")],
+            Some(body.as_bytes()),
+        ),
+    )
+    .await
+    .with_context(|| "Timeout")??;
+    if !(200..300).contains(&code) {
+        bail!("The webhook responded with status {}", code);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_to_json() {
+        let e = Event::Offline {
+            service: "a\"b".into(),
+            peer: "1.2.3.4:5678".into(),
+        };
+        assert_eq!(
+            to_json(&e, "x\ny"),
+            r#"{"event":"offline","service":"a\"b","peer":"1.2.3.4:5678","text":"x\ny"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify() -> Result<()> {
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}/notify", l.local_addr()?))?;
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let (mut conn, _) = l.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = conn.read(&mut buf).await.unwrap();
+            conn.write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            tx.send(String::from_utf8_lossy(&buf[..n]).to_string())
+                .await
+                .unwrap();
+        });
+
+        let notifier = Notifier::new(&NotifyConfig { webhook_url: url });
+        notifier.on_event(&Event::HandshakeFailed {
+            service: "foo".into(),
+        });
+        let req = time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .unwrap();
+        assert!(req.starts_with("POST /notify HTTP/1.0\r\n"));
+        assert!(req.contains("Content-Type: application/json\r\n"));
+        assert!(req.contains(r#""event":"handshake_failed","service":"foo""#));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Instant;
+
+// How long the accept rate is averaged over, in secs
+const RATE_TIME_CONSTANT: f64 = 10.0;
+// Data channels are kept for the visitors expected in this long, in secs,
+// which covers the round trips of creating new ones
+const HEADROOM: f64 = 2.0;
+
+/// Sizes the pool of data channels of a control channel by the recent accept rate of visitors,
+/// between `min` and `max`
+#[derive(Debug)]
+pub struct PoolSizer {
+    min: usize,
+    max: usize,
+    // Visitors per second, averaged exponentially
+    rate: f64,
+    updated: Instant,
+    // Data channels requested, and not taken by visitors yet
+    pooled: usize,
+}
+
+impl PoolSizer {
+    pub fn new(min: usize, max: usize) -> PoolSizer {
+        PoolSizer {
+            min,
+            max,
+            rate: 0.0,
+            updated: Instant::now(),
+            pooled: 0,
+        }
+    }
+
+    /// The number of data channels to request to fill the pool
+    pub fn fill(&mut self) -> usize {
+        let n = self.target().saturating_sub(self.pooled);
+        self.pooled += n;
+        n
+    }
+
+    /// A visitor takes a data channel at `now`.
+    /// Returns the number of data channels to request
+    pub fn take(&mut self, now: Instant) -> usize {
+        self.decay(now);
+        self.rate += 1.0 / RATE_TIME_CONSTANT;
+        self.pooled = self.pooled.saturating_sub(1);
+        self.fill()
+    }
+
+    /// A data channel turns out broken.
+    /// Returns the number of data channels to request
+    pub fn lost(&mut self) -> usize {
+        self.pooled = self.pooled.saturating_sub(1);
+        self.fill()
+    }
+
+    /// The number of pooled data channels beyond the need at `now`
+    pub fn excess(&mut self, now: Instant) -> usize {
+        self.decay(now);
+        self.pooled.saturating_sub(self.target())
+    }
+
+    /// `n` pooled data channels are closed
+    pub fn shrink(&mut self, n: usize) {
+        self.pooled = self.pooled.saturating_sub(n);
+    }
+
+    fn target(&self) -> usize {
+        ((self.rate * HEADROOM).ceil() as usize).clamp(self.min, self.max)
+    }
+
+    fn decay(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.rate *= (-secs / RATE_TIME_CONSTANT).exp();
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pool_sizer() {
+        let mut s = PoolSizer::new(2, 16);
+        let start = Instant::now();
+        assert_eq!(s.fill(), 2);
+        assert_eq!(s.fill(), 0);
+
+        // A visitor takes one, which is replaced
+        assert_eq!(s.take(start), 1);
+
+        // A burst of 100 visitors in a second grows the pool up to `max`
+        let mut requested = 0;
+        for i in 0..100 {
+            requested += s.take(start + Duration::from_millis(10 * i));
+        }
+        assert_eq!(requested, 100 + 14);
+        assert_eq!(s.excess(start + Duration::from_secs(1)), 0);
+
+        // Broken ones are replaced
+        assert_eq!(s.lost(), 1);
+
+        // And it shrinks back to `min` once idle
+        let idle = start + Duration::from_secs(120);
+        assert_eq!(s.excess(idle), 14);
+        s.shrink(14);
+        assert_eq!(s.excess(idle), 0);
+        assert_eq!(s.take(idle), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::CString;
+use tracing::info;
+
+// The capability to bind ports below 1024. See capabilities(7)
+#[cfg(target_os = "linux")]
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+/// Switch the process to `user` and `group`, e.g. after binding low ports as root.
+/// `group` defaults to the primary group of `user`.
+/// On Linux, binding low ports is still allowed afterwards, so that services can be exposed at them.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let (uid, primary_gid) = match user {
+        Some(v) => {
+            let (uid, gid) = lookup_user(v)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(v) => Some(lookup_group(v)?),
+        None => primary_gid,
+    };
+
+    // Already switched, like when the instance restarts
+    if uid.is_none_or(|v| v == unsafe { libc::geteuid() })
+        && gid.is_none_or(|v| v == unsafe { libc::getegid() })
+    {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    if uid.is_some() && unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } < 0 {
+        return Err(last_error("Failed to keep capabilities"));
+    }
+
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(0, std::ptr::null()) } < 0 {
+            return Err(last_error("Failed to drop supplementary groups"));
+        }
+        if unsafe { libc::setgid(gid) } < 0 {
+            return Err(last_error("Failed to switch the group"));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } < 0 {
+            return Err(last_error("Failed to switch the user"));
+        }
+        #[cfg(target_os = "linux")]
+        keep_net_bind_service()?;
+    }
+
+    info!(
+        "Switched to user {} and group {}",
+        unsafe { libc::geteuid() },
+        unsafe { libc::getegid() }
+    );
+    Ok(())
+}
+
+fn last_error(what: &str) -> anyhow::Error {
+    anyhow!(std::io::Error::last_os_error()).context(what.to_string())
+}
+
+// The uid and the primary gid of `user`, a name or a number
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !pw.is_null() {
+        return Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) });
+    }
+    let uid: libc::uid_t = user
+        .parse()
+        .with_context(|| format!("No such user: {}", user))?;
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        bail!("No such user: {}", user);
+    }
+    Ok(unsafe { (uid, (*pw).pw_gid) })
+}
+
+// The gid of `group`, a name or a number
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group)?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !gr.is_null() {
+        return Ok(unsafe { (*gr).gr_gid });
+    }
+    group
+        .parse()
+        .with_context(|| format!("No such group: {}", group))
+}
+
+// Make CAP_NET_BIND_SERVICE effective again, after setuid(2) clears the effective capabilities
+#[cfg(target_os = "linux")]
+fn keep_net_bind_service() -> Result<()> {
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    // _LINUX_CAPABILITY_VERSION_3
+    let mut header = CapHeader {
+        version: 0x2008_0522,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    data[0].effective = 1 << CAP_NET_BIND_SERVICE;
+    data[0].permitted = 1 << CAP_NET_BIND_SERVICE;
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) } < 0 {
+        return Err(last_error("Failed to keep CAP_NET_BIND_SERVICE"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("0").unwrap().0, 0);
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_user("no-such-user-of-rathole").is_err());
+        assert!(lookup_group("no-such-group-of-rathole").is_err());
+    }
+
+    #[test]
+    fn test_noop() {
+        // Switching to the current user does nothing
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let gid = unsafe { libc::getegid() }.to_string();
+        drop_privileges(Some(&uid), Some(&gid)).unwrap();
+    }
+}
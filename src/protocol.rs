@@ -1,5 +1,7 @@
 pub const HASH_WIDTH_IN_BYTES: usize = 32;
 
+use crate::config::ServiceType;
+use crate::conn_log::ConnId;
 use anyhow::{bail, Context, Result};
 use bytes::{Bytes, BytesMut};
 use lazy_static::lazy_static;
@@ -8,18 +10,70 @@ use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::trace;
 
-type ProtocolVersion = u8;
+// Negotiation: each side sends the highest version it speaks in its hello, and the server replies
+// with the lower of the two, which both sides speak from then on. Each side refuses a peer
+// below `MIN_PROTO_VERSION`, so servers must be upgraded before clients.
+pub type ProtocolVersion = u8;
 const _PROTO_V0: u8 = 0u8;
 const PROTO_V1: u8 = 1u8;
-
-pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V1;
+// Adds `RegisterHello`, and `BoundAddr` and `Shutdown` of `ControlChannelCmd`
+pub const PROTO_V2: u8 = 2u8;
+// Adds `StartForwardTcpFrom` of `DataChannelCmd`
+pub const PROTO_V3: u8 = 3u8;
+// Adds `StartForwardTcpConn` of `DataChannelCmd`
+pub const PROTO_V4: u8 = 4u8;
+// Adds `StartMux` of `DataChannelCmd`
+pub const PROTO_V5: u8 = 5u8;
+// Adds `ForService` of `DataChannelCmd`
+pub const PROTO_V6: u8 = 6u8;
+
+pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V6;
+// The oldest version still spoken
+pub const MIN_PROTO_VERSION: ProtocolVersion = PROTO_V1;
+
+/// The version to speak with a peer of version `v`
+pub fn negotiate(v: ProtocolVersion) -> Result<ProtocolVersion> {
+    if v < MIN_PROTO_VERSION {
+        bail!(
+            "Protocol version {} of the peer is too old. At least {} is supported. Please update `rathole` of the peer.",
+            v,
+            MIN_PROTO_VERSION
+        );
+    }
+    Ok(v.min(CURRENT_PROTO_VERSION))
+}
 
 pub type Digest = [u8; HASH_WIDTH_IN_BYTES];
 
 #[derive(Deserialize, Serialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Hello {
     ControlChannelHello(ProtocolVersion, Digest), // sha256sum(service name) or a nonce
     DataChannelHello(ProtocolVersion, Digest),    // token provided by CreateDataChannel
+    // Like ControlChannelHello, but the client also sends a `Registration` after the hello
+    // of the server, in case the service is not configured on the server
+    RegisterHello(ProtocolVersion, Digest),
+}
+
+impl Hello {
+    pub fn version(&self) -> ProtocolVersion {
+        match self {
+            Hello::ControlChannelHello(v, _)
+            | Hello::DataChannelHello(v, _)
+            | Hello::RegisterHello(v, _) => *v,
+        }
+    }
+}
+
+// The maximum length of a serialized `Registration`
+const MAX_REGISTRATION_LEN: u16 = 1024;
+
+/// A service that a client asks the server to expose, under `[server.registration]`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub name: String,
+    pub service_type: ServiceType,
+    pub port: u16,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -50,12 +104,29 @@ impl std::fmt::Display for Ack {
 pub enum ControlChannelCmd {
     CreateDataChannel,
     HeartBeat,
+    // Followed by the address that the service is bound at, if its port is picked by the OS.
+    // Read it with `read_bound_addr`
+    BoundAddr,
+    // The server is shutting down the control channel
+    Shutdown,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DataChannelCmd {
     StartForwardTcp,
     StartForwardUdp,
+    // `StartForwardTcp`, followed by the address of the visitor. Read it with `read_visitor_addr`
+    StartForwardTcpFrom,
+    // `StartForwardTcp`, followed by the ID of the connection and the address of the visitor if known.
+    // Read them with `read_visitor`
+    StartForwardTcpConn,
+    // The rest of the data channel is a session of multiplexed streams, each of which starts
+    // like a data channel with a `DataChannelCmd`
+    StartMux,
+    // Followed by the digest of a service, then the command of a stream for that service,
+    // which may be another one than the data channel is of. Read it with `read_service_digest`
+    ForService,
 }
 
 type UdpPacketLen = u16; // `u16` should be enough for any practical UDP traffic on the Internet
@@ -180,28 +251,8 @@ pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Resu
     conn.read_exact(&mut buf)
         .await
         .with_context(|| "Failed to read hello")?;
-    let hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
-
-    match hello {
-        Hello::ControlChannelHello(v, _) => {
-            if v != CURRENT_PROTO_VERSION {
-                bail!(
-                    "Protocol version mismatched. Expected {}, got {}. Please update `rathole`.",
-                    CURRENT_PROTO_VERSION,
-                    v
-                );
-            }
-        }
-        Hello::DataChannelHello(v, _) => {
-            if v != CURRENT_PROTO_VERSION {
-                bail!(
-                    "Protocol version mismatched. Expected {}, got {}. Please update `rathole`.",
-                    CURRENT_PROTO_VERSION,
-                    v
-                );
-            }
-        }
-    }
+    let hello: Hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
+    negotiate(hello.version())?;
 
     Ok(hello)
 }
@@ -232,6 +283,127 @@ pub async fn read_control_cmd<T: AsyncRead + AsyncWrite + Unpin>(
     bincode::deserialize(&bytes).with_context(|| "Failed to deserialize control cmd")
 }
 
+/// Write `ControlChannelCmd::BoundAddr` and `addr`
+pub async fn write_bound_addr<T: AsyncWrite + Unpin>(conn: &mut T, addr: SocketAddr) -> Result<()> {
+    let mut buf = bincode::serialize(&ControlChannelCmd::BoundAddr).unwrap();
+    push_addr(&mut buf, addr);
+    conn.write_all(&buf).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Read the address following `ControlChannelCmd::BoundAddr`
+pub async fn read_bound_addr<T: AsyncRead + Unpin>(conn: &mut T) -> Result<SocketAddr> {
+    read_addr(conn)
+        .await
+        .with_context(|| "Failed to read the bound address")
+}
+
+/// `DataChannelCmd::StartForwardTcpFrom` and `addr`, to be written to a data channel
+pub fn start_forward_tcp_from(addr: SocketAddr) -> Vec<u8> {
+    let mut buf = bincode::serialize(&DataChannelCmd::StartForwardTcpFrom).unwrap();
+    push_addr(&mut buf, addr);
+    buf
+}
+
+/// Read the address following `DataChannelCmd::StartForwardTcpFrom`
+pub async fn read_visitor_addr<T: AsyncRead + Unpin>(conn: &mut T) -> Result<SocketAddr> {
+    read_addr(conn)
+        .await
+        .with_context(|| "Failed to read the address of the visitor")
+}
+
+/// `DataChannelCmd::StartForwardTcpConn`, `id` and `addr`, to be written to a data channel
+pub fn start_forward_tcp_conn(id: ConnId, addr: Option<SocketAddr>) -> Vec<u8> {
+    let mut buf = bincode::serialize(&DataChannelCmd::StartForwardTcpConn).unwrap();
+    buf.extend_from_slice(&id.0.to_be_bytes());
+    match addr {
+        Some(addr) => push_addr(&mut buf, addr),
+        None => buf.push(0),
+    }
+    buf
+}
+
+/// `DataChannelCmd::ForService` and `digest`, to be written before the command of a stream
+pub fn for_service(digest: &Digest) -> Vec<u8> {
+    let mut buf = bincode::serialize(&DataChannelCmd::ForService).unwrap();
+    buf.extend_from_slice(digest);
+    buf
+}
+
+/// Read the digest following `DataChannelCmd::ForService`
+pub async fn read_service_digest<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Digest> {
+    let mut digest = [0u8; HASH_WIDTH_IN_BYTES];
+    conn.read_exact(&mut digest)
+        .await
+        .with_context(|| "Failed to read the digest of the service")?;
+    Ok(digest)
+}
+
+/// Read the ID and the address following `DataChannelCmd::StartForwardTcpConn`
+pub async fn read_visitor<T: AsyncRead + Unpin>(
+    conn: &mut T,
+) -> Result<(ConnId, Option<SocketAddr>)> {
+    let id = ConnId(
+        conn.read_u64()
+            .await
+            .with_context(|| "Failed to read the ID of the connection")?,
+    );
+    let addr = match conn.read_u8().await? {
+        0 => None,
+        len => Some(
+            read_addr_of_len(conn, len)
+                .await
+                .with_context(|| "Failed to read the address of the visitor")?,
+        ),
+    };
+    Ok((id, addr))
+}
+
+// An address prefixed by its length in a byte
+fn push_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    let v = bincode::serialize(&addr).unwrap();
+    buf.push(v.len() as u8);
+    buf.extend_from_slice(&v);
+}
+
+async fn read_addr<T: AsyncRead + Unpin>(conn: &mut T) -> Result<SocketAddr> {
+    let len = conn.read_u8().await?;
+    read_addr_of_len(conn, len).await
+}
+
+async fn read_addr_of_len<T: AsyncRead + Unpin>(conn: &mut T, len: u8) -> Result<SocketAddr> {
+    let mut buf = vec![0u8; len as usize];
+    conn.read_exact(&mut buf).await?;
+    Ok(bincode::deserialize(&buf)?)
+}
+
+pub async fn write_registration<T: AsyncWrite + Unpin>(
+    conn: &mut T,
+    registration: &Registration,
+) -> Result<()> {
+    let v = bincode::serialize(registration).unwrap();
+    if v.len() > MAX_REGISTRATION_LEN as usize {
+        bail!("The registration is too long");
+    }
+    conn.write_u16(v.len() as u16).await?;
+    conn.write_all(&v).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+pub async fn read_registration<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Registration> {
+    let len = conn.read_u16().await?;
+    if len > MAX_REGISTRATION_LEN {
+        bail!("The registration is too long");
+    }
+    let mut buf = vec![0u8; len as usize];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read the registration")?;
+    bincode::deserialize(&buf).with_context(|| "Failed to deserialize the registration")
+}
+
 pub async fn read_data_cmd<T: AsyncRead + AsyncWrite + Unpin>(
     conn: &mut T,
 ) -> Result<DataChannelCmd> {
@@ -241,3 +413,61 @@ pub async fn read_data_cmd<T: AsyncRead + AsyncWrite + Unpin>(
         .with_context(|| "Failed to read cmd")?;
     bincode::deserialize(&bytes).with_context(|| "Failed to deserialize data cmd")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(PROTO_V1).unwrap(), PROTO_V1);
+        assert_eq!(
+            negotiate(CURRENT_PROTO_VERSION).unwrap(),
+            CURRENT_PROTO_VERSION
+        );
+        // A newer peer speaks ours
+        assert_eq!(
+            negotiate(CURRENT_PROTO_VERSION + 1).unwrap(),
+            CURRENT_PROTO_VERSION
+        );
+        assert!(negotiate(_PROTO_V0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_visitor_addr() {
+        let addr: SocketAddr = "[2001:db8::1]:12345".parse().unwrap();
+        let buf = start_forward_tcp_from(addr);
+        let mut conn = &buf[..];
+        assert!(matches!(
+            bincode::deserialize(&conn[..PACKET_LEN.d_cmd]).unwrap(),
+            DataChannelCmd::StartForwardTcpFrom
+        ));
+        conn = &conn[PACKET_LEN.d_cmd..];
+        assert_eq!(read_visitor_addr(&mut conn).await.unwrap(), addr);
+        assert!(conn.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_visitor() {
+        let addr: SocketAddr = "192.0.2.1:12345".parse().unwrap();
+        for addr in [Some(addr), None] {
+            let buf = start_forward_tcp_conn(ConnId(42), addr);
+            let mut conn = &buf[PACKET_LEN.d_cmd..];
+            assert_eq!(read_visitor(&mut conn).await.unwrap(), (ConnId(42), addr));
+            assert!(conn.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_for_service() {
+        let d = digest(b"foo");
+        let buf = for_service(&d);
+        assert!(matches!(
+            bincode::deserialize(&buf[..PACKET_LEN.d_cmd]).unwrap(),
+            DataChannelCmd::ForService
+        ));
+        let mut conn = &buf[PACKET_LEN.d_cmd..];
+        assert_eq!(read_service_digest(&mut conn).await.unwrap(), d);
+        assert!(conn.is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read a PROXY protocol v1 or v2 header from `conn`, e.g. sent by a load balancer
+/// in front of rathole. Returns the source address of the original connection,
+/// or None if the header doesn't carry one, like a health check of the balancer.
+///
+/// No more than the header is read, so the stream continues with the payload.
+pub async fn read_header<S: AsyncRead + Unpin>(conn: &mut S) -> Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 12];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read the PROXY protocol header")?;
+
+    if &buf == V2_SIGNATURE {
+        read_v2(conn).await
+    } else if buf.starts_with(b"PROXY ") {
+        read_v1(conn, &buf).await
+    } else {
+        bail!("Not a PROXY protocol header")
+    }
+}
+
+// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`, of which `prefix` is read
+async fn read_v1<S: AsyncRead + Unpin>(conn: &mut S, prefix: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut line = Vec::from(prefix);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY protocol v1 header too long");
+        }
+        line.push(conn.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .with_context(|| "Invalid PROXY protocol v1 header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src
+                .parse()
+                .with_context(|| format!("Invalid source address {}", src))?;
+            let port: u16 = sport
+                .parse()
+                .with_context(|| format!("Invalid source port {}", sport))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("Invalid PROXY protocol v1 header: {}", line)),
+    }
+}
+
+// The binary format, after the signature
+async fn read_v2<S: AsyncRead + Unpin>(conn: &mut S) -> Result<Option<SocketAddr>> {
+    let ver_cmd = conn.read_u8().await?;
+    let family = conn.read_u8().await?;
+    let len = conn.read_u16().await? as usize;
+    let mut addrs = vec![0u8; len];
+    conn.read_exact(&mut addrs).await?;
+
+    if ver_cmd >> 4 != 2 {
+        bail!("Unsupported PROXY protocol version {}", ver_cmd >> 4);
+    }
+    // LOCAL, which is sent by the balancer itself
+    if ver_cmd & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    // The address family, regardless of the transport protocol
+    match family >> 4 {
+        // AF_INET: src, dst, src port, dst port
+        1 if len >= 12 => {
+            let ip: [u8; 4] = addrs[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if len >= 36 => {
+            let ip: [u8; 16] = addrs[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC, AF_UNIX or truncated addresses
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read a header from `data`. Returns the address, and what's left after the header
+    async fn read(data: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut conn = data;
+        let addr = read_header(&mut conn).await?;
+        Ok((addr, conn.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 ::1 ::1 56324 443\r\n").await.unwrap();
+        assert_eq!(addr, Some("[::1]:56324".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nping").await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"ping");
+
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        let mut long = b"PROXY ".to_vec();
+        long.extend_from_slice(&[b'x'; 200]);
+        assert!(read(&long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY, TCP over IPv4
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 80]);
+        header.extend_from_slice(b"ping");
+        let (addr, rest) = read(&header).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.1:8080".parse().unwrap()));
+        assert_eq!(rest, b"ping");
+
+        // PROXY, TCP over IPv6
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        let mut src = [0u8; 16];
+        src[15] = 1;
+        header.extend_from_slice(&src);
+        header.extend_from_slice(&[0u8; 16]);
+        header.extend_from_slice(&[0x1f, 0x90, 0, 80]);
+        let (addr, _) = read(&header).await.unwrap();
+        assert_eq!(addr, Some("[::1]:8080".parse().unwrap()));
+
+        // LOCAL
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        header.extend_from_slice(b"ping");
+        let (addr, rest) = read(&header).await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"ping");
+    }
+}
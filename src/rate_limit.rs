@@ -0,0 +1,286 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
+
+/// A token bucket of bytes, which allows a burst of one second of traffic
+#[derive(Debug)]
+struct TokenBucket {
+    // Bytes per second
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// A token bucket that's shared by streams, limiting their total bandwidth
+type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+impl TokenBucket {
+    fn new(bytes_per_sec: f64) -> TokenBucket {
+        TokenBucket {
+            rate: bytes_per_sec,
+            tokens: bytes_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // A bucket of `bytes_per_sec`, or None if it's 0, i.e. unlimited
+    fn shared(bytes_per_sec: u64) -> Option<SharedBucket> {
+        (bytes_per_sec != 0).then(|| Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec as f64))))
+    }
+
+    // Refill the bucket. Returns the number of bytes allowed to pass,
+    // or how long to wait for tokens if there's none
+    fn available(&mut self, now: Instant) -> Result<usize, Duration> {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            Ok(self.tokens as usize)
+        } else {
+            // Tokens go negative if streams sharing the bucket overdraw it
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// The buckets that one direction of a stream draws from
+#[derive(Debug)]
+struct Limits {
+    buckets: Vec<SharedBucket>,
+    // Pending until there are tokens again
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Limits {
+    fn new(buckets: Vec<SharedBucket>) -> Limits {
+        Limits {
+            buckets,
+            sleep: Box::pin(time::sleep_until(Instant::now())),
+        }
+    }
+
+    // Wait for tokens in all buckets. Returns the number of bytes allowed to pass
+    fn poll_acquire(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            let now = Instant::now();
+            let mut max = want;
+            let mut wait = Duration::ZERO;
+            for b in &self.buckets {
+                match b.lock().unwrap().available(now) {
+                    Ok(n) => max = max.min(n),
+                    Err(d) => wait = wait.max(d),
+                }
+            }
+            if wait.is_zero() {
+                return Poll::Ready(max);
+            }
+
+            self.sleep.as_mut().reset(now + wait);
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        for b in &self.buckets {
+            b.lock().unwrap().consume(n);
+        }
+    }
+}
+
+/// A stream with the bandwidth of reading and writing limited independently
+#[derive(Debug)]
+pub struct RateLimitedStream<S> {
+    inner: S,
+    read_limits: Limits,
+    write_limits: Limits,
+}
+
+impl<S> RateLimitedStream<S> {
+    /// Limit the stream on its own. Rates are in bits per second. 0 means unlimited
+    pub fn new(inner: S, read_bps: u64, write_bps: u64) -> RateLimitedStream<S> {
+        let buckets = |bps| Vec::from_iter(TokenBucket::shared(bps / 8));
+        RateLimitedStream {
+            inner,
+            read_limits: Limits::new(buckets(read_bps)),
+            write_limits: Limits::new(buckets(write_bps)),
+        }
+    }
+
+    // Also draw from buckets shared with other streams
+    fn share(mut self, read: Option<&SharedBucket>, write: Option<&SharedBucket>) -> Self {
+        self.read_limits.buckets.extend(read.cloned());
+        self.write_limits.buckets.extend(write.cloned());
+        self
+    }
+}
+
+/// The bandwidth limits of a service, shared by all its connections.
+/// Rates are in bytes per second
+#[derive(Debug, Clone, Default)]
+pub struct ServiceBandwidth {
+    // From visitors to the service
+    upload: Option<SharedBucket>,
+    // From the service to visitors
+    download: Option<SharedBucket>,
+}
+
+impl ServiceBandwidth {
+    pub fn new(max_upload_speed: Option<u64>, max_download_speed: Option<u64>) -> ServiceBandwidth {
+        ServiceBandwidth {
+            upload: TokenBucket::shared(max_upload_speed.unwrap_or_default()),
+            download: TokenBucket::shared(max_download_speed.unwrap_or_default()),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    pub fn is_unlimited(&self) -> bool {
+        self.upload.is_none() && self.download.is_none()
+    }
+
+    /// Limit a visitor stream, which reads uploads and writes downloads
+    pub fn limit_visitor<S>(&self, s: RateLimitedStream<S>) -> RateLimitedStream<S> {
+        s.share(self.upload.as_ref(), self.download.as_ref())
+    }
+
+    /// Limit a stream to the local service, which reads downloads and writes uploads
+    pub fn limit_local<S>(&self, s: RateLimitedStream<S>) -> RateLimitedStream<S> {
+        s.share(self.download.as_ref(), self.upload.as_ref())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let limits = &mut this.read_limits;
+        if limits.buckets.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let max = ready!(limits.poll_acquire(cx, buf.remaining()));
+
+        // Same as `tokio::io::Take`
+        let mut b = buf.take(max);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut b))?;
+        let n = b.filled().len();
+        // SAFETY: `b` reads into the unfilled part of `buf`, and `n` bytes were filled
+        unsafe {
+            buf.assume_init(n);
+        }
+        buf.advance(n);
+        limits.consume(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let limits = &mut this.write_limits;
+        if limits.buckets.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let max = ready!(limits.poll_acquire(cx, buf.len()));
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..max]))?;
+        limits.consume(n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const KB: usize = 1024;
+
+    // Send `len` bytes from `w` to `r`. Returns how long it takes
+    async fn transfer<R, W>(r: &mut R, w: &mut W, len: usize) -> Duration
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let start = std::time::Instant::now();
+        let data = vec![0u8; len];
+        let mut buf = vec![0u8; len];
+        let (res, _) = tokio::join!(w.write_all(&data), r.read_exact(&mut buf));
+        res.unwrap();
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_one_direction() {
+        let (visitor, mut remote) = tokio::io::duplex(4 * KB);
+
+        // Only reading from the visitor, i.e. upload, is limited to 16 KB/s
+        let mut visitor = RateLimitedStream::new(visitor, 16 * KB as u64 * 8, 0);
+
+        // A 16 KB burst passes, and the next 16 KB takes a second
+        let up = transfer(&mut visitor, &mut remote, 32 * KB).await;
+        assert!(up >= Duration::from_millis(900), "upload took {:?}", up);
+
+        // Download runs at full speed
+        let down = transfer(&mut remote, &mut visitor, 1024 * KB).await;
+        assert!(
+            down < Duration::from_millis(500),
+            "download took {:?}",
+            down
+        );
+    }
+
+    #[tokio::test]
+    async fn test_service_bandwidth() {
+        // Uploads of the service are limited to 16 KB/s in total
+        let bandwidth = ServiceBandwidth::new(Some(16 * KB as u64), None);
+        let (a, mut remote_a) = tokio::io::duplex(4 * KB);
+        let (b, mut remote_b) = tokio::io::duplex(4 * KB);
+        let mut a = bandwidth.limit_visitor(RateLimitedStream::new(a, 0, 0));
+        let mut b = bandwidth.limit_visitor(RateLimitedStream::new(b, 0, 0));
+
+        // Either visitor alone fits in the burst, but not both
+        let (up_a, up_b) = tokio::join!(
+            transfer(&mut a, &mut remote_a, 16 * KB),
+            transfer(&mut b, &mut remote_b, 16 * KB)
+        );
+        let up = up_a.max(up_b);
+        assert!(up >= Duration::from_millis(900), "upload took {:?}", up);
+
+        // The local side of the client writes uploads
+        let (local, mut remote) = tokio::io::duplex(4 * KB);
+        let mut local = bandwidth.limit_local(RateLimitedStream::new(local, 0, 0));
+        let down = transfer(&mut local, &mut remote, 1024 * KB).await;
+        assert!(
+            down < Duration::from_millis(500),
+            "download took {:?}",
+            down
+        );
+    }
+}
@@ -1,49 +1,118 @@
-use crate::config::{Config, ServerConfig, ServerServiceConfig, ServiceType, TransportType};
+use crate::accept_error::AcceptErrorHandler;
+use crate::acl::Acl;
+use crate::admin_api::{self, AdminBackend, ClientStatus, ServiceStatus};
+use crate::auth_failure::{AuthFailureTracker, UNKNOWN_SERVICE};
+use crate::ban::BanList;
+use crate::config::{
+    binds_any_port, Config, MuxCoalescing, ScannerPolicy, ServerConfig, ServerServiceConfig,
+    ServiceType, SocketConfig, TransportType,
+};
 use crate::config_watcher::{ConfigChange, ServerServiceChange};
+use crate::conn_limit::ConnectionLimiter;
+use crate::conn_log::{self, ConnId, ConnStats};
+use crate::conn_timeout::{self, ConnTimeout};
+use crate::conn_tracker::{Activity, ConnTracker};
+use crate::connect_webhook::ConnectWebhook;
 use crate::constants::{listen_backoff, UDP_BUFFER_SIZE};
-use crate::helper::{retry_notify_with_deadline, write_and_flush};
+use crate::dispatcher::{Dispatcher, Load};
+use crate::event::{AuthFailureReason, CommandHook, Event, Hooks};
+use crate::handshake_limit::HandshakeLimiter;
+use crate::helper::{
+    copy_bidirectional_with_close_timeout, retry_notify_with_deadline, try_set_linger,
+    try_set_nodelay, try_set_socket_opts, try_set_tcp_keepalive, write_and_flush,
+};
+use crate::metrics::{self, Metrics, OnlineGuard, ServiceMetrics};
 use crate::multi_map::MultiMap;
-use crate::protocol::Hello::{ControlChannelHello, DataChannelHello};
+use crate::mux::{DataChannel, MuxPool, SharedMuxPool};
+use crate::notify::Notifier;
+use crate::pool_sizer::PoolSizer;
+#[cfg(unix)]
+use crate::privilege;
+use crate::protocol::Hello::{ControlChannelHello, DataChannelHello, RegisterHello};
 use crate::protocol::{
-    self, read_auth, read_hello, Ack, ControlChannelCmd, DataChannelCmd, Hello, UdpTraffic,
-    HASH_WIDTH_IN_BYTES,
+    self, read_auth, read_hello, read_registration, write_bound_addr, Ack, ControlChannelCmd,
+    DataChannelCmd, Hello, ProtocolVersion, Registration, UdpTraffic, HASH_WIDTH_IN_BYTES,
+    PROTO_V2, PROTO_V3, PROTO_V4, PROTO_V5, PROTO_V6,
 };
+use crate::proxy_protocol;
+use crate::rate_limit::{RateLimitedStream, ServiceBandwidth};
+use crate::socket::{SocketListener, SocketStream};
+use crate::socket_activation::{self, CONTROL_CHANNEL_SOCKET};
+use crate::systemd;
+use crate::traffic::{Account, Traffic};
 use crate::transport::{SocketOpts, TcpTransport, Transport};
+use crate::vhost::{self, Router};
 use anyhow::{anyhow, bail, Context, Result};
-use backoff::backoff::Backoff;
-use backoff::ExponentialBackoff;
+use async_trait::async_trait;
 
 use rand::RngCore;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use socket2::SockRef;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::time;
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
+#[cfg(all(target_os = "linux", feature = "splice"))]
+use crate::splice;
 #[cfg(feature = "noise")]
 use crate::transport::NoiseTransport;
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
 use crate::transport::TlsTransport;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
-use crate::transport::WebsocketTransport;
+use crate::transport::{HttpTransport, WebsocketTransport};
 
 type ServiceDigest = protocol::Digest; // SHA256 of a service name
 type Nonce = protocol::Digest; // Also called `session_key`
 
-const TCP_POOL_SIZE: usize = 8; // The number of cached connections for TCP servies
+// A visitor, its slot of the service, what was read from it to route it, to be forwarded first,
+// and its address, which is taken from the PROXY protocol header if there's one
+type Visitor = (
+    SocketStream,
+    Option<OwnedSemaphorePermit>,
+    Vec<u8>,
+    Option<SocketAddr>,
+);
+
+// A service, and the peer of the control channel if the service has `multi_client` set
+type ControlChannelKey = (ServiceDigest, Option<SocketAddr>);
+// Visitors and the bandwidth of `multi_client` services, shared by their control channels
+// and indexed by ServiceDigest
+type DispatcherMap = HashMap<ServiceDigest, (Weak<Dispatcher<Visitor>>, ServiceBandwidth)>;
+// Visitors of `sni` or `http` services, shared by their control channels and indexed by `bind_addr`
+type RouterMap = HashMap<String, Weak<Router<Visitor>>>;
+// Multiplexed data channels shared by control channels authenticated with the same token,
+// if `mux_coalescing` is "token", and indexed by the digest of the token.
+// Along with the services that have joined each
+type MuxPoolMap =
+    HashMap<protocol::Digest, (Weak<tokio::sync::Mutex<MuxPool>>, HashSet<ServiceDigest>)>;
+
 const UDP_POOL_SIZE: usize = 2; // The number of cached connections for UDP services
 const CHAN_SIZE: usize = 2048; // The capacity of various chans
-const HANDSHAKE_TIMEOUT: u64 = 5; // Timeout for transport handshake
+const HANDSHAKE_TIMEOUT: u64 = 5; // Timeout for reading the PROXY protocol header, SNI or Host of visitors
+const AUTH_FAILURE_REPORT_INTERVAL: u64 = 10; // At most one auth failure event per service in secs
+const TARPIT_SECS: u64 = 10; // How long to hold a connection that fails the handshake, if tarpitting
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100); // How often to check if data channels are drained
+const POOL_SHRINK_INTERVAL: Duration = Duration::from_secs(5); // How often to close idle data channels beyond the need
 
 // The entrypoint of running a server
 pub async fn run_server(
     config: Config,
     shutdown_rx: broadcast::Receiver<bool>,
     update_rx: mpsc::Receiver<ConfigChange>,
+    mut hooks: Hooks,
 ) -> Result<()> {
+    if let Some(notify) = &config.notify {
+        hooks.push(Arc::new(Notifier::new(notify)));
+    }
+    let metrics = metrics::start(config.metrics.as_ref(), hooks, shutdown_rx.resubscribe()).await?;
+
     let config = match config.server {
             Some(config) => config,
             None => {
@@ -53,13 +122,13 @@ pub async fn run_server(
 
     match config.transport.transport_type {
         TransportType::Tcp => {
-            let mut server = Server::<TcpTransport>::from(config).await?;
+            let mut server = Server::<TcpTransport>::from(config, metrics).await?;
             server.run(shutdown_rx, update_rx).await?;
         }
         TransportType::Tls => {
             #[cfg(any(feature = "native-tls", feature = "rustls"))]
             {
-                let mut server = Server::<TlsTransport>::from(config).await?;
+                let mut server = Server::<TlsTransport>::from(config, metrics).await?;
                 server.run(shutdown_rx, update_rx).await?;
             }
             #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
@@ -68,7 +137,7 @@ pub async fn run_server(
         TransportType::Noise => {
             #[cfg(feature = "noise")]
             {
-                let mut server = Server::<NoiseTransport>::from(config).await?;
+                let mut server = Server::<NoiseTransport>::from(config, metrics).await?;
                 server.run(shutdown_rx, update_rx).await?;
             }
             #[cfg(not(feature = "noise"))]
@@ -77,7 +146,16 @@ pub async fn run_server(
         TransportType::Websocket => {
             #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
             {
-                let mut server = Server::<WebsocketTransport>::from(config).await?;
+                let mut server = Server::<WebsocketTransport>::from(config, metrics).await?;
+                server.run(shutdown_rx, update_rx).await?;
+            }
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            crate::helper::feature_neither_compile("websocket-native-tls", "websocket-rustls")
+        }
+        TransportType::Http => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            {
+                let mut server = Server::<HttpTransport>::from(config, metrics).await?;
                 server.run(shutdown_rx, update_rx).await?;
             }
             #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
@@ -88,9 +166,9 @@ pub async fn run_server(
     Ok(())
 }
 
-// A hash map of ControlChannelHandles, indexed by ServiceDigest or Nonce
+// A hash map of ControlChannelHandles, indexed by ControlChannelKey or Nonce
 // See also MultiMap
-type ControlChannelMap<T> = MultiMap<ServiceDigest, Nonce, ControlChannelHandle<T>>;
+type ControlChannelMap<T> = MultiMap<ControlChannelKey, Nonce, ControlChannelHandle<T>>;
 
 // Server holds all states of running a server
 struct Server<T: Transport> {
@@ -103,6 +181,92 @@ struct Server<T: Transport> {
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     // Wrapper around the transport layer
     transport: Arc<T>,
+    // Failed authentications, indexed by service name
+    auth_failures: Arc<Mutex<AuthFailureTracker>>,
+    // Activity of forwarded connections, if `fd_soft_limit` is set
+    conn_tracker: Option<Arc<ConnTracker>>,
+    // Slots of visitors of all services, if `max_connections` is set
+    conn_limiter: Option<Arc<ConnectionLimiter>>,
+    // Source IPs failing handshakes, if `ban_threshold` is set
+    bans: Option<Arc<BanList>>,
+    // New connections from each source IP, if `handshake_rate_limit` is set
+    handshake_limiter: Option<Arc<HandshakeLimiter>>,
+    // Slots of transport handshakes in progress, if `max_pending_handshakes` is set
+    pending_handshakes: Option<Arc<Semaphore>>,
+    // Dispatchers of `multi_client` services
+    dispatchers: Arc<Mutex<DispatcherMap>>,
+    // Routers of `sni` and `http` services
+    routers: Arc<Mutex<RouterMap>>,
+    // Coalesced multiplexed data channels
+    mux_pools: Arc<Mutex<MuxPoolMap>>,
+    // Counters exposed by `[metrics]`
+    metrics: Arc<Metrics>,
+    // Traffic of services and clients, kept in `server.state_file`
+    traffic: Arc<Traffic>,
+}
+
+// Not derived, since T doesn't have to be Clone
+impl<T: Transport> Clone for Server<T> {
+    fn clone(&self) -> Self {
+        Server {
+            config: self.config.clone(),
+            services: self.services.clone(),
+            control_channels: self.control_channels.clone(),
+            transport: self.transport.clone(),
+            auth_failures: self.auth_failures.clone(),
+            conn_tracker: self.conn_tracker.clone(),
+            conn_limiter: self.conn_limiter.clone(),
+            bans: self.bans.clone(),
+            handshake_limiter: self.handshake_limiter.clone(),
+            pending_handshakes: self.pending_handshakes.clone(),
+            dispatchers: self.dispatchers.clone(),
+            routers: self.routers.clone(),
+            mux_pools: self.mux_pools.clone(),
+            metrics: self.metrics.clone(),
+            traffic: self.traffic.clone(),
+        }
+    }
+}
+
+// A task accepting connections at a listener, which gives the listener back once stopped
+struct AcceptorTask<A> {
+    stop_tx: oneshot::Sender<()>,
+    handle: tokio::task::JoinHandle<A>,
+}
+
+// Listen at `addr` with `n` sockets
+async fn bind_acceptors<T: Transport>(
+    transport: &T,
+    addr: &str,
+    n: usize,
+    reuse_port: bool,
+) -> Result<Vec<T::Acceptor>> {
+    let mut ret = Vec::with_capacity(n);
+    for _ in 0..n {
+        let l = if reuse_port {
+            transport.bind_reuse_port(addr).await
+        } else {
+            transport.bind(addr).await
+        }
+        .with_context(|| "Failed to listen at `server.bind_addr`")?;
+        ret.push(l);
+    }
+    Ok(ret)
+}
+
+// The services of a reloaded `config`. Services registered by clients are kept,
+// if registration is still allowed, for the clients to register them again on reconnecting
+fn reloaded_services(
+    old: &HashMap<ServiceDigest, ServerServiceConfig>,
+    config: &ServerConfig,
+) -> HashMap<ServiceDigest, ServerServiceConfig> {
+    let mut ret = generate_service_hashmap(config);
+    if config.registration.is_some() {
+        for (digest, s) in old.iter().filter(|(_, s)| s.registered) {
+            ret.entry(*digest).or_insert_with(|| s.clone());
+        }
+    }
+    ret
 }
 
 // Generate a hash map of services which is indexed by ServiceDigest
@@ -118,16 +282,40 @@ fn generate_service_hashmap(
 
 impl<T: 'static + Transport> Server<T> {
     // Create a server from `[server]`
-    pub async fn from(config: ServerConfig) -> Result<Server<T>> {
+    pub async fn from(config: ServerConfig, metrics: Arc<Metrics>) -> Result<Server<T>> {
         let config = Arc::new(config);
         let services = Arc::new(RwLock::new(generate_service_hashmap(&config)));
         let control_channels = Arc::new(RwLock::new(ControlChannelMap::new()));
         let transport = Arc::new(T::new(&config.transport)?);
+        let auth_failures = Arc::new(Mutex::new(AuthFailureTracker::new(Duration::from_secs(
+            AUTH_FAILURE_REPORT_INTERVAL,
+        ))));
+        let conn_tracker = config
+            .fd_soft_limit
+            .map(|limit| Arc::new(ConnTracker::new(limit)));
+        let conn_limiter = ConnectionLimiter::from_server_cfg(&config).map(Arc::new);
+        let bans = BanList::from_server_cfg(&config).map(Arc::new);
+        let handshake_limiter = HandshakeLimiter::from_server_cfg(&config).map(Arc::new);
+        let pending_handshakes = config
+            .max_pending_handshakes
+            .map(|n| Arc::new(Semaphore::new(n)));
+        let traffic = Arc::new(Traffic::from_server_cfg(&config)?);
         Ok(Server {
             config,
             services,
             control_channels,
             transport,
+            auth_failures,
+            conn_tracker,
+            conn_limiter,
+            bans,
+            handshake_limiter,
+            pending_handshakes,
+            dispatchers: Default::default(),
+            routers: Default::default(),
+            mux_pools: Default::default(),
+            metrics,
+            traffic,
         })
     }
 
@@ -137,108 +325,343 @@ impl<T: 'static + Transport> Server<T> {
         mut shutdown_rx: broadcast::Receiver<bool>,
         mut update_rx: mpsc::Receiver<ConfigChange>,
     ) -> Result<()> {
-        // Listen at `server.bind_addr`
-        let l = self
-            .transport
-            .bind(&self.config.bind_addr)
-            .await
-            .with_context(|| "Failed to listen at `server.bind_addr`")?;
+        // Listen at `server.bind_addr`, or the socket from systemd
+        let listeners = self.config.listeners.unwrap_or(1);
+        let mut acceptors = Vec::with_capacity(listeners);
+        match socket_activation::listener(CONTROL_CHANNEL_SOCKET)
+            .with_context(|| "Failed to take the socket from systemd")?
+        {
+            Some(SocketListener::Tcp(l)) => acceptors.push(l.into()),
+            #[cfg(unix)]
+            Some(SocketListener::Unix(_)) => {
+                bail!(
+                    "The socket {} from systemd must be TCP",
+                    CONTROL_CHANNEL_SOCKET
+                )
+            }
+            None => (),
+        }
+        acceptors.extend(
+            bind_acceptors(
+                &*self.transport,
+                &self.config.bind_addr,
+                listeners - acceptors.len(),
+                listeners > 1,
+            )
+            .await?,
+        );
         info!("Listening at {}", self.config.bind_addr);
 
-        // Retry at least every 100ms
-        let mut backoff = ExponentialBackoff {
-            max_interval: Duration::from_millis(100),
-            max_elapsed_time: None,
-            ..Default::default()
-        };
+        // Stops the tasks that outlive the loop below, e.g. the admin API reporting the drain
+        let (stop_tx, stop_rx) = broadcast::channel(1);
+
+        // The current `[server]`, which changes on reloads
+        let (config_tx, config_rx) = watch::channel(self.config.clone());
+        let (drain_tx, mut drain_rx) = mpsc::channel(1);
+        if let (Some(addr), Some(token)) = (&self.config.api_addr, &self.config.api_token) {
+            let backend = Arc::new(ServerAdmin {
+                config: config_rx,
+                services: self.services.clone(),
+                control_channels: self.control_channels.clone(),
+                metrics: self.metrics.clone(),
+                drain_tx,
+            });
+            admin_api::start(addr, token.clone(), backend, stop_rx.resubscribe()).await?;
+        }
 
-        // Wait for connections and shutdown signals
+        // Everything that needs the privileges is bound or loaded by now
+        #[cfg(unix)]
+        if self.config.user.is_some() || self.config.group.is_some() {
+            privilege::drop_privileges(self.config.user.as_deref(), self.config.group.as_deref())?;
+        }
+
+        // Accept connections in a task for each listener
+        let mut acceptors = self.spawn_acceptors(acceptors);
+        tokio::spawn(self.traffic.clone().run(stop_rx));
+        systemd::ready();
+
+        // Wait for shutdown signals and config changes
+        let mut draining = false;
         loop {
             tokio::select! {
-                // Wait for incoming control and data channels
-                ret = self.transport.accept(&l) => {
-                    match ret {
-                        Err(err) => {
-                            // Detects whether it's an IO error
-                            if let Some(err) = err.downcast_ref::<io::Error>() {
-                                // If it is an IO error, then it's possibly an
-                                // EMFILE. So sleep for a while and retry
-                                // TODO: Only sleep for EMFILE, ENFILE, ENOMEM, ENOBUFS
-                                if let Some(d) = backoff.next_backoff() {
-                                    error!("Failed to accept: {:#}. Retry in {:?}...", err, d);
-                                    time::sleep(d).await;
-                                } else {
-                                    // This branch will never be executed according to the current retry policy
-                                    error!("Too many retries. Aborting...");
-                                    break;
-                                }
-                            }
-                            // If it's not an IO error, then it comes from
-                            // the transport layer, so just ignore it
-                        }
-                        Ok((conn, addr)) => {
-                            backoff.reset();
-
-                            // Do transport handshake with a timeout
-                            match time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), self.transport.handshake(conn)).await {
-                                Ok(conn) => {
-                                    match conn.with_context(|| "Failed to do transport handshake") {
-                                        Ok(conn) => {
-                                            let services = self.services.clone();
-                                            let control_channels = self.control_channels.clone();
-                                            let server_config = self.config.clone();
-                                            tokio::spawn(async move {
-                                                if let Err(err) = handle_connection(conn, services, control_channels, server_config).await {
-                                                    error!("{:#}", err);
-                                                }
-                                            }.instrument(info_span!("connection", %addr)));
-                                        }, Err(e) => {
-                                            error!("{:#}", e);
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("Transport handshake timeout: {}", e);
-                                }
-                            }
-                        }
-                    }
-                },
                 // Wait for the shutdown signal
                 _ = shutdown_rx.recv() => {
                     info!("Shuting down gracefully...");
                     break;
                 },
-                e = update_rx.recv() => {
-                    if let Some(e) = e {
-                        self.handle_hot_reload(e).await;
+                // Or a drain requested by the admin API, which always waits for the data channels
+                Some(_) = drain_rx.recv() => {
+                    info!("Draining by the admin API...");
+                    draining = true;
+                    break;
+                },
+                e = update_rx.recv() => match e {
+                    Some(ConfigChange::ServerReload(config)) => {
+                        match self.reload(*config, &mut acceptors).await {
+                            Ok(_) => {
+                                config_tx.send_replace(self.config.clone());
+                            }
+                            Err(e) => error!("{:#}. Keep running with the current configuration", e),
+                        }
                     }
+                    Some(e) => self.handle_hot_reload(e).await,
+                    None => (),
                 }
             }
         }
 
+        // Close the listeners
+        for a in acceptors {
+            a.handle.abort();
+            let _ = a.handle.await;
+        }
+
+        if draining || self.config.grace_period != 0 {
+            self.drain().await;
+        }
+        if let Err(e) = self.traffic.save() {
+            error!("{:#}", e);
+        }
+        let _ = stop_tx.send(true);
+
         info!("Shutdown");
 
         Ok(())
     }
 
+    // Stop accepting visitors, telling the clients, and wait for data channels to finish within `grace_period`
+    async fn drain(&self) {
+        let _ = self.control_channels.write().await.remove1_if(|_| true);
+
+        let deadline = time::Instant::now() + Duration::from_secs(self.config.grace_period);
+        loop {
+            let n = self.metrics.data_channels();
+            if n <= 0 {
+                break;
+            }
+            if time::Instant::now() >= deadline {
+                warn!("{} data channels are still forwarding. Close them", n);
+                break;
+            }
+            debug!("Waiting for {} data channels to finish", n);
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    // Accept connections in a task for each of `listeners`
+    fn spawn_acceptors(&self, listeners: Vec<T::Acceptor>) -> Vec<AcceptorTask<T::Acceptor>> {
+        listeners
+            .into_iter()
+            .map(|l| {
+                let (stop_tx, stop_rx) = oneshot::channel();
+                let server = self.clone();
+                let handle =
+                    tokio::spawn(server.run_acceptor(l, stop_rx).instrument(Span::current()));
+                AcceptorTask { stop_tx, handle }
+            })
+            .collect()
+    }
+
+    // Apply a new `[server]`, with the same transport type, by re-establishing the control channels.
+    // The listeners are kept unless `bind_addr` changes, and the data channels keep forwarding
+    async fn reload(
+        &mut self,
+        config: ServerConfig,
+        acceptors: &mut Vec<AcceptorTask<T::Acceptor>>,
+    ) -> Result<()> {
+        let transport =
+            Arc::new(T::new(&config.transport).with_context(|| "Failed to create the transport")?);
+        // Listen at the new address before closing the old listeners
+        let bound = if config.bind_addr != self.config.bind_addr {
+            Some(
+                bind_acceptors(
+                    &*transport,
+                    &config.bind_addr,
+                    acceptors.len(),
+                    config.listeners.unwrap_or(1) > 1,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let mut listeners = Vec::with_capacity(acceptors.len());
+        for a in acceptors.drain(..) {
+            let _ = a.stop_tx.send(());
+            if let Ok(l) = a.handle.await {
+                listeners.push(l);
+            }
+        }
+        let listeners = bound.unwrap_or(listeners);
+
+        self.transport = transport;
+        {
+            let mut services = self.services.write().await;
+            let reloaded = reloaded_services(&services, &config);
+            for (_, s) in services.iter().filter(|(d, _)| !reloaded.contains_key(*d)) {
+                self.metrics.remove_service(&s.name);
+            }
+            *services = reloaded;
+        }
+        self.config = Arc::new(config);
+        // The clients connect again, with the new transport and settings
+        let closed = self.control_channels.write().await.remove1_if(|_| true);
+        info!(
+            "Configuration reloaded. Closed {} control channels to re-establish them",
+            closed.len()
+        );
+
+        *acceptors = self.spawn_acceptors(listeners);
+        info!("Listening at {}", self.config.bind_addr);
+        Ok(())
+    }
+
+    // Accept connections at `l` until stopped by `stop_rx`, and give `l` back
+    async fn run_acceptor(self, l: T::Acceptor, mut stop_rx: oneshot::Receiver<()>) -> T::Acceptor {
+        let mut accept_error_handler = AcceptErrorHandler::new(self.config.accept_error_backoff_ms);
+
+        loop {
+            // Wait for incoming control and data channels
+            let accepted = tokio::select! {
+                v = self.transport.accept(&l) => v,
+                _ = &mut stop_rx => return l,
+            };
+            match accepted {
+                Err(err) => {
+                    // Detects whether it's an IO error
+                    if let Some(err) = err.downcast_ref::<io::Error>() {
+                        // If it is an IO error, then it's possibly an
+                        // EMFILE. So sleep for a while and retry
+                        accept_error_handler.handle(err).await;
+                    }
+                    // If it's not an IO error, then it comes from
+                    // the transport layer, so just ignore it
+                }
+                Ok((conn, addr)) => {
+                    if self.bans.as_ref().is_some_and(|b| b.is_banned(addr.ip())) {
+                        debug!("Dropped a connection from banned {}", addr);
+                        continue;
+                    }
+
+                    if self
+                        .handshake_limiter
+                        .as_ref()
+                        .is_some_and(|l| !l.allow(addr.ip()))
+                    {
+                        debug!(
+                            "Dropped a connection from {} over `handshake_rate_limit`",
+                            addr
+                        );
+                        continue;
+                    }
+                    let permit = match &self.pending_handshakes {
+                        Some(s) => match s.clone().try_acquire_owned() {
+                            Ok(v) => Some(v),
+                            Err(_) => {
+                                debug!(
+                                    "Dropped a connection from {} over `max_pending_handshakes`",
+                                    addr
+                                );
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    // Handshake in a task, so that slow peers don't hold up accepting
+                    let server = self.clone();
+                    tokio::spawn(
+                        server
+                            .handshake(conn, addr, permit)
+                            .instrument(info_span!("connection", %addr)),
+                    );
+                }
+            }
+        }
+    }
+
+    // Do the transport handshake of `conn` with a timeout, then serve it
+    async fn handshake(
+        self,
+        conn: T::RawStream,
+        addr: SocketAddr,
+        permit: Option<OwnedSemaphorePermit>,
+    ) {
+        // The transport takes the connection, so a duplicate of the socket is what's tarpitted
+        let held = match self.config.scanner_policy {
+            ScannerPolicy::Tarpit => SockRef::from(conn.borrow()).try_clone().ok(),
+            _ => None,
+        };
+        let timeout = self.config.handshake_timeout();
+        let conn = match time::timeout(timeout, self.transport.handshake(conn)).await {
+            Ok(conn) => conn.with_context(|| "Failed to do transport handshake"),
+            Err(e) => Err(anyhow!("Transport handshake timeout: {}", e)),
+        };
+        drop(permit);
+        let conn = match conn {
+            Ok(v) => {
+                // Otherwise it would keep the connection open after the stream closes
+                drop(held);
+                v
+            }
+            Err(e) => {
+                self.metrics.handshake_failed();
+                self.record_failure(addr);
+                log_handshake_failure(self.config.scanner_policy, &e);
+                if let Some(_held) = held {
+                    time::sleep(Duration::from_secs(TARPIT_SECS)).await;
+                }
+                return;
+            }
+        };
+
+        if let Err(err) = handle_connection(
+            conn,
+            addr,
+            self.services,
+            self.control_channels,
+            self.config,
+            self.auth_failures,
+            self.conn_tracker,
+            self.conn_limiter,
+            self.bans,
+            self.dispatchers,
+            self.routers,
+            self.mux_pools,
+            self.metrics,
+            self.traffic,
+        )
+        .await
+        {
+            error!("{:#}", err);
+        }
+    }
+
+    fn record_failure(&self, addr: SocketAddr) {
+        if let Some(bans) = &self.bans {
+            bans.record_failure(addr.ip());
+        }
+    }
+
     async fn handle_hot_reload(&mut self, e: ConfigChange) {
         match e {
             ConfigChange::ServerChange(server_change) => match server_change {
                 ServerServiceChange::Add(cfg) => {
                     let hash = protocol::digest(cfg.name.as_bytes());
                     let mut wg = self.services.write().await;
-                    let _ = wg.insert(hash, cfg);
+                    let _ = wg.insert(hash, *cfg);
 
                     let mut wg = self.control_channels.write().await;
-                    let _ = wg.remove1(&hash);
+                    let _ = wg.remove1_if(|k| k.0 == hash);
                 }
                 ServerServiceChange::Delete(s) => {
                     let hash = protocol::digest(s.as_bytes());
                     let _ = self.services.write().await.remove(&hash);
+                    self.metrics.remove_service(&s);
 
                     let mut wg = self.control_channels.write().await;
-                    let _ = wg.remove1(&hash);
+                    let _ = wg.remove1_if(|k| k.0 == hash);
                 }
             },
             ignored => warn!("Ignored {:?} since running as a server", ignored),
@@ -246,23 +669,142 @@ impl<T: 'static + Transport> Server<T> {
     }
 }
 
+// What the admin API sees of a server
+struct ServerAdmin<T: Transport> {
+    config: watch::Receiver<Arc<ServerConfig>>,
+    services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
+    control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+    metrics: Arc<Metrics>,
+    // Asks `Server::run` to drain and exit
+    drain_tx: mpsc::Sender<()>,
+}
+
+#[async_trait]
+impl<T: 'static + Transport> AdminBackend for ServerAdmin<T> {
+    async fn services(&self) -> Vec<ServiceStatus> {
+        let services = self.services.read().await;
+        let control_channels = self.control_channels.read().await;
+        let mut ret: Vec<ServiceStatus> = services
+            .iter()
+            .map(|(digest, s)| ServiceStatus {
+                name: s.name.clone(),
+                service_type: s.service_type,
+                bind_addr: s.bind_addr.clone(),
+                clients: control_channels
+                    .iter()
+                    .filter(|(k, _)| k.0 == *digest)
+                    .map(|(_, h)| h.status())
+                    .collect(),
+            })
+            .collect();
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        ret
+    }
+
+    async fn close(&self, service: &str) -> Option<usize> {
+        let digest = protocol::digest(service.as_bytes());
+        if !self.services.read().await.contains_key(&digest) {
+            return None;
+        }
+        let closed = self
+            .control_channels
+            .write()
+            .await
+            .remove1_if(|k| k.0 == digest);
+        info!(
+            "Closed {} control channels of {} by the admin API",
+            closed.len(),
+            service
+        );
+        Some(closed.len())
+    }
+
+    async fn config(&self) -> ServerConfig {
+        let services = self.services.read().await;
+        ServerConfig {
+            services: services
+                .values()
+                .map(|s| (s.name.clone(), s.clone()))
+                .collect(),
+            ..(**self.config.borrow()).clone()
+        }
+    }
+
+    async fn drain(&self) -> usize {
+        // Full once requested, and closed once the server stops draining
+        let _ = self.drain_tx.try_send(());
+        self.metrics.data_channels().max(0) as usize
+    }
+}
+
+// Secs since the UNIX epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 // Handle connections to `server.bind_addr`
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection<T: 'static + Transport>(
     mut conn: T::Stream,
+    addr: SocketAddr,
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     server_config: Arc<ServerConfig>,
+    auth_failures: Arc<Mutex<AuthFailureTracker>>,
+    conn_tracker: Option<Arc<ConnTracker>>,
+    conn_limiter: Option<Arc<ConnectionLimiter>>,
+    bans: Option<Arc<BanList>>,
+    dispatchers: Arc<Mutex<DispatcherMap>>,
+    routers: Arc<Mutex<RouterMap>>,
+    mux_pools: Arc<Mutex<MuxPoolMap>>,
+    metrics: Arc<Metrics>,
+    traffic: Arc<Traffic>,
 ) -> Result<()> {
     // Read hello
-    let hello = read_hello(&mut conn).await?;
+    let timeout = server_config.handshake_timeout();
+    let hello = time::timeout(timeout, read_hello(&mut conn))
+        .await
+        .map_err(|_| anyhow!("Timeout reading the hello"))
+        .and_then(|v| v);
+    let hello = match hello {
+        Ok(v) => v,
+        Err(e) => {
+            metrics.handshake_failed();
+            if let Some(bans) = &bans {
+                bans.record_failure(addr.ip());
+            }
+            log_handshake_failure(server_config.scanner_policy, &e);
+            if server_config.scanner_policy == ScannerPolicy::Tarpit {
+                time::sleep(Duration::from_secs(TARPIT_SECS)).await;
+            }
+            return Ok(());
+        }
+    };
+    let register = matches!(hello, RegisterHello(..));
+    let version = protocol::negotiate(hello.version())?;
     match hello {
-        ControlChannelHello(_, service_digest) => {
+        ControlChannelHello(_, service_digest) | RegisterHello(_, service_digest) => {
             do_control_channel_handshake(
                 conn,
+                addr,
                 services,
                 control_channels,
                 service_digest,
+                register,
+                version,
                 server_config,
+                auth_failures,
+                conn_tracker,
+                conn_limiter,
+                bans,
+                dispatchers,
+                routers,
+                mux_pools,
+                metrics,
+                traffic,
             )
             .await?;
         }
@@ -273,89 +815,435 @@ async fn handle_connection<T: 'static + Transport>(
     Ok(())
 }
 
+// Log a connection that fails the handshake, according to `server.scanner_policy`
+fn log_handshake_failure(policy: ScannerPolicy, e: &anyhow::Error) {
+    match policy {
+        ScannerPolicy::Log => error!("{:#}", e),
+        ScannerPolicy::Drop | ScannerPolicy::Tarpit => debug!("{:#}", e),
+    }
+}
+
+// Count a failed authentication, and emit a rate-limited event for it
+fn report_auth_failure(
+    auth_failures: &Mutex<AuthFailureTracker>,
+    metrics: &Metrics,
+    peer: SocketAddr,
+    service: &str,
+    reason: AuthFailureReason,
+) {
+    metrics.auth_failed(service);
+    if let Some(e) = auth_failures.lock().unwrap().record(service) {
+        warn!(
+            event = "auth_failure",
+            %peer,
+            service,
+            %reason,
+            total = e.total,
+            suppressed = e.suppressed,
+            "Control channel authentication failed"
+        );
+        metrics.emit(&Event::AuthFailed {
+            service: service.to_string(),
+            peer,
+            reason,
+        });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn do_control_channel_handshake<T: 'static + Transport>(
     mut conn: T::Stream,
+    addr: SocketAddr,
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     service_digest: ServiceDigest,
+    register: bool,
+    // Negotiated with the client
+    version: ProtocolVersion,
     server_config: Arc<ServerConfig>,
+    auth_failures: Arc<Mutex<AuthFailureTracker>>,
+    conn_tracker: Option<Arc<ConnTracker>>,
+    conn_limiter: Option<Arc<ConnectionLimiter>>,
+    bans: Option<Arc<BanList>>,
+    dispatchers: Arc<Mutex<DispatcherMap>>,
+    routers: Arc<Mutex<RouterMap>>,
+    mux_pools: Arc<Mutex<MuxPoolMap>>,
+    metrics: Arc<Metrics>,
+    traffic: Arc<Traffic>,
 ) -> Result<()> {
     info!("Try to handshake a control channel");
+    let timeout = server_config.handshake_timeout();
 
-    T::hint(&conn, SocketOpts::for_control_channel());
+    T::hint(
+        &conn,
+        SocketOpts::for_control_channel(&server_config.transport.tcp),
+    );
 
     // Generate a nonce
     let mut nonce = vec![0u8; HASH_WIDTH_IN_BYTES];
     rand::thread_rng().fill_bytes(&mut nonce);
 
-    // Send hello
-    let hello_send = Hello::ControlChannelHello(
-        protocol::CURRENT_PROTO_VERSION,
-        nonce.clone().try_into().unwrap(),
-    );
+    // Send hello, with the version to speak
+    let hello_send = Hello::ControlChannelHello(version, nonce.clone().try_into().unwrap());
     conn.write_all(&bincode::serialize(&hello_send).unwrap())
         .await?;
     conn.flush().await?;
 
+    // The client tells what to expose, in case the service is not configured
+    let registered = if register {
+        let r = time::timeout(timeout, read_registration(&mut conn))
+            .await
+            .with_context(|| "Timeout reading the registration")??;
+        registered_service(&server_config, &r, &service_digest)
+    } else {
+        None
+    };
+
     // Lookup the service
-    let service_config = match services.read().await.get(&service_digest) {
+    let service_config = match services
+        .read()
+        .await
+        .get(&service_digest)
+        .or(registered.as_ref())
+    {
         Some(v) => v,
         None => {
+            // Counted before telling the peer, which may be gone already
+            debug!("No such a service {}", hex::encode(service_digest));
+            metrics.handshake_failed();
+            if let Some(bans) = &bans {
+                bans.record_failure(addr.ip());
+            }
+            report_auth_failure(
+                &auth_failures,
+                &metrics,
+                addr,
+                UNKNOWN_SERVICE,
+                AuthFailureReason::ServiceNotExist,
+            );
             conn.write_all(&bincode::serialize(&Ack::ServiceNotExist).unwrap())
                 .await?;
-            bail!("No such a service {}", hex::encode(service_digest));
+            return Ok(());
         }
     }
     .to_owned();
 
     let service_name = &service_config.name;
-
-    // Calculate the checksum
-    let mut concat = Vec::from(service_config.token.as_ref().unwrap().as_bytes());
-    concat.append(&mut nonce);
+    let service_metrics = metrics.service(service_name);
 
     // Read auth
-    let protocol::Auth(d) = read_auth(&mut conn).await?;
-
-    // Validate
-    let session_key = protocol::digest(&concat);
-    if session_key != d {
+    let protocol::Auth(d) = time::timeout(timeout, read_auth(&mut conn))
+        .await
+        .with_context(|| "Timeout reading the auth")??;
+
+    // Validate, with any of the accepted tokens. The token that matches identifies the client
+    let matched = service_config
+        .accepted_tokens()
+        .map(|token| {
+            let mut concat = Vec::from(token.as_bytes());
+            concat.extend_from_slice(&nonce);
+            (protocol::digest(&concat), token)
+        })
+        .find(|(session_key, _)| *session_key == d)
+        .map(|(session_key, token)| (session_key, protocol::digest(token.as_bytes())));
+    let Some((session_key, identity)) = matched else {
+        debug!("Got {}, which matches none of the tokens", hex::encode(d));
+        service_metrics.handshake_failed();
+        if let Some(bans) = &bans {
+            bans.record_failure(addr.ip());
+        }
+        report_auth_failure(
+            &auth_failures,
+            &metrics,
+            addr,
+            service_name,
+            AuthFailureReason::IncorrectToken,
+        );
         conn.write_all(&bincode::serialize(&Ack::AuthFailed).unwrap())
             .await?;
-        debug!(
-            "Expect {}, but got {}",
-            hex::encode(session_key),
-            hex::encode(d)
+        return Ok(());
+    };
+
+    if let Some(bans) = &bans {
+        bans.forgive(addr.ip());
+    }
+    if service_config.registered {
+        services
+            .write()
+            .await
+            .entry(service_digest)
+            .or_insert_with(|| {
+                info!(
+                    "Registered service {} at {}",
+                    service_name, service_config.bind_addr
+                );
+                service_config.clone()
+            });
+    }
+    let mut h = control_channels.write().await;
+
+    // Control channels of a `multi_client` service live side by side
+    let key = (service_digest, service_config.multi_client.then_some(addr));
+
+    // If there's already a control channel for the service, then drop the old one.
+    // Because a control channel doesn't report back when it's dead,
+    // the handle in the map could be stall, dropping the old handle enables
+    // the client to reconnect.
+    if h.remove1(&key).is_some() {
+        warn!(
+            "Dropping previous control channel for service {}",
+            service_name
+        );
+    }
+
+    // Send ack
+    conn.write_all(&bincode::serialize(&Ack::Ok).unwrap())
+        .await?;
+    conn.flush().await?;
+
+    info!(service = %service_config.name, "Control channel established");
+    let shared = if service_config.multi_client {
+        let weight = service_config
+            .client_weights
+            .get(&addr.ip())
+            .copied()
+            .unwrap_or(1);
+        let (d, bandwidth) = get_or_create_dispatcher(
+            &dispatchers,
+            service_digest,
+            &service_config,
+            service_metrics.clone(),
         );
-        bail!("Service {} failed the authentication", service_name);
+        Some(SharedVisitors::Dispatcher(d, weight, bandwidth))
+    } else if service_config.service_type.is_virtual_host() {
+        let r = get_or_create_router(&routers, &service_config, service_metrics.clone());
+        Some(SharedVisitors::Router(r))
     } else {
-        let mut h = control_channels.write().await;
-
-        // If there's already a control channel for the service, then drop the old one.
-        // Because a control channel doesn't report back when it's dead,
-        // the handle in the map could be stall, dropping the old handle enables
-        // the client to reconnect.
-        if h.remove1(&service_digest).is_some() {
-            warn!(
-                "Dropping previous control channel for service {}",
-                service_name
-            );
+        None
+    };
+    // Services of a client multiplex over the same data channels, if coalesced.
+    // Older clients can't tell the service of a stream
+    let coalesced = match (
+        server_config.mux_coalescing,
+        service_config.multiplex_channels,
+    ) {
+        (MuxCoalescing::Token, Some(size)) if version >= PROTO_V6 => Some(get_or_create_mux_pool(
+            &mux_pools,
+            identity,
+            service_digest,
+            size,
+            service_metrics.clone(),
+        )),
+        _ => None,
+    };
+    let registered = service_config.registered;
+    let account = traffic.account(service_name, service_config.monthly_quota, addr.ip());
+    let mut handle = ControlChannelHandle::new(
+        conn,
+        addr,
+        version,
+        service_config,
+        server_config.heartbeat_interval,
+        conn_tracker,
+        conn_limiter,
+        shared,
+        coalesced,
+        service_metrics,
+        account,
+    );
+
+    // Since control channels of a `multi_client` service don't replace each other,
+    // forget the handle once the control channel is closed.
+    // So is a registered service, once no client exposes it
+    if key.1.is_some() || registered {
+        let closed = handle.closed.take();
+        let control_channels = control_channels.clone();
+        let services = services.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Some(closed) = closed {
+                let _ = closed.await;
+            }
+            let (removed, exposed) = {
+                let mut h = control_channels.write().await;
+                // Not in the map if it's replaced, or closed by a reload for the client to reconnect
+                let removed = h.remove2(&session_key).is_some();
+                let exposed = h.iter().any(|(k, _)| k.0 == service_digest);
+                (removed, exposed)
+            };
+            if registered && removed && !exposed {
+                let mut services = services.write().await;
+                if let Some(s) = services.get(&service_digest).filter(|s| s.registered) {
+                    info!("Unregistered service {}", s.name);
+                    metrics.remove_service(&s.name);
+                    services.remove(&service_digest);
+                }
+            }
+        });
+    }
+
+    // Insert the new handle
+    let _ = h.insert(key, session_key, handle);
+
+    Ok(())
+}
+
+// The service that a client asks for, if `[server.registration]` allows it
+fn registered_service(
+    server_config: &ServerConfig,
+    r: &Registration,
+    service_digest: &ServiceDigest,
+) -> Option<ServerServiceConfig> {
+    let config = server_config.registration.as_ref()?;
+    if protocol::digest(r.name.as_bytes()) != *service_digest || r.service_type.is_virtual_host() {
+        return None;
+    }
+    let Some(bind_addr) = config.bind_addr_of(r.port) else {
+        warn!(
+            "Service {} can't be registered at port {}, which is out of `server.registration.bind_addr`",
+            r.name, r.port
+        );
+        return None;
+    };
+    Some(ServerServiceConfig {
+        service_type: r.service_type,
+        name: r.name.clone(),
+        bind_addr,
+        token: Some(config.token.clone()),
+        accept_error_backoff_ms: Some(server_config.accept_error_backoff_ms),
+        registered: true,
+        ..Default::default()
+    })
+}
+
+// Get the dispatcher of a `multi_client` service, creating one if no control channel holds it
+fn get_or_create_dispatcher(
+    dispatchers: &Mutex<DispatcherMap>,
+    service_digest: ServiceDigest,
+    service: &ServerServiceConfig,
+    metrics: Arc<ServiceMetrics>,
+) -> (Arc<Dispatcher<Visitor>>, ServiceBandwidth) {
+    let mut dispatchers = dispatchers.lock().unwrap();
+    if let Some((d, bandwidth)) = dispatchers.get(&service_digest) {
+        if let Some(d) = d.upgrade() {
+            return (d, bandwidth.clone());
         }
+    }
 
-        // Send ack
-        conn.write_all(&bincode::serialize(&Ack::Ok).unwrap())
-            .await?;
-        conn.flush().await?;
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let visitor_rx = listen_for_visitors(service, metrics, None, shutdown_rx);
+    let d = Arc::new(Dispatcher::new(
+        visitor_rx,
+        service.load_balance,
+        shutdown_tx,
+    ));
+    let bandwidth = ServiceBandwidth::new(service.max_upload_speed, service.max_download_speed);
+    dispatchers.insert(service_digest, (Arc::downgrade(&d), bandwidth.clone()));
+    (d, bandwidth)
+}
 
-        info!(service = %service_config.name, "Control channel established");
-        let handle =
-            ControlChannelHandle::new(conn, service_config, server_config.heartbeat_interval);
+// Get the multiplexed data channels of the client of `identity` for `service`, creating a pool of
+// `size` ones if no control channel holds it. True if created.
+// A service joining the same pool again is of another instance of the client, e.g. a restarted one,
+// whose data channels can't be those of the previous instance
+fn get_or_create_mux_pool(
+    mux_pools: &Mutex<MuxPoolMap>,
+    identity: protocol::Digest,
+    service: ServiceDigest,
+    size: usize,
+    metrics: Arc<ServiceMetrics>,
+) -> (SharedMuxPool, bool) {
+    let mut mux_pools = mux_pools.lock().unwrap();
+    if let Some((p, joined)) = mux_pools.get_mut(&identity) {
+        if let Some(p) = p.upgrade() {
+            if joined.insert(service) {
+                return (p, false);
+            }
+        }
+    }
 
-        // Insert the new handle
-        let _ = h.insert(service_digest, session_key, handle);
+    let p = Arc::new(tokio::sync::Mutex::new(MuxPool::new(size, metrics)));
+    mux_pools.insert(identity, (Arc::downgrade(&p), HashSet::from([service])));
+    (p, true)
+}
+
+// Get the router at the `bind_addr` of an `sni` or `http` service, creating one if no control channel holds it
+fn get_or_create_router(
+    routers: &Mutex<RouterMap>,
+    service: &ServerServiceConfig,
+    metrics: Arc<ServiceMetrics>,
+) -> Arc<Router<Visitor>> {
+    let mut routers = routers.lock().unwrap();
+    if let Some(r) = routers.get(&service.bind_addr).and_then(Weak::upgrade) {
+        return r;
     }
 
-    Ok(())
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let visitor_rx = tcp_listen_and_send(
+        service.bind_addr.clone(),
+        None,
+        None,
+        None,
+        None,
+        false,
+        service.accept_error_backoff_ms.unwrap_or_default(),
+        metrics,
+        None,
+        shutdown_rx,
+    );
+    let r = Arc::new(Router::new(shutdown_tx));
+    route_visitors(visitor_rx, service.service_type, Arc::downgrade(&r));
+    routers.insert(service.bind_addr.clone(), Arc::downgrade(&r));
+    r
+}
+
+// Send visitors from the shared listener to the services by their hostnames
+fn route_visitors(
+    mut visitor_rx: mpsc::Receiver<Visitor>,
+    service_type: ServiceType,
+    router: Weak<Router<Visitor>>,
+) {
+    tokio::spawn(
+        async move {
+            while let Some((mut conn, permit, mut head, addr)) = visitor_rx.recv().await {
+                let router = router.clone();
+                tokio::spawn(
+                    async move {
+                        match route_visitor(&mut conn, &mut head, service_type, &router).await {
+                            Ok(tx) => {
+                                let _ = tx.send((conn, permit, head, addr)).await;
+                            }
+                            Err(e) => info!("Visitor is closed: {:#}", e),
+                        }
+                    }
+                    .instrument(Span::current()),
+                );
+            }
+        }
+        .instrument(Span::current()),
+    );
+}
+
+// Find where a visitor goes by the server name in its TLS ClientHello,
+// or the Host header of its HTTP request
+async fn route_visitor(
+    conn: &mut SocketStream,
+    head: &mut Vec<u8>,
+    service_type: ServiceType,
+    router: &Weak<Router<Visitor>>,
+) -> Result<mpsc::Sender<Visitor>> {
+    let hostname = time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), async {
+        match service_type {
+            ServiceType::Http => vhost::read_http_host(conn, head).await,
+            _ => vhost::read_sni(conn, head).await,
+        }
+    })
+    .await
+    .with_context(|| "Timeout reading the hostname")??;
+    router
+        .upgrade()
+        .and_then(|r| r.route(&hostname))
+        .ok_or_else(|| anyhow!("No service for {}", hostname))
 }
 
 async fn do_data_channel_handshake<T: 'static + Transport>(
@@ -385,11 +1273,52 @@ async fn do_data_channel_handshake<T: 'static + Transport>(
     Ok(())
 }
 
+// Where visitors of a control channel come from, if shared with other control channels
+enum SharedVisitors {
+    // The visitors of a `multi_client` service, the weight of the control channel,
+    // and the bandwidth of the service
+    Dispatcher(Arc<Dispatcher<Visitor>>, u32, ServiceBandwidth),
+    // The visitors at the `bind_addr` of `sni` or `http` services
+    Router(Arc<Router<Visitor>>),
+}
+
+impl SharedVisitors {
+    // Join to receive the visitors of `service`, and the load to report if the dispatcher asks
+    fn join(&self, service: &ServerServiceConfig) -> (mpsc::Receiver<Visitor>, Option<Load>) {
+        match self {
+            SharedVisitors::Dispatcher(d, weight, _) => {
+                let (rx, load) = d.join(*weight);
+                (rx, Some(load))
+            }
+            SharedVisitors::Router(r) => (r.join(&service.hostnames), None),
+        }
+    }
+
+    // The bandwidth of the service shared with the other clients, if any
+    fn bandwidth(&self) -> Option<&ServiceBandwidth> {
+        match self {
+            SharedVisitors::Dispatcher(_, _, bandwidth) => Some(bandwidth),
+            SharedVisitors::Router(_) => None,
+        }
+    }
+}
+
 pub struct ControlChannelHandle<T: Transport> {
     // Shutdown the control channel by dropping it
     _shutdown_tx: broadcast::Sender<bool>,
     data_ch_tx: mpsc::Sender<T::Stream>,
     service: ServerServiceConfig,
+    // Where visitors come from, if shared with other control channels
+    _shared: Option<SharedVisitors>,
+    // Resolves when the control channel is closed
+    closed: Option<oneshot::Receiver<()>>,
+    // The client
+    addr: SocketAddr,
+    connected_at: u64,
+    // When the last heartbeat was sent, in secs since the UNIX epoch. 0 if never
+    last_heartbeat: Arc<AtomicU64>,
+    // Where the listener of its own is bound at
+    bound_addr: watch::Receiver<Option<SocketAddr>>,
 }
 
 impl<T> ControlChannelHandle<T>
@@ -398,11 +1327,22 @@ where
 {
     // Create a control channel handle, where the control channel handling task
     // and the connection pool task are created.
+    // Visitors come from `shared` if given, or a listener of its own.
+    // Multiplexed ones go over the data channels of `coalesced` if given, and whether it's new
     #[instrument(name = "handle", skip_all, fields(service = %service.name))]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         conn: T::Stream,
+        addr: SocketAddr,
+        version: ProtocolVersion,
         service: ServerServiceConfig,
         heartbeat_interval: u64,
+        conn_tracker: Option<Arc<ConnTracker>>,
+        conn_limiter: Option<Arc<ConnectionLimiter>>,
+        shared: Option<SharedVisitors>,
+        coalesced: Option<(SharedMuxPool, bool)>,
+        metrics: Arc<ServiceMetrics>,
+        account: Arc<Account>,
     ) -> ControlChannelHandle<T> {
         // Create a shutdown channel
         let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
@@ -413,9 +1353,37 @@ where
         // Store data channel creation requests
         let (data_ch_req_tx, data_ch_req_rx) = mpsc::unbounded_channel();
 
+        // Older clients don't multiplex
+        let multiplex = service.multiplex_channels.filter(|_| version >= PROTO_V5);
+        if service.multiplex_channels.is_some() && multiplex.is_none() {
+            warn!("The client is too old to multiplex visitors. Fall back to a data channel per visitor");
+        }
+        // Data channels of a coalesced pool are requested ahead by the control channel creating it,
+        // and by the others only when the pool runs short
+        let mux = multiplex.map(|size| match coalesced {
+            Some((pool, created)) => MuxSource {
+                pool,
+                pending: if created { size } else { 0 },
+                for_service: Some(protocol::for_service(&protocol::digest(
+                    service.name.as_bytes(),
+                ))),
+            },
+            None => MuxSource {
+                pool: Arc::new(tokio::sync::Mutex::new(MuxPool::new(size, metrics.clone()))),
+                pending: size,
+                for_service: None,
+            },
+        });
+
         // Cache some data channels for later use
         let pool_size = match service.service_type {
-            ServiceType::Tcp => TCP_POOL_SIZE,
+            ServiceType::Tcp
+            | ServiceType::Echo
+            | ServiceType::Sni
+            | ServiceType::Http
+            | ServiceType::Socks5
+            // Otherwise sized by the connection pool by the accept rate
+            | ServiceType::HttpProxy => mux.as_ref().map_or(0, |m| m.pending),
             ServiceType::Udp => UDP_POOL_SIZE,
         };
 
@@ -427,11 +1395,48 @@ where
 
         let shutdown_rx_clone = shutdown_tx.subscribe();
         let bind_addr = service.bind_addr.clone();
+        let service_clone = service.clone();
+        let (bound_tx, bound_rx) = watch::channel(None);
+        metrics.set_command_hook(CommandHook {
+            on_connect: service.on_connect.clone(),
+            on_disconnect: service.on_disconnect.clone(),
+        });
+        let ch_metrics = metrics.clone();
+        let (visitor_rx, load) = match shared.as_ref().map(|s| s.join(&service)) {
+            Some((rx, load)) => (Some(rx), load),
+            None => (None, None),
+        };
+        let bandwidth = match shared.as_ref().and_then(SharedVisitors::bandwidth) {
+            Some(v) => v.clone(),
+            None => ServiceBandwidth::new(service.max_upload_speed, service.max_download_speed),
+        };
         match service.service_type {
-            ServiceType::Tcp => tokio::spawn(
+            ServiceType::Tcp
+            | ServiceType::Echo
+            | ServiceType::Sni
+            | ServiceType::Http
+            | ServiceType::Socks5
+            | ServiceType::HttpProxy => tokio::spawn(
                 async move {
+                    let visitor_rx = visitor_rx.unwrap_or_else(|| {
+                        listen_for_visitors(
+                            &service_clone,
+                            metrics.clone(),
+                            Some(bound_tx),
+                            shutdown_rx_clone.resubscribe(),
+                        )
+                    });
                     if let Err(e) = run_tcp_connection_pool::<T>(
-                        bind_addr,
+                        service_clone,
+                        visitor_rx,
+                        version,
+                        mux,
+                        load,
+                        conn_tracker,
+                        conn_limiter,
+                        metrics,
+                        account,
+                        bandwidth,
                         data_ch_rx,
                         data_ch_req_tx,
                         shutdown_rx_clone,
@@ -448,6 +1453,11 @@ where
                 async move {
                     if let Err(e) = run_udp_connection_pool::<T>(
                         bind_addr,
+                        service_clone.name,
+                        service_clone.visitor_socket,
+                        metrics,
+                        account,
+                        bound_tx,
                         data_ch_rx,
                         data_ch_req_tx,
                         shutdown_rx_clone,
@@ -463,11 +1473,21 @@ where
         };
 
         // Create the control channel
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let last_heartbeat = Arc::new(AtomicU64::new(0));
         let ch = ControlChannel::<T> {
             conn,
             shutdown_rx,
             data_ch_req_rx,
             heartbeat_interval,
+            last_heartbeat: last_heartbeat.clone(),
+            bound_rx: bound_rx.clone(),
+            // Older clients don't know the command
+            report_bound_addr: binds_any_port(&service.bind_addr) && version >= PROTO_V2,
+            version,
+            _online: ch_metrics.online(addr.to_string()),
+            metrics: ch_metrics,
+            _closed_tx: closed_tx,
         };
 
         // Run the control channel
@@ -484,6 +1504,22 @@ where
             _shutdown_tx: shutdown_tx,
             data_ch_tx,
             service,
+            _shared: shared,
+            closed: Some(closed_rx),
+            addr,
+            connected_at: unix_now(),
+            last_heartbeat,
+            bound_addr: bound_rx,
+        }
+    }
+
+    fn status(&self) -> ClientStatus {
+        let last_heartbeat = self.last_heartbeat.load(Ordering::Relaxed);
+        ClientStatus {
+            addr: self.addr.to_string(),
+            connected_at: self.connected_at,
+            last_heartbeat: (last_heartbeat != 0).then_some(last_heartbeat),
+            bound_addr: self.bound_addr.borrow().map(|v| v.to_string()),
         }
     }
 }
@@ -494,6 +1530,13 @@ struct ControlChannel<T: Transport> {
     shutdown_rx: broadcast::Receiver<bool>,        // Receives the shutdown signal
     data_ch_req_rx: mpsc::UnboundedReceiver<bool>, // Receives visitor connections
     heartbeat_interval: u64,                       // Application-layer heartbeat interval in secs
+    last_heartbeat: Arc<AtomicU64>,                // When the last heartbeat was sent
+    bound_rx: watch::Receiver<Option<SocketAddr>>, // Where the service is bound at
+    report_bound_addr: bool,                       // Tell the client where the service is bound
+    version: ProtocolVersion,                      // Negotiated with the client
+    metrics: Arc<ServiceMetrics>,                  // Counters of the service
+    _online: OnlineGuard,                          // Tells the service is offline when dropped
+    _closed_tx: oneshot::Sender<()>,               // Dropped when the control channel is closed
 }
 
 impl<T: Transport> ControlChannel<T> {
@@ -508,10 +1551,31 @@ impl<T: Transport> ControlChannel<T> {
     async fn run(mut self) -> Result<()> {
         let create_ch_cmd = bincode::serialize(&ControlChannelCmd::CreateDataChannel).unwrap();
         let heartbeat = bincode::serialize(&ControlChannelCmd::HeartBeat).unwrap();
+        let mut buf = [0u8; 1];
 
         // Wait for data channel requests and the shutdown signal
         loop {
             tokio::select! {
+                // The client sends nothing after the handshake, so this only sees it closing,
+                // e.g. when its local service is unhealthy
+                val = self.conn.read(&mut buf) => {
+                    if matches!(val, Ok(0) | Err(_)) {
+                        info!("Control channel closed by the client");
+                        break;
+                    }
+                },
+                val = self.bound_rx.changed(), if self.report_bound_addr => {
+                    // The listener is gone, or bound once
+                    self.report_bound_addr = false;
+                    let addr = *self.bound_rx.borrow();
+                    if let (Ok(()), Some(addr)) = (val, addr) {
+                        self.metrics.set_bound_port(addr.port());
+                        if let Err(e) = write_bound_addr(&mut self.conn, addr).await {
+                            error!("{:#}", e);
+                            break;
+                        }
+                    }
+                },
                 val = self.data_ch_req_rx.recv() => {
                     match val {
                         Some(_) => {
@@ -530,9 +1594,16 @@ impl<T: Transport> ControlChannel<T> {
                                 error!("{:#}", e);
                                 break;
                             }
+                            self.last_heartbeat.store(unix_now(), Ordering::Relaxed);
                 }
                 // Wait for the shutdown signal
                 _ = self.shutdown_rx.recv() => {
+                    if self.version < PROTO_V2 {
+                        break;
+                    }
+                    // Let the client know that it's not a network failure
+                    let cmd = bincode::serialize(&ControlChannelCmd::Shutdown).unwrap();
+                    let _ = time::timeout(Duration::from_secs(1), self.write_and_flush(&cmd)).await;
                     break;
                 }
             }
@@ -544,22 +1615,57 @@ impl<T: Transport> ControlChannel<T> {
     }
 }
 
+// Listen at `bind_addr` of the service, admitting visitors as configured
+fn listen_for_visitors(
+    service: &ServerServiceConfig,
+    metrics: Arc<ServiceMetrics>,
+    bound_tx: Option<watch::Sender<Option<SocketAddr>>>,
+    shutdown_rx: broadcast::Receiver<bool>,
+) -> mpsc::Receiver<Visitor> {
+    tcp_listen_and_send(
+        service.bind_addr.clone(),
+        Some(service.name.clone()),
+        Acl::from_service_cfg(service).map(Arc::new),
+        ConnectionLimiter::from_service_cfg(service).map(Arc::new),
+        ConnectWebhook::from_service_cfg(service).map(Arc::new),
+        service.proxy_protocol,
+        service.accept_error_backoff_ms.unwrap_or_default(),
+        metrics,
+        bound_tx,
+        shutdown_rx,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 fn tcp_listen_and_send(
     addr: String,
-    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    // The name of the socket from systemd to listen at instead, if passed
+    socket_name: Option<String>,
+    acl: Option<Arc<Acl>>,
+    limiter: Option<Arc<ConnectionLimiter>>,
+    webhook: Option<Arc<ConnectWebhook>>,
+    proxy_protocol: bool,
+    accept_error_backoff_ms: u64,
+    metrics: Arc<ServiceMetrics>,
+    bound_tx: Option<watch::Sender<Option<SocketAddr>>>,
     mut shutdown_rx: broadcast::Receiver<bool>,
-) -> mpsc::Receiver<TcpStream> {
+) -> mpsc::Receiver<Visitor> {
     let (tx, rx) = mpsc::channel(CHAN_SIZE);
 
     tokio::spawn(async move {
         let l = retry_notify_with_deadline(listen_backoff(),  || async {
-            Ok(TcpListener::bind(&addr).await?)
+            if let Some(name) = &socket_name {
+                if let Some(l) = socket_activation::listener(name)? {
+                    return Ok(l);
+                }
+            }
+            Ok(SocketListener::bind(&addr).await?)
         }, |e, duration| {
             error!("{:#}. Retry in {:?}", e, duration);
         }, &mut shutdown_rx).await
         .with_context(|| "Failed to listen for the service");
 
-        let l: TcpListener = match l {
+        let l: SocketListener = match l {
             Ok(v) => v,
             Err(e) => {
                 error!("{:#}", e);
@@ -567,14 +1673,17 @@ fn tcp_listen_and_send(
             }
         };
 
-        info!("Listening at {}", &addr);
+        match l.local_addr() {
+            Some(bound) => {
+                info!("Listening at {}", bound);
+                if let Some(tx) = bound_tx {
+                    tx.send_replace(Some(bound));
+                }
+            }
+            None => info!("Listening at {}", &addr),
+        }
 
-        // Retry at least every 1s
-        let mut backoff = ExponentialBackoff {
-            max_interval: Duration::from_secs(1),
-            max_elapsed_time: None,
-            ..Default::default()
-        };
+        let mut accept_error_handler = AcceptErrorHandler::new(accept_error_backoff_ms);
 
         // Wait for visitors and the shutdown signal
         loop {
@@ -582,31 +1691,50 @@ fn tcp_listen_and_send(
                 val = l.accept() => {
                     match val {
                         Err(e) => {
-                            // `l` is a TCP listener so this must be a IO error
                             // Possibly a EMFILE. So sleep for a while
-                            error!("{}. Sleep for a while", e);
-                            if let Some(d) = backoff.next_backoff() {
-                                time::sleep(d).await;
-                            } else {
-                                // This branch will never be reached for current backoff policy
-                                error!("Too many retries. Aborting...");
-                                break;
-                            }
+                            accept_error_handler.handle(&e).await;
                         }
-                        Ok((incoming, addr)) => {
-                            // For every visitor, request to create a data channel
-                            if data_ch_req_tx.send(true).with_context(|| "Failed to send data chan create request").is_err() {
-                                // An error indicates the control channel is broken
-                                // So break the loop
-                                break;
+                        Ok((mut incoming, addr)) => {
+                            let peer = addr.map_or_else(|| "a Unix socket".to_string(), |v| v.to_string());
+                            debug!("New visitor from {}", peer);
+
+                            if proxy_protocol || acl.is_some() || limiter.is_some() || webhook.is_some() {
+                                // Admit the visitor without blocking the listener
+                                let acl = acl.clone();
+                                let limiter = limiter.clone();
+                                let webhook = webhook.clone();
+                                let metrics = metrics.clone();
+                                let tx = tx.clone();
+                                tokio::spawn(async move {
+                                    let admitted = admit_visitor(
+                                        &mut incoming,
+                                        addr,
+                                        proxy_protocol,
+                                        acl.as_deref(),
+                                        limiter.as_deref(),
+                                        webhook.as_deref(),
+                                        &metrics,
+                                    );
+                                    match admitted.await {
+                                        Ok((permit, addr)) => {
+                                            metrics.visitor_connected(addr);
+                                            let _ = tx.send((incoming, permit, Vec::new(), addr)).await;
+                                        }
+                                        Err(e) => {
+                                            info!("Visitor from {} is closed: {:#}", peer, e);
+                                        }
+                                    }
+                                }.instrument(Span::current()));
+                                continue;
                             }
 
-                            backoff.reset();
-
-                            debug!("New visitor from {}", addr);
-
                             // Send the visitor to the connection pool
-                            let _ = tx.send(incoming).await;
+                            metrics.visitor_connected(addr);
+                            if tx.send((incoming, None, Vec::new(), addr)).await.is_err() {
+                                // An error indicates the connection pool is gone
+                                // So break the loop
+                                break;
+                            }
                         }
                     }
                 },
@@ -616,33 +1744,305 @@ fn tcp_listen_and_send(
             }
         }
 
-        info!("TCPListener shutdown");
+        info!("Listener shutdown");
     }.instrument(Span::current()));
 
     rx
 }
 
+// Decide whether to forward a visitor. Returns the slot it takes, if the service is limited,
+// and its address
+async fn admit_visitor(
+    conn: &mut SocketStream,
+    mut addr: Option<SocketAddr>,
+    proxy_protocol: bool,
+    acl: Option<&Acl>,
+    limiter: Option<&ConnectionLimiter>,
+    webhook: Option<&ConnectWebhook>,
+    metrics: &ServiceMetrics,
+) -> Result<(Option<OwnedSemaphorePermit>, Option<SocketAddr>)> {
+    if proxy_protocol {
+        let header = time::timeout(
+            Duration::from_secs(HANDSHAKE_TIMEOUT),
+            proxy_protocol::read_header(conn),
+        )
+        .await
+        .with_context(|| "Timeout reading the PROXY protocol header")??;
+        // Without an address, e.g. a health check of the balancer, keep the peer's
+        if let Some(v) = header {
+            debug!("Visitor is proxied for {}", v);
+            addr = Some(v);
+        }
+    }
+    if let Some(acl) = acl {
+        let addr = addr.ok_or_else(|| anyhow!("No address of the visitor to check the ACL"))?;
+        if !acl.allows(addr.ip()) {
+            return Err(anyhow!("Denied by the ACL"));
+        }
+    }
+    if let Some(webhook) = webhook {
+        let addr =
+            addr.ok_or_else(|| anyhow!("No address of the visitor to ask the connect webhook"))?;
+        if !webhook.allows(addr).await {
+            return Err(anyhow!("Refused by the connect webhook"));
+        }
+    }
+    let permit = match limiter {
+        Some(limiter) => Some(
+            limiter
+                .admit()
+                .await
+                .inspect_err(|_| metrics.connection_rejected())?,
+        ),
+        None => None,
+    };
+    Ok((permit, addr))
+}
+
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 async fn run_tcp_connection_pool<T: Transport>(
-    bind_addr: String,
+    service: ServerServiceConfig,
+    mut visitor_rx: mpsc::Receiver<Visitor>,
+    version: ProtocolVersion,
+    // The data channels to multiplex visitors over, if multiplexed
+    mut mux: Option<MuxSource>,
+    load: Option<Load>,
+    conn_tracker: Option<Arc<ConnTracker>>,
+    conn_limiter: Option<Arc<ConnectionLimiter>>,
+    metrics: Arc<ServiceMetrics>,
+    account: Arc<Account>,
+    // Limits all visitors of the service in total
+    bandwidth: ServiceBandwidth,
     mut data_ch_rx: mpsc::Receiver<T::Stream>,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
-    shutdown_rx: broadcast::Receiver<bool>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
 ) -> Result<()> {
-    let mut visitor_rx = tcp_listen_and_send(bind_addr, data_ch_req_tx.clone(), shutdown_rx);
-    let cmd = bincode::serialize(&DataChannelCmd::StartForwardTcp).unwrap();
+    let start_forward_tcp = bincode::serialize(&DataChannelCmd::StartForwardTcp).unwrap();
+    // From the visitor's point of view. Upload is read from it, and download is written to it
+    let up_bps = service.rate_limit_up_bps.unwrap_or_default();
+    let down_bps = service.rate_limit_down_bps.unwrap_or_default();
+    let close_timeout = service.close_timeout_secs.map(Duration::from_secs);
+    let timeout = ConnTimeout::from_service_cfg(&service);
+    let rewrite_http = service.service_type == ServiceType::Http
+        && (service.host_header_rewrite.is_some() || service.x_forwarded_for);
+    // Nothing has to be done on the data besides forwarding it
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    let can_splice = up_bps == 0
+        && down_bps == 0
+        && bandwidth.is_unlimited()
+        && conn_tracker.is_none()
+        && timeout.is_none()
+        && service.monthly_quota.is_none()
+        && splice::is_supported();
+    let (pool_min, pool_max) = service.pool_bounds();
+    let mut sizer = PoolSizer::new(pool_min, pool_max);
+    let mut shrink = time::interval(POOL_SHRINK_INTERVAL);
+    if mux.is_none() && !request_data_channels(&data_ch_req_tx, sizer.fill()) {
+        return Ok(());
+    }
 
-    'pool: while let Some(mut visitor) = visitor_rx.recv().await {
+    'pool: loop {
+        let (visitor, permit, head, addr) = tokio::select! {
+            val = visitor_rx.recv() => match val {
+                Some(v) => v,
+                None => break,
+            },
+            _ = shrink.tick(), if mux.is_none() => {
+                // Close the idle data channels beyond the need
+                let mut closed = 0;
+                while closed < sizer.excess(Instant::now()) && data_ch_rx.try_recv().is_ok() {
+                    closed += 1;
+                }
+                if closed > 0 {
+                    debug!("Closed {} idle data channels", closed);
+                    sizer.shrink(closed);
+                }
+                continue;
+            },
+            _ = shutdown_rx.recv() => break,
+        };
+
+        if account.exceeded() {
+            info!("Visitor is closed: the monthly quota of the service is used up");
+            continue;
+        }
+
+        let id = ConnId::generate();
+        let span = conn_log::span(id, addr);
+        // Older clients don't know the commands
+        let mut cmd = match addr {
+            _ if version >= PROTO_V4 => protocol::start_forward_tcp_conn(id, addr),
+            Some(addr) if version >= PROTO_V3 => protocol::start_forward_tcp_from(addr),
+            _ => start_forward_tcp.clone(),
+        };
+        // Tell the client which service the stream is of, since the data channel may be of another
+        if let Some(prefix) = mux.as_ref().and_then(|m| m.for_service.as_ref()) {
+            cmd.splice(0..0, prefix.iter().copied());
+        }
+
+        // Only the first request of the visitor is seen, and the backend closes the connection after it
+        let head = if rewrite_http {
+            let forwarded_for = visitor
+                .tcp()
+                .and_then(|v| v.peer_addr().ok())
+                .map(|v| v.ip())
+                .filter(|_| service.x_forwarded_for);
+            match vhost::rewrite_http_head(
+                &head,
+                service.host_header_rewrite.as_deref(),
+                forwarded_for,
+            ) {
+                Ok(v) => v,
+                Err(e) => {
+                    info!("Visitor is closed: {:#}", e);
+                    continue;
+                }
+            }
+        } else {
+            head
+        };
+
+        // Take a slot of `server.max_connections`, which never waits
+        let global_permit = match conn_limiter.as_ref() {
+            Some(l) => match l.admit().await {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    metrics.connection_rejected();
+                    info!("Visitor is closed: the server is full");
+                    continue;
+                }
+            },
+            None => None,
+        };
+        // Hold the slots, and count the visitor in the load, until it's closed
+        let permits = (permit, global_permit, load.as_ref().map(Load::start));
+
+        // For every visitor, request to create data channels as the pool needs, unless multiplexed
+        if mux.is_none() && !request_data_channels(&data_ch_req_tx, sizer.take(Instant::now())) {
+            // An error indicates the control channel is broken
+            break;
+        }
+
+        if let (Some(secs), Some(tcp)) = (service.linger_secs, visitor.tcp()) {
+            if let Err(e) = try_set_linger(tcp, Duration::from_secs(secs)) {
+                error!("Failed to set linger: {:#}", e);
+            }
+        }
+        if let (Some(nodelay), Some(tcp)) = (service.visitor_nodelay, visitor.tcp()) {
+            if let Err(e) = try_set_nodelay(tcp, nodelay) {
+                error!("Failed to set nodelay: {:#}", e);
+            }
+        }
+        if let (Some(keepalive), Some(tcp)) = (&service.visitor_keepalive, visitor.tcp()) {
+            if let Err(e) = try_set_tcp_keepalive(tcp, keepalive) {
+                error!("Failed to set keepalive: {:#}", e);
+            }
+        }
+        if let (Some(socket), Some(tcp)) = (&service.visitor_socket, visitor.tcp()) {
+            if let Err(e) = try_set_socket_opts(tcp.into(), socket) {
+                error!("{:#}", e);
+            }
+        }
         loop {
-            if let Some(mut ch) = data_ch_rx.recv().await {
-                if write_and_flush(&mut ch, &cmd).await.is_ok() {
-                    tokio::spawn(async move {
-                        let _ = copy_bidirectional(&mut ch, &mut visitor).await;
-                    });
+            let ch = match mux.as_mut() {
+                Some(m) => m
+                    .pool
+                    .lock()
+                    .await
+                    .open(&mut data_ch_rx, &data_ch_req_tx, &mut m.pending)
+                    .await
+                    .map(DataChannel::Muxed),
+                None => data_ch_rx.recv().await.map(DataChannel::Direct),
+            };
+            if let Some(mut ch) = ch {
+                if write_and_flush(&mut ch, &cmd).await.is_ok()
+                    && (head.is_empty() || write_and_flush(&mut ch, &head).await.is_ok())
+                {
+                    #[cfg(all(target_os = "linux", feature = "splice"))]
+                    if can_splice
+                        && ch.direct().and_then(T::as_plain_tcp).is_some()
+                        && visitor.tcp().is_some()
+                    {
+                        let metrics = metrics.clone();
+                        let account = account.clone();
+                        let stats = ConnStats::new();
+                        let data_channel = metrics.data_channel();
+                        tokio::spawn(
+                            async move {
+                                let ch = ch.direct().and_then(T::as_plain_tcp).unwrap();
+                                let visitor = visitor.tcp().unwrap();
+                                let _ = splice::splice_bidirectional_with_close_timeout(
+                                    visitor,
+                                    ch,
+                                    close_timeout,
+                                    |n| {
+                                        metrics.add_bytes_in(n);
+                                        account.add(n);
+                                        stats.add_bytes_in(n);
+                                    },
+                                    |n| {
+                                        metrics.add_bytes_out(n);
+                                        account.add(n);
+                                        stats.add_bytes_out(n);
+                                    },
+                                )
+                                .await;
+                                stats.log_closed();
+                                drop(data_channel);
+                                drop(permits);
+                            }
+                            .instrument(span),
+                        );
+                        break;
+                    }
+
+                    let visitor = RateLimitedStream::new(visitor, up_bps, down_bps);
+                    let stats = ConnStats::new();
+                    let visitor = metrics
+                        .count_visitor(bandwidth.limit_visitor(visitor))
+                        .with_conn(&stats);
+                    let visitor = account.wrap(visitor);
+                    let activity = Activity::since(Instant::now());
+                    let mut visitor = activity.wrap(visitor);
+                    let data_channel = metrics.data_channel();
+                    match conn_tracker.as_ref() {
+                        Some(tracker) => {
+                            // Make room for the new connection before it takes more fds
+                            tracker.reap_if_needed();
+                            let mut conn = tracker.track();
+                            tokio::spawn(async move {
+                                let mut visitor = conn.wrap(visitor);
+                                tokio::select! {
+                                    _ = copy_bidirectional_with_close_timeout(&mut ch, &mut visitor, close_timeout) => {},
+                                    _ = &mut conn.reaped => {
+                                        debug!("Idle connection reaped");
+                                    }
+                                    _ = conn_timeout::expired(timeout, activity) => {}
+                                }
+                                stats.log_closed();
+                                drop(data_channel);
+                                drop(permits);
+                            }.instrument(span));
+                        }
+                        None => {
+                            tokio::spawn(async move {
+                                tokio::select! {
+                                    _ = copy_bidirectional_with_close_timeout(&mut ch, &mut visitor, close_timeout) => {},
+                                    _ = conn_timeout::expired(timeout, activity) => {}
+                                }
+                                stats.log_closed();
+                                drop(data_channel);
+                                // Free the slot
+                                drop(permits);
+                            }.instrument(span));
+                        }
+                    }
                     break;
                 } else {
-                    // Current data channel is broken. Request for a new one
-                    if data_ch_req_tx.send(true).is_err() {
+                    // Current data channel is broken. Request for a new one.
+                    // Broken sessions are replaced by the pool
+                    if mux.is_none() && !request_data_channels(&data_ch_req_tx, sizer.lost()) {
                         break 'pool;
                     }
                 }
@@ -656,9 +2056,30 @@ async fn run_tcp_connection_pool<T: Transport>(
     Ok(())
 }
 
+// The multiplexed data channels of a control channel
+struct MuxSource {
+    pool: SharedMuxPool,
+    // Data channels requested from the client, but not received yet
+    pending: usize,
+    // Written before the command of each stream, if the pool is shared with other services
+    for_service: Option<Vec<u8>>,
+}
+
+// Request `n` data channels. False if the control channel is broken
+fn request_data_channels(data_ch_req_tx: &mpsc::UnboundedSender<bool>, n: usize) -> bool {
+    (0..n).all(|_| data_ch_req_tx.send(true).is_ok())
+}
+
 #[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
 async fn run_udp_connection_pool<T: Transport>(
     bind_addr: String,
+    // The socket from systemd named after it is used instead, if passed
+    name: String,
+    socket: Option<SocketConfig>,
+    metrics: Arc<ServiceMetrics>,
+    account: Arc<Account>,
+    bound_tx: watch::Sender<Option<SocketAddr>>,
     mut data_ch_rx: mpsc::Receiver<T::Stream>,
     _data_ch_req_tx: mpsc::UnboundedSender<bool>,
     mut shutdown_rx: broadcast::Receiver<bool>,
@@ -667,7 +2088,12 @@ async fn run_udp_connection_pool<T: Transport>(
 
     let l = retry_notify_with_deadline(
         listen_backoff(),
-        || async { Ok(UdpSocket::bind(&bind_addr).await?) },
+        || async {
+            match socket_activation::udp_socket(&name)? {
+                Some(l) => Ok(l),
+                None => Ok(UdpSocket::bind(&bind_addr).await?),
+            }
+        },
         |e, duration| {
             warn!("{:#}. Retry in {:?}", e, duration);
         },
@@ -675,8 +2101,13 @@ async fn run_udp_connection_pool<T: Transport>(
     )
     .await
     .with_context(|| "Failed to listen for the service")?;
+    if let Some(v) = &socket {
+        try_set_socket_opts((&l).into(), v)?;
+    }
 
-    info!("Listening at {}", &bind_addr);
+    let bound = l.local_addr()?;
+    info!("Listening at {}", bound);
+    bound_tx.send_replace(Some(bound));
 
     let cmd = bincode::serialize(&DataChannelCmd::StartForwardUdp).unwrap();
 
@@ -686,6 +2117,7 @@ async fn run_udp_connection_pool<T: Transport>(
         .await
         .ok_or_else(|| anyhow!("No available data channels"))?;
     write_and_flush(&mut conn, &cmd).await?;
+    let _data_channel = metrics.data_channel();
 
     let mut buf = [0u8; UDP_BUFFER_SIZE];
     loop {
@@ -693,12 +2125,19 @@ async fn run_udp_connection_pool<T: Transport>(
             // Forward inbound traffic to the client
             val = l.recv_from(&mut buf) => {
                 let (n, from) = val?;
+                if account.exceeded() {
+                    continue;
+                }
+                metrics.add_bytes_in(n);
+                account.add(n);
                 UdpTraffic::write_slice(&mut conn, from, &buf[..n]).await?;
             },
 
             // Forward outbound traffic from the client to the visitor
             hdr_len = conn.read_u8() => {
                 let t = UdpTraffic::read(&mut conn, hdr_len?).await?;
+                metrics.add_bytes_out(t.data.len());
+                account.add(t.data.len());
                 l.send_to(&t.data, t.from).await?;
             }
 
@@ -712,3 +2151,223 @@ async fn run_udp_connection_pool<T: Transport>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ObfsStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    // Counts error logs
+    struct ErrorCounter(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for ErrorCounter {
+        fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+            if *event.metadata().level() == Level::ERROR {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Connect to the server and disconnect without a handshake.
+    // Returns once the server is done with the connection
+    async fn scan(policy: ScannerPolicy) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let scanner = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+        let (conn, addr) = l.accept().await.unwrap();
+        drop(scanner);
+
+        let server_config = ServerConfig {
+            scanner_policy: policy,
+            ..Default::default()
+        };
+        handle_connection::<TcpTransport>(
+            ObfsStream::plain(conn),
+            addr,
+            Default::default(),
+            Arc::new(RwLock::new(ControlChannelMap::new())),
+            Arc::new(server_config),
+            Arc::new(Mutex::new(AuthFailureTracker::new(Duration::from_secs(1)))),
+            None,
+            None,
+            None,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scanner_policy() {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry().with(ErrorCounter(errors.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        scan(ScannerPolicy::Log).await;
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        scan(ScannerPolicy::Drop).await;
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        // The connection is held for a while
+        assert!(
+            time::timeout(Duration::from_millis(500), scan(ScannerPolicy::Tarpit))
+                .await
+                .is_err()
+        );
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "noise")]
+    #[tokio::test]
+    async fn test_tarpit_transport_handshake() -> Result<()> {
+        let config = ServerConfig {
+            scanner_policy: ScannerPolicy::Tarpit,
+            transport: toml::from_str("type = \"noise\"\n[noise]\n")?,
+            ..Default::default()
+        };
+        let server = Server::<NoiseTransport>::from(config, Default::default()).await?;
+
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        let mut scanner = TcpStream::connect(l.local_addr()?).await?;
+        let (conn, addr) = l.accept().await?;
+        let handshake = tokio::spawn(server.handshake(conn, addr, None));
+
+        // Too short for a noise handshake message
+        scanner.write_all(b"\x02\x00ab").await?;
+        let mut buf = [0u8; 64];
+        // The connection is held open instead of closed
+        assert!(
+            time::timeout(Duration::from_millis(500), scanner.read(&mut buf))
+                .await
+                .is_err()
+        );
+        assert!(!handshake.is_finished());
+        handshake.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure() -> Result<()> {
+        let mut config = ServerConfig::default();
+        let mut foo = ServerServiceConfig::with_name("foo");
+        foo.bind_addr = "127.0.0.1:0".into();
+        foo.token = Some("token".into());
+        config.services.insert("foo".into(), foo);
+        let (event_tx, mut event_rx) = broadcast::channel(64);
+        let mut hooks = Hooks::default();
+        hooks.push(Arc::new(event_tx));
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let metrics = metrics::start(None, hooks, shutdown_rx).await?;
+        let server = Server::<TcpTransport>::from(config, metrics.clone()).await?;
+
+        // Present an incorrect token repeatedly
+        let l = TcpListener::bind("127.0.0.1:0").await?;
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(l.local_addr()?).await?;
+            let (conn, addr) = l.accept().await?;
+            let handshake = tokio::spawn(server.clone().handshake(conn, addr, None));
+
+            let hello = Hello::ControlChannelHello(PROTO_V5, protocol::digest(b"foo"));
+            client.write_all(&bincode::serialize(&hello)?).await?;
+            read_hello(&mut client).await?;
+            let auth = protocol::Auth(protocol::digest(b"incorrect"));
+            client.write_all(&bincode::serialize(&auth)?).await?;
+            let ack = protocol::read_ack(&mut client).await?;
+            assert!(matches!(ack, Ack::AuthFailed), "{:?}", ack);
+            handshake.await?;
+        }
+
+        let body = metrics.render();
+        assert!(
+            body.lines()
+                .any(|l| l == "rathole_auth_failures_total{service=\"foo\"} 3"),
+            "{}",
+            body
+        );
+
+        // But the event is emitted only once in a while
+        let mut auth_failed = vec![];
+        while let Ok(e) = event_rx.try_recv() {
+            if let crate::event::Event::AuthFailed { .. } = e {
+                auth_failed.push(e);
+            }
+        }
+        match &auth_failed[..] {
+            [crate::event::Event::AuthFailed {
+                service, reason, ..
+            }] => {
+                assert_eq!(service, "foo");
+                assert_eq!(*reason, AuthFailureReason::IncorrectToken);
+            }
+            v => panic!("Unexpected events {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reloaded_services() {
+        let mut config = ServerConfig::default();
+        config
+            .services
+            .insert("foo".into(), ServerServiceConfig::with_name("foo"));
+        let mut old = generate_service_hashmap(&config);
+        let mut bar = ServerServiceConfig::with_name("bar");
+        bar.registered = true;
+        old.insert(protocol::digest(b"bar"), bar);
+
+        // Registered services are kept as long as registration is allowed
+        let services = reloaded_services(&old, &config);
+        assert_eq!(services.len(), 1);
+        config.registration = Some(crate::config::RegistrationConfig {
+            token: "t".into(),
+            bind_addr: "0.0.0.0:20000-20100".into(),
+        });
+        let services = reloaded_services(&old, &config);
+        assert!(services[&protocol::digest(b"bar")].registered);
+        assert!(!services[&protocol::digest(b"foo")].registered);
+    }
+
+    #[test]
+    fn test_mux_pools() {
+        let mux_pools = Mutex::new(MuxPoolMap::new());
+        let metrics = Arc::new(ServiceMetrics::default());
+        let (alice, bob) = (protocol::digest(b"alice"), protocol::digest(b"bob"));
+        let (foo, bar) = (protocol::digest(b"foo"), protocol::digest(b"bar"));
+        let get = |identity, service| {
+            get_or_create_mux_pool(&mux_pools, identity, service, 2, metrics.clone())
+        };
+
+        // Control channels authenticated with the same token share the data channels
+        let (a1, created) = get(alice, foo);
+        assert!(created);
+        let (a2, created) = get(alice, bar);
+        assert!(!created);
+        assert!(Arc::ptr_eq(&a1, &a2));
+
+        // While another token gets its own
+        let (b, created) = get(bob, foo);
+        assert!(created);
+        assert!(!Arc::ptr_eq(&a1, &b));
+
+        // A service reconnecting is of a new instance of the client, which the other services join
+        let (a3, created) = get(alice, foo);
+        assert!(created);
+        assert!(!Arc::ptr_eq(&a1, &a3));
+        let (a4, created) = get(alice, bar);
+        assert!(!created);
+        assert!(Arc::ptr_eq(&a3, &a4));
+
+        // Until none holds them
+        drop((a3, a4));
+        assert!(get(alice, foo).1);
+    }
+}
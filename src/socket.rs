@@ -0,0 +1,289 @@
+use anyhow::{Context as _, Result};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::NamedPipeClient;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// The prefix of addresses of Unix domain sockets, e.g. `unix:///run/php-fpm.sock`
+const UNIX_ADDR_PREFIX: &str = "unix://";
+
+/// The path of the Unix domain socket at `addr`, if it is one
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_ADDR_PREFIX)
+}
+
+/// The path of the Windows named pipe at `addr`, if it is one, e.g. `\\.\pipe\myapp`
+pub fn named_pipe_path(addr: &str) -> Option<&str> {
+    // `\\<server>\pipe\<name>`, where the server is `.` for the local one
+    let (server, rest) = addr.strip_prefix(r"\\")?.split_once('\\')?;
+    let is_pipe = !server.is_empty()
+        && rest
+            .get(..5)
+            .is_some_and(|v| v.eq_ignore_ascii_case(r"pipe\"))
+        && rest.len() > 5;
+    is_pipe.then_some(addr)
+}
+
+/// Whether `addr` is a TCP address, rather than a Unix domain socket or a named pipe
+pub fn is_tcp_addr(addr: &str) -> bool {
+    unix_socket_path(addr).is_none() && named_pipe_path(addr).is_none()
+}
+
+#[cfg(windows)]
+async fn connect_named_pipe(path: &str) -> io::Result<NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    use tokio::time::{self, Duration, Instant};
+
+    // All instances of the pipe are serving others
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const PIPE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    let start = Instant::now();
+    loop {
+        match ClientOptions::new().open(path) {
+            Err(e)
+                if e.raw_os_error() == Some(ERROR_PIPE_BUSY)
+                    && start.elapsed() < PIPE_BUSY_TIMEOUT => {}
+            ret => return ret,
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(not(windows))]
+fn named_pipe_not_supported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Named pipes are only supported on Windows",
+    )
+}
+
+#[cfg(not(unix))]
+fn unix_not_supported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/// A stream of TCP, a Unix domain socket or a named pipe, where services are forwarded from or to
+#[derive(Debug)]
+pub enum SocketStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(windows)]
+    Pipe(NamedPipeClient),
+}
+
+impl SocketStream {
+    /// Connect to `addr`, which is a TCP address, a `unix://` path or a named pipe
+    pub async fn connect(addr: &str) -> Result<SocketStream> {
+        let conn = match (unix_socket_path(addr), named_pipe_path(addr)) {
+            #[cfg(unix)]
+            (Some(path), _) => UnixStream::connect(path).await.map(SocketStream::Unix),
+            #[cfg(not(unix))]
+            (Some(_), _) => Err(unix_not_supported()),
+            #[cfg(windows)]
+            (_, Some(path)) => connect_named_pipe(path).await.map(SocketStream::Pipe),
+            #[cfg(not(windows))]
+            (_, Some(_)) => Err(named_pipe_not_supported()),
+            (None, None) => TcpStream::connect(addr).await.map(SocketStream::Tcp),
+        };
+        conn.with_context(|| format!("Failed to connect to {}", addr))
+    }
+
+    /// Connect to the TCP address `addr` from `from`, which is usually an address of another host,
+    /// like a visitor. This needs CAP_NET_ADMIN, and replies to `from` must be routed to this host,
+    /// e.g. by the TPROXY target of iptables
+    #[cfg(target_os = "linux")]
+    pub async fn connect_transparent(addr: &str, from: SocketAddr) -> Result<SocketStream> {
+        use std::os::fd::AsRawFd;
+        use tokio::net::TcpSocket;
+
+        let conn = async {
+            let dest = tokio::net::lookup_host(addr)
+                .await?
+                .find(|v| v.is_ipv4() == from.is_ipv4())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "No address of the family")
+                })?;
+            let (socket, level, name) = match from {
+                SocketAddr::V4(_) => (TcpSocket::new_v4()?, libc::SOL_IP, libc::IP_TRANSPARENT),
+                SocketAddr::V6(_) => (TcpSocket::new_v6()?, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+            };
+            let on: libc::c_int = 1;
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    &on as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&on) as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Only the IP is kept, so that the port of a closed connection can't get in the way
+            socket.bind(SocketAddr::new(from.ip(), 0))?;
+            socket.connect(dest).await
+        };
+        conn.await
+            .map(SocketStream::Tcp)
+            .with_context(|| format!("Failed to connect to {} from {}", addr, from.ip()))
+    }
+
+    /// The TCP stream, for setting TCP options
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            SocketStream::Tcp(v) => Some(v),
+            #[cfg(unix)]
+            SocketStream::Unix(_) => None,
+            #[cfg(windows)]
+            SocketStream::Pipe(_) => None,
+        }
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $s:ident => $e:expr) => {
+        match $self.get_mut() {
+            SocketStream::Tcp($s) => $e,
+            #[cfg(unix)]
+            SocketStream::Unix($s) => $e,
+            #[cfg(windows)]
+            SocketStream::Pipe($s) => $e,
+        }
+    };
+}
+
+impl AsyncRead for SocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, s => Pin::new(s).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for SocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, s => Pin::new(s).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, s => Pin::new(s).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, s => Pin::new(s).poll_shutdown(cx))
+    }
+}
+
+/// A listener of TCP or a Unix domain socket
+#[derive(Debug)]
+pub enum SocketListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl SocketListener {
+    /// Listen at `addr`, which is a TCP address or a `unix://` path.
+    /// A file left at the path, e.g. by a previous run, is removed first
+    pub async fn bind(addr: &str) -> io::Result<SocketListener> {
+        match unix_socket_path(addr) {
+            #[cfg(unix)]
+            Some(path) => {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+                Ok(SocketListener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_not_supported()),
+            None => Ok(SocketListener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// The address that it's bound at. None for Unix domain sockets
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            SocketListener::Tcp(l) => l.local_addr().ok(),
+            #[cfg(unix)]
+            SocketListener::Unix(_) => None,
+        }
+    }
+
+    /// Accept a connection. The peer address is unknown for Unix domain sockets
+    pub async fn accept(&self) -> io::Result<(SocketStream, Option<SocketAddr>)> {
+        match self {
+            SocketListener::Tcp(l) => {
+                let (conn, addr) = l.accept().await?;
+                Ok((SocketStream::Tcp(conn), Some(addr)))
+            }
+            #[cfg(unix)]
+            SocketListener::Unix(l) => {
+                let (conn, _) = l.accept().await?;
+                Ok((SocketStream::Unix(conn), None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_pipe_path() {
+        assert!(named_pipe_path(r"\\.\pipe\myapp").is_some());
+        assert!(named_pipe_path(r"\\host\PIPE\myapp").is_some());
+        assert!(named_pipe_path(r"\\.\pipe\").is_none());
+        assert!(named_pipe_path(r"\\.\share\myapp").is_none());
+        assert!(named_pipe_path("127.0.0.1:80").is_none());
+        assert!(!is_tcp_addr(r"\\.\pipe\myapp"));
+        assert!(!is_tcp_addr("unix:///run/app.sock"));
+        assert!(is_tcp_addr("localhost:80"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("rathole-test-{}.sock", std::process::id()));
+        let addr = format!("unix://{}", path.display());
+
+        // A stale file doesn't stop listening
+        std::fs::write(&path, b"")?;
+        let l = SocketListener::bind(&addr).await?;
+
+        let mut a = SocketStream::connect(&addr).await?;
+        let (mut b, peer) = l.accept().await?;
+        assert!(peer.is_none());
+        assert!(a.tcp().is_none());
+
+        a.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
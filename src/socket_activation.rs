@@ -0,0 +1,145 @@
+use crate::socket::SocketListener;
+use std::io;
+use tokio::net::UdpSocket;
+
+#[cfg(unix)]
+use lazy_static::lazy_static;
+#[cfg(unix)]
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::os::fd::{OwnedFd, RawFd};
+#[cfg(unix)]
+use tracing::debug;
+
+// The first fd passed by socket activation. See sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The name of the socket for the control channel. Sockets of services are named after the services
+pub const CONTROL_CHANNEL_SOCKET: &str = "rathole";
+
+#[cfg(unix)]
+lazy_static! {
+    // Sockets passed by systemd, by their `FileDescriptorName`
+    static ref SOCKETS: HashMap<String, OwnedFd> = listen_fds();
+}
+
+// Take the sockets passed to this process
+#[cfg(unix)]
+fn listen_fds() -> HashMap<String, OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    let fds = parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+    fds.into_iter()
+        .map(|(name, fd)| {
+            // Not to leak into commands run by hooks
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            debug!("Got socket {} from systemd", name);
+            // Safety: systemd hands the fds over to this process
+            (name, unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+// The names and the fds from the environment variables of socket activation.
+// Unnamed sockets are named `unknown`, as systemd does
+#[cfg(unix)]
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Vec<(String, RawFd)> {
+    if pid.and_then(|v| v.parse().ok()) != Some(own_pid) {
+        return Vec::new();
+    }
+    let n: RawFd = match fds.and_then(|v| v.parse().ok()) {
+        Some(n) => n,
+        None => return Vec::new(),
+    };
+    let mut names = names.unwrap_or_default().split(':');
+    (0..n)
+        .map(|i| {
+            let name = names.next().filter(|v| !v.is_empty()).unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + i)
+        })
+        .collect()
+}
+
+// A duplicate of the socket named `name`, so that it's still there after restarts of the instance
+#[cfg(unix)]
+fn socket(name: &str) -> io::Result<Option<socket2::Socket>> {
+    let Some(fd) = SOCKETS.get(name) else {
+        return Ok(None);
+    };
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    // Safety: the fd is just duplicated and owned by nobody else
+    let s = unsafe { socket2::Socket::from_raw_fd(fd.try_clone()?.into_raw_fd()) };
+    s.set_nonblocking(true)?;
+    Ok(Some(s))
+}
+
+/// The listening socket named `name` passed by systemd, if any. It's either TCP or Unix domain
+pub fn listener(name: &str) -> io::Result<Option<SocketListener>> {
+    #[cfg(unix)]
+    if let Some(s) = socket(name)? {
+        if s.r#type()? != socket2::Type::STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The socket {} from systemd is not a stream socket", name),
+            ));
+        }
+        let l = if s.local_addr()?.as_socket().is_some() {
+            SocketListener::Tcp(tokio::net::TcpListener::from_std(s.into())?)
+        } else {
+            SocketListener::Unix(tokio::net::UnixListener::from_std(s.into())?)
+        };
+        return Ok(Some(l));
+    }
+    let _ = name;
+    Ok(None)
+}
+
+/// The UDP socket named `name` passed by systemd, if any
+pub fn udp_socket(name: &str) -> io::Result<Option<UdpSocket>> {
+    #[cfg(unix)]
+    if let Some(s) = socket(name)? {
+        if s.r#type()? != socket2::Type::DGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The socket {} from systemd is not a datagram socket", name),
+            ));
+        }
+        return Ok(Some(UdpSocket::from_std(s.into())?));
+    }
+    let _ = name;
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("3"), Some("rathole:ssh"), 42),
+            vec![
+                ("rathole".to_string(), 3),
+                ("ssh".to_string(), 4),
+                ("unknown".to_string(), 5)
+            ]
+        );
+        // For another process
+        assert!(parse_listen_fds(Some("43"), Some("1"), None, 42).is_empty());
+        assert!(parse_listen_fds(None, Some("1"), None, 42).is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::ProxyAuth;
+use anyhow::{bail, Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+const VERSION: u8 = 5;
+const CONNECT_TIMEOUT: u64 = 10; // Timeout for connecting to the destination in secs
+
+// Methods of authentication. See RFC 1928 and RFC 1929
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const AUTH_VERSION: u8 = 1;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// Replies
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Serve a SOCKS5 visitor on `conn`, and return the connection to the destination it asks for.
+/// Only `CONNECT` is supported. The visitor must present `auth` if given
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    auth: Option<&ProxyAuth>,
+) -> Result<TcpStream> {
+    // Pick the method
+    if conn.read_u8().await? != VERSION {
+        bail!("Not a SOCKS5 request");
+    }
+    let n = conn.read_u8().await?;
+    let mut methods = vec![0u8; n as usize];
+    conn.read_exact(&mut methods).await?;
+    let method = if auth.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    if !methods.contains(&method) {
+        conn.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("No acceptable authentication methods");
+    }
+    conn.write_all(&[VERSION, method]).await?;
+
+    if let Some(auth) = auth {
+        if conn.read_u8().await? != AUTH_VERSION {
+            bail!("Unsupported version of username/password authentication");
+        }
+        let username = read_string(conn).await?;
+        let password = read_string(conn).await?;
+        if username != auth.username || password != *auth.password {
+            conn.write_all(&[AUTH_VERSION, 1]).await?;
+            bail!("Incorrect username or password");
+        }
+        conn.write_all(&[AUTH_VERSION, 0]).await?;
+    }
+
+    // Read the request
+    let mut head = [0u8; 4];
+    conn.read_exact(&mut head).await?;
+    let [ver, cmd, _, atyp] = head;
+    if ver != VERSION {
+        bail!("Not a SOCKS5 request");
+    }
+    let host = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            conn.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => read_string(conn).await?,
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            conn.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            reply(conn, ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            bail!("Unsupported address type {}", atyp);
+        }
+    };
+    let port = conn.read_u16().await?;
+    if cmd != CMD_CONNECT {
+        reply(conn, COMMAND_NOT_SUPPORTED, None).await?;
+        bail!("Unsupported command {}", cmd);
+    }
+
+    debug!("Connecting to {}:{}", host, port);
+    let dest = time::timeout(
+        Duration::from_secs(CONNECT_TIMEOUT),
+        TcpStream::connect((host.as_str(), port)),
+    )
+    .await
+    .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))
+    .and_then(|r| r);
+    match dest {
+        Ok(dest) => {
+            reply(conn, SUCCEEDED, dest.local_addr().ok()).await?;
+            Ok(dest)
+        }
+        Err(e) => {
+            let rep = match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+                std::io::ErrorKind::TimedOut => HOST_UNREACHABLE,
+                _ => GENERAL_FAILURE,
+            };
+            reply(conn, rep, None).await?;
+            Err(e).with_context(|| format!("Failed to connect to {}:{}", host, port))
+        }
+    }
+}
+
+// A string prefixed by its length in a byte
+async fn read_string<S: AsyncRead + Unpin>(conn: &mut S) -> Result<String> {
+    let len = conn.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    conn.read_exact(&mut buf).await?;
+    String::from_utf8(buf).with_context(|| "Invalid string")
+}
+
+async fn reply<S: AsyncWrite + Unpin>(
+    conn: &mut S,
+    rep: u8,
+    bound: Option<SocketAddr>,
+) -> Result<()> {
+    let mut buf = vec![VERSION, rep, 0];
+    match bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))) {
+        SocketAddr::V4(a) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(a) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&bound.map_or(0, |v| v.port()).to_be_bytes());
+    conn.write_all(&buf).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::net::TcpListener;
+
+    // Send a CONNECT request for `localhost:port` and return the reply code
+    async fn request(visitor: &mut DuplexStream, port: u16) -> Result<u8> {
+        let mut req = vec![VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 9];
+        req.extend_from_slice(b"localhost");
+        req.extend_from_slice(&port.to_be_bytes());
+        visitor.write_all(&req).await?;
+        let mut rep = [0u8; 10];
+        visitor.read_exact(&mut rep).await?;
+        Ok(rep[1])
+    }
+
+    #[tokio::test]
+    async fn test_connect() -> Result<()> {
+        let dest = TcpListener::bind("127.0.0.1:0").await?;
+        let port = dest.local_addr()?.port();
+        let (mut visitor, mut conn) = duplex(1024);
+        let server = tokio::spawn(async move { accept(&mut conn, None).await });
+
+        visitor.write_all(&[VERSION, 1, NO_AUTH]).await?;
+        let mut buf = [0u8; 2];
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(buf, [VERSION, NO_AUTH]);
+        assert_eq!(request(&mut visitor, port).await?, SUCCEEDED);
+
+        let (mut accepted, _) = dest.accept().await?;
+        let mut out = server.await??;
+        out.write_all(b"hi").await?;
+        let mut buf = [0u8; 2];
+        accepted.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hi");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> Result<()> {
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "pass".into(),
+        };
+
+        // Visitors without credentials are refused
+        let (mut visitor, mut conn) = duplex(1024);
+        let a = auth.clone();
+        let server = tokio::spawn(async move { accept(&mut conn, Some(&a)).await });
+        visitor.write_all(&[VERSION, 1, NO_AUTH]).await?;
+        let mut buf = [0u8; 2];
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(buf, [VERSION, NO_ACCEPTABLE_METHODS]);
+        assert!(server.await?.is_err());
+
+        // Nor are ones with the wrong password
+        let (mut visitor, mut conn) = duplex(1024);
+        let a = auth.clone();
+        let server = tokio::spawn(async move { accept(&mut conn, Some(&a)).await });
+        visitor
+            .write_all(&[VERSION, 1, USERNAME_PASSWORD, AUTH_VERSION, 4])
+            .await?;
+        visitor.write_all(b"user\x05wrong").await?;
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(buf, [VERSION, USERNAME_PASSWORD]);
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(buf, [AUTH_VERSION, 1]);
+        assert!(server.await?.is_err());
+
+        // A refused destination is reported
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let (mut visitor, mut conn) = duplex(1024);
+        let server = tokio::spawn(async move { accept(&mut conn, Some(&auth)).await });
+        visitor
+            .write_all(&[VERSION, 1, USERNAME_PASSWORD, AUTH_VERSION, 4])
+            .await?;
+        visitor.write_all(b"user\x04pass").await?;
+        visitor.read_exact(&mut buf).await?;
+        visitor.read_exact(&mut buf).await?;
+        assert_eq!(buf, [AUTH_VERSION, 0]);
+        assert_eq!(request(&mut visitor, port).await?, CONNECTION_REFUSED);
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+}
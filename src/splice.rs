@@ -0,0 +1,213 @@
+use crate::helper::close_within;
+use lazy_static::lazy_static;
+use socket2::SockRef;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+// The default capacity of a pipe
+const PIPE_SIZE: usize = 1 << 16;
+
+lazy_static! {
+    // splice(2) can be unavailable even on Linux, like in some sandboxes
+    static ref SUPPORTED: bool = match probe() {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("splice is not supported: {}. Fall back to copying", e);
+            false
+        }
+    };
+}
+
+/// Whether data can be forwarded with splice(2) on this system
+pub fn is_supported() -> bool {
+    *SUPPORTED
+}
+
+struct Pipe {
+    rd: OwnedFd,
+    wr: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: both fds are just created and owned by nobody else
+        unsafe {
+            Ok(Pipe {
+                rd: OwnedFd::from_raw_fd(fds[0]),
+                wr: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+// Move a byte between two pipes to see if splice works
+fn probe() -> io::Result<()> {
+    let a = Pipe::new()?;
+    let b = Pipe::new()?;
+    if unsafe { libc::write(a.wr.as_raw_fd(), b"x".as_ptr() as *const _, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    splice(a.rd.as_raw_fd(), b.wr.as_raw_fd(), 1).map(|_| ())
+}
+
+// Move data from `from` to `to` through a pipe until `from` is closed, then close the write half of `to`
+async fn splice_one_way<F: Fn(usize)>(
+    from: &TcpStream,
+    to: &TcpStream,
+    on_move: F,
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+    loop {
+        // The pipe is always drained before, so blocking means `from` is not readable
+        let n = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.wr.as_raw_fd(), PIPE_SIZE)
+            })
+            .await?;
+        if n == 0 {
+            break;
+        }
+
+        let mut left = n;
+        while left > 0 {
+            let n = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.rd.as_raw_fd(), to.as_raw_fd(), left)
+                })
+                .await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            left -= n;
+            on_move(n);
+        }
+    }
+    SockRef::from(to).shutdown(Shutdown::Write)
+}
+
+/// Like `copy_bidirectional_with_close_timeout`, but the data never goes through the userspace.
+/// `a_to_b` and `b_to_a` are called with the number of bytes moved in each direction.
+pub async fn splice_bidirectional_with_close_timeout<F1, F2>(
+    a: &TcpStream,
+    b: &TcpStream,
+    close_timeout: Option<Duration>,
+    a_to_b: F1,
+    b_to_a: F2,
+) -> io::Result<()>
+where
+    F1: Fn(usize),
+    F2: Fn(usize),
+{
+    let a_to_b = splice_one_way(a, b, a_to_b);
+    let b_to_a = splice_one_way(b, a, b_to_a);
+
+    let close_timeout = match close_timeout {
+        Some(v) => v,
+        None => return tokio::try_join!(a_to_b, b_to_a).map(|_| ()),
+    };
+
+    tokio::pin!(a_to_b, b_to_a);
+    tokio::select! {
+        r = &mut a_to_b => {
+            r?;
+            close_within(close_timeout, b_to_a).await
+        }
+        r = &mut b_to_a => {
+            r?;
+            close_within(close_timeout, a_to_b).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Returns both ends of a TCP connection
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        let (a, b) = tokio::join!(TcpStream::connect(addr), l.accept());
+        (a.unwrap(), b.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splice() {
+        if !is_supported() {
+            return;
+        }
+
+        let (mut client, a) = tcp_pair().await;
+        let (b, mut server) = tcp_pair().await;
+        let up = AtomicUsize::new(0);
+        let down = AtomicUsize::new(0);
+
+        let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let forward = splice_bidirectional_with_close_timeout(
+            &a,
+            &b,
+            None,
+            |n| {
+                up.fetch_add(n, Ordering::Relaxed);
+            },
+            |n| {
+                down.fetch_add(n, Ordering::Relaxed);
+            },
+        );
+        let peers = async {
+            let client = async {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut buf = Vec::new();
+                client.read_to_end(&mut buf).await.unwrap();
+                buf
+            };
+            let server = async {
+                let mut buf = Vec::new();
+                server.read_to_end(&mut buf).await.unwrap();
+                server.write_all(b"bye").await.unwrap();
+                server.shutdown().await.unwrap();
+                buf
+            };
+            tokio::join!(client, server)
+        };
+        let (r, (received_by_client, received_by_server)) = tokio::join!(forward, peers);
+        r.unwrap();
+
+        assert_eq!(received_by_server, data);
+        assert_eq!(received_by_client, b"bye");
+        assert_eq!(up.load(Ordering::Relaxed), data.len());
+        assert_eq!(down.load(Ordering::Relaxed), 3);
+    }
+}
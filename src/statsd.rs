@@ -0,0 +1,235 @@
+// Push metrics to StatsD, in the DogStatsD flavor of the text protocol
+use crate::config::StatsdConfig;
+use crate::helper::udp_connect;
+use crate::metrics::{Family, Metrics};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{info, warn};
+
+// Datagrams are kept within a common MTU
+const MAX_DATAGRAM_LEN: usize = 1432;
+
+/// Push `metrics` to `config.addr` every `config.interval_secs`, until shutdown
+pub async fn start(
+    metrics: Arc<Metrics>,
+    config: &StatsdConfig,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+) -> Result<()> {
+    let socket = udp_connect(&config.addr, false)
+        .await
+        .with_context(|| format!("Failed to connect to `metrics.statsd.addr` {}", config.addr))?;
+    info!("Pushing metrics to StatsD at {}", config.addr);
+
+    let mut encoder = Encoder::new(&config.prefix);
+    let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    for datagram in encoder.encode(&metrics.families()) {
+                        if let Err(e) = socket.send(datagram.as_bytes()).await {
+                            warn!("Failed to push metrics to StatsD: {:#}", e);
+                            break;
+                        }
+                    }
+                }
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+// Turns metrics into StatsD lines. Counters are sent as the increase since the last push,
+// since StatsD adds them up
+struct Encoder {
+    prefix: String,
+    // The last value of each counter, by its line without the value
+    counters: HashMap<String, i64>,
+}
+
+impl Encoder {
+    fn new(prefix: &str) -> Encoder {
+        Encoder {
+            prefix: prefix.to_string(),
+            counters: HashMap::new(),
+        }
+    }
+
+    // Datagrams of lines of `families`
+    fn encode(&mut self, families: &[Family]) -> Vec<String> {
+        let mut counters = HashMap::new();
+        let mut datagrams = Vec::new();
+        let mut datagram = String::new();
+        for f in families {
+            let name = f.name.strip_prefix("rathole_").unwrap_or(f.name);
+            for (labels, value) in f.samples.iter() {
+                let tags: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", k, escape_tag(v)))
+                    .collect();
+                let suffix = if tags.is_empty() {
+                    String::new()
+                } else {
+                    format!("|#{}", tags.join(","))
+                };
+
+                let line = if f.kind == "counter" {
+                    let key = format!("{}{}", name, suffix);
+                    // A counter that goes down is reset, e.g. by a restart of the instance
+                    let delta = match self.counters.get(&key) {
+                        Some(last) if last <= value => value - last,
+                        _ => *value,
+                    };
+                    counters.insert(key, *value);
+                    if delta == 0 {
+                        continue;
+                    }
+                    format!("{}:{}|c{}", self.metric_name(name), delta, suffix)
+                } else {
+                    // A signed gauge changes the value by that much in StatsD
+                    format!("{}:{}|g{}", self.metric_name(name), (*value).max(0), suffix)
+                };
+
+                if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_LEN {
+                    datagrams.push(std::mem::take(&mut datagram));
+                }
+                if !datagram.is_empty() {
+                    datagram.push('\n');
+                }
+                datagram.push_str(&line);
+            }
+        }
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+        // Counters of removed services are forgotten
+        self.counters = counters;
+        datagrams
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        }
+    }
+}
+
+// Replace what separates tags or fields in a tag value
+fn escape_tag(s: &str) -> String {
+    s.replace([',', '|', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Hooks;
+    use crate::metrics;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_encode() {
+        let families = |n: i64| {
+            vec![
+                Family {
+                    name: "rathole_service_bytes_in_total",
+                    kind: "counter",
+                    help: "",
+                    samples: vec![(vec![("service", "a,b".to_string())], n)],
+                },
+                Family {
+                    name: "rathole_service_data_channels",
+                    kind: "gauge",
+                    help: "",
+                    samples: vec![(vec![("service", "a,b".to_string())], 2)],
+                },
+            ]
+        };
+        let mut encoder = Encoder::new("rathole");
+        assert_eq!(
+            encoder.encode(&families(10)),
+            ["rathole.service_bytes_in_total:10|c|#service:a_b\n\
+              rathole.service_data_channels:2|g|#service:a_b"]
+        );
+        // Only the increase of counters
+        assert_eq!(
+            encoder.encode(&families(15)),
+            ["rathole.service_bytes_in_total:5|c|#service:a_b\n\
+              rathole.service_data_channels:2|g|#service:a_b"]
+        );
+        assert_eq!(
+            encoder.encode(&families(15)),
+            ["rathole.service_data_channels:2|g|#service:a_b"]
+        );
+        // Reset
+        assert_eq!(
+            encoder.encode(&families(3)),
+            ["rathole.service_bytes_in_total:3|c|#service:a_b\n\
+              rathole.service_data_channels:2|g|#service:a_b"]
+        );
+
+        // Lines are split across datagrams
+        let many = vec![Family {
+            name: "rathole_service_data_channels",
+            kind: "gauge",
+            help: "",
+            samples: (0..100)
+                .map(|i| (vec![("service", format!("service{}", i))], i))
+                .collect(),
+        }];
+        let datagrams = Encoder::new("").encode(&many);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_DATAGRAM_LEN));
+        assert_eq!(
+            datagrams.iter().map(|d| d.lines().count()).sum::<usize>(),
+            100
+        );
+        assert!(datagrams[0].starts_with("service_data_channels:0|g|#service:service0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_statsd() -> Result<()> {
+        // A mock StatsD server
+        let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+        let config = StatsdConfig {
+            addr: receiver.local_addr()?.to_string(),
+            prefix: "test".into(),
+            interval_secs: 1,
+        };
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let metrics = metrics::start(None, Hooks::default(), shutdown_rx.resubscribe()).await?;
+        let foo = metrics.service("foo");
+        foo.add_bytes_in(4);
+        let _guard = foo.data_channel();
+        start(metrics.clone(), &config, shutdown_rx).await?;
+
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        let n = time::timeout(Duration::from_secs(1), receiver.recv(&mut buf)).await??;
+        let body = String::from_utf8(buf[..n].to_vec())?;
+        for line in [
+            "test.service_bytes_in_total:4|c|#service:foo",
+            "test.service_data_channels:1|g|#service:foo",
+            "test.service_bound_port:0|g|#service:foo",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in\n{}", line, body);
+        }
+
+        // The next push tells what has changed since
+        foo.add_bytes_in(2);
+        let n = time::timeout(Duration::from_secs(2), receiver.recv(&mut buf)).await??;
+        let body = String::from_utf8(buf[..n].to_vec())?;
+        assert!(body
+            .lines()
+            .any(|l| l == "test.service_bytes_in_total:2|c|#service:foo"));
+        assert!(!body.contains("connects_total"), "{}", body);
+
+        shutdown_tx.send(true)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use crate::event::{Event, Hook};
+use std::collections::HashSet;
+use std::env;
+use std::sync::Mutex;
+use tokio::time::{self, Duration, Interval, MissedTickBehavior};
+use tracing::debug;
+
+/// Send `state` to systemd, if run as a `Type=notify` unit. See sd_notify(3)
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    match send(&path, state) {
+        Ok(_) => debug!("Notified systemd of {}", state),
+        Err(e) => debug!("Failed to notify systemd of {}: {}", state, e),
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let sock = UnixDatagram::unbound()?;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        // An abstract socket
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            sock.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Tell systemd that the instance is up
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Ticks for answering systemd's watchdog, if `WatchdogSec` is set for the unit.
+/// The interval is half of the timeout, as sd_watchdog_enabled(3) suggests.
+pub fn watchdog() -> Option<Interval> {
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    if usec == 0 || env::var_os("NOTIFY_SOCKET").is_none() {
+        return None;
+    }
+    let mut interval = time::interval(Duration::from_micros(usec / 2));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(interval)
+}
+
+/// Wait for the next tick of `watchdog`, or forever if there's no watchdog
+pub async fn tick(watchdog: &mut Option<Interval>) {
+    match watchdog {
+        Some(w) => {
+            w.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Calls `ready` once the control channels of all the services have been online
+#[derive(Debug)]
+pub struct Readiness {
+    pending: Mutex<Option<HashSet<String>>>,
+}
+
+impl Readiness {
+    pub fn new<I: IntoIterator<Item = String>>(services: I) -> Readiness {
+        let pending: HashSet<_> = services.into_iter().collect();
+        if pending.is_empty() {
+            ready();
+            return Readiness {
+                pending: Mutex::new(None),
+            };
+        }
+        Readiness {
+            pending: Mutex::new(Some(pending)),
+        }
+    }
+
+    // Returns true if the last pending service gets online
+    fn online(&self, service: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(p) = pending.as_mut() else {
+            return false;
+        };
+        p.remove(service);
+        if p.is_empty() {
+            *pending = None;
+            return true;
+        }
+        false
+    }
+}
+
+impl Hook for Readiness {
+    fn on_event(&self, event: &Event) {
+        if let Event::Online { service, .. } = event {
+            if self.online(service) {
+                ready();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let r = Readiness::new(["a".to_string(), "b".to_string()]);
+        assert!(!r.online("a"));
+        assert!(!r.online("a"));
+        assert!(r.online("b"));
+        // Only once
+        assert!(!r.online("b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = env::temp_dir().join(format!("rathole-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&dir);
+        let sock = UnixDatagram::bind(&dir).unwrap();
+        send(dir.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let _ = std::fs::remove_file(&dir);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::config::ServerConfig;
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::broadcast;
+use tokio::time::{self, Duration};
+use tracing::{error, info};
+
+// Save the counters to `server.state_file` this often, in secs
+const SAVE_INTERVAL: u64 = 60;
+
+// Bytes forwarded in both directions
+#[derive(Debug, Default)]
+struct Counter {
+    // Since the month began
+    month: AtomicU64,
+    // Since first seen
+    total: AtomicU64,
+}
+
+impl Counter {
+    fn add(&self, n: u64) {
+        self.month.fetch_add(n, Ordering::Relaxed);
+        self.total.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            month: self.month.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+struct Usage {
+    month: u64,
+    total: u64,
+}
+
+// What's kept in `server.state_file`
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct State {
+    // The month of the monthly counters, like "2024-01"
+    month: String,
+    #[serde(default)]
+    services: BTreeMap<String, Usage>,
+    // By the IPs of clients
+    #[serde(default)]
+    clients: BTreeMap<String, Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    month: String,
+    services: BTreeMap<String, Arc<Counter>>,
+    clients: BTreeMap<IpAddr, Arc<Counter>>,
+}
+
+/// The traffic of services and clients, monthly and in total. Kept across restarts in
+/// `server.state_file`, if set. Months are in UTC
+#[derive(Debug, Default)]
+pub struct Traffic {
+    path: Option<String>,
+    counters: Mutex<Counters>,
+}
+
+impl Traffic {
+    /// Create the counters, loading them from `server.state_file` if it exists
+    pub fn from_server_cfg(cfg: &ServerConfig) -> Result<Traffic> {
+        let traffic = Traffic {
+            path: cfg.state_file.clone(),
+            counters: Mutex::new(Counters {
+                month: current_month(),
+                ..Default::default()
+            }),
+        };
+        if let Some(path) = cfg.state_file.as_deref().filter(|v| Path::new(v).exists()) {
+            let s = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the state file {}", path))?;
+            let state: State = toml::from_str(&s)
+                .with_context(|| format!("Failed to parse the state file {}", path))?;
+            traffic.restore(state);
+        }
+        Ok(traffic)
+    }
+
+    fn restore(&self, state: State) {
+        let mut counters = self.counters.lock().unwrap();
+        let same_month = state.month == counters.month;
+        let counter = |v: Usage| {
+            Arc::new(Counter {
+                month: AtomicU64::new(if same_month { v.month } else { 0 }),
+                total: AtomicU64::new(v.total),
+            })
+        };
+        for (name, v) in state.services {
+            counters.services.insert(name, counter(v));
+        }
+        for (ip, v) in state.clients {
+            if let Ok(ip) = ip.parse() {
+                counters.clients.insert(ip, counter(v));
+            }
+        }
+    }
+
+    /// Count the traffic of a control channel of `service` from `client`
+    pub fn account(&self, service: &str, quota: Option<u64>, client: IpAddr) -> Arc<Account> {
+        let mut counters = self.counters.lock().unwrap();
+        Arc::new(Account {
+            service: counters
+                .services
+                .entry(service.to_string())
+                .or_default()
+                .clone(),
+            client: counters.clients.entry(client).or_default().clone(),
+            quota,
+        })
+    }
+
+    // Reset the monthly counters if `month` is a new one
+    fn roll_over(&self, month: String) {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.month == month {
+            return;
+        }
+        info!("Reset the monthly traffic counters for {}", month);
+        for c in counters.services.values().chain(counters.clients.values()) {
+            c.month.store(0, Ordering::Relaxed);
+        }
+        counters.month = month;
+    }
+
+    fn state(&self) -> State {
+        let counters = self.counters.lock().unwrap();
+        State {
+            month: counters.month.clone(),
+            services: counters
+                .services
+                .iter()
+                .map(|(k, v)| (k.clone(), v.usage()))
+                .collect(),
+            clients: counters
+                .clients
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.usage()))
+                .collect(),
+        }
+    }
+
+    /// Write the counters to `server.state_file`, if set
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let s = toml::to_string(&self.state())?;
+        // Replace the file at once, so that it's never seen half written
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, s)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Failed to write the state file {}", path))
+    }
+
+    /// Start a new month when it comes, and save the counters periodically
+    pub async fn run(self: Arc<Self>, mut shutdown_rx: broadcast::Receiver<bool>) {
+        loop {
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(SAVE_INTERVAL)) => {
+                    self.roll_over(current_month());
+                    if let Err(e) = self.save() {
+                        error!("{:#}", e);
+                    }
+                }
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+    }
+}
+
+/// Counts the traffic of a control channel, to its service and its client
+#[derive(Debug)]
+pub struct Account {
+    service: Arc<Counter>,
+    client: Arc<Counter>,
+    // `monthly_quota` of the service
+    quota: Option<u64>,
+}
+
+impl Account {
+    pub fn add(&self, n: usize) {
+        self.service.add(n as u64);
+        self.client.add(n as u64);
+    }
+
+    /// Whether the service has used up its `monthly_quota`
+    pub fn exceeded(&self) -> bool {
+        self.quota
+            .is_some_and(|q| self.service.month.load(Ordering::Relaxed) >= q)
+    }
+
+    /// Count the traffic through `s`, which fails reading once the quota is used up
+    pub fn wrap<S>(self: &Arc<Self>, s: S) -> AccountedStream<S> {
+        AccountedStream {
+            inner: s,
+            account: self.clone(),
+        }
+    }
+}
+
+pub struct AccountedStream<S> {
+    inner: S,
+    account: Arc<Account>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AccountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.account.exceeded() {
+            return Poll::Ready(Err(io::Error::other(
+                "The monthly quota of the service is used up",
+            )));
+        }
+        let filled = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.account.add(buf.filled().len() - filled);
+        ret
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AccountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret {
+            self.account.add(n);
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// The current month in UTC, like "2024-01"
+fn current_month() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    month_of(secs)
+}
+
+// The month of `secs` since the UNIX epoch in UTC, by the civil-from-days algorithm of Howard Hinnant
+fn month_of(secs: u64) -> String {
+    let z = secs / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    format!("{:04}-{:02}", y, m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_month_of() {
+        assert_eq!(month_of(0), "1970-01");
+        assert_eq!(month_of(951782400), "2000-02");
+        assert_eq!(month_of(1709251199), "2024-02");
+        assert_eq!(month_of(1709251200), "2024-03");
+        assert_eq!(month_of(1735689599), "2024-12");
+    }
+
+    #[test]
+    fn test_persist() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("rathole-state-{}.toml", std::process::id()));
+        let cfg = ServerConfig {
+            state_file: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let ip = "10.0.0.1".parse()?;
+
+        let traffic = Traffic::from_server_cfg(&cfg)?;
+        traffic.account("foo", None, ip).add(100);
+        traffic.account("bar", None, ip).add(10);
+        traffic.save()?;
+
+        let traffic = Traffic::from_server_cfg(&cfg)?;
+        let state = traffic.state();
+        assert_eq!(
+            state.services["foo"],
+            Usage {
+                month: 100,
+                total: 100
+            }
+        );
+        assert_eq!(
+            state.clients["10.0.0.1"],
+            Usage {
+                month: 110,
+                total: 110
+            }
+        );
+
+        // A new month keeps the totals
+        traffic.roll_over("2000-01".into());
+        let state = traffic.state();
+        assert_eq!(
+            state.services["foo"],
+            Usage {
+                month: 0,
+                total: 100
+            }
+        );
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quota() -> Result<()> {
+        let traffic = Traffic::default();
+        let account = traffic.account("foo", Some(6), "10.0.0.1".parse()?);
+        let (visitor, mut remote) = tokio::io::duplex(64);
+        let mut visitor = account.wrap(visitor);
+
+        remote.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        visitor.read_exact(&mut buf).await?;
+        assert!(!account.exceeded());
+        visitor.write_all(b"ok").await?;
+        assert!(account.exceeded());
+        remote.write_all(b"ping").await?;
+        assert!(visitor.read(&mut buf).await.is_err());
+
+        // So are other control channels of the service
+        assert!(traffic
+            .account("foo", Some(6), "10.0.0.2".parse()?)
+            .exceeded());
+        Ok(())
+    }
+}
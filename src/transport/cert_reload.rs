@@ -0,0 +1,98 @@
+use crate::config::TlsConfig;
+use anyhow::Result;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+use tracing::{error, info};
+
+// Loads the acceptor of a TLS server from the files of the config
+type Loader<A> = fn(&TlsConfig) -> Result<A>;
+
+/// The acceptor of a TLS server, which is swapped for a new one in the background
+/// when the certificate files change. Established connections are not affected by the reloading
+pub struct ReloadableAcceptor<A> {
+    current: Arc<RwLock<A>>,
+}
+
+impl<A> Debug for ReloadableAcceptor<A> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReloadableAcceptor")
+    }
+}
+
+impl<A: Clone + Send + Sync + 'static> ReloadableAcceptor<A> {
+    /// Load the acceptor, and watch the files every `tls.cert_reload_interval` until it's dropped
+    pub fn new(config: &TlsConfig, load: Loader<A>) -> Result<ReloadableAcceptor<A>> {
+        // Taken before loading, so that a change in the middle is loaded again
+        let modified = server_cert_modified(config);
+        let current = Arc::new(RwLock::new(load(config)?));
+        if let (Some(interval), Ok(rt)) = (
+            config.cert_reload_interval(),
+            tokio::runtime::Handle::try_current(),
+        ) {
+            rt.spawn(watch(
+                Arc::downgrade(&current),
+                config.clone(),
+                interval,
+                modified,
+                load,
+            ));
+        }
+        Ok(ReloadableAcceptor { current })
+    }
+
+    pub fn get(&self) -> A {
+        self.current.read().unwrap().clone()
+    }
+}
+
+// Reload `acceptor` by `load` when the files of `config` change, until the acceptor is dropped
+async fn watch<A: Send + Sync + 'static>(
+    acceptor: Weak<RwLock<A>>,
+    config: TlsConfig,
+    interval: Duration,
+    mut modified: Vec<Option<SystemTime>>,
+    load: Loader<A>,
+) {
+    loop {
+        time::sleep(interval).await;
+        if acceptor.strong_count() == 0 {
+            break;
+        }
+
+        // Reading the files blocks
+        let (cfg, last) = (config.clone(), modified.clone());
+        let checked = tokio::task::spawn_blocking(move || {
+            let modified = server_cert_modified(&cfg);
+            // Not tried again until the next change, even if it fails
+            let loaded = (modified != last).then(|| load(&cfg));
+            (modified, loaded)
+        })
+        .await;
+        let Ok((m, loaded)) = checked else {
+            break;
+        };
+        modified = m;
+
+        let Some(acceptor) = acceptor.upgrade() else {
+            break;
+        };
+        match loaded {
+            Some(Ok(v)) => {
+                *acceptor.write().unwrap() = v;
+                info!("Reloaded the TLS certificate");
+            }
+            Some(Err(e)) => error!("{:#}. Keep using the old TLS certificate", e),
+            None => (),
+        }
+    }
+}
+
+// The modification times of the certificate files of a TLS server, to tell if they have changed
+fn server_cert_modified(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    config
+        .server_cert_files()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
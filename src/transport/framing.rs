@@ -0,0 +1,190 @@
+use std::io::{self, Error};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::{AddrMaybeCached, ObfsStream, TcpTransport, TlsTransport, Transport};
+use bytes::Bytes;
+use futures_core::stream::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_util::io::StreamReader;
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use super::tls::get_tcpstream;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use super::tls::TlsStream;
+
+/// A connection to the peer, which is wrapped in TLS or not
+#[derive(Debug)]
+pub enum TransportStream {
+    Insecure(ObfsStream),
+    Secure(TlsStream<ObfsStream>),
+}
+
+impl TransportStream {
+    pub fn get_tcpstream(&self) -> &TcpStream {
+        match self {
+            TransportStream::Insecure(s) => s,
+            TransportStream::Secure(s) => get_tcpstream(s),
+        }
+    }
+}
+
+impl AsyncRead for TransportStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TransportStream::Insecure(s) => Pin::new(s).poll_read(cx, buf),
+            TransportStream::Secure(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TransportStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match self.get_mut() {
+            TransportStream::Insecure(s) => Pin::new(s).poll_write(cx, buf),
+            TransportStream::Secure(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            TransportStream::Insecure(s) => Pin::new(s).poll_flush(cx),
+            TransportStream::Secure(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match self.get_mut() {
+            TransportStream::Insecure(s) => Pin::new(s).poll_shutdown(cx),
+            TransportStream::Secure(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The transport beneath the framing, which is TLS or plain TCP
+#[derive(Debug)]
+pub enum SubTransport {
+    Secure(Box<TlsTransport>),
+    Insecure(Box<TcpTransport>),
+}
+
+impl SubTransport {
+    pub async fn bind_reuse_port<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> anyhow::Result<TcpListener> {
+        match self {
+            SubTransport::Secure(t) => t.bind_reuse_port(addr).await,
+            SubTransport::Insecure(t) => t.bind_reuse_port(addr).await,
+        }
+    }
+
+    pub async fn accept(&self, a: &TcpListener) -> anyhow::Result<(TcpStream, SocketAddr)> {
+        match self {
+            SubTransport::Insecure(t) => t.accept(a).await,
+            SubTransport::Secure(t) => t.accept(a).await,
+        }
+    }
+
+    pub async fn handshake(&self, conn: TcpStream) -> anyhow::Result<TransportStream> {
+        Ok(match self {
+            SubTransport::Insecure(t) => TransportStream::Insecure(t.handshake(conn).await?),
+            SubTransport::Secure(t) => TransportStream::Secure(t.handshake(conn).await?),
+        })
+    }
+
+    pub async fn connect(&self, addr: &AddrMaybeCached) -> anyhow::Result<TransportStream> {
+        Ok(match self {
+            SubTransport::Insecure(t) => TransportStream::Insecure(t.connect(addr).await?),
+            SubTransport::Secure(t) => TransportStream::Secure(t.connect(addr).await?),
+        })
+    }
+}
+
+/// Frames of data exchanged with the peer, like websocket messages or HTTP chunks
+pub trait Frames:
+    Stream<Item = io::Result<Bytes>> + Sink<Bytes, Error = io::Error> + Unpin
+{
+    fn get_tcpstream(&self) -> &TcpStream;
+}
+
+/// A byte stream over `Frames`, where each write is sent as a frame
+#[derive(Debug)]
+pub struct FramedTunnel<F> {
+    inner: StreamReader<F, Bytes>,
+}
+
+impl<F: Frames> FramedTunnel<F> {
+    pub fn new(frames: F) -> Self {
+        FramedTunnel {
+            inner: StreamReader::new(frames),
+        }
+    }
+
+    pub fn get_tcpstream(&self) -> &TcpStream {
+        self.inner.get_ref().get_tcpstream()
+    }
+}
+
+impl<F: Frames> AsyncRead for FramedTunnel<F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<F: Frames> AsyncBufRead for FramedTunnel<F> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt)
+    }
+}
+
+impl<F: Frames> AsyncWrite for FramedTunnel<F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let frames = self.get_mut().inner.get_mut();
+        ready!(Pin::new(&mut *frames).poll_ready(cx))?;
+        Pin::new(&mut *frames).start_send(Bytes::copy_from_slice(buf))?;
+        // Send it right away, since callers may wait for a reply without flushing.
+        // If it can't be sent yet, it's sent by the next write or flush
+        if let Poll::Ready(Err(e)) = Pin::new(frames).poll_flush(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_close(cx)
+    }
+}
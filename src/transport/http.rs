@@ -0,0 +1,414 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::framing::{FramedTunnel, Frames, SubTransport, TransportStream};
+use super::{AddrMaybeCached, SocketOpts, TcpTransport, TlsTransport, Transport};
+use crate::config::TransportConfig;
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_core::stream::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+
+const MAX_HTTP_HEAD_LEN: usize = 16384;
+const MAX_HTTP_HEADERS: usize = 64;
+// The longest line of a chunk size, with extensions
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+
+// Ask proxies not to buffer or cache the streams
+const RESPONSE_HEAD: &[u8] = b"HTTP/1.1 200 OK\r\n\
+    Content-Type: application/octet-stream\r\n\
+    Transfer-Encoding: chunked\r\n\
+    Cache-Control: no-cache, no-store\r\n\
+    X-Accel-Buffering: no\r\n\r\n";
+
+/// Tunnels over a long-lived HTTP/1.1 POST request, with both the request body and the response
+/// body in chunked encoding. Each write is a chunk, and a zero-sized chunk ends the stream.
+/// For networks where only plain HTTP passes through, and websocket upgrades don't
+#[derive(Debug)]
+pub struct HttpTransport {
+    sub: SubTransport,
+    path: String,
+    host: Option<String>,
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = FramedTunnel<HttpFrames>;
+
+    fn new(config: &TransportConfig) -> Result<Self> {
+        let http_config = config
+            .http
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing http config"))?;
+        let sub = match http_config.tls {
+            true => SubTransport::Secure(Box::new(TlsTransport::new(config)?)),
+            false => SubTransport::Insecure(Box::new(TcpTransport::new(config)?)),
+        };
+        Ok(HttpTransport {
+            sub,
+            path: http_config.path.clone(),
+            host: http_config.host.clone(),
+        })
+    }
+
+    fn hint(conn: &Self::Stream, opt: SocketOpts) {
+        opt.apply(conn.get_tcpstream())
+    }
+
+    async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
+        TcpListener::bind(addr).await.map_err(Into::into)
+    }
+
+    async fn bind_reuse_port<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> Result<Self::Acceptor> {
+        self.sub.bind_reuse_port(addr).await
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.sub.accept(a).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let mut conn = self.sub.handshake(conn).await?;
+        let (head, rest) = read_head(&mut conn).await?;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        if !matches!(req.parse(&head), Ok(httparse::Status::Complete(_)))
+            || req.method != Some("POST")
+            || !is_chunked(req.headers)
+        {
+            reply(&mut conn, "400 Bad Request").await?;
+            bail!("Not a request of the http transport");
+        }
+        let path = req.path.unwrap_or_default();
+        if path.split('?').next() != Some(self.path.as_str()) {
+            reply(&mut conn, "404 Not Found").await?;
+            bail!("Unexpected path {}", path);
+        }
+
+        conn.write_all(RESPONSE_HEAD).await?;
+        conn.flush().await?;
+        Ok(FramedTunnel::new(HttpFrames::new(conn, rest)))
+    }
+
+    async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
+        let mut conn = self.sub.connect(addr).await?;
+        let host = self.host.as_deref().unwrap_or(addr.addr.as_str());
+        let req = format!(
+            "POST {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Transfer-Encoding: chunked\r\n\
+             Cache-Control: no-cache, no-store\r\n\r\n",
+            self.path, host
+        );
+        conn.write_all(req.as_bytes()).await?;
+        conn.flush().await?;
+
+        let (head, rest) = read_head(&mut conn).await?;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+        let mut resp = httparse::Response::new(&mut headers);
+        if !matches!(resp.parse(&head), Ok(httparse::Status::Complete(_))) {
+            bail!("Invalid HTTP response");
+        }
+        if resp.code != Some(200) || !is_chunked(resp.headers) {
+            bail!(
+                "Unexpected HTTP response {} {}",
+                resp.code.unwrap_or_default(),
+                resp.reason.unwrap_or_default()
+            );
+        }
+        Ok(FramedTunnel::new(HttpFrames::new(conn, rest)))
+    }
+}
+
+// Read the head of an HTTP message, and return it along with what's read beyond it
+async fn read_head(conn: &mut TransportStream) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(i + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() >= MAX_HTTP_HEAD_LEN {
+            bail!("The HTTP head is too large");
+        }
+        let mut b = [0u8; 4096];
+        let n = conn.read(&mut b).await?;
+        if n == 0 {
+            bail!("Closed before the HTTP head is complete");
+        }
+        buf.extend_from_slice(&b[..n]);
+    }
+}
+
+fn is_chunked(headers: &[httparse::Header]) -> bool {
+    headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("transfer-encoding")
+            && String::from_utf8_lossy(h.value)
+                .to_ascii_lowercase()
+                .contains("chunked")
+    })
+}
+
+async fn reply(conn: &mut TransportStream, status: &str) -> Result<()> {
+    conn.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+        .await?;
+    conn.flush().await.with_context(|| "Failed to reply")
+}
+
+/// Chunks of the body in both directions
+#[derive(Debug)]
+pub struct HttpFrames {
+    inner: Framed<TransportStream, ChunkedCodec>,
+    // Whether the zero-sized chunk is received
+    ended: bool,
+    // Whether the zero-sized chunk is sent
+    closed: bool,
+}
+
+impl HttpFrames {
+    // `read` is what's already read after the head
+    fn new(conn: TransportStream, read: Vec<u8>) -> HttpFrames {
+        let mut parts = FramedParts::new::<Bytes>(conn, ChunkedCodec::default());
+        parts.read_buf = BytesMut::from(&read[..]);
+        HttpFrames {
+            inner: Framed::from_parts(parts),
+            ended: false,
+            closed: false,
+        }
+    }
+}
+
+impl Stream for HttpFrames {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            Some(Ok(b)) if b.is_empty() => {
+                this.ended = true;
+                Poll::Ready(None)
+            }
+            v => Poll::Ready(v),
+        }
+    }
+}
+
+impl Sink<Bytes> for HttpFrames {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if !this.closed {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx))?;
+            Pin::new(&mut this.inner).start_send(Bytes::new())?;
+            this.closed = true;
+        }
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl Frames for HttpFrames {
+    fn get_tcpstream(&self) -> &TcpStream {
+        self.inner.get_ref().get_tcpstream()
+    }
+}
+
+#[derive(Debug, Default)]
+enum DecodeState {
+    // Expecting the size line of a chunk
+    #[default]
+    Size,
+    // Bytes remaining in the chunk
+    Data(usize),
+    // Expecting CRLF after the data of a chunk
+    DataEnd,
+    // The zero-sized chunk is received. Trailers are ignored
+    Done,
+}
+
+/// The chunked transfer coding. The data of a chunk is decoded as it arrives, without waiting
+/// for the whole chunk. The zero-sized chunk is decoded as empty bytes, and empty bytes are
+/// encoded as the zero-sized chunk
+#[derive(Debug, Default)]
+pub struct ChunkedCodec {
+    state: DecodeState,
+}
+
+impl Decoder for ChunkedCodec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        loop {
+            match self.state {
+                DecodeState::Size => {
+                    let i = match src.windows(2).position(|w| w == b"\r\n") {
+                        Some(v) => v,
+                        None if src.len() > MAX_CHUNK_LINE_LEN => {
+                            return Err(invalid_chunk("The chunk size line is too long"))
+                        }
+                        None => return Ok(None),
+                    };
+                    let line = src.split_to(i + 2);
+                    let size = std::str::from_utf8(&line[..i])
+                        .ok()
+                        .and_then(|v| v.split(';').next())
+                        .and_then(|v| usize::from_str_radix(v.trim(), 16).ok())
+                        .ok_or_else(|| invalid_chunk("Invalid chunk size"))?;
+                    if size == 0 {
+                        self.state = DecodeState::Done;
+                        return Ok(Some(Bytes::new()));
+                    }
+                    self.state = DecodeState::Data(size);
+                }
+                DecodeState::Data(n) => {
+                    if src.is_empty() {
+                        return Ok(None);
+                    }
+                    let k = n.min(src.len());
+                    self.state = if k == n {
+                        DecodeState::DataEnd
+                    } else {
+                        DecodeState::Data(n - k)
+                    };
+                    return Ok(Some(src.split_to(k).freeze()));
+                }
+                DecodeState::DataEnd => {
+                    if src.len() < 2 {
+                        return Ok(None);
+                    }
+                    if &src[..2] != b"\r\n" {
+                        return Err(invalid_chunk("Missing CRLF after the chunk data"));
+                    }
+                    src.advance(2);
+                    self.state = DecodeState::Size;
+                }
+                DecodeState::Done => {
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for ChunkedCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        dst.reserve(item.len() + 20);
+        dst.put_slice(format!("{:x}\r\n", item.len()).as_bytes());
+        dst.put_slice(&item);
+        dst.put_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+fn invalid_chunk(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HttpConfig, TransportType};
+
+    #[tokio::test]
+    async fn test_tunnel() -> Result<()> {
+        let config = TransportConfig {
+            transport_type: TransportType::Http,
+            http: Some(HttpConfig {
+                tls: false,
+                path: "/tunnel".into(),
+                host: None,
+            }),
+            ..Default::default()
+        };
+        let server = HttpTransport::new(&config)?;
+        let l = server.bind("127.0.0.1:0").await?;
+        let addr = AddrMaybeCached::new(&l.local_addr()?.to_string());
+        // An echo server, which closes after the peer does
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept(&l).await {
+                let mut conn = server.handshake(conn).await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    conn.read_to_end(&mut buf).await.unwrap();
+                    conn.write_all(&buf).await.unwrap();
+                    conn.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let client = HttpTransport::new(&config)?;
+        let mut conn = client.connect(&addr).await?;
+        conn.write_all(b"ping").await?;
+        conn.write_all(b"pong").await?;
+        conn.shutdown().await?;
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"pingpong");
+
+        // Other paths are rejected
+        let mut config = config;
+        config.http.as_mut().unwrap().path = "/other".into();
+        let client = HttpTransport::new(&config)?;
+        assert!(client.connect(&addr).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked_codec() {
+        let mut codec = ChunkedCodec::default();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Bytes::from_static(b"hello"), &mut buf)
+            .unwrap();
+        codec.encode(Bytes::new(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"5\r\nhello\r\n0\r\n\r\n");
+
+        // Data is decoded as it arrives
+        let mut src = BytesMut::from(&b"a;ext=1\r\n0123"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "0123");
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(b"456789\r\n0\r\n\r\n");
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "456789");
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "");
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+
+        let mut codec = ChunkedCodec::default();
+        assert!(codec.decode(&mut BytesMut::from(&b"xyz\r\n"[..])).is_err());
+        let mut codec = ChunkedCodec::default();
+        let mut src = BytesMut::from(&b"1\r\nabc"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), "a");
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
@@ -1,12 +1,19 @@
-use crate::config::{ClientServiceConfig, ServerServiceConfig, TcpConfig, TransportConfig};
-use crate::helper::{to_socket_addr, try_set_tcp_keepalive};
-use anyhow::{Context, Result};
+use crate::config::{
+    ClientServiceConfig, KeepaliveConfig, ServerServiceConfig, SocketConfig, TcpConfig,
+    TransportConfig,
+};
+use crate::helper::{try_set_socket_opts, try_set_tcp_keepalive};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use std::borrow::Borrow;
 use std::fmt::{Debug, Display};
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::net::{lookup_host, TcpListener, TcpSocket, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use tokio::time;
 use tracing::{error, trace};
 
 pub const DEFAULT_NODELAY: bool = true;
@@ -14,45 +21,66 @@ pub const DEFAULT_NODELAY: bool = true;
 pub const DEFAULT_KEEPALIVE_SECS: u64 = 20;
 pub const DEFAULT_KEEPALIVE_INTERVAL: u64 = 8;
 
+// The Connection Attempt Delay recommended by RFC 8305
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+
 #[derive(Clone)]
 pub struct AddrMaybeCached {
     pub addr: String,
-    pub socket_addr: Option<SocketAddr>,
+    // All addresses `addr` resolves to, which are tried like Happy Eyeballs
+    pub socket_addrs: Vec<SocketAddr>,
 }
 
 impl AddrMaybeCached {
     pub fn new(addr: &str) -> AddrMaybeCached {
         AddrMaybeCached {
             addr: addr.to_string(),
-            socket_addr: None,
+            socket_addrs: Vec::new(),
         }
     }
 
     pub async fn resolve(&mut self) -> Result<()> {
-        match to_socket_addr(&self.addr).await {
-            Ok(s) => {
-                self.socket_addr = Some(s);
-                Ok(())
-            }
-            Err(e) => Err(e),
+        let addrs: Vec<SocketAddr> = lookup_host(&self.addr).await?.collect();
+        if addrs.is_empty() {
+            bail!("Failed to lookup the host");
         }
+        self.socket_addrs = addrs;
+        Ok(())
     }
 }
 
 impl Display for AddrMaybeCached {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.socket_addr {
+        match self.socket_addrs.first() {
             Some(s) => f.write_fmt(format_args!("{}", s)),
             None => f.write_str(&self.addr),
         }
     }
 }
 
+/// Marks a handshake error that retrying soon won't fix, like the certificate
+/// of the server failing the validation. Attached to errors as the context
+#[derive(Debug)]
+pub struct PermanentHandshakeError;
+
+impl Display for PermanentHandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Permanent handshake failure")
+    }
+}
+
+/// Whether `e` comes from a `PermanentHandshakeError`
+pub fn is_permanent_handshake_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<PermanentHandshakeError>().is_some()
+}
+
 /// Specify a transport layer, like TCP, TLS
 #[async_trait]
 pub trait Transport: Debug + Send + Sync {
-    type Acceptor: Send + Sync;
-    type RawStream: Send + Sync;
+    // Sockets from systemd are taken as acceptors
+    type Acceptor: Send + Sync + From<TcpListener>;
+    // The socket is borrowed to hold on to connections that fail the handshake
+    type RawStream: Send + Sync + Borrow<TcpStream>;
     type Stream: 'static + AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug;
 
     fn new(config: &TransportConfig) -> Result<Self>
@@ -61,13 +89,25 @@ pub trait Transport: Debug + Send + Sync {
     /// Provide the transport with socket options, which can be handled at the need of the transport
     fn hint(conn: &Self::Stream, opts: SocketOpts);
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor>;
+    /// Same as `bind`, but with SO_REUSEPORT, so that several acceptors can listen at `addr`
+    async fn bind_reuse_port<T: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: T,
+    ) -> Result<Self::Acceptor>;
     /// accept must be cancel safe
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)>;
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream>;
     async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream>;
+    /// The underlying TCP stream, if the data is not wrapped in any way
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    fn as_plain_tcp(_conn: &Self::Stream) -> Option<&TcpStream> {
+        None
+    }
 }
 
+mod obfs;
 mod tcp;
+pub use obfs::ObfsStream;
 pub use tcp::TcpTransport;
 
 #[cfg(all(feature = "native-tls", feature = "rustls"))]
@@ -85,30 +125,35 @@ use rustls as tls;
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
 pub(crate) use tls::TlsTransport;
 
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+mod cert_reload;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use cert_reload::ReloadableAcceptor;
+
 #[cfg(feature = "noise")]
 mod noise;
 #[cfg(feature = "noise")]
 pub use noise::NoiseTransport;
 
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+mod framing;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+mod http;
+#[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+pub use http::HttpTransport;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 mod websocket;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 pub use websocket::WebsocketTransport;
 
-#[derive(Debug, Clone, Copy)]
-struct Keepalive {
-    // tcp_keepalive_time if the underlying protocol is TCP
-    pub keepalive_secs: u64,
-    // tcp_keepalive_intvl if the underlying protocol is TCP
-    pub keepalive_interval: u64,
-}
-
 #[derive(Debug, Clone, Copy)]
 pub struct SocketOpts {
     // None means do not change
     nodelay: Option<bool>,
-    // keepalive must be Some or None at the same time, or the behavior will be platform-dependent
-    keepalive: Option<Keepalive>,
+    // Applied if the underlying protocol is TCP
+    keepalive: Option<KeepaliveConfig>,
+    // Buffers and DSCP, which connections usually take from the listener or before connecting
+    socket: Option<SocketConfig>,
 }
 
 impl SocketOpts {
@@ -116,14 +161,15 @@ impl SocketOpts {
         SocketOpts {
             nodelay: None,
             keepalive: None,
+            socket: None,
         }
     }
 
     /// Socket options for the control channel
-    pub fn for_control_channel() -> SocketOpts {
+    pub fn for_control_channel(cfg: &TcpConfig) -> SocketOpts {
         SocketOpts {
-            nodelay: Some(true),  // Always set nodelay for the control channel
-            ..SocketOpts::none()  // None means do not change. Keepalive is set by TcpTransport
+            nodelay: Some(cfg.control_nodelay),
+            ..SocketOpts::none() // None means do not change. Keepalive is set by TcpTransport
         }
     }
 }
@@ -132,16 +178,20 @@ impl SocketOpts {
     pub fn from_cfg(cfg: &TcpConfig) -> SocketOpts {
         SocketOpts {
             nodelay: Some(cfg.nodelay),
-            keepalive: Some(Keepalive {
-                keepalive_secs: cfg.keepalive_secs,
-                keepalive_interval: cfg.keepalive_interval,
+            // Both the time and the interval, or the behavior will be platform-dependent
+            keepalive: Some(KeepaliveConfig {
+                secs: Some(cfg.keepalive_secs),
+                interval: Some(cfg.keepalive_interval),
+                ..Default::default()
             }),
+            socket: Some(cfg.socket()).filter(|v| !v.is_empty()),
         }
     }
 
     pub fn from_client_cfg(cfg: &ClientServiceConfig) -> SocketOpts {
         SocketOpts {
             nodelay: cfg.nodelay,
+            keepalive: cfg.keepalive,
             ..SocketOpts::none()
         }
     }
@@ -149,17 +199,21 @@ impl SocketOpts {
     pub fn from_server_cfg(cfg: &ServerServiceConfig) -> SocketOpts {
         SocketOpts {
             nodelay: cfg.nodelay,
+            keepalive: cfg.keepalive,
             ..SocketOpts::none()
         }
     }
 
     pub fn apply(&self, conn: &TcpStream) {
-        if let Some(v) = self.keepalive {
-            let keepalive_duration = Duration::from_secs(v.keepalive_secs);
-            let keepalive_interval = Duration::from_secs(v.keepalive_interval);
+        if let Some(v) = &self.socket {
+            if let Err(e) = try_set_socket_opts(conn.into(), v) {
+                error!("{:#}", e);
+            }
+        }
 
-            if let Err(e) = try_set_tcp_keepalive(conn, keepalive_duration, keepalive_interval)
-                .with_context(|| "Failed to set keepalive")
+        if let Some(v) = &self.keepalive {
+            if let Err(e) =
+                try_set_tcp_keepalive(conn, v).with_context(|| "Failed to set keepalive")
             {
                 error!("{:#}", e);
             }
@@ -176,3 +230,163 @@ impl SocketOpts {
         }
     }
 }
+
+/// How connections to the server are made. They go out from `bind_addr` and `bind_interface`,
+/// and addresses of the server are tried `happy_eyeballs_delay_ms` apart
+#[derive(Debug, Clone)]
+pub struct ConnectOpts {
+    addr: Option<IpAddr>,
+    interface: Option<String>,
+    attempt_delay: Duration,
+    // Set before connecting, so that the buffers count in the window scaling
+    socket: SocketConfig,
+}
+
+impl ConnectOpts {
+    pub fn from_cfg(cfg: &TransportConfig) -> ConnectOpts {
+        ConnectOpts {
+            addr: cfg.bind_addr,
+            interface: cfg.bind_interface.clone(),
+            attempt_delay: Duration::from_millis(
+                cfg.happy_eyeballs_delay_ms
+                    .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY_MS),
+            ),
+            socket: cfg.tcp.socket(),
+        }
+    }
+
+    /// Connect to any address that `addr` resolves to
+    pub async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        self.connect_any(lookup_host(addr).await?.collect()).await
+    }
+
+    /// Connect to any of `addrs` like Happy Eyeballs (RFC 8305). Attempts start one after another,
+    /// once the previous one fails or is still pending after the delay, and the first connection wins.
+    /// So a broken IPv6 network only delays the connection a bit
+    pub async fn connect_any(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut addrs = interleave_families(
+            addrs
+                .into_iter()
+                // The source address must be of the same family
+                .filter(|a| self.addr.is_none_or(|ip| ip.is_ipv4() == a.is_ipv4()))
+                .collect(),
+        )
+        .into_iter();
+
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = addrs.next() {
+                let opts = self.clone();
+                attempts.spawn(async move { opts.connect_addr(addr).await });
+            }
+
+            let ret = if addrs.len() == 0 {
+                attempts.join_next().await
+            } else {
+                match time::timeout(self.attempt_delay, attempts.join_next()).await {
+                    Ok(v) => v,
+                    // Start the next attempt
+                    Err(_) => continue,
+                }
+            };
+            match ret {
+                Some(Ok(Ok(s))) => return Ok(s),
+                Some(Ok(Err(e))) => last_err = Some(e),
+                Some(Err(e)) => last_err = Some(io::Error::other(e)),
+                None => break,
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No address to connect to of the same family as `bind_addr`",
+            )
+        }))
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(name) = &self.interface {
+            socket2::SockRef::from(&socket).bind_device(Some(name.as_bytes()))?;
+        }
+        if let Some(ip) = self.addr {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        if let Err(e) = try_set_socket_opts((&socket).into(), &self.socket) {
+            error!("{:#}", e);
+        }
+        socket.connect(addr).await
+    }
+}
+
+// Alternate between address families, starting with the family of the first address
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|a| a.is_ipv6() == first_is_ipv6);
+    let mut ret = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ret.extend(a.into_iter().chain(b)),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = [
+            "[::1]:1",
+            "[::1]:2",
+            "[::1]:3",
+            "127.0.0.1:4",
+            "127.0.0.1:5",
+        ]
+        .iter()
+        .map(|v| v.parse().unwrap())
+        .collect();
+        let ports: Vec<u16> = interleave_families(addrs)
+            .iter()
+            .map(|v| v.port())
+            .collect();
+        assert_eq!(ports, [1, 4, 2, 5, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let opts = ConnectOpts::from_cfg(&TransportConfig {
+            happy_eyeballs_delay_ms: Some(100),
+            ..Default::default()
+        });
+
+        // The first address never answers, or fails right away
+        let addrs = vec!["192.0.2.1:2333".parse().unwrap(), l.local_addr().unwrap()];
+        let start = time::Instant::now();
+        opts.connect_any(addrs).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Only an address of the same family as `bind_addr` is tried
+        let opts = ConnectOpts::from_cfg(&TransportConfig {
+            bind_addr: Some("::1".parse().unwrap()),
+            ..Default::default()
+        });
+        assert!(opts
+            .connect_any(vec![l.local_addr().unwrap()])
+            .await
+            .is_err());
+    }
+}
@@ -1,12 +1,15 @@
-use crate::config::{TlsConfig, TransportConfig};
+use crate::config::{TlsConfig, TlsVersion, TransportConfig};
 use crate::helper::host_port_pair;
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
-use anyhow::{anyhow, Context, Result};
+use crate::transport::{
+    AddrMaybeCached, ObfsStream, PermanentHandshakeError, ReloadableAcceptor, SocketOpts,
+    TcpTransport, Transport,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use std::fs;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio_native_tls::native_tls::{self, Certificate, Identity};
+use tokio_native_tls::native_tls::{self, Certificate, Identity, Protocol};
 pub(crate) use tokio_native_tls::TlsStream;
 use tokio_native_tls::{TlsAcceptor, TlsConnector};
 
@@ -15,14 +18,76 @@ pub struct TlsTransport {
     tcp: TcpTransport,
     config: TlsConfig,
     connector: Option<TlsConnector>,
-    tls_acceptor: Option<TlsAcceptor>,
+    tls_acceptor: Option<ReloadableAcceptor<TlsAcceptor>>,
+}
+
+fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let ident = match (config.cert.as_ref(), config.key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let cert = fs::read(cert).with_context(|| format!("Failed to read {}", cert))?;
+            let key = fs::read(key).with_context(|| format!("Failed to read {}", key))?;
+            Identity::from_pkcs8(&cert, &key)
+                .with_context(|| "Failed to load `tls.cert` and `tls.key`")?
+        }
+        _ => {
+            let path = config.pkcs12.as_ref().unwrap();
+            Identity::from_pkcs12(
+                &fs::read(path).with_context(|| format!("Failed to read {}", path))?,
+                config.pkcs12_password.as_ref().unwrap(),
+            )
+            .with_context(|| "Failed to create identitiy")?
+        }
+    };
+    let (min, max) = protocol_versions(config)?;
+    let acceptor = native_tls::TlsAcceptor::builder(ident)
+        .min_protocol_version(min)
+        .max_protocol_version(max)
+        .build()?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+// The range of TLS versions to negotiate, where `None` leaves it to the backend.
+// native_tls can't choose cipher suites, nor require TLS 1.3
+fn protocol_versions(config: &TlsConfig) -> Result<(Option<Protocol>, Option<Protocol>)> {
+    if config.cipher_suites.is_some() {
+        bail!("`tls.cipher_suites` is not supported by native-tls. Build rathole with the `rustls` feature instead");
+    }
+    let (min, max) = config.tls_versions();
+    let min = match min {
+        None => None,
+        Some(TlsVersion::V1_0) => Some(Protocol::Tlsv10),
+        Some(TlsVersion::V1_1) => Some(Protocol::Tlsv11),
+        Some(TlsVersion::V1_2) => Some(Protocol::Tlsv12),
+        Some(TlsVersion::V1_3) => bail!("Requiring TLS 1.3 is not supported by native-tls. Build rathole with the `rustls` feature instead"),
+    };
+    let max = match max {
+        // The highest version that the backend supports
+        None | Some(TlsVersion::V1_3) => None,
+        Some(TlsVersion::V1_0) => Some(Protocol::Tlsv10),
+        Some(TlsVersion::V1_1) => Some(Protocol::Tlsv11),
+        Some(TlsVersion::V1_2) => Some(Protocol::Tlsv12),
+    };
+    Ok((min, max))
+}
+
+// The certificate that the client presents for mutual authentication, if any
+fn load_client_identity(config: &TlsConfig) -> Result<Option<Identity>> {
+    let (cert, key) = match (config.client_cert.as_ref(), config.client_key.as_ref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let cert = fs::read(cert).with_context(|| format!("Failed to read {}", cert))?;
+    let key = fs::read(key).with_context(|| format!("Failed to read {}", key))?;
+    let ident = Identity::from_pkcs8(&cert, &key)
+        .with_context(|| "Failed to load `tls.client_cert` and `tls.client_key`")?;
+    Ok(Some(ident))
 }
 
 #[async_trait]
 impl Transport for TlsTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = TlsStream<TcpStream>;
+    type Stream = TlsStream<ObfsStream>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         let tcp = TcpTransport::new(config)?;
@@ -31,36 +96,30 @@ impl Transport for TlsTransport {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing tls config"))?;
 
-        let connector = match config.trusted_root.as_ref() {
-            Some(path) => {
-                let s = fs::read_to_string(path)
-                    .with_context(|| "Failed to read the `tls.trusted_root`")?;
-                let cert = Certificate::from_pem(s.as_bytes())
-                    .with_context(|| "Failed to read certificate from `tls.trusted_root`")?;
-                let connector = native_tls::TlsConnector::builder()
-                    .add_root_certificate(cert)
-                    .build()?;
-                Some(TlsConnector::from(connector))
-            }
-            None => {
-                // if no trusted_root is specified, allow TlsConnector to use system default
-                let connector = native_tls::TlsConnector::builder().build()?;
-                Some(TlsConnector::from(connector))
-            }
-        };
+        // if no trusted_root is specified, allow TlsConnector to use system default
+        let mut builder = native_tls::TlsConnector::builder();
+        let (min, max) = protocol_versions(config)?;
+        builder.min_protocol_version(min).max_protocol_version(max);
+        if let Some(path) = config.trusted_root.as_ref() {
+            let s = fs::read_to_string(path)
+                .with_context(|| "Failed to read the `tls.trusted_root`")?;
+            let cert = Certificate::from_pem(s.as_bytes())
+                .with_context(|| "Failed to read certificate from `tls.trusted_root`")?;
+            builder.add_root_certificate(cert);
+        }
+        if let Some(ident) = load_client_identity(config)? {
+            builder.identity(ident);
+        }
+        let connector = Some(TlsConnector::from(builder.build()?));
 
-        let tls_acceptor = match config.pkcs12.as_ref() {
-            Some(path) => {
-                let ident = Identity::from_pkcs12(
-                    &fs::read(path)?,
-                    config.pkcs12_password.as_ref().unwrap(),
-                )
-                .with_context(|| "Failed to create identitiy")?;
-                Some(TlsAcceptor::from(
-                    native_tls::TlsAcceptor::new(ident).unwrap(),
-                ))
+        let tls_acceptor = if config.pkcs12.is_some() || config.cert.is_some() {
+            // native_tls servers can't request certificates from clients
+            if config.ca.is_some() {
+                bail!("Verifying client certificates with `tls.ca` is not supported by native-tls. Build rathole with the `rustls` feature instead");
             }
-            None => None,
+            Some(ReloadableAcceptor::new(config, load_acceptor)?)
+        } else {
+            None
         };
 
         Ok(TlsTransport {
@@ -82,6 +141,13 @@ impl Transport for TlsTransport {
         Ok(l)
     }
 
+    async fn bind_reuse_port<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> Result<Self::Acceptor> {
+        self.tcp.bind_reuse_port(addr).await
+    }
+
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
         self.tcp
             .accept(a)
@@ -90,7 +156,9 @@ impl Transport for TlsTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = self.tls_acceptor.as_ref().unwrap().accept(conn).await?;
+        let conn = self.tcp.handshake(conn).await?;
+        let acceptor = self.tls_acceptor.as_ref().unwrap().get();
+        let conn = acceptor.accept(conn).await?;
         Ok(conn)
     }
 
@@ -98,7 +166,7 @@ impl Transport for TlsTransport {
         let conn = self.tcp.connect(addr).await?;
 
         let connector = self.connector.as_ref().unwrap();
-        Ok(connector
+        connector
             .connect(
                 self.config
                     .hostname
@@ -106,11 +174,285 @@ impl Transport for TlsTransport {
                     .unwrap_or(host_port_pair(&addr.addr)?.0),
                 conn,
             )
-            .await?)
+            .await
+            .map_err(|e| {
+                let permanent = is_cert_error(&e);
+                let e = anyhow::Error::new(e);
+                if permanent {
+                    e.context(PermanentHandshakeError)
+                } else {
+                    e
+                }
+            })
     }
 }
 
+// Whether the certificate of the server fails the validation.
+// native_tls doesn't tell kinds of errors, but all backends mention the certificate.
+fn is_cert_error(e: &native_tls::Error) -> bool {
+    e.to_string().to_lowercase().contains("certificate")
+}
+
 #[cfg(feature = "websocket-native-tls")]
-pub(crate) fn get_tcpstream(s: &TlsStream<TcpStream>) -> &TcpStream {
+pub(crate) fn get_tcpstream(s: &TlsStream<ObfsStream>) -> &TcpStream {
     s.get_ref().get_ref().get_ref()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TransportType;
+    use crate::transport::is_permanent_handshake_error;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Connect to `addr`, accepting any certificate. Returns the stream and the server certificate
+    async fn connect(addr: SocketAddr) -> (TlsStream<TcpStream>, Vec<u8>) {
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .unwrap();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let conn = TlsConnector::from(connector)
+            .connect("localhost", conn)
+            .await
+            .unwrap();
+        let cert = conn
+            .get_ref()
+            .peer_certificate()
+            .unwrap()
+            .unwrap()
+            .to_der()
+            .unwrap();
+        (conn, cert)
+    }
+
+    #[tokio::test]
+    async fn test_reload_certificate() {
+        let path = std::env::temp_dir().join(format!("rathole-{}.pfx", std::process::id()));
+        fs::copy("examples/tls/identity.pfx", &path).unwrap();
+
+        let config = TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                hostname: None,
+                trusted_root: None,
+                pkcs12: Some(path.to_str().unwrap().to_string()),
+                pkcs12_password: Some("1234".into()),
+                cert_reload_interval: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let transport = TlsTransport::new(&config).unwrap();
+        let l = transport.bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+
+        // An echo server
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = transport.accept(&l).await.unwrap();
+                let mut conn = transport.handshake(conn).await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4];
+                    while conn.read_exact(&mut buf).await.is_ok() {
+                        conn.write_all(&buf).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let (mut old_conn, old_cert) = connect(addr).await;
+
+        // Renew the certificate
+        fs::copy("tests/for_tls_reload/identity_renewed.pfx", &path).unwrap();
+
+        // New handshakes use the new certificate, once it's reloaded
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connect(addr).await.1 == old_cert {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // The existing connection persists
+        let mut buf = [0u8; 4];
+        old_conn.write_all(b"ping").await.unwrap();
+        old_conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_cert_error_is_permanent() {
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                hostname: None,
+                trusted_root: None,
+                pkcs12: Some("examples/tls/identity.pfx".into()),
+                pkcs12_password: Some("1234".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached::new(&l.local_addr().unwrap().to_string());
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept(&l).await {
+                let _ = server.handshake(conn).await;
+            }
+        });
+
+        // The client doesn't trust the self-signed certificate
+        let client = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                hostname: Some("localhost".into()),
+                trusted_root: None,
+                pkcs12: None,
+                pkcs12_password: None,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let e = client.connect(&addr).await.unwrap_err();
+        assert!(is_permanent_handshake_error(&e), "{:#}", e);
+
+        // A server going away in the middle of the handshake
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached::new(&l.local_addr().unwrap().to_string());
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = l.accept().await {
+                drop(conn);
+            }
+        });
+        let e = client.connect(&addr).await.unwrap_err();
+        assert!(!is_permanent_handshake_error(&e), "{:#}", e);
+    }
+
+    #[tokio::test]
+    async fn test_tls_versions() {
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                pkcs12: Some("examples/tls/identity.pfx".into()),
+                pkcs12_password: Some("1234".into()),
+                min_tls_version: Some(TlsVersion::V1_2),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept(&l).await {
+                let _ = server.handshake(conn).await;
+            }
+        });
+        connect(addr).await;
+
+        // A client that only speaks older versions
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .max_protocol_version(Some(Protocol::Tlsv11))
+            .build()
+            .unwrap();
+        let conn = TcpStream::connect(addr).await.unwrap();
+        assert!(TlsConnector::from(connector)
+            .connect("localhost", conn)
+            .await
+            .is_err());
+
+        // Not supported by native_tls
+        for tls in [
+            TlsConfig {
+                tls13_only: true,
+                ..Default::default()
+            },
+            TlsConfig {
+                cipher_suites: Some(vec!["TLS_AES_128_GCM_SHA256".into()]),
+                ..Default::default()
+            },
+        ] {
+            assert!(TlsTransport::new(&TransportConfig {
+                transport_type: TransportType::Tls,
+                tls: Some(tls),
+                ..Default::default()
+            })
+            .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pem_certificate() {
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                cert: Some("tests/for_tls_reload/cert.pem".into()),
+                key: Some("tests/for_tls_reload/key.pem".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept(&l).await {
+                let _ = server.handshake(conn).await;
+            }
+        });
+
+        let (_, cert) = connect(addr).await;
+        let pem = fs::read("tests/for_tls_reload/cert.pem").unwrap();
+        assert_eq!(cert, Certificate::from_pem(&pem).unwrap().to_der().unwrap());
+    }
+
+    #[test]
+    fn test_client_cert() {
+        let client = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                client_cert: Some("tests/for_mtls/client.crt".into()),
+                client_key: Some("tests/for_mtls/client.key".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert!(client.is_ok());
+
+        // The key doesn't match the certificate
+        let client = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                client_cert: Some("tests/for_mtls/client.crt".into()),
+                client_key: Some("examples/tls/rootCA.crt".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert!(client.is_err());
+
+        // Servers can't verify client certificates
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                pkcs12: Some("examples/tls/identity.pfx".into()),
+                pkcs12_password: Some("1234".into()),
+                ca: Some("examples/tls/rootCA.crt".into()),
+                require_client_cert: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert!(server.is_err());
+    }
+}
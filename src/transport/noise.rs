@@ -1,18 +1,23 @@
 use std::net::SocketAddr;
 
-use super::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
+use super::{AddrMaybeCached, ObfsStream, SocketOpts, TcpTransport, Transport};
 use crate::config::{NoiseConfig, TransportConfig};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use snowstorm::snow::params::HandshakeModifier;
 use snowstorm::{Builder, NoiseParams, NoiseStream};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
+// The length of a pre-shared key
+const PSK_LEN: usize = 32;
+
 pub struct NoiseTransport {
     tcp: TcpTransport,
     config: NoiseConfig,
     params: NoiseParams,
     local_private_key: Vec<u8>,
     remote_public_key: Option<Vec<u8>>,
+    psk: Option<Vec<u8>>,
 }
 
 impl std::fmt::Debug for NoiseTransport {
@@ -22,20 +27,118 @@ impl std::fmt::Debug for NoiseTransport {
 }
 
 impl NoiseTransport {
-    fn builder(&self) -> Builder {
-        let builder = Builder::new(self.params.clone()).local_private_key(&self.local_private_key);
-        match &self.remote_public_key {
-            Some(x) => builder.remote_public_key(x),
-            None => builder,
+    fn builder(&self) -> Builder<'_> {
+        builder(
+            &self.params,
+            &self.local_private_key,
+            self.remote_public_key.as_deref(),
+            self.psk.as_deref(),
+        )
+    }
+
+    /// Check `[transport.noise]`, where the pattern decides which keys are necessary
+    pub fn validate_config(config: &NoiseConfig, is_server: bool) -> Result<()> {
+        let params = noise_params(config)?;
+        let pattern = params.handshake.pattern;
+        let initiator = !is_server;
+        if pattern.need_known_remote_pubkey(initiator) && config.remote_public_key.is_none() {
+            bail!("`remote_public_key` is necessary for {}", params.name);
+        }
+        // Known to the peer beforehand, so it can't be generated
+        if pattern.need_known_remote_pubkey(!initiator) && config.local_private_key.is_none() {
+            bail!("`local_private_key` is necessary for {}", params.name);
+        }
+        let psk = decode_psk(config)?;
+        if params.handshake.is_psk() && psk.is_none() {
+            bail!("`psk` is necessary for {}", params.name);
+        }
+
+        // Build a handshake with made-up keys, for other problems with the pattern
+        let keypair = Builder::new(params.clone()).generate_keypair()?;
+        builder(
+            &params,
+            &keypair.private,
+            Some(&keypair.public),
+            psk.as_deref(),
+        )
+        .build_initiator()
+        .with_context(|| format!("Invalid noise pattern {}", params.name))?;
+        Ok(())
+    }
+}
+
+fn builder<'a>(
+    params: &NoiseParams,
+    local_private_key: &'a [u8],
+    remote_public_key: Option<&'a [u8]>,
+    psk: Option<&'a [u8]>,
+) -> Builder<'a> {
+    let mut builder = Builder::new(params.clone()).local_private_key(local_private_key);
+    if let Some(x) = remote_public_key {
+        builder = builder.remote_public_key(x);
+    }
+    if let Some(psk) = psk {
+        for m in params.handshake.modifiers.list.iter() {
+            if let HandshakeModifier::Psk(position) = m {
+                builder = builder.psk(*position, psk);
+            }
         }
     }
+    builder
+}
+
+// The params of `pattern`, with a psk modifier at `psk_position` if `psk` is set and there's none
+fn noise_params(config: &NoiseConfig) -> Result<NoiseParams> {
+    let params: NoiseParams = config
+        .pattern
+        .parse()
+        .with_context(|| format!("Invalid noise pattern {}", config.pattern))?;
+    if params.handshake.is_psk() {
+        if config.psk_position.is_some() {
+            bail!(
+                "`psk_position` conflicts with the psk modifier of {}",
+                config.pattern
+            );
+        }
+        return Ok(params);
+    }
+    if config.psk.is_none() {
+        if config.psk_position.is_some() {
+            bail!("`psk_position` is set without `psk`");
+        }
+        return Ok(params);
+    }
+
+    // Like `Noise_NKpsk0_25519_ChaChaPoly_BLAKE2s`, or `Noise_XXfallback+psk0_...`
+    let mut parts: Vec<String> = config.pattern.split('_').map(String::from).collect();
+    let sep = if params.handshake.modifiers.list.is_empty() {
+        ""
+    } else {
+        "+"
+    };
+    parts[1] = format!("{}{}psk{}", parts[1], sep, config.psk_position.unwrap_or(0));
+    let pattern = parts.join("_");
+    pattern
+        .parse()
+        .with_context(|| format!("Invalid noise pattern {}", pattern))
+}
+
+fn decode_psk(config: &NoiseConfig) -> Result<Option<Vec<u8>>> {
+    let psk = match &config.psk {
+        Some(v) => base64::decode(v.as_bytes()).with_context(|| "Failed to decode psk")?,
+        None => return Ok(None),
+    };
+    if psk.len() != PSK_LEN {
+        bail!("`psk` must be {} bytes, but is {}", PSK_LEN, psk.len());
+    }
+    Ok(Some(psk))
 }
 
 #[async_trait]
 impl Transport for NoiseTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = snowstorm::stream::NoiseStream<TcpStream>;
+    type Stream = snowstorm::stream::NoiseStream<ObfsStream>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         let tcp = TcpTransport::new(config)?;
@@ -59,7 +162,8 @@ impl Transport for NoiseTransport {
             None => builder.generate_keypair()?.private,
         };
 
-        let params: NoiseParams = config.pattern.parse()?;
+        let params = noise_params(&config)?;
+        let psk = decode_psk(&config)?;
 
         Ok(NoiseTransport {
             tcp,
@@ -67,6 +171,7 @@ impl Transport for NoiseTransport {
             params,
             local_private_key,
             remote_public_key,
+            psk,
         })
     }
 
@@ -78,6 +183,13 @@ impl Transport for NoiseTransport {
         Ok(TcpListener::bind(addr).await?)
     }
 
+    async fn bind_reuse_port<T: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: T,
+    ) -> Result<Self::Acceptor> {
+        self.tcp.bind_reuse_port(addr).await
+    }
+
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
         self.tcp
             .accept(a)
@@ -86,6 +198,7 @@ impl Transport for NoiseTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let conn = self.tcp.handshake(conn).await?;
         let conn = NoiseStream::handshake(conn, self.builder().build_responder()?)
             .await
             .with_context(|| "Failed to do noise handshake")?;
@@ -105,3 +218,107 @@ impl Transport for NoiseTransport {
         return Ok(conn);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TransportType;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn noise_config(pattern: &str) -> NoiseConfig {
+        NoiseConfig {
+            pattern: pattern.to_string(),
+            local_private_key: None,
+            remote_public_key: None,
+            psk: None,
+            psk_position: None,
+        }
+    }
+
+    fn transport_config(noise: NoiseConfig) -> TransportConfig {
+        TransportConfig {
+            transport_type: TransportType::Noise,
+            noise: Some(noise),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let psk = base64::encode([7u8; PSK_LEN]);
+        let nk = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+        let validate = |c: &NoiseConfig, is_server| NoiseTransport::validate_config(c, is_server);
+
+        // Keys known to the peer beforehand are necessary
+        assert!(validate(&noise_config(nk), true).is_err());
+        assert!(validate(&noise_config(nk), false).is_err());
+        let xx = noise_config("Noise_XX_25519_ChaChaPoly_BLAKE2s");
+        assert!(validate(&xx, true).is_ok());
+        assert!(validate(&xx, false).is_ok());
+
+        let mut c = xx.clone();
+        c.psk_position = Some(0);
+        assert!(validate(&c, true).is_err());
+        c.psk = Some(psk.as_str().into());
+        assert!(validate(&c, true).is_ok());
+        // XX has 3 messages
+        c.psk_position = Some(4);
+        assert!(validate(&c, true).is_err());
+
+        let mut c = noise_config("Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s");
+        assert!(validate(&c, true).is_err());
+        c.psk = Some("c2hvcnQ=".into());
+        assert!(validate(&c, true).is_err());
+        c.psk = Some(psk.as_str().into());
+        assert!(validate(&c, true).is_ok());
+        c.psk_position = Some(0);
+        assert!(validate(&c, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_psk() {
+        let keypair = Builder::new(default_params()).generate_keypair().unwrap();
+        let mut server = noise_config("Noise_NK_25519_ChaChaPoly_BLAKE2s");
+        server.local_private_key = Some(base64::encode(&keypair.private).as_str().into());
+        server.psk = Some(base64::encode([1u8; PSK_LEN]).as_str().into());
+        server.psk_position = Some(2);
+        let mut client = server.clone();
+        client.local_private_key = None;
+        client.remote_public_key = Some(base64::encode(&keypair.public));
+
+        let server = NoiseTransport::new(&transport_config(server)).unwrap();
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached {
+            addr: l.local_addr().unwrap().to_string(),
+            socket_addrs: vec![l.local_addr().unwrap()],
+        };
+        let accept = async {
+            let (conn, _) = server.accept(&l).await.unwrap();
+            server.handshake(conn).await
+        };
+
+        // The same psk
+        let c = NoiseTransport::new(&transport_config(client.clone())).unwrap();
+        let (s, c) = tokio::join!(accept, c.connect(&addr));
+        let (mut s, mut c) = (s.unwrap(), c.unwrap());
+        c.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // A different psk
+        let accept = async {
+            let (conn, _) = server.accept(&l).await.unwrap();
+            server.handshake(conn).await
+        };
+        client.psk = Some(base64::encode([2u8; PSK_LEN]).as_str().into());
+        let c = NoiseTransport::new(&transport_config(client)).unwrap();
+        // psk2 is mixed into the response, so the client finds out
+        let (_, c) = tokio::join!(accept, c.connect(&addr));
+        assert!(c.is_err());
+    }
+
+    fn default_params() -> NoiseParams {
+        "Noise_NK_25519_ChaChaPoly_BLAKE2s".parse().unwrap()
+    }
+}
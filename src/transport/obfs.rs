@@ -0,0 +1,322 @@
+use crate::config::{ObfsConfig, ObfsType};
+use crate::helper::MaybeTlsStream;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+// Each direction starts with a random salt in the clear, followed by the obfuscated data
+const SALT_LEN: usize = 16;
+const BLOCK_LEN: usize = 32;
+// The most to obfuscate for a single write
+const MAX_WRITE: usize = 64 * 1024;
+
+/// How connections are obfuscated, as `[transport.obfs]` says
+#[derive(Clone)]
+pub struct Obfs {
+    // SHA-256 with the key absorbed
+    keyed: Box<Sha256>,
+}
+
+impl Debug for Obfs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Obfs")
+    }
+}
+
+impl Obfs {
+    pub fn new(config: &ObfsConfig) -> Obfs {
+        match config.obfs_type {
+            ObfsType::Xor => Obfs {
+                keyed: Box::new(Sha256::new().chain_update(config.key.as_bytes())),
+            },
+        }
+    }
+
+    pub fn wrap(&self, conn: impl Into<MaybeTlsStream>) -> ObfsStream {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        ObfsStream {
+            inner: conn.into(),
+            state: Some(Box::new(State {
+                keyed: (*self.keyed).clone(),
+                write: Keystream::new(&self.keyed, &salt),
+                salt,
+                salt_written: 0,
+                write_pos: 0,
+                peer_salt: [0u8; SALT_LEN],
+                peer_salt_read: 0,
+                read: None,
+                read_pos: 0,
+                scratch: Vec::new(),
+            })),
+        }
+    }
+}
+
+// The keystream of a direction, where block i is SHA-256(key || salt || i)
+struct Keystream {
+    salted: Sha256,
+    index: Option<u64>,
+    block: [u8; BLOCK_LEN],
+}
+
+impl Keystream {
+    fn new(keyed: &Sha256, salt: &[u8]) -> Keystream {
+        Keystream {
+            salted: keyed.clone().chain_update(salt),
+            index: None,
+            block: [0u8; BLOCK_LEN],
+        }
+    }
+
+    // XOR `data`, which is at `pos` of the stream
+    fn apply(&mut self, pos: u64, data: &mut [u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            let p = pos + i as u64;
+            let index = p / BLOCK_LEN as u64;
+            let offset = (p % BLOCK_LEN as u64) as usize;
+            if self.index != Some(index) {
+                self.block = self
+                    .salted
+                    .clone()
+                    .chain_update(index.to_le_bytes())
+                    .finalize()
+                    .into();
+                self.index = Some(index);
+            }
+            let n = (BLOCK_LEN - offset).min(data.len() - i);
+            for (b, k) in data[i..i + n].iter_mut().zip(&self.block[offset..]) {
+                *b ^= k;
+            }
+            i += n;
+        }
+    }
+}
+
+struct State {
+    keyed: Sha256,
+    salt: [u8; SALT_LEN],
+    salt_written: usize,
+    write: Keystream,
+    write_pos: u64,
+    peer_salt: [u8; SALT_LEN],
+    peer_salt_read: usize,
+    // Known once the salt of the peer is read
+    read: Option<Keystream>,
+    read_pos: u64,
+    scratch: Vec<u8>,
+}
+
+/// A TCP connection, which is obfuscated if `[transport.obfs]` is configured
+pub struct ObfsStream {
+    inner: MaybeTlsStream,
+    state: Option<Box<State>>,
+}
+
+impl Debug for ObfsStream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObfsStream")
+            .field("inner", &self.inner)
+            .field("obfuscated", &self.state.is_some())
+            .finish()
+    }
+}
+
+impl ObfsStream {
+    pub fn plain(conn: impl Into<MaybeTlsStream>) -> ObfsStream {
+        ObfsStream {
+            inner: conn.into(),
+            state: None,
+        }
+    }
+
+    /// The TCP stream, if the data goes as is
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    pub fn as_plain(&self) -> Option<&TcpStream> {
+        match self.state {
+            Some(_) => None,
+            None => self.inner.as_tcp(),
+        }
+    }
+}
+
+impl Deref for ObfsStream {
+    type Target = TcpStream;
+    fn deref(&self) -> &TcpStream {
+        self.inner.tcp()
+    }
+}
+
+impl AsyncRead for ObfsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let state = match &mut this.state {
+            Some(v) => v,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+
+        while state.read.is_none() {
+            let mut salt = ReadBuf::new(&mut state.peer_salt[state.peer_salt_read..]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut salt))?;
+            let n = salt.filled().len();
+            if n == 0 {
+                if state.peer_salt_read == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Truncated obfuscation salt",
+                )));
+            }
+            state.peer_salt_read += n;
+            if state.peer_salt_read == SALT_LEN {
+                state.read = Some(Keystream::new(&state.keyed, &state.peer_salt));
+            }
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let data = &mut buf.filled_mut()[filled..];
+        state.read.as_mut().unwrap().apply(state.read_pos, data);
+        state.read_pos += data.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ObfsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let state = match &mut this.state {
+            Some(v) => v,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        while state.salt_written < SALT_LEN {
+            let n = ready!(
+                Pin::new(&mut this.inner).poll_write(cx, &state.salt[state.salt_written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            state.salt_written += n;
+        }
+
+        // Obfuscated again on the next write if it's not taken, since the position stays
+        let buf = &buf[..buf.len().min(MAX_WRITE)];
+        state.scratch.clear();
+        state.scratch.extend_from_slice(buf);
+        state.write.apply(state.write_pos, &mut state.scratch);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &state.scratch))?;
+        state.write_pos += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn obfs(key: &str) -> Obfs {
+        Obfs::new(&ObfsConfig {
+            obfs_type: ObfsType::Xor,
+            key: key.into(),
+        })
+    }
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+        (conn, l.accept().await.unwrap().0)
+    }
+
+    #[test]
+    fn test_keystream() {
+        let o = obfs("key");
+        let mut data = vec![0u8; 100];
+        Keystream::new(&o.keyed, b"salt").apply(0, &mut data);
+        assert_ne!(data, vec![0u8; 100]);
+
+        // Any split of the stream gets the same keystream
+        let mut split = vec![0u8; 100];
+        let mut k = Keystream::new(&o.keyed, b"salt");
+        k.apply(0, &mut split[..7]);
+        k.apply(7, &mut split[7..40]);
+        k.apply(40, &mut split[40..]);
+        assert_eq!(data, split);
+
+        let mut other = vec![0u8; 100];
+        Keystream::new(&o.keyed, b"other").apply(0, &mut other);
+        assert_ne!(data, other);
+    }
+
+    #[tokio::test]
+    async fn test_obfs_stream() {
+        let (a, b) = pair().await;
+        let mut a = obfs("key").wrap(a);
+        let mut b = obfs("key").wrap(b);
+        #[cfg(all(target_os = "linux", feature = "splice"))]
+        assert!(a.as_plain().is_none());
+
+        let msg: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let sent = msg.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, msg);
+        let mut a = writer.await.unwrap();
+
+        b.write_all(b"reply").await.unwrap();
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_obfuscated() {
+        // Nothing of the data is on the wire as is
+        let (a, mut b) = pair().await;
+        let mut a = obfs("key").wrap(a);
+        a.write_all(b"hello, hello").await.unwrap();
+        let mut buf = [0u8; SALT_LEN + 12];
+        b.read_exact(&mut buf).await.unwrap();
+        assert!(!buf.windows(5).any(|w| w == b"hello"));
+
+        // A different key makes garbage
+        let (a, b) = pair().await;
+        let mut a = obfs("key").wrap(a);
+        let mut b = obfs("wrong").wrap(b);
+        a.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_ne!(&buf, b"hello");
+    }
+}
@@ -1,17 +1,26 @@
-use crate::config::{TlsConfig, TransportConfig};
+use crate::config::{TlsConfig, TlsVersion, TransportConfig};
 use crate::helper::host_port_pair;
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
+use crate::transport::{
+    AddrMaybeCached, ObfsStream, PermanentHandshakeError, ReloadableAcceptor, SocketOpts,
+    TcpTransport, Transport,
+};
 use std::fmt::Debug;
 use std::fs;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use p12::PFX;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::{
+    self, ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion,
+};
 pub(crate) use tokio_rustls::TlsStream;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 
@@ -19,7 +28,7 @@ pub struct TlsTransport {
     tcp: TcpTransport,
     config: TlsConfig,
     connector: Option<TlsConnector>,
-    tls_acceptor: Option<TlsAcceptor>,
+    tls_acceptor: Option<ReloadableAcceptor<TlsAcceptor>>,
 }
 
 // workaround for TlsConnector and TlsAcceptor not implementing Debug
@@ -32,28 +41,123 @@ impl Debug for TlsTransport {
     }
 }
 
+// Read all certificates in the PEM file at `path`
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let f = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    rustls_pemfile::certs(&mut std::io::BufReader::new(f))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path))
+}
+
+// The cipher suites of the provider, limited to `tls.cipher_suites` if set
+fn crypto_provider(config: &TlsConfig) -> Result<Arc<CryptoProvider>> {
+    let mut provider = ring::default_provider();
+    if let Some(names) = config.cipher_suites.as_ref() {
+        let mut suites = Vec::new();
+        for name in names {
+            let suite = provider
+                .cipher_suites
+                .iter()
+                .find(|s| cipher_suite_matches(&format!("{:?}", s.suite()), name))
+                .ok_or_else(|| anyhow!("Unknown or unsupported cipher suite {}", name))?;
+            suites.push(*suite);
+        }
+        provider.cipher_suites = suites;
+    }
+    Ok(Arc::new(provider))
+}
+
+// Whether `name` is the rustls name of a cipher suite, like "TLS13_AES_128_GCM_SHA256",
+// or its IANA name, like "TLS_AES_128_GCM_SHA256"
+fn cipher_suite_matches(rustls_name: &str, name: &str) -> bool {
+    rustls_name.eq_ignore_ascii_case(name)
+        || rustls_name
+            .replacen("TLS13_", "TLS_", 1)
+            .eq_ignore_ascii_case(name)
+}
+
+// The TLS versions within `tls.min_tls_version` and `tls.max_tls_version`.
+// rustls supports TLS 1.2 and 1.3
+fn protocol_versions(config: &TlsConfig) -> Result<Vec<&'static SupportedProtocolVersion>> {
+    let (min, max) = config.tls_versions();
+    let versions: Vec<_> = [
+        (TlsVersion::V1_2, &rustls::version::TLS12),
+        (TlsVersion::V1_3, &rustls::version::TLS13),
+    ]
+    .into_iter()
+    .filter(|(v, _)| min.is_none_or(|min| *v >= min) && max.is_none_or(|max| *v <= max))
+    .map(|(_, v)| v)
+    .collect();
+    if versions.is_empty() {
+        bail!("None of the TLS versions allowed is supported by rustls, which supports TLS 1.2 and 1.3");
+    }
+    Ok(versions)
+}
+
+// The certificate chain and the private key of the server
+fn load_server_cert(
+    config: &TlsConfig,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    if let (Some(cert), Some(key)) = (config.cert.as_ref(), config.key.as_ref()) {
+        let f = fs::File::open(key).with_context(|| format!("Failed to open {}", key))?;
+        let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(f))?
+            .ok_or_else(|| anyhow!("No private key in {}", key))?;
+        return Ok((load_certs(cert)?, key));
+    }
+
+    let pkcs12_path = config.pkcs12.as_ref().unwrap();
+    let buf = fs::read(pkcs12_path).with_context(|| format!("Failed to read {}", pkcs12_path))?;
+    let pfx = PFX::parse(buf.as_slice())?;
+    let pass = config.pkcs12_password.as_ref().unwrap();
+
+    let certs = pfx.cert_bags(pass)?;
+    let keys = pfx.key_bags(pass)?;
+
+    let chain = certs.into_iter().map(CertificateDer::from).collect();
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key in {}", pkcs12_path))?;
+    Ok((chain, PrivatePkcs8KeyDer::from(key).into()))
+}
+
 fn load_server_config(config: &TlsConfig) -> Result<Option<ServerConfig>> {
-    if let Some(pkcs12_path) = config.pkcs12.as_ref() {
-        let buf = fs::read(pkcs12_path)?;
-        let pfx = PFX::parse(buf.as_slice())?;
-        let pass = config.pkcs12_password.as_ref().unwrap();
-
-        let certs = pfx.cert_bags(pass)?;
-        let keys = pfx.key_bags(pass)?;
-
-        let chain: Vec<CertificateDer> = certs.into_iter().map(CertificateDer::from).collect();
-        let key = PrivatePkcs8KeyDer::from(keys.into_iter().next().unwrap());
-
-        Ok(Some(
-            ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(chain, key.into())?,
-        ))
+    if config.pkcs12.is_some() || config.cert.is_some() {
+        let (chain, key) = load_server_cert(config)?;
+
+        let builder = ServerConfig::builder_with_provider(crypto_provider(config)?)
+            .with_protocol_versions(&protocol_versions(config)?)?;
+        let builder = match config.ca.as_ref() {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = if config.require_client_cert {
+                    verifier
+                } else {
+                    // Certificates are still verified if presented
+                    verifier.allow_unauthenticated()
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Some(builder.with_single_cert(chain, key)?))
     } else {
         Ok(None)
     }
 }
 
+// The acceptor of a server with a certificate in `config`
+fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let c = load_server_config(config)?
+        .ok_or_else(|| anyhow!("Missing the certificate of the server"))?;
+    Ok(Arc::new(c).into())
+}
+
 fn load_client_config(config: &TlsConfig) -> Result<Option<ClientConfig>> {
     let cert = if let Some(path) = config.trusted_root.as_ref() {
         rustls_pemfile::certs(&mut std::io::BufReader::new(fs::File::open(path).unwrap()))
@@ -74,18 +178,27 @@ fn load_client_config(config: &TlsConfig) -> Result<Option<ClientConfig>> {
     let mut root_certs = RootCertStore::empty();
     root_certs.add(cert).unwrap();
 
-    Ok(Some(
-        ClientConfig::builder()
-            .with_root_certificates(root_certs)
-            .with_no_client_auth(),
-    ))
+    let builder = ClientConfig::builder_with_provider(crypto_provider(config)?)
+        .with_protocol_versions(&protocol_versions(config)?)?
+        .with_root_certificates(root_certs);
+    let client_config = match (config.client_cert.as_ref(), config.client_key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            let f = fs::File::open(key).with_context(|| format!("Failed to open {}", key))?;
+            let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(f))?
+                .ok_or_else(|| anyhow!("No private key in {}", key))?;
+            builder.with_client_auth_cert(load_certs(cert)?, key)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(Some(client_config))
 }
 
 #[async_trait]
 impl Transport for TlsTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = TlsStream<TcpStream>;
+    type Stream = TlsStream<ObfsStream>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         let tcp = TcpTransport::new(config)?;
@@ -94,12 +207,11 @@ impl Transport for TlsTransport {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing tls config"))?;
 
-        let connector = load_client_config(config)
-            .unwrap()
-            .map(|c| Arc::new(c).into());
-        let tls_acceptor = load_server_config(config)
-            .unwrap()
-            .map(|c| Arc::new(c).into());
+        let connector = load_client_config(config)?.map(|c| Arc::new(c).into());
+        let tls_acceptor = match config.pkcs12.is_some() || config.cert.is_some() {
+            true => Some(ReloadableAcceptor::new(config, load_acceptor)?),
+            false => None,
+        };
 
         Ok(TlsTransport {
             tcp,
@@ -120,6 +232,13 @@ impl Transport for TlsTransport {
         Ok(l)
     }
 
+    async fn bind_reuse_port<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> Result<Self::Acceptor> {
+        self.tcp.bind_reuse_port(addr).await
+    }
+
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
         self.tcp
             .accept(a)
@@ -128,7 +247,9 @@ impl Transport for TlsTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = self.tls_acceptor.as_ref().unwrap().accept(conn).await?;
+        let conn = self.tcp.handshake(conn).await?;
+        let acceptor = self.tls_acceptor.as_ref().unwrap().get();
+        let conn = acceptor.accept(conn).await?;
         Ok(tokio_rustls::TlsStream::Server(conn))
     }
 
@@ -143,14 +264,181 @@ impl Transport for TlsTransport {
             .as_deref()
             .unwrap_or(host_port_pair(&addr.addr)?.0);
 
-        Ok(tokio_rustls::TlsStream::Client(
-            connector
-                .connect(ServerName::try_from(host_name)?.to_owned(), conn)
-                .await?,
-        ))
+        let conn = connector
+            .connect(ServerName::try_from(host_name)?.to_owned(), conn)
+            .await
+            .map_err(|e| {
+                let permanent = is_cert_error(&e);
+                let e = anyhow::Error::new(e);
+                if permanent {
+                    e.context(PermanentHandshakeError)
+                } else {
+                    e
+                }
+            })?;
+        Ok(tokio_rustls::TlsStream::Client(conn))
     }
 }
 
-pub(crate) fn get_tcpstream(s: &TlsStream<TcpStream>) -> &TcpStream {
-    &s.get_ref().0
+// Whether the certificate of the server fails the validation
+fn is_cert_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented)
+    )
+}
+
+pub(crate) fn get_tcpstream(s: &TlsStream<ObfsStream>) -> &TcpStream {
+    s.get_ref().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TransportType;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn client_trusting(cert: &str) -> TlsTransport {
+        TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                trusted_root: Some(cert.into()),
+                hostname: Some("localhost".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reload_certificate() {
+        let dir = std::env::temp_dir().join(format!("rathole-rustls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy("tests/for_tls_reload/cert.pem", &cert).unwrap();
+        fs::copy("tests/for_tls_reload/key.pem", &key).unwrap();
+
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                cert: Some(cert.to_str().unwrap().to_string()),
+                key: Some(key.to_str().unwrap().to_string()),
+                cert_reload_interval: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let l = server.bind("127.0.0.1:0").await.unwrap();
+        let addr = AddrMaybeCached::new(&l.local_addr().unwrap().to_string());
+
+        // An echo server
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = server.accept(&l).await {
+                let Ok(mut conn) = server.handshake(conn).await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4];
+                    while conn.read_exact(&mut buf).await.is_ok() {
+                        conn.write_all(&buf).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = client_trusting("tests/for_tls_reload/cert.pem");
+        let mut old_conn = client.connect(&addr).await.unwrap();
+
+        // Renew the certificate
+        fs::copy("tests/for_tls_reload/cert_renewed.pem", &cert).unwrap();
+        fs::copy("tests/for_tls_reload/key_renewed.pem", &key).unwrap();
+
+        // New handshakes use the new certificate, once it's reloaded
+        let renewed = client_trusting("tests/for_tls_reload/cert_renewed.pem");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while renewed.connect(&addr).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(client.connect(&addr).await.is_err());
+
+        // The existing connection persists
+        let mut buf = [0u8; 4];
+        old_conn.write_all(b"ping").await.unwrap();
+        old_conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        let versions = |tls: TlsConfig| {
+            protocol_versions(&tls).map(|v| v.iter().map(|v| v.version).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            versions(TlsConfig::default()).unwrap(),
+            [
+                rustls::ProtocolVersion::TLSv1_2,
+                rustls::ProtocolVersion::TLSv1_3
+            ]
+        );
+        assert_eq!(
+            versions(TlsConfig {
+                tls13_only: true,
+                ..Default::default()
+            })
+            .unwrap(),
+            [rustls::ProtocolVersion::TLSv1_3]
+        );
+        assert!(versions(TlsConfig {
+            max_tls_version: Some(TlsVersion::V1_1),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_cipher_suites() {
+        let provider = crypto_provider(&TlsConfig {
+            cipher_suites: Some(vec![
+                "TLS_AES_256_GCM_SHA384".into(),
+                "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".into(),
+            ]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            provider
+                .cipher_suites
+                .iter()
+                .map(|s| s.suite())
+                .collect::<Vec<_>>(),
+            [
+                rustls::CipherSuite::TLS13_AES_256_GCM_SHA384,
+                rustls::CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+            ]
+        );
+
+        assert!(crypto_provider(&TlsConfig {
+            cipher_suites: Some(vec!["TLS_RSA_WITH_RC4_128_MD5".into()]),
+            ..Default::default()
+        })
+        .is_err());
+
+        // No suite for TLS 1.3
+        let tls = TlsConfig {
+            pkcs12: Some("examples/tls/identity.pfx".into()),
+            pkcs12_password: Some("1234".into()),
+            cipher_suites: Some(vec!["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".into()]),
+            tls13_only: true,
+            ..Default::default()
+        };
+        assert!(load_server_config(&tls).is_err());
+    }
 }
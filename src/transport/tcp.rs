@@ -1,9 +1,11 @@
 use crate::{
     config::{TcpConfig, TransportConfig},
-    helper::tcp_connect_with_proxy,
+    env_proxy,
+    helper::{tcp_connect_with_proxy, tcp_listen_reuse_port, try_set_socket_opts, MaybeTlsStream},
 };
 
-use super::{AddrMaybeCached, SocketOpts, Transport};
+use super::obfs::{Obfs, ObfsStream};
+use super::{AddrMaybeCached, ConnectOpts, SocketOpts, Transport};
 use anyhow::Result;
 use async_trait::async_trait;
 use std::net::SocketAddr;
@@ -12,19 +14,32 @@ use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 #[derive(Debug)]
 pub struct TcpTransport {
     socket_opts: SocketOpts,
+    connect_opts: ConnectOpts,
     cfg: TcpConfig,
+    obfs: Option<Obfs>,
+}
+
+impl TcpTransport {
+    fn wrap(&self, conn: impl Into<MaybeTlsStream>) -> ObfsStream {
+        match &self.obfs {
+            Some(v) => v.wrap(conn),
+            None => ObfsStream::plain(conn),
+        }
+    }
 }
 
 #[async_trait]
 impl Transport for TcpTransport {
     type Acceptor = TcpListener;
-    type Stream = TcpStream;
+    type Stream = ObfsStream;
     type RawStream = TcpStream;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         Ok(TcpTransport {
             socket_opts: SocketOpts::from_cfg(&config.tcp),
+            connect_opts: ConnectOpts::from_cfg(config),
             cfg: config.tcp.clone(),
+            obfs: config.obfs.as_ref().map(Obfs::new),
         })
     }
 
@@ -33,7 +48,19 @@ impl Transport for TcpTransport {
     }
 
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
-        Ok(TcpListener::bind(addr).await?)
+        let l = TcpListener::bind(addr).await?;
+        // Inherited by the connections accepted
+        try_set_socket_opts((&l).into(), &self.cfg.socket())?;
+        Ok(l)
+    }
+
+    async fn bind_reuse_port<T: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: T,
+    ) -> Result<Self::Acceptor> {
+        let l = tcp_listen_reuse_port(addr).await?;
+        try_set_socket_opts((&l).into(), &self.cfg.socket())?;
+        Ok(l)
     }
 
     async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
@@ -43,12 +70,18 @@ impl Transport for TcpTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        Ok(conn)
+        Ok(self.wrap(conn))
     }
 
     async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
-        let s = tcp_connect_with_proxy(addr, self.cfg.proxy.as_ref()).await?;
-        self.socket_opts.apply(&s);
-        Ok(s)
+        let proxies = env_proxy::proxies(&self.cfg, &addr.addr)?;
+        let s = tcp_connect_with_proxy(addr, &proxies, &self.connect_opts).await?;
+        self.socket_opts.apply(s.tcp());
+        Ok(self.wrap(s))
+    }
+
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    fn as_plain_tcp(conn: &Self::Stream) -> Option<&TcpStream> {
+        conn.as_plain()
     }
 }
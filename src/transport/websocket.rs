@@ -2,88 +2,27 @@ use core::result::Result;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{ready, Context, Poll};
+use std::task::{Context, Poll};
 
+use super::framing::{FramedTunnel, Frames, SubTransport, TransportStream};
 use super::{AddrMaybeCached, SocketOpts, TcpTransport, TlsTransport, Transport};
 use crate::config::TransportConfig;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context as _};
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures_core::stream::Stream;
 use futures_sink::Sink;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
-#[cfg(any(feature = "native-tls", feature = "rustls"))]
-use super::tls::get_tcpstream;
-#[cfg(any(feature = "native-tls", feature = "rustls"))]
-use super::tls::TlsStream;
-
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{HeaderMap, HeaderName, HeaderValue, HOST};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
-use tokio_tungstenite::{accept_async_with_config, client_async_with_config, WebSocketStream};
-use tokio_util::io::StreamReader;
-use url::Url;
+use tokio_tungstenite::{accept_hdr_async_with_config, client_async_with_config, WebSocketStream};
 
 #[derive(Debug)]
-enum TransportStream {
-    Insecure(TcpStream),
-    Secure(TlsStream<TcpStream>),
-}
-
-impl TransportStream {
-    fn get_tcpstream(&self) -> &TcpStream {
-        match self {
-            TransportStream::Insecure(s) => s,
-            TransportStream::Secure(s) => get_tcpstream(s),
-        }
-    }
-}
-
-impl AsyncRead for TransportStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        match self.get_mut() {
-            TransportStream::Insecure(s) => Pin::new(s).poll_read(cx, buf),
-            TransportStream::Secure(s) => Pin::new(s).poll_read(cx, buf),
-        }
-    }
-}
-
-impl AsyncWrite for TransportStream {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, std::io::Error>> {
-        match self.get_mut() {
-            TransportStream::Insecure(s) => Pin::new(s).poll_write(cx, buf),
-            TransportStream::Secure(s) => Pin::new(s).poll_write(cx, buf),
-        }
-    }
-
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        match self.get_mut() {
-            TransportStream::Insecure(s) => Pin::new(s).poll_flush(cx),
-            TransportStream::Secure(s) => Pin::new(s).poll_flush(cx),
-        }
-    }
-
-    fn poll_shutdown(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Result<(), std::io::Error>> {
-        match self.get_mut() {
-            TransportStream::Insecure(s) => Pin::new(s).poll_shutdown(cx),
-            TransportStream::Secure(s) => Pin::new(s).poll_shutdown(cx),
-        }
-    }
-}
-
-#[derive(Debug)]
-struct StreamWrapper {
+pub struct StreamWrapper {
     inner: WebSocketStream<TransportStream>,
 }
 
@@ -94,9 +33,7 @@ impl Stream for StreamWrapper {
         match Pin::new(&mut self.get_mut().inner).poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(Err(err))) => {
-                Poll::Ready(Some(Err(Error::new(ErrorKind::Other, err))))
-            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(Error::other(err)))),
             Poll::Ready(Some(Ok(res))) => {
                 if let Message::Binary(b) = res {
                     Poll::Ready(Some(Ok(Bytes::from(b))))
@@ -115,71 +52,49 @@ impl Stream for StreamWrapper {
     }
 }
 
-#[derive(Debug)]
-pub struct WebsocketTunnel {
-    inner: StreamReader<StreamWrapper, Bytes>,
-}
-
-impl AsyncRead for WebsocketTunnel {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
-    }
-}
-
-impl AsyncBufRead for WebsocketTunnel {
-    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
-        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
-    }
-
-    fn consume(self: Pin<&mut Self>, amt: usize) {
-        Pin::new(&mut self.get_mut().inner).consume(amt)
-    }
-}
+impl Sink<Bytes> for StreamWrapper {
+    type Error = Error;
 
-impl AsyncWrite for WebsocketTunnel {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, std::io::Error>> {
-        let sw = self.get_mut().inner.get_mut();
-        ready!(Pin::new(&mut sw.inner)
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner)
             .poll_ready(cx)
-            .map_err(|err| Error::new(ErrorKind::Other, err)))?;
+            .map_err(Error::other)
+    }
 
-        match Pin::new(&mut sw.inner).start_send(Message::Binary(buf.to_vec())) {
-            Ok(()) => Poll::Ready(Ok(buf.len())),
-            Err(e) => Poll::Ready(Err(Error::new(ErrorKind::Other, e))),
-        }
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Error> {
+        Pin::new(&mut self.get_mut().inner)
+            .start_send(Message::Binary(item.to_vec()))
+            .map_err(Error::other)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut self.get_mut().inner.get_mut().inner)
+        Pin::new(&mut self.get_mut().inner)
             .poll_flush(cx)
-            .map_err(|err| Error::new(ErrorKind::Other, err))
+            .map_err(Error::other)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut self.get_mut().inner.get_mut().inner)
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner)
             .poll_close(cx)
-            .map_err(|err| Error::new(ErrorKind::Other, err))
+            .map_err(Error::other)
     }
 }
 
-#[derive(Debug)]
-enum SubTransport {
-    Secure(TlsTransport),
-    Insecure(TcpTransport),
+impl Frames for StreamWrapper {
+    fn get_tcpstream(&self) -> &TcpStream {
+        self.inner.get_ref().get_tcpstream()
+    }
 }
 
+pub type WebsocketTunnel = FramedTunnel<StreamWrapper>;
+
 #[derive(Debug)]
 pub struct WebsocketTransport {
     sub: SubTransport,
     conf: WebSocketConfig,
+    path: String,
+    // Set on the upgrade requests of clients, including the `Host` override
+    headers: HeaderMap,
 }
 
 #[async_trait]
@@ -199,14 +114,34 @@ impl Transport for WebsocketTransport {
             ..WebSocketConfig::default()
         };
         let sub = match wsconfig.tls {
-            true => SubTransport::Secure(TlsTransport::new(config)?),
-            false => SubTransport::Insecure(TcpTransport::new(config)?),
+            true => SubTransport::Secure(Box::new(TlsTransport::new(config)?)),
+            false => SubTransport::Insecure(Box::new(TcpTransport::new(config)?)),
         };
-        Ok(WebsocketTransport { sub, conf })
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in &wsconfig.headers {
+            let name = HeaderName::from_bytes(k.as_bytes())
+                .with_context(|| format!("Invalid websocket header name {}", k))?;
+            let value = HeaderValue::from_str(v)
+                .with_context(|| format!("Invalid value of websocket header {}", k))?;
+            headers.insert(name, value);
+        }
+        if let Some(host) = &wsconfig.host {
+            let value = HeaderValue::from_str(host)
+                .with_context(|| format!("Invalid websocket host {}", host))?;
+            headers.insert(HOST, value);
+        }
+
+        Ok(WebsocketTransport {
+            sub,
+            conf,
+            path: wsconfig.path.clone(),
+            headers,
+        })
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn.inner.get_ref().inner.get_ref().get_tcpstream())
+        opt.apply(conn.get_tcpstream())
     }
 
     async fn bind<A: ToSocketAddrs + Send + Sync>(
@@ -216,39 +151,44 @@ impl Transport for WebsocketTransport {
         TcpListener::bind(addr).await.map_err(Into::into)
     }
 
+    async fn bind_reuse_port<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> anyhow::Result<Self::Acceptor> {
+        self.sub.bind_reuse_port(addr).await
+    }
+
     async fn accept(&self, a: &Self::Acceptor) -> anyhow::Result<(Self::RawStream, SocketAddr)> {
-        let (s, addr) = match &self.sub {
-            SubTransport::Insecure(t) => t.accept(a).await?,
-            SubTransport::Secure(t) => t.accept(a).await?,
-        };
-        Ok((s, addr))
+        self.sub.accept(a).await
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> anyhow::Result<Self::Stream> {
-        let tsream = match &self.sub {
-            SubTransport::Insecure(t) => TransportStream::Insecure(t.handshake(conn).await?),
-            SubTransport::Secure(t) => TransportStream::Secure(t.handshake(conn).await?),
-        };
-        let wsstream = accept_async_with_config(tsream, Some(self.conf)).await?;
-        let tun = WebsocketTunnel {
-            inner: StreamReader::new(StreamWrapper { inner: wsstream }),
+        let tsream = self.sub.handshake(conn).await?;
+        // The error type is dictated by tungstenite
+        #[allow(clippy::result_large_err)]
+        let check_path = |req: &Request, resp: Response| {
+            if req.uri().path() == self.path {
+                Ok(resp)
+            } else {
+                let mut resp = ErrorResponse::new(None);
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                Err(resp)
+            }
         };
-        Ok(tun)
+        let wsstream = accept_hdr_async_with_config(tsream, check_path, Some(self.conf)).await?;
+        Ok(FramedTunnel::new(StreamWrapper { inner: wsstream }))
     }
 
     async fn connect(&self, addr: &AddrMaybeCached) -> anyhow::Result<Self::Stream> {
-        let u = format!("ws://{}", &addr.addr.as_str());
-        let url = Url::parse(&u).unwrap();
-        let tstream = match &self.sub {
-            SubTransport::Insecure(t) => TransportStream::Insecure(t.connect(addr).await?),
-            SubTransport::Secure(t) => TransportStream::Secure(t.connect(addr).await?),
-        };
-        let (wsstream, _) = client_async_with_config(url, tstream, Some(self.conf))
+        let url = format!("ws://{}{}", &addr.addr.as_str(), self.path);
+        let mut req = (&url)
+            .into_client_request()
+            .with_context(|| format!("Invalid websocket url {}", url))?;
+        req.headers_mut().extend(self.headers.clone());
+        let tstream = self.sub.connect(addr).await?;
+        let (wsstream, _) = client_async_with_config(req, tstream, Some(self.conf))
             .await
-            .expect("failed to connect");
-        let tun = WebsocketTunnel {
-            inner: StreamReader::new(StreamWrapper { inner: wsstream }),
-        };
-        Ok(tun)
+            .with_context(|| "Failed to do the websocket handshake")?;
+        Ok(FramedTunnel::new(StreamWrapper { inner: wsstream }))
     }
 }
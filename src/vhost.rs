@@ -0,0 +1,402 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{broadcast, mpsc};
+
+// The capacity of the chan of each route
+const ROUTE_CHAN_SIZE: usize = 2048;
+// The maximum length of the body of a TLS record
+const MAX_TLS_RECORD_LEN: usize = 16384;
+// The size of the header of a TLS record
+const TLS_RECORD_HEADER_LEN: usize = 5;
+// The maximum length of the head of an HTTP request
+const MAX_HTTP_HEAD_LEN: usize = 16384;
+// The maximum number of headers of an HTTP request
+const MAX_HTTP_HEADERS: usize = 64;
+
+/// Routes items, e.g. visitors on a port shared by services, to the services by hostname.
+/// `*.example.com` matches any subdomain of `example.com`, and the longest match wins.
+/// Routes are gone once the services drop their receivers.
+pub struct Router<T> {
+    routes: Mutex<HashMap<String, mpsc::Sender<T>>>,
+    // Shutdown the source of items by dropping it
+    _shutdown_tx: broadcast::Sender<bool>,
+}
+
+impl<T> Router<T> {
+    /// Create a router. `shutdown_tx` is dropped along with it
+    pub fn new(shutdown_tx: broadcast::Sender<bool>) -> Router<T> {
+        Router {
+            routes: Default::default(),
+            _shutdown_tx: shutdown_tx,
+        }
+    }
+
+    /// Route `hostnames` to the returned receiver, taking them over from whoever had them
+    pub fn join(&self, hostnames: &[String]) -> mpsc::Receiver<T> {
+        let (tx, rx) = mpsc::channel(ROUTE_CHAN_SIZE);
+        let mut routes = self.routes.lock().unwrap();
+        routes.retain(|_, tx| !tx.is_closed());
+        for hostname in hostnames {
+            routes.insert(hostname.to_ascii_lowercase(), tx.clone());
+        }
+        rx
+    }
+
+    /// Where the item for `hostname` goes, if anyone has it
+    pub fn route(&self, hostname: &str) -> Option<mpsc::Sender<T>> {
+        let hostname = hostname.to_ascii_lowercase();
+        let routes = self.routes.lock().unwrap();
+        let live = |h: &str| routes.get(h).filter(|tx| !tx.is_closed());
+        live(&hostname)
+            .or_else(|| {
+                // Try `*.b.example.com`, then `*.example.com` and so on
+                hostname
+                    .match_indices('.')
+                    .find_map(|(i, _)| live(&format!("*{}", &hostname[i..])))
+            })
+            .cloned()
+    }
+}
+
+/// Read the TLS ClientHello from `conn` for the server name in it.
+/// What is read is appended to `head`, which should be forwarded before the rest of `conn`
+pub async fn read_sni<S: AsyncRead + Unpin>(conn: &mut S, head: &mut Vec<u8>) -> Result<String> {
+    let start = head.len();
+    read_more(conn, head, TLS_RECORD_HEADER_LEN).await?;
+    let header = &head[start..];
+    // A handshake record
+    if header[0] != 0x16 {
+        bail!("Not a TLS handshake");
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_TLS_RECORD_LEN {
+        bail!("Invalid TLS record length {}", len);
+    }
+
+    read_more(conn, head, len).await?;
+    parse_sni(&head[start + TLS_RECORD_HEADER_LEN..])?
+        .ok_or_else(|| anyhow!("No server name in the ClientHello"))
+}
+
+/// Read the head of an HTTP request from `conn` for the hostname in the Host header.
+/// What is read is appended to `head`, which should be forwarded before the rest of `conn`
+pub async fn read_http_host<S: AsyncRead + Unpin>(
+    conn: &mut S,
+    head: &mut Vec<u8>,
+) -> Result<String> {
+    let start = head.len();
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        if req.parse(&head[start..])?.is_complete() {
+            let host = req
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("host"))
+                .ok_or_else(|| anyhow!("No Host header in the HTTP request"))?;
+            return Ok(strip_port(std::str::from_utf8(host.value)?).to_string());
+        }
+
+        if head.len() - start >= MAX_HTTP_HEAD_LEN {
+            bail!("The head of the HTTP request is too large");
+        }
+        let mut buf = [0u8; 4096];
+        let n = conn.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Closed before the head of the HTTP request is complete");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+}
+
+// `example.com` of `example.com:8080`
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((v, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => v,
+        _ => host,
+    }
+}
+
+/// Rewrite the HTTP request in `head`, which is read by `read_http_host`.
+/// The Host header is replaced with `host`, and `forwarded_for` is appended to X-Forwarded-For, if given.
+/// Later requests on the connection are not rewritten, so the backend is told to close it after this one,
+/// unless it's upgraded to another protocol, like WebSocket
+pub fn rewrite_http_head(
+    head: &[u8],
+    host: Option<&str>,
+    forwarded_for: Option<IpAddr>,
+) -> Result<Vec<u8>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HTTP_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    let len = match req.parse(head)? {
+        httparse::Status::Complete(v) => v,
+        httparse::Status::Partial => bail!("Incomplete head of the HTTP request"),
+    };
+
+    let mut ret = format!(
+        "{} {} HTTP/1.{}\r\n",
+        req.method.unwrap_or_default(),
+        req.path.unwrap_or_default(),
+        req.version.unwrap_or_default()
+    )
+    .into_bytes();
+    let upgrade = req.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("connection")
+            && h.value
+                .split(|b| *b == b',')
+                .any(|v| v.trim_ascii().eq_ignore_ascii_case(b"upgrade"))
+    });
+    let mut forwarded_for = forwarded_for.map(|ip| ip.to_string());
+    for h in req.headers.iter() {
+        if !upgrade
+            && (h.name.eq_ignore_ascii_case("connection")
+                || h.name.eq_ignore_ascii_case("keep-alive"))
+        {
+            continue;
+        }
+        ret.extend_from_slice(h.name.as_bytes());
+        ret.extend_from_slice(b": ");
+        match host {
+            Some(host) if h.name.eq_ignore_ascii_case("host") => {
+                ret.extend_from_slice(host.as_bytes())
+            }
+            _ => ret.extend_from_slice(h.value),
+        }
+        if h.name.eq_ignore_ascii_case("x-forwarded-for") {
+            if let Some(ip) = forwarded_for.take() {
+                ret.extend_from_slice(format!(", {}", ip).as_bytes());
+            }
+        }
+        ret.extend_from_slice(b"\r\n");
+    }
+    if let Some(ip) = forwarded_for {
+        ret.extend_from_slice(format!("X-Forwarded-For: {}\r\n", ip).as_bytes());
+    }
+    if !upgrade {
+        ret.extend_from_slice(b"Connection: close\r\n");
+    }
+    ret.extend_from_slice(b"\r\n");
+    ret.extend_from_slice(&head[len..]);
+    Ok(ret)
+}
+
+// Read `n` more bytes from `conn` into `buf`
+async fn read_more<S: AsyncRead + Unpin>(conn: &mut S, buf: &mut Vec<u8>, n: usize) -> Result<()> {
+    let start = buf.len();
+    buf.resize(start + n, 0);
+    conn.read_exact(&mut buf[start..]).await?;
+    Ok(())
+}
+
+// Reads fields of a TLS message
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("Truncated ClientHello");
+        }
+        let (v, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(v)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<usize> {
+        Ok(self.take(n)?.iter().fold(0, |v, b| v << 8 | *b as usize))
+    }
+
+    // A vector prefixed with its length of `n` bytes
+    fn vec(&mut self, n: usize) -> Result<Fields<'a>> {
+        let len = self.uint(n)?;
+        self.take(len).map(Fields)
+    }
+}
+
+// Find the server name in the ClientHello in the body of a handshake record
+fn parse_sni(record: &[u8]) -> Result<Option<String>> {
+    let mut record = Fields(record);
+    // A ClientHello
+    if record.uint(1)? != 1 {
+        bail!("Not a ClientHello");
+    }
+    let mut hello = record.vec(3)?;
+    // The version and the random
+    hello.take(2 + 32)?;
+    // The session id, the cipher suites and the compression methods
+    hello.vec(1)?;
+    hello.vec(2)?;
+    hello.vec(1)?;
+    if hello.0.is_empty() {
+        return Ok(None);
+    }
+
+    let mut extensions = hello.vec(2)?;
+    while !extensions.0.is_empty() {
+        let ty = extensions.uint(2)?;
+        let mut data = extensions.vec(2)?;
+        // The server_name extension
+        if ty != 0 {
+            continue;
+        }
+        let mut names = data.vec(2)?;
+        while !names.0.is_empty() {
+            let name_type = names.uint(1)?;
+            let name = names.vec(2)?;
+            // A host_name
+            if name_type == 0 {
+                return Ok(Some(String::from_utf8(name.0.to_vec())?));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ClientHello record for `hostname`, with the extensions before and after server_name
+    fn client_hello(hostname: &str) -> Vec<u8> {
+        let name = hostname.as_bytes();
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00];
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+        extensions.extend_from_slice(&[0x00, 0x17, 0x00, 0x00]);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[tokio::test]
+    async fn test_read_sni() {
+        let record = client_hello("example.com");
+        let conn = [record.as_slice(), b"rest"].concat();
+        let mut conn = conn.as_slice();
+        let mut head = Vec::new();
+        assert_eq!(read_sni(&mut conn, &mut head).await.unwrap(), "example.com");
+        // Everything read is kept, and nothing more
+        assert_eq!(head, record);
+        assert_eq!(conn, b"rest");
+
+        let mut head = Vec::new();
+        assert!(read_sni(&mut &b"GET / HTTP/1.1\r\n\r\n"[..], &mut head)
+            .await
+            .is_err());
+        let truncated = &record[..record.len() - 1];
+        assert!(read_sni(&mut &truncated[..], &mut Vec::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_http_host() {
+        let req = b"GET / HTTP/1.1\r\nHost: Example.com:8080\r\n\r\nbody";
+        let mut conn = &req[..];
+        let mut head = Vec::new();
+        assert_eq!(
+            read_http_host(&mut conn, &mut head).await.unwrap(),
+            "Example.com"
+        );
+        assert_eq!(head, req);
+
+        let req = b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n";
+        assert_eq!(
+            read_http_host(&mut &req[..], &mut Vec::new())
+                .await
+                .unwrap(),
+            "[::1]"
+        );
+
+        for req in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"GET / HTTP/1.1\r\nHost: a"] {
+            assert!(read_http_host(&mut &req[..], &mut Vec::new())
+                .await
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_rewrite_http_head() {
+        let ip = "10.0.0.1".parse().ok();
+        let head = b"POST /x HTTP/1.1\r\nhost: a.com\r\nContent-Length: 4\r\n\r\nbody";
+        assert_eq!(
+            rewrite_http_head(head, Some("localhost"), ip).unwrap(),
+            b"POST /x HTTP/1.1\r\nhost: localhost\r\nContent-Length: 4\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: close\r\n\r\nbody"
+        );
+
+        let head = b"GET / HTTP/1.0\r\nHost: a.com\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n";
+        assert_eq!(
+            rewrite_http_head(head, None, ip).unwrap(),
+            b"GET / HTTP/1.0\r\nHost: a.com\r\nX-Forwarded-For: 1.2.3.4, 10.0.0.1\r\nConnection: close\r\n\r\n"
+        );
+
+        // Later requests with a spoofed X-Forwarded-For would go as is, so keep-alive is turned off
+        let head = b"GET / HTTP/1.1\r\nHost: a.com\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\n";
+        assert_eq!(
+            rewrite_http_head(head, None, ip).unwrap(),
+            b"GET / HTTP/1.1\r\nHost: a.com\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: close\r\n\r\n"
+        );
+        // But not an upgrade, after which there are no more requests
+        let head = b"GET / HTTP/1.1\r\nHost: a.com\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(rewrite_http_head(head, None, None).unwrap(), head);
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let router = Router::new(shutdown_tx);
+        let mut a = router.join(&["a.example.com".into(), "*.example.com".into()]);
+        let b = router.join(&["*.b.example.com".into()]);
+
+        router
+            .route("A.example.com")
+            .unwrap()
+            .send(1)
+            .await
+            .unwrap();
+        router
+            .route("c.example.com")
+            .unwrap()
+            .send(2)
+            .await
+            .unwrap();
+        assert_eq!(a.recv().await, Some(1));
+        assert_eq!(a.recv().await, Some(2));
+        assert!(router.route("example.com").is_none());
+        assert!(router
+            .route("x.y.b.example.com")
+            .unwrap()
+            .same_channel(&router.route("y.b.example.com").unwrap()));
+
+        // Gone with the receiver
+        drop(b);
+        assert!(router
+            .route("x.b.example.com")
+            .unwrap()
+            .send(3)
+            .await
+            .is_ok());
+        assert_eq!(a.recv().await, Some(3));
+    }
+}
@@ -1,10 +1,16 @@
 use anyhow::{Ok, Result};
 use common::{run_rathole_client, PING, PONG};
 use rand::Rng;
-use std::time::Duration;
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
+    fs,
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpStream, UdpSocket},
+    process::Command,
     sync::broadcast,
     time,
 };
@@ -21,6 +27,39 @@ const ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2334";
 const PINGPONG_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2335";
 const HITTER_NUM: usize = 4;
 
+const STDIN_ECHO_SERVER_ADDR: &str = "127.0.0.1:8090";
+const STDIN_ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2341";
+
+const TOKEN_ROTATION_ECHO_SERVER_ADDR: &str = "127.0.0.1:8091";
+const TOKEN_ROTATION_ECHO_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2343";
+
+const ECHO_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2345";
+
+const MULTI_CLIENT_SERVER_A_ADDR: &str = "127.0.0.1:8092";
+const MULTI_CLIENT_SERVER_B_ADDR: &str = "127.0.0.1:8093";
+const MULTI_CLIENT_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2347";
+
+const SNI_SERVER_A_ADDR: &str = "127.0.0.1:8095";
+const SNI_SERVER_B_ADDR: &str = "127.0.0.1:8096";
+const SNI_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2352";
+
+const HTTP_SERVER_ADDR_EXPOSED: &str = "127.0.0.1:2354";
+
+const REGISTERED_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2357";
+
+const GRACE_PERIOD_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2362";
+
+const DRAIN_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2374";
+const DRAIN_API_ADDR: &str = "127.0.0.1:2375";
+
+const HOT_RELOAD_SERVER_ADDR: &str = "127.0.0.1:2365";
+const HOT_RELOAD_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2366";
+
+const BOTH_ROLES_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2369";
+
+const REGISTRY_ADDR: &str = "127.0.0.1:2371";
+const DISCOVERED_SERVICE_ADDR_EXPOSED: &str = "127.0.0.1:2372";
+
 #[derive(Clone, Copy, Debug)]
 enum Type {
     Tcp,
@@ -55,6 +94,8 @@ async fn tcp() -> Result<()> {
     });
 
     test("tests/for_tcp/tcp_transport.toml", Type::Tcp).await?;
+    test("tests/for_tcp/mux_transport.toml", Type::Tcp).await?;
+    test("tests/for_tcp/mux_coalescing_transport.toml", Type::Tcp).await?;
     // FIXME: Self-signed certificate on Mac requires mannual interference. Disable CI for now
     #[cfg(not(target_os = "macos"))]
     #[cfg(any(feature = "native-tls", feature = "rustls"))]
@@ -63,6 +104,9 @@ async fn tcp() -> Result<()> {
     #[cfg(feature = "noise")]
     test("tests/for_tcp/noise_transport.toml", Type::Tcp).await?;
 
+    #[cfg(feature = "noise")]
+    test("tests/for_tcp/obfs_transport.toml", Type::Tcp).await?;
+
     #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
     test("tests/for_tcp/websocket_transport.toml", Type::Tcp).await?;
 
@@ -70,6 +114,9 @@ async fn tcp() -> Result<()> {
     #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
     test("tests/for_tcp/websocket_tls_transport.toml", Type::Tcp).await?;
 
+    #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+    test("tests/for_tcp/http_transport.toml", Type::Tcp).await?;
+
     Ok(())
 }
 
@@ -107,6 +154,597 @@ async fn udp() -> Result<()> {
     #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
     test("tests/for_udp/websocket_tls_transport.toml", Type::Udp).await?;
 
+    #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+    test("tests/for_udp/http_transport.toml", Type::Udp).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stdin_config() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(STDIN_ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    // Pipe the config to the server
+    let config_path = "tests/for_stdin/tcp_transport.toml";
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rathole"))
+        .args(["--server", "-"])
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = server.stdin.take().unwrap();
+    stdin
+        .write_all(fs::read_to_string(config_path).await?.as_bytes())
+        .await?;
+    drop(stdin);
+
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    // The service defined in the piped config is exposed
+    echo_hitter(STDIN_ECHO_SERVER_ADDR_EXPOSED, Type::Tcp).await?;
+
+    client_shutdown_tx.send(true)?;
+    let _ = tokio::join!(client);
+    server.kill().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn token_rotation() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = common::tcp::echo_server(TOKEN_ROTATION_ECHO_SERVER_ADDR).await {
+            panic!("Failed to run the echo server for testing: {:?}", e);
+        }
+    });
+
+    // The server only accepts `token_next` of the client
+    let config_path = "tests/for_token_rotation/tcp_transport.toml";
+    let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(500)).await; // Wait for the server to start
+
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to switch tokens
+
+    echo_hitter(TOKEN_ROTATION_ECHO_SERVER_ADDR_EXPOSED, Type::Tcp).await?;
+
+    client_shutdown_tx.send(true)?;
+    server_shutdown_tx.send(true)?;
+    let _ = tokio::join!(client, server);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn echo_service() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // No echo server is needed. The client echoes by itself
+    let config_path = "tests/for_echo/tcp_transport.toml";
+    let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    echo_hitter(ECHO_SERVICE_ADDR_EXPOSED, Type::Tcp).await?;
+
+    client_shutdown_tx.send(true)?;
+    server_shutdown_tx.send(true)?;
+    let _ = tokio::join!(client, server);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn both_roles() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // Neither `--server` nor `--client`, so both run in one process
+    let cli = rathole::Cli {
+        config_path: Some("tests/for_both/tcp_transport.toml".into()),
+        ..Default::default()
+    };
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let instance = tokio::spawn(async move { rathole::run(cli, shutdown_rx).await.unwrap() });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    echo_hitter(BOTH_ROLES_SERVICE_ADDR_EXPOSED, Type::Tcp).await?;
+
+    shutdown_tx.send(true)?;
+    let _ = instance.await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn hot_reload() -> Result<()> {
+    init();
+
+    if cfg!(not(all(
+        feature = "client",
+        feature = "server",
+        feature = "hot-reload"
+    ))) {
+        return Ok(());
+    }
+
+    // The config is changed in place, so work on a copy
+    let config_path =
+        std::env::temp_dir().join(format!("rathole_hot_reload_{}.toml", std::process::id()));
+    fs::copy("tests/for_hot_reload/tcp_transport.toml", &config_path).await?;
+    let path = config_path.to_str().unwrap().to_owned();
+    let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let server = tokio::spawn(async move {
+        run_rathole_server(&path, server_shutdown_rx).await.unwrap();
+    });
+    let path = config_path.to_str().unwrap().to_owned();
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(&path, client_shutdown_rx).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    let mut conn = TcpStream::connect(HOT_RELOAD_SERVICE_ADDR_EXPOSED).await?;
+    let mut buf = [0u8; PING.len()];
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+
+    // Move the server to another port, and change the heartbeat
+    fs::copy("tests/for_hot_reload/reloaded.toml", &config_path).await?;
+    time::sleep(Duration::from_millis(3000)).await; // Wait for the client to connect again
+
+    // The visitor keeps being forwarded, and new ones go through the new control channel
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(&buf, PING.as_bytes());
+    echo_hitter(HOT_RELOAD_SERVICE_ADDR_EXPOSED, Type::Tcp).await?;
+    assert!(TcpStream::connect(HOT_RELOAD_SERVER_ADDR).await.is_err());
+
+    client_shutdown_tx.send(true)?;
+    server_shutdown_tx.send(true)?;
+    let _ = tokio::join!(client, server);
+    let _ = fs::remove_file(&config_path).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn grace_period() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    let config_path = "tests/for_grace_period/tcp_transport.toml";
+    let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let mut server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    let mut conn = TcpStream::connect(GRACE_PERIOD_SERVICE_ADDR_EXPOSED).await?;
+    let mut buf = [0u8; PING.len()];
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+
+    // New visitors are refused, but the existing one goes on
+    server_shutdown_tx.send(true)?;
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(GRACE_PERIOD_SERVICE_ADDR_EXPOSED)
+        .await
+        .is_err());
+    assert!(!server.is_finished());
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(buf, PING.as_bytes());
+
+    // The server exits once the visitor is gone, before the grace period ends
+    drop(conn);
+    time::timeout(Duration::from_secs(3), &mut server).await??;
+
+    client_shutdown_tx.send(true)?;
+    let _ = client.await;
+
+    Ok(())
+}
+
+// `POST /drain` to the admin API, returning the response body
+async fn drain_request() -> Result<String> {
+    let mut conn = TcpStream::connect(DRAIN_API_ADDR).await?;
+    conn.write_all(
+        b"POST /drain HTTP/1.1\r\nHost: rathole\r\nAuthorization: Bearer admin_secret\r\n\r\n",
+    )
+    .await?;
+    let mut resp = String::new();
+    conn.read_to_string(&mut resp).await?;
+    assert!(resp.contains(" 200 OK\r\n"), "{}", resp);
+    Ok(resp
+        .split("\r\n\r\n")
+        .nth(1)
+        .unwrap_or_default()
+        .to_string())
+}
+
+#[tokio::test]
+async fn drain() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    let config_path = "tests/for_drain/tcp_transport.toml";
+    // Never sent. The admin API makes the server exit
+    let (_server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let mut server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    let mut conn = TcpStream::connect(DRAIN_SERVICE_ADDR_EXPOSED).await?;
+    let mut buf = [0u8; PING.len()];
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+
+    // New visitors are refused, but the server waits for the existing one
+    assert_eq!(drain_request().await?, "remaining = 1\n");
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(DRAIN_SERVICE_ADDR_EXPOSED)
+        .await
+        .is_err());
+    assert!(!server.is_finished());
+    conn.write_all(PING.as_bytes()).await?;
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(buf, PING.as_bytes());
+
+    // Asking again tells the progress
+    assert_eq!(drain_request().await?, "remaining = 1\n");
+
+    // The server exits once the visitor is gone
+    drop(conn);
+    time::timeout(Duration::from_secs(3), &mut server).await??;
+    assert!(TcpStream::connect(DRAIN_API_ADDR).await.is_err());
+
+    client_shutdown_tx.send(true)?;
+    let _ = client.await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn registration() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // The server doesn't configure the service, but lets the client register it
+    let config_path = "tests/for_registration/tcp_transport.toml";
+    let (server_shutdown_tx, server_shutdown_rx) = broadcast::channel(1);
+    let server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let (client_shutdown_tx, client_shutdown_rx) = broadcast::channel(1);
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    echo_hitter(REGISTERED_SERVICE_ADDR_EXPOSED, Type::Tcp).await?;
+
+    // The service is gone with the client
+    client_shutdown_tx.send(true)?;
+    let _ = client.await;
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(TcpStream::connect(REGISTERED_SERVICE_ADDR_EXPOSED)
+        .await
+        .is_err());
+
+    server_shutdown_tx.send(true)?;
+    let _ = server.await;
+
+    Ok(())
+}
+
+// A registry of services that serves `doc` to requests with the token
+async fn registry(doc: Arc<Mutex<String>>) -> Result<()> {
+    let l = tokio::net::TcpListener::bind(REGISTRY_ADDR).await?;
+    loop {
+        let (mut conn, _) = l.accept().await?;
+        let mut buf = [0u8; 1024];
+        let n = conn.read(&mut buf).await?;
+        let req = String::from_utf8_lossy(&buf[..n]);
+        let resp = match req.contains("\r\nAuthorization: Bearer registry_token\r\n") {
+            true => format!("HTTP/1.0 200 OK\r\n\r\n{}", doc.lock().unwrap()),
+            false => String::from("HTTP/1.0 401 Unauthorized\r\n\r\n"),
+        };
+        conn.write_all(resp.as_bytes()).await?;
+    }
+}
+
+#[tokio::test]
+async fn discovery() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // The client has no services until they show up in the registry
+    let doc = Arc::new(Mutex::new(String::new()));
+    tokio::spawn(registry(doc.clone()));
+    let config_path = "tests/for_discovery/tcp_transport.toml";
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let server = tokio::spawn(run_rathole_server(config_path, shutdown_tx.subscribe()));
+    let client = tokio::spawn(run_rathole_client(config_path, shutdown_tx.subscribe()));
+    time::sleep(Duration::from_millis(2500)).await;
+    assert!(TcpStream::connect(DISCOVERED_SERVICE_ADDR_EXPOSED)
+        .await
+        .is_err());
+
+    // Added to the registry mid-run
+    *doc.lock().unwrap() = String::from("[services.discovered]\ntype = \"echo\"\n");
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the next poll and the control channel
+    echo_hitter(DISCOVERED_SERVICE_ADDR_EXPOSED, Type::Tcp).await?;
+
+    shutdown_tx.send(true)?;
+    client.await??;
+    server.await??;
+    Ok(())
+}
+
+// Tell visitors who serves them, and close
+async fn name_server(addr: &'static str, name: &'static str) -> Result<()> {
+    let l = tokio::net::TcpListener::bind(addr).await?;
+    loop {
+        let (mut conn, _) = l.accept().await?;
+        conn.write_all(name.as_bytes()).await?;
+    }
+}
+
+#[tokio::test]
+async fn multi_client() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // Each client forwards to a different local service
+    tokio::spawn(name_server(MULTI_CLIENT_SERVER_A_ADDR, "a"));
+    tokio::spawn(name_server(MULTI_CLIENT_SERVER_B_ADDR, "b"));
+
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let server_shutdown_rx = shutdown_tx.subscribe();
+    let server = tokio::spawn(async move {
+        run_rathole_server("tests/for_multi_client/client_a.toml", server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let mut clients = Vec::new();
+    for config_path in [
+        "tests/for_multi_client/client_a.toml",
+        "tests/for_multi_client/client_b.toml",
+    ] {
+        let client_shutdown_rx = shutdown_tx.subscribe();
+        clients.push(tokio::spawn(async move {
+            run_rathole_client(config_path, client_shutdown_rx)
+                .await
+                .unwrap();
+        }));
+    }
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the clients to connect
+
+    // Visitors are served by both clients
+    let mut served = Vec::new();
+    for _ in 0..32 {
+        let mut conn = TcpStream::connect(MULTI_CLIENT_SERVER_ADDR_EXPOSED).await?;
+        let mut name = String::new();
+        conn.read_to_string(&mut name).await?;
+        served.push(name);
+    }
+    assert!(served.iter().any(|v| v == "a"), "{:?}", served);
+    assert!(served.iter().any(|v| v == "b"), "{:?}", served);
+
+    shutdown_tx.send(true)?;
+    for client in clients {
+        let _ = client.await;
+    }
+    let _ = server.await;
+
+    Ok(())
+}
+
+// A TLS ClientHello record with nothing but the server name
+fn client_hello(hostname: &str) -> Vec<u8> {
+    let name = hostname.as_bytes();
+    let len = |n: usize| (n as u16).to_be_bytes();
+
+    let mut sni = [&len(name.len() + 3)[..], &[0], &len(name.len()), name].concat();
+    sni = [&[0, 0][..], &len(sni.len()), &sni].concat();
+    let mut hello = [&[3, 3][..], &[0; 32], &[0, 0, 2, 0x13, 1, 1, 0]].concat();
+    hello.extend_from_slice(&len(sni.len()));
+    hello.extend_from_slice(&sni);
+    let handshake = [&[1, 0][..], &len(hello.len()), &hello].concat();
+    [&[0x16, 3, 1][..], &len(handshake.len()), &handshake].concat()
+}
+
+#[tokio::test]
+async fn sni_routing() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // The services share a port, and each client forwards to a different local service
+    tokio::spawn(name_server(SNI_SERVER_A_ADDR, "a"));
+    tokio::spawn(name_server(SNI_SERVER_B_ADDR, "b"));
+
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let server_shutdown_rx = shutdown_tx.subscribe();
+    let server = tokio::spawn(async move {
+        run_rathole_server("tests/for_sni/client_a.toml", server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let mut clients = Vec::new();
+    for config_path in ["tests/for_sni/client_a.toml", "tests/for_sni/client_b.toml"] {
+        let client_shutdown_rx = shutdown_tx.subscribe();
+        clients.push(tokio::spawn(async move {
+            run_rathole_client(config_path, client_shutdown_rx)
+                .await
+                .unwrap();
+        }));
+    }
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the clients to connect
+
+    for (hostname, expected) in [("a.test", "a"), ("x.b.test", "b"), ("A.TEST", "a")] {
+        let mut conn = TcpStream::connect(SNI_SERVER_ADDR_EXPOSED).await?;
+        conn.write_all(&client_hello(hostname)).await?;
+        let mut name = String::new();
+        conn.read_to_string(&mut name).await?;
+        assert_eq!(name, expected, "{}", hostname);
+    }
+
+    // Visitors for nobody are closed
+    let mut conn = TcpStream::connect(SNI_SERVER_ADDR_EXPOSED).await?;
+    conn.write_all(&client_hello("c.test")).await?;
+    let mut name = String::new();
+    conn.read_to_string(&mut name).await?;
+    assert!(name.is_empty());
+
+    shutdown_tx.send(true)?;
+    for client in clients {
+        let _ = client.await;
+    }
+    let _ = server.await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_routing() -> Result<()> {
+    init();
+
+    if cfg!(not(all(feature = "client", feature = "server"))) {
+        return Ok(());
+    }
+
+    // Both services echo, so visitors see the requests as forwarded
+    let config_path = "tests/for_http/tcp_transport.toml";
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let server_shutdown_rx = shutdown_tx.subscribe();
+    let server = tokio::spawn(async move {
+        run_rathole_server(config_path, server_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    let client_shutdown_rx = shutdown_tx.subscribe();
+    let client = tokio::spawn(async move {
+        run_rathole_client(config_path, client_shutdown_rx)
+            .await
+            .unwrap();
+    });
+    time::sleep(Duration::from_millis(2500)).await; // Wait for the client to connect
+
+    let cases = [
+        (
+            "GET / HTTP/1.1\r\nHost: a.test\r\n\r\nping",
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 127.0.0.1\r\nConnection: close\r\n\r\nping",
+        ),
+        (
+            "GET / HTTP/1.1\r\nHost: x.b.test:2354\r\n\r\nping",
+            "GET / HTTP/1.1\r\nHost: x.b.test:2354\r\n\r\nping",
+        ),
+    ];
+    for (req, expected) in cases {
+        let mut conn = TcpStream::connect(HTTP_SERVER_ADDR_EXPOSED).await?;
+        conn.write_all(req.as_bytes()).await?;
+        let mut buf = vec![0u8; expected.len()];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(String::from_utf8(buf)?, expected);
+    }
+
+    // Visitors for nobody are closed
+    let mut conn = TcpStream::connect(HTTP_SERVER_ADDR_EXPOSED).await?;
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: c.test\r\n\r\n")
+        .await?;
+    assert_eq!(conn.read(&mut [0u8; 1]).await?, 0);
+
+    shutdown_tx.send(true)?;
+    let _ = tokio::join!(client, server);
+
     Ok(())
 }
 